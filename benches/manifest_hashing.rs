@@ -0,0 +1,165 @@
+//! Benchmark of the content-hash manifest on a 100k-file tree
+//!
+//! remotebuild is a binary crate, so the manifest and pattern modules are
+//! compiled straight into this benchmark, with stand-ins for the few items of
+//! the binary they refer to (the state directory, `stable_hash`, `Config`),
+//! which only matter for loading and saving manifests.
+//!
+//! Run it with `cargo bench --bench manifest_hashing`. The tree is generated
+//! in the temp directory; `REMOTEBUILD_BENCH_FILES` changes its size. Three
+//! cases are timed, each as the median of several runs:
+//! - a full hash, as on the first sync or after the sync options changed
+//! - an unchanged tree, where every hash is reused from the size and mtime
+//! - a tree with 1% of its files rewritten
+
+#![allow(dead_code, clippy::unwrap_used)]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[path = "../src/manifest.rs"]
+mod manifest;
+// Checking benches sets `cfg(test)` without the test harness, which leaves
+// the module's tests half compiled
+#[path = "../src/patterns.rs"]
+#[cfg_attr(test, allow(unused_imports, clippy::missing_docs_in_private_items))]
+mod patterns;
+
+use manifest::Manifest;
+use patterns::ExcludeSet;
+
+/// Files generated unless `REMOTEBUILD_BENCH_FILES` says otherwise
+const DEFAULT_FILES: usize = 100_000;
+
+/// Files per generated directory
+const FILES_PER_DIR: usize = 500;
+
+/// Runs per case, of which the median is reported
+const RUNS: usize = 5;
+
+/// Stand-in for the binary's state directory
+mod state {
+    use std::path::{Path, PathBuf};
+
+    /// Location of a project's state file, in the temp directory
+    pub(crate) fn project_state_path(_project_dir: &Path, extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("remotebuild-bench.{}", extension))
+    }
+}
+
+/// Stand-in for the binary's FNV-1a hash
+fn stable_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Stand-in for the settings the manifest keys its file by
+struct Config {
+    /// SSH host
+    host: String,
+    /// Remote project directory
+    remote_path: String,
+}
+
+fn main() {
+    let files = env::var("REMOTEBUILD_BENCH_FILES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_FILES);
+    let root = env::temp_dir().join(format!("remotebuild-bench-{}", std::process::id()));
+    let bytes = generate(&root, files);
+    // Hashes are only reused for files older than the manifest's second
+    wait_for_next_second();
+
+    let excludes = ExcludeSet::new(["build/", "*.o"]);
+    let build = |previous: Option<&Manifest>| {
+        Manifest::build(&root, &excludes, String::new(), previous).unwrap()
+    };
+
+    let full = median(|| {
+        build(None);
+    });
+    let previous = build(None);
+    wait_for_next_second();
+    let unchanged = median(|| {
+        build(Some(&previous));
+    });
+    let rewritten = files / 100;
+    for i in 0..rewritten {
+        fs::write(file_path(&root, i * 100), format!("rewritten {}", i)).unwrap();
+    }
+    let changed = median(|| {
+        build(Some(&previous));
+    });
+
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    println!(
+        "manifest hashing: {} files, {:.1} MB, {} threads",
+        files,
+        bytes as f64 / 1_000_000.0,
+        threads
+    );
+    println!("  full hash   {:>8.3} s", full.as_secs_f64());
+    println!("  unchanged   {:>8.3} s", unchanged.as_secs_f64());
+    println!(
+        "  1% changed  {:>8.3} s  ({} files rewritten)",
+        changed.as_secs_f64(),
+        rewritten
+    );
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+/// Path of the `i`th generated file
+fn file_path(root: &Path, i: usize) -> PathBuf {
+    root.join(format!(
+        "src/dir{:04}/file{:04}.rs",
+        i / FILES_PER_DIR,
+        i % FILES_PER_DIR
+    ))
+}
+
+/// Write `files` files of 100 B to 8 KB below `root`, plus excluded build
+/// output, returning the bytes written
+fn generate(root: &Path, files: usize) -> u64 {
+    let _ = fs::remove_dir_all(root);
+    let mut total = 0;
+    for i in 0..files {
+        let path = file_path(root, i);
+        if i % FILES_PER_DIR == 0 {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+        }
+        let size = 100 + (i * 7919) % 8_000;
+        let contents: Vec<u8> = (0..size).map(|j| (i + j) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        total += size as u64;
+    }
+    fs::create_dir_all(root.join("build")).unwrap();
+    fs::write(root.join("build/app.o"), vec![0; 1 << 20]).unwrap();
+    total
+}
+
+/// Sleep until the wall clock enters a new second
+fn wait_for_next_second() {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    thread::sleep(Duration::from_nanos(
+        1_000_000_000 - u64::from(now.subsec_nanos()) + 1_000_000,
+    ));
+}
+
+/// Median duration of [`RUNS`] runs of `f`
+fn median(mut f: impl FnMut()) -> Duration {
+    let mut times: Vec<Duration> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[RUNS / 2]
+}
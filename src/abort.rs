@@ -0,0 +1,358 @@
+//! Leaving the terminal usable when a run ends abnormally
+//!
+//! A [`RunGuard`] is held while a build pipeline runs and knows its current
+//! phase: by every single build (batch projects included), by each matrix
+//! platform and by each workspace component, after the workspace's shared
+//! sync. When remotebuild panics or receives SIGINT, SIGTERM or SIGHUP, the
+//! half-drawn status line is erased, the cursor is shown again, stdout is
+//! flushed, and every running pipeline gets one line on stderr such as
+//! `🛑 Aborted during build after 41.2s (interrupted)`, so the shell prompt
+//! doesn't hide where the run stopped.
+//!
+//! Signals are turned into a byte on a pipe by the handler, and a thread
+//! reading the pipe does the reporting and exits with `128 + signal`, so
+//! nothing but `write` runs in signal context. SIGKILL can't be caught.
+
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::panic;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::Instant;
+
+/// Erases the current line and shows the cursor
+const RESTORE_TERMINAL: &str = "\r\x1b[2K\x1b[?25h";
+
+/// Pipelines currently running, keyed by guard id
+static RUNS: Mutex<BTreeMap<u64, Run>> = Mutex::new(BTreeMap::new());
+
+/// Id of the next guard
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Installs the panic hook and signal handlers once per process
+static INSTALL: Once = Once::new();
+
+/// A running pipeline
+struct Run {
+    /// Name shown before the report when several pipelines run at once
+    label: Option<String>,
+    /// Phase currently running
+    phase: &'static str,
+    /// When the pipeline started
+    started: Instant,
+}
+
+/// Registration of a running pipeline, removed again when dropped
+pub(crate) struct RunGuard {
+    /// Key in [`RUNS`]
+    id: u64,
+}
+
+impl RunGuard {
+    /// Register a pipeline, named by `label` in the report when several run
+    /// at once, installing the handlers on first use
+    pub(crate) fn new(label: Option<String>) -> Self {
+        INSTALL.call_once(|| {
+            install_panic_hook();
+            #[cfg(unix)]
+            signals::install();
+        });
+        Self::register(label)
+    }
+
+    /// Register a pipeline without installing the handlers
+    fn register(label: Option<String>) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        if let Ok(mut runs) = RUNS.lock() {
+            runs.insert(
+                id,
+                Run {
+                    label,
+                    phase: "start",
+                    started: Instant::now(),
+                },
+            );
+        }
+        Self { id }
+    }
+
+    /// Record that the pipeline entered `phase`
+    pub(crate) fn enter(&self, phase: &'static str) {
+        if let Ok(mut runs) = RUNS.lock() {
+            if let Some(run) = runs.get_mut(&self.id) {
+                run.phase = phase;
+            }
+        }
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        // A panicking pipeline was already reported by the hook
+        if let Ok(mut runs) = RUNS.lock() {
+            runs.remove(&self.id);
+        }
+    }
+}
+
+/// Report the abort after the default panic message
+fn install_panic_hook() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default(info);
+        report("panicked");
+    }));
+}
+
+/// Erase a half-drawn status line and show the cursor, if stdout is a
+/// terminal
+fn restore_terminal() {
+    let stdout = std::io::stdout();
+    let terminal = stdout.is_terminal();
+    restore_terminal_to(&mut stdout.lock(), terminal);
+}
+
+/// Restore the terminal written to by `out`, flushing what it holds
+fn restore_terminal_to(out: &mut impl Write, terminal: bool) {
+    if terminal {
+        let _ = out.write_all(RESTORE_TERMINAL.as_bytes());
+    }
+    let _ = out.flush();
+}
+
+/// Print where each running pipeline stopped
+fn report(reason: &str) {
+    report_to(&mut std::io::stderr().lock(), reason);
+}
+
+/// Write where each running pipeline stopped to `out`
+fn report_to(out: &mut impl Write, reason: &str) {
+    // A panic while the lock is held must not keep the report from printing
+    let runs = match RUNS.lock() {
+        Ok(runs) => runs,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    for run in runs.values() {
+        let label = match &run.label {
+            Some(label) => format!("{}: ", label),
+            None => String::new(),
+        };
+        let _ = writeln!(
+            out,
+            "🛑 {}Aborted during {} after {:.1}s ({})",
+            label,
+            run.phase,
+            run.started.elapsed().as_secs_f64(),
+            reason
+        );
+    }
+    let _ = out.flush();
+}
+
+/// Signal handling through a pipe read by a reporting thread
+#[cfg(unix)]
+mod signals {
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+
+    /// Write end of the pipe, used by the handler
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    /// Signals that end the run with a report
+    const SIGNALS: [libc::c_int; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+    /// Pass the signal number to the reporting thread
+    extern "C" fn on_signal(signal: libc::c_int) {
+        let fd = PIPE.load(Ordering::SeqCst);
+        if fd >= 0 {
+            let byte = signal as u8;
+            // SAFETY: write is async-signal-safe and the buffer outlives the
+            // call
+            unsafe {
+                libc::write(fd, std::ptr::addr_of!(byte).cast(), 1);
+            }
+        }
+    }
+
+    /// Start the reporting thread and install the handlers
+    pub(super) fn install() {
+        let mut fds = [0; 2];
+        // SAFETY: fds has room for the two descriptors pipe writes
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return;
+        }
+        PIPE.store(fds[1], Ordering::SeqCst);
+        // SAFETY: the read end was just created and is owned by nothing else
+        let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+
+        let spawned = thread::Builder::new()
+            .name("signals".to_string())
+            .spawn(move || {
+                let mut byte = [0u8];
+                if reader.read_exact(&mut byte).is_err() {
+                    return;
+                }
+                let signal = libc::c_int::from(byte[0]);
+                super::restore_terminal();
+                super::report(match signal {
+                    libc::SIGINT => "interrupted",
+                    libc::SIGHUP => "terminal closed",
+                    _ => "terminated",
+                });
+                crate::ssh_master::close_unpersisted();
+                std::process::exit(128 + signal);
+            });
+        if spawned.is_err() {
+            return;
+        }
+
+        let handler: extern "C" fn(libc::c_int) = on_signal;
+        for signal in SIGNALS {
+            // SAFETY: on_signal only calls async-signal-safe functions
+            unsafe {
+                libc::signal(signal, handler as libc::sighandler_t);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use std::thread;
+
+    use crate::{ssh_master, Config};
+
+    /// The phases `run_remote_build` goes through
+    const PHASES: [&str; 11] = [
+        "start",
+        "pre-sync hook",
+        "container check",
+        "sync",
+        "setup",
+        "nix shell",
+        "build",
+        "post-build hook",
+        "sync back",
+        "artifact download",
+        "post-artifacts hook",
+    ];
+
+    /// What the panic hook wrote to stdout and stderr
+    type Written = (Vec<u8>, Vec<u8>);
+
+    /// What the panic hook wrote, by the name of the panicking thread
+    static CAPTURED: Mutex<BTreeMap<String, Written>> = Mutex::new(BTreeMap::new());
+
+    /// Install a hook doing what the real one does for the test threads,
+    /// writing into [`CAPTURED`] instead of stdout and stderr
+    fn install_capturing_hook() {
+        static HOOK: Once = Once::new();
+        HOOK.call_once(|| {
+            let default = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                let name = thread::current().name().unwrap_or_default().to_string();
+                if !name.starts_with("abort-test") {
+                    return default(info);
+                }
+                let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+                restore_terminal_to(&mut stdout, true);
+                report_to(&mut stderr, "panicked");
+                if let Ok(mut captured) = CAPTURED.lock() {
+                    captured.insert(name, (stdout, stderr));
+                }
+            }));
+        });
+    }
+
+    /// Labels of the registered pipelines
+    fn running() -> Vec<Option<String>> {
+        RUNS.lock()
+            .unwrap()
+            .values()
+            .map(|run| run.label.clone())
+            .collect()
+    }
+
+    /// A panic in any phase restores the terminal, names the phase, and
+    /// unwinds the registration and the master's start lock
+    #[test]
+    fn panic_in_each_phase() {
+        install_capturing_hook();
+        let dir =
+            std::env::temp_dir().join(format!("remotebuild-test-abort-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config: Config = serde_yaml::from_str("host: build-box").unwrap();
+
+        for phase in PHASES {
+            let name = format!("abort-test {}", phase);
+            let control = dir.join(phase.replace(' ', "_"));
+            let control = control.to_string_lossy().into_owned();
+            let (config, label) = (&config, name.clone());
+            let result = thread::scope(|s| {
+                thread::Builder::new()
+                    .name(name.clone())
+                    .spawn_scoped(s, || {
+                        let guard = RunGuard::register(Some(label));
+                        guard.enter(phase);
+                        let _lock = ssh_master::lock(config, &control).unwrap();
+                        assert!(Path::new(&format!("{}.lock", control)).exists());
+                        panic!("induced in {}", phase);
+                    })
+                    .unwrap()
+                    .join()
+            });
+            assert!(result.is_err());
+
+            let (stdout, stderr) = CAPTURED.lock().unwrap().remove(&name).unwrap();
+            assert_eq!(stdout, RESTORE_TERMINAL.as_bytes());
+            let stderr = String::from_utf8(stderr).unwrap();
+            let line = format!("🛑 {}: Aborted during {} after ", name, phase);
+            let reported: Vec<&str> = stderr.lines().filter(|l| l.starts_with(&line)).collect();
+            assert_eq!(reported.len(), 1, "{}", stderr);
+            assert!(reported[0].ends_with("s (panicked)"), "{}", reported[0]);
+
+            assert!(!running().contains(&Some(name)));
+            assert!(!Path::new(&format!("{}.lock", control)).exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Only a terminal gets the restoring sequence
+    #[test]
+    fn restore_sequence() {
+        assert_eq!(RESTORE_TERMINAL, "\r\x1b[2K\x1b[?25h");
+        let mut out = Vec::new();
+        restore_terminal_to(&mut out, false);
+        assert!(out.is_empty());
+        restore_terminal_to(&mut out, true);
+        assert_eq!(out, RESTORE_TERMINAL.as_bytes());
+    }
+
+    /// Concurrent pipelines, like matrix platforms, batch projects and
+    /// workspace components, each get their own line
+    #[test]
+    fn reports_every_pipeline() {
+        let linux = RunGuard::register(Some("abort-every linux".to_string()));
+        let macos = RunGuard::register(Some("abort-every macos".to_string()));
+        linux.enter("build");
+        macos.enter("artifact download");
+
+        let mut out = Vec::new();
+        report_to(&mut out, "interrupted");
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("🛑 abort-every linux: Aborted during build after "));
+        assert!(out.contains("🛑 abort-every macos: Aborted during artifact download after "));
+
+        drop(linux);
+        let labels = running();
+        assert!(!labels.contains(&Some("abort-every linux".to_string())));
+        assert!(labels.contains(&Some("abort-every macos".to_string())));
+    }
+}
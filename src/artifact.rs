@@ -0,0 +1,235 @@
+//! Artifacts and where they are copied to
+//!
+//! An artifact is a remote path or pattern, relative to `remote_path` unless
+//! it starts with `/`, copied into the project directory (or `artifact_dir`,
+//! or a target's or platform's), wherever remotebuild was run from.
+//! Written as a `{remote: ..., local: ...}` mapping it is copied to `local`
+//! instead, relative to the same directory, with missing parent directories
+//! created. As with `cp`, a `local` ending in `/` is a directory receiving
+//! what `remote` matches, and any other path names the copy itself.
+
+use anyhow::Result;
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// A configured artifact
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Artifact {
+    /// Remote path or pattern
+    pub(crate) remote: String,
+    /// Local destination, relative to the artifact directory, when not the
+    /// directory itself
+    pub(crate) local: Option<String>,
+    /// Command-line flag the artifact was given with, for verbose output
+    pub(crate) flag: Option<&'static str>,
+}
+
+impl From<String> for Artifact {
+    fn from(remote: String) -> Self {
+        Self {
+            remote,
+            local: None,
+            flag: None,
+        }
+    }
+}
+
+impl From<&str> for Artifact {
+    fn from(remote: &str) -> Self {
+        Self::from(remote.to_string())
+    }
+}
+
+impl Artifact {
+    /// An artifact pattern given with the command-line `flag`
+    pub(crate) fn from_flag(remote: String, flag: &'static str) -> Self {
+        Self {
+            flag: Some(flag),
+            ..Self::from(remote)
+        }
+    }
+
+    /// Apply `f` to the remote and local paths
+    pub(crate) fn map(&self, f: impl Fn(&str) -> String) -> Self {
+        Self {
+            remote: f(&self.remote),
+            local: self.local.as_deref().map(f),
+            flag: self.flag,
+        }
+    }
+
+    /// Apply the fallible `f` to the remote and local paths
+    pub(crate) fn try_map(&self, f: impl Fn(&str) -> Result<String>) -> Result<Self> {
+        Ok(Self {
+            remote: f(&self.remote)?,
+            local: self.local.as_deref().map(f).transpose()?,
+            flag: self.flag,
+        })
+    }
+
+    /// The local rsync destination when copying into `local_dir`: the
+    /// directory the copy is put into, or for a renamed copy the copy itself
+    ///
+    /// A trailing `/` of `local` is kept, telling rsync it names a directory.
+    pub(crate) fn destination(&self, local_dir: &Path) -> PathBuf {
+        match &self.local {
+            Some(local) => local_dir.join(local),
+            None => local_dir.to_path_buf(),
+        }
+    }
+}
+
+impl fmt::Display for Artifact {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.local {
+            Some(local) => write!(f, "{} → {}", self.remote, local),
+            None => f.write_str(&self.remote),
+        }
+    }
+}
+
+/// The mapping form of an artifact
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mapped {
+    /// Remote path or pattern
+    remote: String,
+    /// Local destination
+    local: String,
+}
+
+impl Serialize for Artifact {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.local {
+            Some(local) => Mapped {
+                remote: self.remote.clone(),
+                local: local.clone(),
+            }
+            .serialize(serializer),
+            None => serializer.serialize_str(&self.remote),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Artifact {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// Accepts a pattern or a `{remote, local}` mapping
+        struct ArtifactVisitor;
+
+        impl<'de> Visitor<'de> for ArtifactVisitor {
+            type Value = Artifact;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an artifact pattern or a mapping with remote and local")
+            }
+
+            fn visit_str<E: de::Error>(self, remote: &str) -> Result<Self::Value, E> {
+                Ok(Artifact::from(remote))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+                let mapped = Mapped::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                if mapped.local.is_empty() {
+                    return Err(de::Error::custom("artifact local path can't be empty"));
+                }
+                Ok(Artifact {
+                    remote: mapped.remote,
+                    local: Some(mapped.local),
+                    flag: None,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(ArtifactVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a YAML list of artifacts
+    fn parse(yaml: &str) -> Result<Vec<Artifact>, serde_yaml::Error> {
+        serde_yaml::from_str(yaml)
+    }
+
+    /// Artifacts are patterns or `{remote, local}` mappings, and serialize
+    /// back the way they were written
+    #[test]
+    fn patterns_and_mappings() {
+        let yaml = "- build/*.nds\n- remote: build/app.elf\n  local: dist/app.elf\n";
+        let artifacts = parse(yaml).unwrap();
+        assert_eq!(
+            artifacts,
+            [
+                Artifact::from("build/*.nds"),
+                Artifact {
+                    remote: "build/app.elf".to_string(),
+                    local: Some("dist/app.elf".to_string()),
+                    flag: None,
+                },
+            ]
+        );
+        assert_eq!(serde_yaml::to_string(&artifacts).unwrap(), yaml);
+        assert_eq!(artifacts[0].to_string(), "build/*.nds");
+        assert_eq!(artifacts[1].to_string(), "build/app.elf → dist/app.elf");
+    }
+
+    /// A mapping needs both keys, a non-empty `local` and nothing else
+    #[test]
+    fn bad_mappings_are_rejected() {
+        for yaml in [
+            "- remote: out.bin",
+            "- local: out.bin",
+            "- {remote: out.bin, local: ''}",
+            "- {remote: out.bin, local: dist/, mode: 755}",
+            "- [out.bin]",
+        ] {
+            assert!(parse(yaml).is_err(), "{}", yaml);
+        }
+        let error = parse("- {remote: out.bin, local: ''}").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("artifact local path can't be empty"));
+    }
+
+    /// Without `local` the copy goes into the artifact directory; `local`
+    /// names the copy, or with a trailing `/` the directory receiving it
+    #[test]
+    fn destinations() {
+        let dir = Path::new("/home/me/project");
+        let parsed = parse("- out.bin\n- {remote: out.bin, local: dist/app.bin}\n- {remote: 'gen/', local: 'include/gen/'}")
+            .unwrap();
+        let destinations: Vec<PathBuf> = parsed.iter().map(|a| a.destination(dir)).collect();
+        assert_eq!(
+            destinations,
+            [
+                PathBuf::from("/home/me/project"),
+                PathBuf::from("/home/me/project/dist/app.bin"),
+                PathBuf::from("/home/me/project/include/gen/"),
+            ]
+        );
+        assert!(destinations[2].to_string_lossy().ends_with('/'));
+    }
+
+    /// Mapping applies to both paths and keeps the flag
+    #[test]
+    fn map_applies_to_both_paths() {
+        let artifact = Artifact {
+            flag: Some("--artifact"),
+            ..parse("- {remote: '${TARGET}/app', local: 'dist/${TARGET}'}").unwrap()[0].clone()
+        };
+        let mapped = artifact.map(|path| path.replace("${TARGET}", "arm9"));
+        assert_eq!(mapped.remote, "arm9/app");
+        assert_eq!(mapped.local.as_deref(), Some("dist/arm9"));
+        assert_eq!(mapped.flag, Some("--artifact"));
+
+        let failed = artifact.try_map(|path| Err(anyhow::anyhow!("no {}", path)));
+        assert_eq!(failed.unwrap_err().to_string(), "no ${TARGET}/app");
+        let from_flag = Artifact::from_flag("out.bin".to_string(), "-a");
+        assert_eq!(from_flag.flag, Some("-a"));
+        assert_eq!(from_flag.local, None);
+    }
+}
@@ -0,0 +1,121 @@
+//! Artifact patterns expanded by remotebuild instead of the remote shell
+//!
+//! Artifact patterns are normally handed to rsync unquoted, so the remote
+//! shell expands them. That shell knows no `**` (it acts like `*`) and splits
+//! patterns at spaces. Patterns with `**` or whitespace are therefore
+//! expanded here: the directory part before the first glob, the base, is
+//! listed with `find`, and the entries are matched against the rest of the
+//! pattern with the exclude patterns' glob rules (`*` stays within one
+//! directory, `**/` also matches none). A single rsync then copies the
+//! matches with `--files-from`, keeping their path below the base, so
+//! `build/**/*.map` puts `build/a/b.map` at `a/b.map` in the artifact
+//! directory.
+
+use anyhow::Result;
+use shell_escape::escape;
+use std::borrow::Cow;
+
+use crate::patterns::glob_match;
+use crate::{run_ssh_command_output, Config, REMOTE_META_DIR};
+
+/// Characters starting a glob in a pattern component
+const GLOB_CHARS: &[char] = &['*', '?', '['];
+
+/// Whether remotebuild expands `pattern` itself
+pub(crate) fn needs_expansion(pattern: &str) -> bool {
+    pattern.contains("**") || pattern.contains(char::is_whitespace)
+}
+
+/// A pattern expanded on the remote
+#[derive(Debug)]
+pub(crate) struct Expansion {
+    /// Directory the paths are relative to, relative to the remote path
+    /// unless absolute; empty for the remote path itself
+    pub(crate) base: String,
+    /// Matching paths below `base`
+    pub(crate) paths: Vec<String>,
+}
+
+impl Expansion {
+    /// The rsync source for the base directory's contents
+    pub(crate) fn rsync_source(&self, config: &Config) -> String {
+        let quoted = escape(Cow::Borrowed(self.base.as_str()));
+        if self.base.starts_with('/') {
+            format!("{}:{}/", config.host, quoted)
+        } else if self.base.is_empty() {
+            config.remote_dir().rsync(&config.host, "")
+        } else {
+            config
+                .remote_dir()
+                .rsync(&config.host, &format!("{}/", quoted))
+        }
+    }
+}
+
+/// Split `pattern` into the directory before its first glob component and
+/// the rest
+fn split_base(pattern: &str) -> (&str, &str) {
+    let trimmed = pattern.trim_end_matches('/');
+    let mut end = 0;
+    for (i, _) in trimmed.match_indices('/') {
+        if trimmed[end..i].contains(GLOB_CHARS) {
+            break;
+        }
+        end = i + 1;
+    }
+    let base = pattern[..end].trim_end_matches('/');
+    // An absolute pattern keeps its root as the base
+    let base = if base.is_empty() && end == 1 {
+        "/"
+    } else {
+        base
+    };
+    (base, &pattern[end..])
+}
+
+/// Expand `pattern` on the remote, listing the base with `find`
+pub(crate) fn expand(config: &Config, pattern: &str) -> Result<Expansion> {
+    let (base, rest) = split_base(pattern);
+    let dirs_only = rest.ends_with('/');
+    let rest = rest.trim_end_matches('/');
+
+    // Without `**`, nothing deeper than the pattern's own components matches
+    let depth = if rest.contains("**") {
+        String::new()
+    } else {
+        format!(" -maxdepth {}", rest.split('/').count())
+    };
+    let cd = if base.starts_with('/') {
+        format!("cd {}", escape(Cow::Borrowed(base)))
+    } else if base.is_empty() {
+        format!("cd {}", config.remote_dir().shell())
+    } else {
+        format!(
+            "cd {} && cd {}",
+            config.remote_dir().shell(),
+            escape(Cow::Borrowed(base))
+        )
+    };
+    // A missing base just matches nothing
+    let script = format!(
+        "{cd} 2>/dev/null || exit 0; find .{depth} -name {meta} -prune -o \
+         -type d -exec printf 'd%s\\0' {{}} + -o -exec printf 'f%s\\0' {{}} +",
+        cd = cd,
+        depth = depth,
+        meta = REMOTE_META_DIR
+    );
+
+    let listing = run_ssh_command_output(config, &script)?;
+    let paths = listing
+        .split('\0')
+        .filter_map(|entry| {
+            let kind = entry.get(..1)?;
+            let path = entry.get(1..)?.strip_prefix("./")?;
+            (!(dirs_only && kind != "d") && glob_match(rest, path)).then(|| path.to_string())
+        })
+        .collect();
+    Ok(Expansion {
+        base: base.to_string(),
+        paths,
+    })
+}
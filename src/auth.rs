@@ -0,0 +1,145 @@
+//! Password and keyboard-interactive authentication
+//!
+//! The control master is first started with `BatchMode=yes`, so it never
+//! waits for input nobody can see. When that fails because the host wants a
+//! password, a 2FA code or a host key confirmation, the master is started
+//! again in a way that can answer:
+//!
+//! - with `password_command`, remotebuild itself is ssh's `SSH_ASKPASS`
+//!   program and answers password prompts with the command's output, so the
+//!   secret is never stored in the config or passed in the environment
+//! - with `SSH_ASKPASS` already set, ssh uses that program
+//! - when stdin is a terminal, ssh prompts on it in the foreground
+//!
+//! Without any of these the run fails with an error instead of hanging.
+
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::io::{IsTerminal, Write};
+use std::process::{Command, Stdio};
+
+use crate::Config;
+
+/// Environment variable holding the password command when remotebuild runs
+/// as ssh's askpass program
+const PASSWORD_COMMAND_ENV: &str = "REMOTEBUILD_PASSWORD_COMMAND";
+
+/// How a master that needs interaction is started
+pub(crate) enum Interaction {
+    /// Answer password prompts with the output of `password_command`
+    PasswordCommand(String),
+    /// Leave the prompts to the user's `SSH_ASKPASS` program
+    Askpass,
+    /// Prompt on the terminal
+    Terminal,
+}
+
+impl Interaction {
+    /// Pick how to authenticate to the config's host, if any way is available
+    pub(crate) fn for_config(config: &Config) -> Option<Self> {
+        if let Some(command) = &config.password_command {
+            Some(Self::PasswordCommand(command.clone()))
+        } else if env::var_os("SSH_ASKPASS").is_some() {
+            Some(Self::Askpass)
+        } else if std::io::stdin().is_terminal() {
+            Some(Self::Terminal)
+        } else {
+            None
+        }
+    }
+
+    /// Set up the ssh command starting the master
+    pub(crate) fn apply(&self, ssh: &mut Command) -> Result<()> {
+        match self {
+            Self::PasswordCommand(command) => {
+                let exe = env::current_exe().context("Failed to locate the remotebuild binary")?;
+                ssh.env("SSH_ASKPASS", exe)
+                    .env("SSH_ASKPASS_REQUIRE", "force")
+                    .env(PASSWORD_COMMAND_ENV, command)
+                    .stdin(Stdio::null());
+                // ssh before 8.4 only uses askpass with a display set
+                if env::var_os("DISPLAY").is_none() {
+                    ssh.env("DISPLAY", "remotebuild");
+                }
+            }
+            Self::Askpass => {
+                ssh.env("SSH_ASKPASS_REQUIRE", "prefer");
+            }
+            Self::Terminal => {
+                // Clear a status line the prompt would otherwise continue
+                print!("\r\x1b[2K");
+                std::io::stdout().flush().ok();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a failed connection attempt's output says the host wanted input
+///
+/// ssh lists the methods the server offered after `Permission denied`, so a
+/// server accepting only keys isn't mistaken for one wanting a password.
+pub(crate) fn needs_interaction(stderr: &str) -> bool {
+    stderr.lines().any(|line| {
+        line.contains("Host key verification failed")
+            || (line.contains("Permission denied")
+                && (line.contains("password") || line.contains("keyboard-interactive")))
+    })
+}
+
+/// The error for a host that can't be reached without interaction
+pub(crate) fn interaction_required(config: &Config, stderr: &str) -> anyhow::Error {
+    anyhow!(
+        "{} requires interactive authentication ({}); run remotebuild from a terminal, \
+         set password_command or SSH_ASKPASS, or set up key authentication",
+        config.host,
+        stderr.trim()
+    )
+}
+
+/// When remotebuild was started by ssh as its askpass program, answer the
+/// prompt and return the exit code
+///
+/// Only password prompts are answered with the command's output; anything
+/// else (host keys, one-time codes) is declined.
+pub(crate) fn run_as_askpass() -> Option<i32> {
+    let command = env::var(PASSWORD_COMMAND_ENV).ok()?;
+    let prompt = env::args().nth(1).unwrap_or_default();
+    if !prompt.to_lowercase().contains("password") {
+        eprintln!(
+            "remotebuild: password_command can't answer: {}",
+            prompt.trim()
+        );
+        return Some(1);
+    }
+
+    let output = match Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("remotebuild: failed to run password_command: {}", e);
+            return Some(1);
+        }
+    };
+    if !output.status.success() {
+        eprintln!(
+            "remotebuild: password_command failed with {}",
+            output.status
+        );
+        return Some(1);
+    }
+
+    // Only the first line is the secret, as with `pass show`
+    let secret = String::from_utf8_lossy(&output.stdout);
+    let secret = secret.lines().next().unwrap_or_default();
+    let mut stdout = std::io::stdout();
+    if writeln!(stdout, "{}", secret).is_err() {
+        return Some(1);
+    }
+    Some(0)
+}
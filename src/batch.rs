@@ -0,0 +1,295 @@
+//! Building several independent projects in one invocation
+//!
+//! `remotebuild batch <dir>...` runs the normal pipeline for each project
+//! directory with that directory's own config, and ends with one summary
+//! table. Projects on the same host share its control connection, so only
+//! the first one pays for connecting. By default the projects build one after
+//! the other, each under a header; with `--parallel N` up to N build at once,
+//! with progress output off and the build output of each prefixed with the
+//! project name. The run fails when any project fails, after all of them
+//! had their turn.
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::profiles;
+use crate::{
+    compression, config_format, default_project_remote_path, detect, ensure_ssh_connection,
+    gitignore, lint_artifacts, load_config, local_log, run_remote_build, sync_excludes,
+    BuildFailed, Config, SyncScope,
+};
+
+/// Options controlling a batch build
+pub(crate) struct BatchOptions<'a> {
+    /// Project directories, as given on the command line
+    pub(crate) dirs: &'a [PathBuf],
+    /// Name of the config file in each project, else each project's own
+    /// default name
+    pub(crate) config_name: Option<&'a str>,
+    /// Profile selected in every project instead of its `default_profile`
+    pub(crate) profile: Option<&'a str>,
+    /// Output level replacing the projects' own
+    pub(crate) output: Option<&'a str>,
+    /// Which files the sync considers
+    pub(crate) scope: SyncScope,
+    /// Number of projects building at the same time
+    pub(crate) parallel: usize,
+}
+
+/// A project of the batch
+struct Project {
+    /// Directory as given on the command line, used as its name
+    name: String,
+    /// Canonical project directory
+    dir: PathBuf,
+    /// The project's config, or why it couldn't be loaded
+    config: Result<Config, String>,
+}
+
+/// Result of building a single project
+enum Outcome {
+    /// Built and fetched successfully in the given time
+    Built(Duration),
+    /// Failed after the given time, with the reason
+    Failed(Duration, String),
+}
+
+/// Build every project of the batch
+pub(crate) fn run_batch(options: &BatchOptions) -> Result<()> {
+    if options.dirs.is_empty() {
+        return Err(anyhow!("No project directories given"));
+    }
+    let parallel = options.parallel.max(1);
+    let projects: Vec<Project> = options
+        .dirs
+        .iter()
+        .map(|dir| load_project(dir, options, parallel > 1))
+        .collect();
+
+    println!(
+        "🗃  Building {} projects{}",
+        projects.len(),
+        if parallel > 1 {
+            format!(", {} at a time", parallel.min(projects.len()))
+        } else {
+            String::new()
+        }
+    );
+    println!();
+
+    let outcomes = if parallel > 1 {
+        run_parallel(&projects, options.scope, parallel)
+    } else {
+        projects
+            .iter()
+            .map(|project| {
+                println!("\x1b[1m── {} ──\x1b[0m", project.name);
+                let outcome = build(project, options.scope);
+                if let Outcome::Failed(_, reason) = &outcome {
+                    eprintln!("   ✗ {}: {}", project.name, reason);
+                }
+                println!();
+                outcome
+            })
+            .collect()
+    };
+
+    print_summary(&projects, &outcomes);
+
+    let failed: Vec<&str> = projects
+        .iter()
+        .zip(&outcomes)
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Failed(..)))
+        .map(|(project, _)| project.name.as_str())
+        .collect();
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} of {} projects failed: {}",
+            failed.len(),
+            projects.len(),
+            failed.join(", ")
+        ))
+    }
+}
+
+/// Load a project's config the way a build in that directory would
+fn load_project(dir: &Path, options: &BatchOptions, prefixed: bool) -> Project {
+    let name = dir.display().to_string();
+    let loaded = fs::canonicalize(dir)
+        .with_context(|| format!("No such project directory: {}", dir.display()))
+        .and_then(|dir| {
+            let config_name = options
+                .config_name
+                .unwrap_or_else(|| config_format::default_name(&dir));
+            let config_path = dir.join(config_name);
+            if !config_path.is_file() {
+                return Err(anyhow!("No config file at {}", config_path.display()));
+            }
+            let mut config = load_config(&config_path)?;
+            profiles::apply(&mut config, options.profile)?;
+            if config.remote_path.is_empty() {
+                config.remote_path = default_project_remote_path(&dir);
+            }
+            if let Some(output) = options.output {
+                config.output = output.to_string();
+            }
+            if prefixed {
+                // Concurrent spinners would garble each other
+                config.output = "quiet".to_string();
+                config.output_prefix = Some(name.clone());
+            } else if config.keep_local_logs > 0 {
+                config.local_log_dir = Some(local_log::dir(&dir));
+            }
+            if config.host.is_empty() {
+                return Err(anyhow!("No host configured in {}", config_path.display()));
+            }
+            if config.build_command.is_empty() {
+                return Err(anyhow!(
+                    "No build_command configured in {}",
+                    config_path.display()
+                ));
+            }
+            detect::apply(&dir, &mut config);
+            gitignore::apply(&dir, &mut config);
+            lint_artifacts(&dir, &config);
+            sync_excludes(&config, true).lint();
+            compression::load_cached(&dir, &config)?;
+            Ok((dir, config))
+        });
+
+    match loaded {
+        Ok((dir, config)) => Project {
+            name,
+            dir,
+            config: Ok(config),
+        },
+        Err(e) => Project {
+            name,
+            dir: dir.to_path_buf(),
+            config: Err(format!("{:#}", e)),
+        },
+    }
+}
+
+/// Build the projects with up to `parallel` at a time
+///
+/// The control connection of every host is established first, one host at a
+/// time, so concurrent projects on one host don't race to start it.
+fn run_parallel(projects: &[Project], scope: SyncScope, parallel: usize) -> Vec<Outcome> {
+    let mut connections: BTreeMap<&str, Result<(), String>> = BTreeMap::new();
+    for project in projects {
+        if let Ok(config) = &project.config {
+            connections
+                .entry(config.host.as_str())
+                .or_insert_with(|| ensure_ssh_connection(config).map_err(|e| format!("{:#}", e)));
+        }
+    }
+
+    let next = AtomicUsize::new(0);
+    let outcomes: Mutex<Vec<Option<Outcome>>> = Mutex::new(projects.iter().map(|_| None).collect());
+    thread::scope(|s| {
+        for _ in 0..parallel.min(projects.len()) {
+            s.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(project) = projects.get(index) else {
+                    break;
+                };
+                let connected = project
+                    .config
+                    .as_ref()
+                    .ok()
+                    .and_then(|config| connections.get(config.host.as_str()));
+                let outcome = match connected {
+                    Some(Err(e)) => Outcome::Failed(Duration::ZERO, e.clone()),
+                    _ => build(project, scope),
+                };
+                match &outcome {
+                    Outcome::Built(_) => println!("   ✓ {}: done", project.name),
+                    Outcome::Failed(_, reason) => eprintln!("   ✗ {}: {}", project.name, reason),
+                }
+                if let Ok(mut outcomes) = outcomes.lock() {
+                    outcomes[index] = Some(outcome);
+                }
+            });
+        }
+    });
+    println!();
+
+    outcomes
+        .into_inner()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|outcome| {
+            outcome.unwrap_or_else(|| Outcome::Failed(Duration::ZERO, "not run".to_string()))
+        })
+        .collect()
+}
+
+/// Run the pipeline of one project
+fn build(project: &Project, scope: SyncScope) -> Outcome {
+    let config = match &project.config {
+        Ok(config) => config,
+        Err(e) => return Outcome::Failed(Duration::ZERO, e.clone()),
+    };
+    let start = Instant::now();
+    match run_remote_build(&project.dir, config, scope) {
+        Ok(()) => Outcome::Built(start.elapsed()),
+        Err(e) => {
+            let reason = match e.downcast_ref::<BuildFailed>() {
+                Some(failed) if failed.cancelled() => "cancelled".to_string(),
+                Some(failed) => match failed.status.code() {
+                    Some(code) => format!("build exited with {}", code),
+                    None => "build was killed".to_string(),
+                },
+                None => format!("{:#}", e),
+            };
+            Outcome::Failed(start.elapsed(), reason)
+        }
+    }
+}
+
+/// Print the per-project summary table
+fn print_summary(projects: &[Project], outcomes: &[Outcome]) {
+    let width = projects
+        .iter()
+        .map(|project| project.name.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    println!("📋 Batch summary");
+    for (project, outcome) in projects.iter().zip(outcomes) {
+        let artifacts = match &project.config {
+            Ok(config) if matches!(outcome, Outcome::Built(_)) => match config.artifacts.len() {
+                0 => "no artifacts".to_string(),
+                1 => "1 artifact".to_string(),
+                n => format!("{} artifacts", n),
+            },
+            _ => "-".to_string(),
+        };
+        let (mark, status, duration) = match outcome {
+            Outcome::Built(duration) => ("✓", "built".to_string(), duration),
+            Outcome::Failed(duration, reason) => {
+                // The full reason was printed when the project failed
+                let reason = reason.lines().next().unwrap_or("failed");
+                ("✗", format!("failed: {}", reason), duration)
+            }
+        };
+        println!(
+            "   {} {:<width$}  {:>7.1}s  {:<14}  {}",
+            mark,
+            project.name,
+            duration.as_secs_f64(),
+            artifacts,
+            status,
+            width = width
+        );
+    }
+}
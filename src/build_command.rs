@@ -0,0 +1,167 @@
+//! Build commands given as one command or as a list of steps
+//!
+//! `build_command` is either a single shell command, run as it is, or a list
+//! of commands run one after another, e.g. configure, build and test. Each
+//! step runs in its own subshell starting in `remote_path`, the first failing
+//! step ends the build with its exit code and a line naming it, and in
+//! normal and verbose output every step is announced with a header.
+
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::fmt;
+
+/// The configured build command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BuildCommand {
+    /// One shell command
+    Single(String),
+    /// Commands run in order, stopping at the first failure
+    Steps {
+        /// Shell code ending in `&& ` run once before the steps, such as
+        /// exports
+        setup: String,
+        /// The commands
+        steps: Vec<String>,
+    },
+}
+
+impl Default for BuildCommand {
+    fn default() -> Self {
+        Self::Single(String::new())
+    }
+}
+
+impl From<String> for BuildCommand {
+    fn from(command: String) -> Self {
+        Self::Single(command)
+    }
+}
+
+impl BuildCommand {
+    /// Whether no command is configured
+    pub(crate) fn is_empty(&self) -> bool {
+        match self {
+            Self::Single(command) => command.is_empty(),
+            Self::Steps { steps, .. } => steps.is_empty(),
+        }
+    }
+
+    /// Apply `f` to the command or to every step
+    pub(crate) fn map(&self, f: impl Fn(&str) -> String) -> Self {
+        match self {
+            Self::Single(command) => Self::Single(f(command)),
+            Self::Steps { setup, steps } => Self::Steps {
+                setup: setup.clone(),
+                steps: steps.iter().map(|step| f(step)).collect(),
+            },
+        }
+    }
+
+    /// Run `prefix`, shell code ending in `&& `, before the command or once
+    /// before all steps
+    pub(crate) fn prefixed(self, prefix: &str) -> Self {
+        match self {
+            Self::Single(command) => Self::Single(format!("{}{}", prefix, command)),
+            Self::Steps { setup, steps } => Self::Steps {
+                setup: format!("{}{}", prefix, setup),
+                steps,
+            },
+        }
+    }
+
+    /// Append `suffix` to the command, or to the last step
+    pub(crate) fn append(&mut self, suffix: &str) {
+        match self {
+            Self::Single(command) => command.push_str(suffix),
+            Self::Steps { steps, .. } => {
+                if let Some(last) = steps.last_mut() {
+                    last.push_str(suffix);
+                }
+            }
+        }
+    }
+
+    /// Shell code running the command, with step headers when `headers`
+    pub(crate) fn script(&self, headers: bool) -> String {
+        let (setup, steps) = match self {
+            Self::Single(command) => return command.clone(),
+            Self::Steps { setup, steps } => (setup, steps),
+        };
+        let count = steps.len();
+        let mut script = format!("( {}{{", setup);
+        for (i, step) in steps.iter().enumerate() {
+            let quoted = escape(Cow::Borrowed(step.as_str()));
+            if headers {
+                let header = format!("▶ Step {}/{}: {}", i + 1, count, step);
+                script.push_str(&format!(" printf '%s\\n' {};", escape(Cow::Owned(header))));
+            }
+            // The newline ends a trailing comment in the step
+            script.push_str(&format!(
+                " ( {step}\n) || {{ rb_step=$?; \
+                 printf '✗ Step {n}/{count} failed with exit code %s: %s\\n' \"$rb_step\" {quoted} >&2; \
+                 exit \"$rb_step\"; }};",
+                step = step,
+                n = i + 1,
+                count = count,
+                quoted = quoted
+            ));
+        }
+        script.push_str(" } )");
+        script
+    }
+}
+
+impl fmt::Display for BuildCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Single(command) => f.write_str(command),
+            Self::Steps { steps, .. } => f.write_str(&steps.join(" && ")),
+        }
+    }
+}
+
+impl Serialize for BuildCommand {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Single(command) => serializer.serialize_str(command),
+            Self::Steps { steps, .. } => steps.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BuildCommand {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// Accepts a string or a sequence of strings
+        struct CommandVisitor;
+
+        impl<'de> Visitor<'de> for CommandVisitor {
+            type Value = BuildCommand;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a command or a list of commands")
+            }
+
+            fn visit_str<E: de::Error>(self, command: &str) -> Result<Self::Value, E> {
+                Ok(BuildCommand::Single(command.to_string()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut steps = Vec::new();
+                while let Some(step) = seq.next_element::<String>()? {
+                    if step.trim().is_empty() {
+                        return Err(de::Error::custom("build_command steps can't be empty"));
+                    }
+                    steps.push(step);
+                }
+                Ok(BuildCommand::Steps {
+                    setup: String::new(),
+                    steps,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(CommandVisitor)
+    }
+}
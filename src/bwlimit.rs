@@ -0,0 +1,48 @@
+//! Bandwidth limit for rsync transfers
+//!
+//! `bwlimit` (or `--bwlimit`) caps the throughput of every rsync remotebuild
+//! starts, the sync and the artifact downloads alike, for metered links.
+//! Values follow rsync: a rate in KiB per second, optionally with a `k`, `m`
+//! or `g` suffix (`500k`, `2m`, `1.5m`), and `0` for no limit. The rate is
+//! checked when the config is loaded and handed to rsync in plain KiB, which
+//! rsync releases before 3.1 need.
+
+use anyhow::{anyhow, Result};
+
+use crate::Config;
+
+/// Parse a rate into KiB per second
+pub(crate) fn parse(value: &str) -> Result<u64> {
+    let invalid = || {
+        anyhow!(
+            "Invalid bwlimit: {} (expected a rate in KiB/s such as 500k or 2m, or 0)",
+            value
+        )
+    };
+    let trimmed = value.trim();
+    let (number, multiplier) = match trimmed.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&trimmed[..trimmed.len() - 1], 1.0),
+        Some('m') => (&trimmed[..trimmed.len() - 1], 1024.0),
+        Some('g') => (&trimmed[..trimmed.len() - 1], 1024.0 * 1024.0),
+        _ => (trimmed, 1.0),
+    };
+    if !number.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let rate: f64 = number.parse().map_err(|_| invalid())?;
+    if !rate.is_finite() {
+        return Err(invalid());
+    }
+    // Rounded up, so a rate under 1 KiB/s doesn't turn into no limit
+    Ok((rate * multiplier).ceil() as u64)
+}
+
+/// The rsync argument applying the configured limit, if there is one
+pub(crate) fn rsync_arg(config: &Config) -> Option<String> {
+    config
+        .bwlimit
+        .as_deref()
+        .and_then(|value| parse(value).ok())
+        .filter(|kib| *kib > 0)
+        .map(|kib| format!("--bwlimit={}", kib))
+}
@@ -0,0 +1,545 @@
+//! Moving build caches between build hosts
+//!
+//! `remotebuild cache push <name>` packs the configured `cache_archive.paths`
+//! of the remote tree (such as `target/` or an absolute ccache directory)
+//! into a compressed tar stream and stores it as `<name>.tar.gz`, with a
+//! `<name>.json` describing it, either in a local directory or on a cache
+//! host. `remotebuild cache pull <name>` unpacks such an archive into the
+//! tree of the configured host, e.g. a new builder, so its first build is
+//! incremental. The archive is streamed through this machine in both
+//! directions and never touches the disk of the build host twice.
+//!
+//! A pull refuses to overwrite a tree that was built (as recorded in the
+//! local state) after the archive was pushed, unless forced.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::fs;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::gc::format_size;
+use crate::history::format_age;
+use crate::remote_path::RemotePath;
+use crate::state::State;
+use crate::{ensure_ssh_connection, remote_command, run_ssh_command_output, Config, OutputLevel};
+
+/// Directory of the archives on a cache host when `dir` isn't set
+const DEFAULT_REMOTE_DIR: &str = "~/remotebuild-caches";
+
+/// File the archive is unpacked from on the build host during a pull,
+/// relative to the remote project directory
+const PULL_FILE: &str = ".remotebuild/cache-pull.tar.gz";
+
+/// Bytes between two progress updates
+const PROGRESS_STEP: u64 = 4 * 1024 * 1024;
+
+/// The `cache_archive` section of the config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CacheArchiveConfig {
+    /// Paths to archive, relative to `remote_path` or absolute
+    paths: Vec<String>,
+
+    /// Host storing the archives (default: this machine)
+    #[serde(default)]
+    host: Option<String>,
+
+    /// Directory of the archives (default: `~/remotebuild-caches` on the
+    /// cache host, the remotebuild cache directory locally)
+    #[serde(default)]
+    dir: Option<String>,
+}
+
+/// What `cache` does
+pub(crate) enum CacheAction<'a> {
+    /// Archive the remote tree's caches under a name
+    Push(&'a str),
+    /// Unpack a named archive into the remote tree, `true` skipping the
+    /// check for newer builds
+    Pull(&'a str, bool),
+}
+
+/// Description stored next to an archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ArchiveInfo {
+    /// Unix timestamp (seconds) of the push
+    created_at: u64,
+    /// Host the caches were packed on
+    host: String,
+    /// Remote tree the caches were packed from
+    remote_path: String,
+    /// Archived paths
+    paths: Vec<String>,
+    /// Size of the archive in bytes
+    size: u64,
+}
+
+/// Where the archives are kept
+enum Store {
+    /// In a local directory
+    Local(PathBuf),
+    /// In a directory on a cache host, reached with this config
+    Remote(Box<Config>, String),
+}
+
+/// Run `cache push` or `cache pull`
+pub(crate) fn run_cache(project_dir: &Path, config: &Config, action: CacheAction) -> Result<()> {
+    let Some(archive) = &config.cache_archive else {
+        return Err(anyhow!(
+            "No cache_archive configured: list the paths to archive under cache_archive.paths"
+        ));
+    };
+    if archive.paths.is_empty() {
+        return Err(anyhow!("cache_archive.paths is empty"));
+    }
+    let store = store(config, archive)?;
+    ensure_ssh_connection(config)?;
+    if let Store::Remote(cache_config, _) = &store {
+        ensure_ssh_connection(cache_config)?;
+    }
+
+    match action {
+        CacheAction::Push(name) => push(config, archive, &store, valid_name(name)?),
+        CacheAction::Pull(name, force) => {
+            pull(project_dir, config, &store, valid_name(name)?, force)
+        }
+    }
+}
+
+/// Check that an archive name is usable as a file name
+fn valid_name(name: &str) -> Result<&str> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if valid {
+        Ok(name)
+    } else {
+        Err(anyhow!(
+            "Invalid cache name: {} (use letters, digits, '.', '_' and '-')",
+            name
+        ))
+    }
+}
+
+/// Where the archives of this config are kept
+fn store(config: &Config, archive: &CacheArchiveConfig) -> Result<Store> {
+    match &archive.host {
+        Some(host) => {
+            let cache_config = Config {
+                host: host.clone(),
+                ..config.clone()
+            };
+            let dir = archive.dir.as_deref().unwrap_or(DEFAULT_REMOTE_DIR);
+            Ok(Store::Remote(Box::new(cache_config), dir.to_string()))
+        }
+        None => {
+            let dir = match &archive.dir {
+                Some(dir) => PathBuf::from(dir),
+                None => dirs::cache_dir()
+                    .context("No cache directory for the archives; set cache_archive.dir")?
+                    .join("remotebuild")
+                    .join("archives"),
+            };
+            Ok(Store::Local(dir))
+        }
+    }
+}
+
+impl Store {
+    /// Human-readable location of an archive file
+    fn describe(&self, file: &str) -> String {
+        match self {
+            Store::Local(dir) => dir.join(file).display().to_string(),
+            Store::Remote(config, dir) => {
+                format!("{}:{}/{}", config.host, dir.trim_end_matches('/'), file)
+            }
+        }
+    }
+
+    /// Shell path of an archive file on the cache host
+    fn remote_file(dir: &str, file: &str) -> String {
+        format!(
+            "{}/{}",
+            RemotePath::new(dir).shell(),
+            escape(Cow::Borrowed(file))
+        )
+    }
+
+    /// Read an archive's description
+    fn read_info(&self, name: &str) -> Result<ArchiveInfo> {
+        let file = format!("{}.json", name);
+        let content = match self {
+            Store::Local(dir) => fs::read_to_string(dir.join(&file)).ok(),
+            Store::Remote(config, dir) => run_ssh_command_output(
+                config,
+                &format!("cat {} 2>/dev/null || true", Self::remote_file(dir, &file)),
+            )
+            .ok()
+            .filter(|content| !content.trim().is_empty()),
+        };
+        let content = content
+            .ok_or_else(|| anyhow!("No cache archive named {} in {}", name, self.describe("")))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Unreadable archive description: {}", self.describe(&file)))
+    }
+
+    /// Write an archive's description
+    fn write_info(&self, name: &str, info: &ArchiveInfo) -> Result<()> {
+        let file = format!("{}.json", name);
+        let content = serde_json::to_string_pretty(info)?;
+        match self {
+            Store::Local(dir) => fs::write(dir.join(&file), content)
+                .with_context(|| format!("Failed to write {}", self.describe(&file))),
+            Store::Remote(config, dir) => run_ssh_command_output(
+                config,
+                &format!(
+                    "printf '%s\\n' {} > {}",
+                    escape(Cow::Owned(content)),
+                    Self::remote_file(dir, &file)
+                ),
+            )
+            .map(|_| ()),
+        }
+    }
+}
+
+/// Pack the caches of the remote tree and store them under `name`
+fn push(config: &Config, archive: &CacheArchiveConfig, store: &Store, name: &str) -> Result<()> {
+    let file = format!("{}.tar.gz", name);
+    let output = config.output_level();
+    if !matches!(output, OutputLevel::Quiet) {
+        println!(
+            "📦 Packing {} from {}:{}",
+            archive.paths.join(", "),
+            config.host,
+            config.remote_path
+        );
+    }
+
+    // Paths that don't exist are left out rather than failing tar; relative
+    // ones are archived from the tree, then absolute ones from /
+    let mut paths = String::new();
+    for path in &archive.paths {
+        paths.push(' ');
+        paths.push_str(&escape(Cow::Borrowed(path.trim_end_matches('/'))));
+    }
+    let script = format!(
+        "cd {dir} || exit 1; set --; \
+         for p in{paths}; do [ -e \"$p\" ] || echo \"missing $p\" >&2; done; \
+         for p in{paths}; do case \"$p\" in /*) ;; *) [ -e \"$p\" ] && set -- \"$@\" \"$p\";; esac; done; \
+         for p in{paths}; do case \"$p\" in /*) [ -e \"$p\" ] && set -- \"$@\" -C / \"${{p#/}}\";; esac; done; \
+         [ $# -gt 0 ] || {{ echo 'none of the paths exist' >&2; exit 3; }}; \
+         exec tar czf - \"$@\"",
+        dir = config.remote_dir().shell(),
+        paths = paths
+    );
+
+    let mut source = remote_command(config, &script)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run tar over SSH")?;
+    let mut stdout = source
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow!("tar output not captured"))?;
+    let stderr = source.stderr.take();
+    let errors = std::thread::spawn(move || {
+        let mut text = String::new();
+        if let Some(mut stderr) = stderr {
+            let _ = stderr.read_to_string(&mut text);
+        }
+        text
+    });
+
+    let (size, archived) = match store {
+        Store::Local(dir) => {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+            let partial = dir.join(format!("{}.partial", file));
+            let mut out = fs::File::create(&partial)
+                .with_context(|| format!("Failed to create {}", partial.display()))?;
+            let size = copy_with_progress(&mut stdout, &mut out, "⬆ Pushing", output)?;
+            let status = source.wait()?;
+            let errors = errors.join().unwrap_or_default();
+            if !status.success() {
+                fs::remove_file(&partial).ok();
+                return Err(tar_error("Packing", status, &errors));
+            }
+            fs::rename(&partial, dir.join(&file))
+                .with_context(|| format!("Failed to store {}", store.describe(&file)))?;
+            (size, report_missing(&archive.paths, &errors))
+        }
+        Store::Remote(cache_config, dir) => {
+            let target = Store::remote_file(dir, &file);
+            let mut sink = remote_command(
+                cache_config,
+                &format!(
+                    "mkdir -p {dir} && cat > {target}.partial && mv {target}.partial {target}",
+                    dir = RemotePath::new(dir).shell(),
+                    target = target
+                ),
+            )
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Failed to reach the cache host")?;
+            let mut stdin = sink
+                .stdin
+                .take()
+                .ok_or_else(|| anyhow!("cache host input not captured"))?;
+            let size = copy_with_progress(&mut stdout, &mut stdin, "⬆ Pushing", output);
+            drop(stdin);
+            let status = source.wait()?;
+            let errors = errors.join().unwrap_or_default();
+            let stored = sink.wait()?;
+            let size = size?;
+            if !status.success() {
+                return Err(tar_error("Packing", status, &errors));
+            }
+            if !stored.success() {
+                return Err(anyhow!(
+                    "Could not store {} ({})",
+                    store.describe(&file),
+                    stored
+                ));
+            }
+            (size, report_missing(&archive.paths, &errors))
+        }
+    };
+
+    let info = ArchiveInfo {
+        created_at: unix_now(),
+        host: config.host.clone(),
+        remote_path: config.remote_path.clone(),
+        paths: archived,
+        size,
+    };
+    store.write_info(name, &info)?;
+
+    if !matches!(output, OutputLevel::Quiet) {
+        println!(
+            "✅ Pushed cache {} ({}) to {}",
+            name,
+            format_size(size.div_euclid(1024)),
+            store.describe(&file)
+        );
+    }
+    Ok(())
+}
+
+/// Unpack the archive `name` into the remote tree
+fn pull(project_dir: &Path, config: &Config, store: &Store, name: &str, force: bool) -> Result<()> {
+    let file = format!("{}.tar.gz", name);
+    let output = config.output_level();
+    let info = store.read_info(name)?;
+    let now = unix_now();
+
+    let built = State::load(project_dir)
+        .last_builds
+        .get(&destination(config))
+        .copied();
+    if let Some(built) = built.filter(|built| *built > info.created_at) {
+        if !force {
+            return Err(anyhow!(
+                "{}:{} was built {}, after cache {} was pushed ({}); \
+                 pass --force to overwrite its caches anyway",
+                config.host,
+                config.remote_path,
+                format_age(now.saturating_sub(built)),
+                name,
+                format_age(now.saturating_sub(info.created_at))
+            ));
+        }
+    }
+
+    if !matches!(output, OutputLevel::Quiet) {
+        println!(
+            "📦 Restoring cache {} ({}, pushed {} from {}:{}) into {}:{}",
+            name,
+            format_size(info.size.div_euclid(1024)),
+            format_age(now.saturating_sub(info.created_at)),
+            info.host,
+            info.remote_path,
+            config.host,
+            config.remote_path
+        );
+    }
+
+    // The archive is stored first, so relative and absolute paths can be
+    // extracted into their own roots
+    let mut relative = Vec::new();
+    let mut absolute = Vec::new();
+    for path in &info.paths {
+        let path = path.trim_end_matches('/');
+        match path.strip_prefix('/') {
+            Some(path) => absolute.push(escape(Cow::Owned(path.to_string())).to_string()),
+            None => relative.push(escape(Cow::Owned(path.to_string())).to_string()),
+        }
+    }
+    let extract = |root: &str, members: &[String]| {
+        if members.is_empty() {
+            String::new()
+        } else {
+            format!("tar xzf \"$F\" -C {} {} && ", root, members.join(" "))
+        }
+    };
+    let script = format!(
+        "mkdir -p {dir}/.remotebuild && cd {dir} && F=\"$PWD\"/{pull} && cat > \"$F\" && \
+         {relative}{absolute}rm -f \"$F\"",
+        dir = config.remote_dir().shell(),
+        pull = PULL_FILE,
+        relative = extract("\"$PWD\"", &relative),
+        absolute = extract("/", &absolute)
+    );
+    let mut sink = remote_command(config, &script)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to run tar over SSH")?;
+    let mut stdin = sink
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("tar input not captured"))?;
+
+    let copied = match store {
+        Store::Local(dir) => {
+            let path = dir.join(&file);
+            let mut input = fs::File::open(&path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            copy_with_progress(&mut input, &mut stdin, "⬇ Pulling", output)
+        }
+        Store::Remote(cache_config, dir) => {
+            let mut source = remote_command(
+                cache_config,
+                &format!("cat {}", Store::remote_file(dir, &file)),
+            )
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to reach the cache host")?;
+            let mut stdout = source
+                .stdout
+                .take()
+                .ok_or_else(|| anyhow!("cache host output not captured"))?;
+            let copied = copy_with_progress(&mut stdout, &mut stdin, "⬇ Pulling", output);
+            let status = source.wait()?;
+            if !status.success() {
+                return Err(anyhow!(
+                    "Could not read {} ({})",
+                    store.describe(&file),
+                    status
+                ));
+            }
+            copied
+        }
+    };
+    drop(stdin);
+    let status = sink.wait()?;
+    copied?;
+    if !status.success() {
+        return Err(anyhow!("Unpacking the cache failed ({})", status));
+    }
+
+    if !matches!(output, OutputLevel::Quiet) {
+        println!(
+            "✅ Restored {} into {}:{}",
+            info.paths.join(", "),
+            config.host,
+            config.remote_path
+        );
+    }
+    Ok(())
+}
+
+/// Remember when the destination was last built successfully, for the check
+/// before a pull
+pub(crate) fn record_build(project_dir: &Path, config: &Config) {
+    let mut state = State::load(project_dir);
+    state.last_builds.insert(destination(config), unix_now());
+    if let Err(e) = state.save(project_dir) {
+        eprintln!("   ⚠ Warning: Could not save build state: {}", e);
+    }
+}
+
+/// Key of the destination in the state
+fn destination(config: &Config) -> String {
+    format!("{}:{}", config.host, config.remote_path)
+}
+
+/// Copy a stream, showing the bytes copied so far on a terminal
+fn copy_with_progress(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    label: &str,
+    level: OutputLevel,
+) -> Result<u64> {
+    let show = !matches!(level, OutputLevel::Quiet) && std::io::stderr().is_terminal();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut total = 0u64;
+    let mut next_update = 0u64;
+    loop {
+        let read = input.read(&mut buffer).context("Transfer interrupted")?;
+        if read == 0 {
+            break;
+        }
+        output
+            .write_all(&buffer[..read])
+            .context("Transfer interrupted")?;
+        total += read as u64;
+        if show && total >= next_update {
+            eprint!("\r\x1b[2K   {}: {}", label, format_size(total / 1024));
+            next_update = total + PROGRESS_STEP;
+        }
+    }
+    output.flush().context("Transfer interrupted")?;
+    if show {
+        eprint!("\r\x1b[2K");
+    }
+    Ok(total)
+}
+
+/// Warn about configured paths that didn't exist when packing, returning
+/// the ones that were archived
+fn report_missing(paths: &[String], errors: &str) -> Vec<String> {
+    let missing: Vec<&str> = errors
+        .lines()
+        .filter_map(|line| line.strip_prefix("missing "))
+        .collect();
+    for path in &missing {
+        eprintln!(
+            "   ⚠ Warning: {} doesn't exist on the remote and wasn't archived",
+            path
+        );
+    }
+    paths
+        .iter()
+        .filter(|path| !missing.contains(&path.trim_end_matches('/')))
+        .cloned()
+        .collect()
+}
+
+/// Error for a failed tar run, with what tar printed
+fn tar_error(what: &str, status: std::process::ExitStatus, errors: &str) -> anyhow::Error {
+    let details: Vec<&str> = errors
+        .lines()
+        .filter(|line| !line.starts_with("missing "))
+        .collect();
+    anyhow!(
+        "{} the cache failed ({}){}",
+        what,
+        status,
+        crate::indented_tail(&details.join("\n"))
+    )
+}
+
+/// Current time as seconds since the Unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
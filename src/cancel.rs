@@ -0,0 +1,116 @@
+//! Stopping a running build from another terminal
+//!
+//! `remotebuild cancel` reads the lock of the project's remote tree, which
+//! names the shell running the build, and sends TERM to that shell and every
+//! process below it. Processes still alive after a grace period are killed,
+//! including those orphaned by their parent stopping first.
+//! The shell itself is left to its trap, which exits with
+//! [`CANCELLED_EXIT_CODE`] because the cancel file names it, so the
+//! invocation that started the build reports a cancellation rather than a
+//! failure, and the lock is removed by the usual cleanup. A lock left behind
+//! by a shell that had to be killed is removed by `cancel` itself.
+//!
+//! Builds of other users sharing the tree are only cancelled with `--force`.
+
+use anyhow::{anyhow, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::history::{format_age, local_user};
+use crate::shared::{alive, CANCELLED_EXIT_CODE};
+use crate::{run_ssh_command_output, Config};
+
+/// Seconds the build gets to stop after TERM before it is killed
+const GRACE_SECS: u32 = 10;
+
+/// Seconds the build shell gets to exit once its children were killed
+const SHELL_GRACE_SECS: u32 = 5;
+
+/// Cancel the build running in the project's remote tree
+pub(crate) fn run_cancel(config: &Config, force: bool) -> Result<()> {
+    // The processes below the build shell, found by walking parent pids.
+    // They are collected while the build stops, since a process whose
+    // parent died is no longer below the shell
+    let tree = "ps -eo pid=,ppid= | awk -v root=\"$lock_pid\" \
+                '{ parent[$1] = $2 } END { for (p in parent) { q = p; \
+                for (i = 0; i < 64 && (q in parent) && q != root; i++) q = parent[q]; \
+                if (q == root && p != root) print p } }'";
+    let script = format!(
+        "cd {dir} 2>/dev/null || {{ echo none; exit 0; }}; LOCK=.remotebuild/lock; \
+         if ! [ -f \"$LOCK\" ] \
+         || ! IFS='\t' read -r lock_user lock_pid lock_since rest < \"$LOCK\" \
+         || ! {alive}; then echo none; exit 0; fi; \
+         if [ \"$lock_user\" != {me} ] && [ {force} != 1 ]; then \
+         printf 'other\\t%s\\n' \"$lock_user\"; exit 0; fi; \
+         printf 'found\\t%s\\t%s\\t%s\\n' \"$lock_user\" \"$lock_pid\" \"$lock_since\"; \
+         echo \"$lock_pid\" > .remotebuild/cancel; \
+         running() {{ kill -0 \"$1\" 2>/dev/null || [ -d \"/proc/$1\" ]; }}; \
+         kids=$({tree}); kill -TERM \"$lock_pid\" $kids 2>/dev/null; \
+         i=0; while [ $i -lt {grace} ]; do kids=\"$kids $({tree})\"; left=; \
+         for p in \"$lock_pid\" $kids; do running \"$p\" && left=\"$left $p\"; done; \
+         [ -z \"$left\" ] && break; sleep 1; i=$((i+1)); done; \
+         left=; for p in $kids; do running \"$p\" && left=\"$left $p\"; done; \
+         if [ -n \"$left\" ]; then echo killed; kill -KILL $left 2>/dev/null; \
+         i=0; while {alive} && [ $i -lt {shell_grace} ]; do sleep 1; i=$((i+1)); done; fi; \
+         if {alive}; then kill -KILL \"$lock_pid\" 2>/dev/null; sleep 1; fi; \
+         if IFS='\t' read -r u p rest < \"$LOCK\" 2>/dev/null && [ \"$p\" = \"$lock_pid\" ] \
+         && ! {alive}; then rm -f \"$LOCK\"; fi; \
+         rm -f .remotebuild/cancel; echo done",
+        dir = config.remote_dir().shell(),
+        alive = alive("lock_pid"),
+        me = escape(Cow::Owned(local_user())),
+        force = u8::from(force),
+        tree = tree,
+        grace = GRACE_SECS,
+        shell_grace = SHELL_GRACE_SECS
+    );
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let output = run_ssh_command_output(config, &script)?;
+    let mut lines = output
+        .lines()
+        .map(|line| line.split('\t').collect::<Vec<_>>());
+    match lines.next().as_deref() {
+        Some(["none"]) => {
+            println!(
+                "Nothing is building in {}:{}",
+                config.host, config.remote_path
+            );
+            Ok(())
+        }
+        Some(["other", user]) => Err(anyhow!(
+            "The running build belongs to {}; pass --force to cancel it anyway",
+            user
+        )),
+        Some(["found", user, pid, since]) => {
+            let age = since
+                .parse::<u64>()
+                .map(|since| format!(", started {}", format_age(now.saturating_sub(since))))
+                .unwrap_or_default();
+            println!("🛑 Cancelling the build of {} (pid {}{})", user, pid, age);
+            let rest: Vec<Vec<&str>> = lines.collect();
+            if rest.iter().any(|line| line.as_slice() == ["killed"]) {
+                eprintln!(
+                    "   ⚠ Warning: The build didn't stop within {}s and was killed",
+                    GRACE_SECS
+                );
+            }
+            if !rest.iter().any(|line| line.as_slice() == ["done"]) {
+                return Err(anyhow!("Cancelling stopped before it was confirmed"));
+            }
+            println!(
+                "✅ Build cancelled; it exits with code {}",
+                CANCELLED_EXIT_CODE
+            );
+            Ok(())
+        }
+        _ => Err(anyhow!(
+            "Unexpected answer from the remote: {}",
+            output.trim()
+        )),
+    }
+}
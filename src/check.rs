@@ -0,0 +1,202 @@
+//! Remote `cargo check` with diagnostics mapped back to local paths
+//!
+//! `remotebuild check` syncs the project, runs `cargo check` with a JSON
+//! message format on the remote and reads the messages as they stream back.
+//! Every string in a message has the remote project directory replaced by the
+//! local one, so file names in diagnostics point at local files. Messages are
+//! either rendered like cargo's human output or re-emitted as JSON, which
+//! makes the command usable as rust-analyzer's check-on-save override.
+//!
+//! Lines that are not cargo JSON messages are passed through untouched.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::path::Path;
+use std::process::Stdio;
+
+use crate::{remote_command, resolve_remote_dir, setup, sync_to_remote, Config, SyncScope};
+
+/// Message format that renders diagnostics locally instead of emitting JSON
+const HUMAN_FORMAT: &str = "human";
+
+/// Exclude that keeps the remote target directory out of the sync, so that
+/// `--delete` never removes it and incremental checks stay fast
+const TARGET_EXCLUDE: &str = "/target/";
+
+/// Options for a remote check
+pub(crate) struct CheckOptions<'a> {
+    /// `human`, or a cargo JSON message format to request and re-emit
+    pub(crate) message_format: &'a str,
+    /// Extra arguments passed to `cargo check`
+    pub(crate) cargo_args: &'a [String],
+    /// Which files the sync considers
+    pub(crate) scope: SyncScope,
+}
+
+/// Sync the project and run `cargo check` remotely, returning its exit code
+pub(crate) fn run_check(
+    project_dir: &Path,
+    config: &Config,
+    options: &CheckOptions,
+) -> Result<i32> {
+    let human = options.message_format == HUMAN_FORMAT;
+    if !human && !options.message_format.starts_with("json") {
+        return Err(anyhow!(
+            "Unsupported message format: {} (expected human or a json format)",
+            options.message_format
+        ));
+    }
+
+    let mut sync_config = config.clone();
+    sync_config
+        .exclude_patterns
+        .push(TARGET_EXCLUDE.to_string());
+    if !human {
+        // Keep stdout clean for tools that parse the JSON stream
+        sync_config.output = "quiet".to_string();
+    }
+
+    sync_to_remote(project_dir, &sync_config, options.scope)?;
+    setup::run_setup(&sync_config)?;
+
+    // Paths in cargo messages are absolute, so `~` in remote_path must be resolved
+    let remote_dir = resolve_remote_dir(config)?;
+    let local_dir = project_dir.to_string_lossy().to_string();
+
+    let cargo_format = if !human {
+        options.message_format
+    } else if io::stderr().is_terminal() {
+        "json-diagnostic-rendered-ansi"
+    } else {
+        "json"
+    };
+    let mut cmd = format!(
+        "cd {} && cargo check --message-format={}",
+        config.remote_dir().shell(),
+        cargo_format
+    );
+    for arg in options.cargo_args {
+        cmd.push(' ');
+        cmd.push_str(&escape(Cow::Borrowed(arg.as_str())));
+    }
+
+    let mut child = remote_command(config, &cmd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .context("Failed to run cargo check over SSH")?;
+
+    if let Some(stdout) = child.stdout.take() {
+        forward_messages(BufReader::new(stdout), &remote_dir, &local_dir, human)?;
+    }
+
+    let status = child.wait().context("Failed to wait for cargo check")?;
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Remap and print each line of cargo's output as it arrives
+fn forward_messages(
+    mut reader: impl BufRead,
+    remote_dir: &str,
+    local_dir: &str,
+    human: bool,
+) -> Result<()> {
+    let stdout = io::stdout();
+    let stderr = io::stderr();
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+
+        let Some(mut message) = parse_message(&line) else {
+            let mut out = stdout.lock();
+            out.write_all(&line)?;
+            out.flush()?;
+            continue;
+        };
+        remap_value(&mut message, remote_dir, local_dir);
+
+        if human {
+            // Like cargo, diagnostics go to stderr; other messages are dropped
+            let rendered = message
+                .get("message")
+                .and_then(|m| m.get("rendered"))
+                .and_then(Value::as_str);
+            if let (Some("compiler-message"), Some(rendered)) =
+                (message.get("reason").and_then(Value::as_str), rendered)
+            {
+                let mut err = stderr.lock();
+                err.write_all(rendered.as_bytes())?;
+                err.flush()?;
+            }
+        } else {
+            let mut out = stdout.lock();
+            serde_json::to_writer(&mut out, &message)?;
+            out.write_all(b"\n")?;
+            out.flush()?;
+        }
+    }
+}
+
+/// Parse a line as a cargo JSON message (an object with a `reason` field)
+fn parse_message(line: &[u8]) -> Option<Value> {
+    let value: Value = serde_json::from_slice(line).ok()?;
+    value.get("reason")?;
+    Some(value)
+}
+
+/// Replace the remote project directory with the local one in every string
+fn remap_value(value: &mut Value, from: &str, to: &str) {
+    match value {
+        Value::String(s) => {
+            if let Some(remapped) = remap_str(s, from, to) {
+                *s = remapped;
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                remap_value(item, from, to);
+            }
+        }
+        Value::Object(map) => {
+            for item in map.values_mut() {
+                remap_value(item, from, to);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replace whole-path occurrences of `from` in `s`, if there are any
+///
+/// An occurrence only counts when it is not followed by another file name
+/// character, so `/src/app` is not rewritten inside `/src/app2`.
+fn remap_str(s: &str, from: &str, to: &str) -> Option<String> {
+    let mut result = String::new();
+    let mut last = 0;
+    for (start, _) in s.match_indices(from) {
+        let end = start + from.len();
+        let continues_name = s[end..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'));
+        if continues_name {
+            continue;
+        }
+        result.push_str(&s[last..start]);
+        result.push_str(to);
+        last = end;
+    }
+
+    if last == 0 {
+        return None;
+    }
+    result.push_str(&s[last..]);
+    Some(result)
+}
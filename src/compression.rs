@@ -0,0 +1,390 @@
+//! Choosing transfer compression from the measured link speed
+//!
+//! With `compression: auto` (the default) the link to the host is measured
+//! on the first sync of a run: the round-trip time of a no-op command over
+//! the control connection, and the throughput of sending a small
+//! incompressible probe. Fast links sync without compression, slow links get
+//! rsync's highest level and ssh compression, and links in between an rsync
+//! level in proportion. The measurement and the decision are kept per host in
+//! the state file and reused for `compression_auto.remeasure` (default: 1h).
+//! Hosts that are obviously this machine or on the local link (`localhost`,
+//! loopback and link-local addresses, `.local` names) aren't measured and
+//! sync without compression.
+//!
+//! ssh compression is a property of the control connection, so it only
+//! applies to connections started after a slow link was measured.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::gc::parse_duration;
+use crate::state::State;
+use crate::{ssh_command, Config, OutputLevel};
+
+/// Size of the probe sent to measure throughput
+const PROBE_BYTES: usize = 256 * 1024;
+
+/// rsync's own default level, used when no measurement is available
+const RSYNC_DEFAULT_LEVEL: u32 = 6;
+
+/// Highest level used between the fast and slow thresholds
+const MAX_PROPORTIONAL_LEVEL: f64 = 6.0;
+
+/// Decisions made or loaded in this run, keyed by host
+static CHOICES: Mutex<BTreeMap<String, Choice>> = Mutex::new(BTreeMap::new());
+
+/// Thresholds of `compression: auto`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AutoThresholds {
+    /// Throughput (Mbit/s) from which transfers aren't compressed
+    #[serde(default = "default_fast_mbps")]
+    fast_mbps: f64,
+
+    /// Throughput (Mbit/s) up to which transfers use rsync level 9 and ssh
+    /// compression
+    #[serde(default = "default_slow_mbps")]
+    slow_mbps: f64,
+
+    /// How long a measurement is reused, e.g. `1h`
+    #[serde(default = "default_remeasure")]
+    remeasure: String,
+}
+
+impl Default for AutoThresholds {
+    fn default() -> Self {
+        Self {
+            fast_mbps: default_fast_mbps(),
+            slow_mbps: default_slow_mbps(),
+            remeasure: default_remeasure(),
+        }
+    }
+}
+
+/// Default throughput above which compression is off
+fn default_fast_mbps() -> f64 {
+    200.0
+}
+
+/// Default throughput below which compression is at its highest
+fn default_slow_mbps() -> f64 {
+    20.0
+}
+
+/// Default lifetime of a measurement
+fn default_remeasure() -> String {
+    "1h".to_string()
+}
+
+/// Default compression mode
+pub(crate) fn default_mode() -> String {
+    "auto".to_string()
+}
+
+/// Compression used for a host
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Choice {
+    /// rsync compression level, if rsync compresses
+    rsync_level: Option<u32>,
+    /// Whether the ssh connection is compressed
+    ssh: bool,
+}
+
+impl Choice {
+    /// No compression at all
+    const OFF: Self = Self {
+        rsync_level: None,
+        ssh: false,
+    };
+
+    /// rsync compression at the given level
+    fn rsync(level: u32) -> Self {
+        Self {
+            rsync_level: Some(level),
+            ssh: false,
+        }
+    }
+
+    /// Arguments selecting this compression for rsync
+    pub(crate) fn rsync_args(&self) -> Vec<String> {
+        match self.rsync_level {
+            Some(level) => vec!["-z".to_string(), format!("--compress-level={}", level)],
+            None => Vec::new(),
+        }
+    }
+
+    /// Short description for messages and reports
+    pub(crate) fn describe(&self) -> String {
+        match (self.rsync_level, self.ssh) {
+            (None, _) => "off".to_string(),
+            (Some(level), false) => format!("rsync level {}", level),
+            (Some(level), true) => format!("rsync level {}, ssh -C", level),
+        }
+    }
+}
+
+/// A measurement of the link to a host with the decision made from it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LinkRecord {
+    /// Unix timestamp (seconds) of the measurement
+    measured_at: u64,
+    /// Effective throughput in Mbit/s
+    throughput_mbps: f64,
+    /// Round-trip time of a no-op command in milliseconds
+    rtt_ms: f64,
+    /// Compression chosen for the link
+    choice: Choice,
+}
+
+/// The compression configured explicitly, or `None` for `auto`
+fn configured(config: &Config) -> Result<Option<Choice>> {
+    match config.compression.trim() {
+        "auto" => Ok(None),
+        "off" | "none" | "0" => Ok(Some(Choice::OFF)),
+        level => match level.parse::<u32>() {
+            Ok(level @ 1..=9) => Ok(Some(Choice::rsync(level))),
+            _ => Err(anyhow!(
+                "Invalid compression: {} (expected auto, off or a level from 1 to 9)",
+                config.compression
+            )),
+        },
+    }
+}
+
+/// Check the configured mode and load a recent measurement of the host from
+/// the state file, so that the control connection can be started with ssh
+/// compression
+pub(crate) fn load_cached(project_dir: &Path, config: &Config) -> Result<()> {
+    if configured(config)?.is_none() {
+        if let Some(record) = cached_record(project_dir, config) {
+            remember(&config.host, record.choice);
+        }
+    }
+    Ok(())
+}
+
+/// Whether connections to the host should be compressed by ssh
+pub(crate) fn ssh_compression(host: &str) -> bool {
+    CHOICES
+        .lock()
+        .ok()
+        .and_then(|choices| choices.get(host).copied())
+        .is_some_and(|choice| choice.ssh)
+}
+
+/// The compression already chosen for the config's host, without measuring
+///
+/// Falls back to rsync's default level when `auto` has no decision yet.
+pub(crate) fn current(config: &Config) -> Choice {
+    if let Ok(Some(choice)) = configured(config) {
+        return choice;
+    }
+    CHOICES
+        .lock()
+        .ok()
+        .and_then(|choices| choices.get(&config.host).copied())
+        .unwrap_or(Choice::rsync(RSYNC_DEFAULT_LEVEL))
+}
+
+/// Choose the compression for a sync to the config's host, measuring the
+/// link if `auto` has no recent measurement
+///
+/// The SSH connection must already be up.
+pub(crate) fn choose(project_dir: &Path, config: &Config) -> Result<Choice> {
+    let verbose = matches!(config.output_level(), OutputLevel::Verbose);
+    if let Some(choice) = configured(config)? {
+        if verbose {
+            println!("   Compression: {} (configured)", choice.describe());
+        }
+        return Ok(choice);
+    }
+    if is_local(&config.host) {
+        if verbose {
+            println!(
+                "   Link to {}: local host, compression {}",
+                config.host,
+                Choice::OFF.describe()
+            );
+        }
+        remember(&config.host, Choice::OFF);
+        return Ok(Choice::OFF);
+    }
+
+    if let Some(record) = cached_record(project_dir, config) {
+        if verbose {
+            print_record(config, &record, "cached");
+        }
+        remember(&config.host, record.choice);
+        return Ok(record.choice);
+    }
+
+    let (rtt, throughput) = match measure(config) {
+        Ok(measured) => measured,
+        Err(e) => {
+            eprintln!("   ⚠ Warning: Could not measure the link: {:#}", e);
+            return Ok(Choice::rsync(RSYNC_DEFAULT_LEVEL));
+        }
+    };
+    let record = LinkRecord {
+        measured_at: now(),
+        throughput_mbps: throughput,
+        rtt_ms: rtt.as_secs_f64() * 1000.0,
+        choice: decide(throughput, &config.compression_auto)?,
+    };
+    if verbose {
+        print_record(config, &record, "measured");
+    }
+    remember(&config.host, record.choice);
+
+    let mut state = State::load(project_dir);
+    state.links.insert(config.host.clone(), record.clone());
+    if let Err(e) = state.save(project_dir) {
+        eprintln!("   ⚠ Warning: Could not save link measurement: {}", e);
+    }
+    Ok(record.choice)
+}
+
+/// Whether `host` (with an optional `user@`) is obviously this machine or
+/// on the local link, so compression can only slow the transfer down
+///
+/// Private addresses don't count, as they are often reached over a VPN.
+fn is_local(host: &str) -> bool {
+    let name = host.rsplit('@').next().unwrap_or(host);
+    let name = name
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    if name == "localhost" || name.ends_with(".localhost") || name.ends_with(".local") {
+        return true;
+    }
+    match name.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => ip.is_loopback() || ip.is_link_local(),
+        Ok(IpAddr::V6(ip)) => ip.is_loopback() || (ip.segments()[0] & 0xffc0) == 0xfe80,
+        Err(_) => false,
+    }
+}
+
+/// The host's measurement from the state file, if it is recent enough
+fn cached_record(project_dir: &Path, config: &Config) -> Option<LinkRecord> {
+    let remeasure = parse_duration(&config.compression_auto.remeasure).ok()?;
+    State::load(project_dir)
+        .links
+        .remove(&config.host)
+        .filter(|record| now().saturating_sub(record.measured_at) < remeasure)
+}
+
+/// Record the decision for the rest of the run
+fn remember(host: &str, choice: Choice) {
+    if let Ok(mut choices) = CHOICES.lock() {
+        choices.insert(host.to_string(), choice);
+    }
+}
+
+/// Print a measurement and its decision in verbose output
+fn print_record(config: &Config, record: &LinkRecord, source: &str) {
+    println!(
+        "   Link to {}: {:.1} Mbit/s, {:.1} ms RTT ({}), compression {}",
+        config.host,
+        record.throughput_mbps,
+        record.rtt_ms,
+        source,
+        record.choice.describe()
+    );
+}
+
+/// Measure the round-trip time and the throughput in Mbit/s
+///
+/// On a fast network this costs two no-op round trips and a 256 KiB write.
+fn measure(config: &Config) -> Result<(Duration, f64)> {
+    let mut rtt = Duration::MAX;
+    for _ in 0..2 {
+        let start = Instant::now();
+        let status = ssh_command(config)
+            .arg("true")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .context("Failed to run ssh")?;
+        if !status.success() {
+            return Err(anyhow!("ssh exited with {}", status));
+        }
+        rtt = rtt.min(start.elapsed());
+    }
+
+    let probe = probe_data(PROBE_BYTES);
+    let start = Instant::now();
+    let mut child = ssh_command(config)
+        .arg("cat >/dev/null")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .context("Failed to run ssh")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&probe).context("Failed to send probe")?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow!("ssh exited with {}", status));
+    }
+
+    // The round trip covers starting ssh and the command, leaving the transfer
+    let transfer = start
+        .elapsed()
+        .saturating_sub(rtt)
+        .max(Duration::from_micros(100));
+    let throughput = (PROBE_BYTES * 8) as f64 / transfer.as_secs_f64() / 1_000_000.0;
+    Ok((rtt, throughput))
+}
+
+/// Pick the compression for a throughput in Mbit/s
+fn decide(throughput: f64, thresholds: &AutoThresholds) -> Result<Choice> {
+    let (fast, slow) = (thresholds.fast_mbps, thresholds.slow_mbps);
+    if !(slow > 0.0 && fast > slow) {
+        return Err(anyhow!(
+            "compression_auto.fast_mbps must be greater than slow_mbps, and both positive"
+        ));
+    }
+
+    Ok(if throughput >= fast {
+        Choice::OFF
+    } else if throughput <= slow {
+        Choice {
+            rsync_level: Some(9),
+            ssh: true,
+        }
+    } else {
+        // Proportional on a log scale, since link speeds span magnitudes
+        let position = (fast.ln() - throughput.ln()) / (fast.ln() - slow.ln());
+        Choice::rsync(1 + (position * (MAX_PROPORTIONAL_LEVEL - 1.0)).round() as u32)
+    })
+}
+
+/// Incompressible bytes, so that an already compressed connection doesn't
+/// inflate the measurement
+fn probe_data(len: usize) -> Vec<u8> {
+    let mut x: u64 = 0x9e37_79b9_7f4a_7c15;
+    (0..len)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            (x >> 24) as u8
+        })
+        .collect()
+}
+
+/// Current Unix time in seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,346 @@
+//! Config files in YAML, TOML or JSON
+//!
+//! The format of a config file follows its extension: `.toml` and `.json`
+//! files are read as TOML and JSON, anything else (`.yaml`, `.yml`, stdin) as
+//! YAML. All three deserialize into the same [`Config`](crate::Config), and
+//! are turned into a YAML value when laid over the global config. Without
+//! `--config`, the first of [`DEFAULT_NAMES`] present in the project is used.
+//!
+//! Like git and cargo, remotebuild run in a subdirectory of a project finds
+//! the config in the nearest parent directory that has one, and uses that
+//! directory as the project root.
+
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+
+use crate::unknown_keys;
+
+/// Config names tried in order when `--config` isn't given
+pub(crate) const DEFAULT_NAMES: [&str; 3] = [
+    ".remotebuild.yaml",
+    ".remotebuild.toml",
+    ".remotebuild.json",
+];
+
+/// Syntax of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConfigFormat {
+    /// YAML, also used for stdin and unknown extensions
+    Yaml,
+    /// TOML, for `.toml` files
+    Toml,
+    /// JSON, for `.json` files
+    Json,
+}
+
+impl ConfigFormat {
+    /// Format of the config file at `path`, from its extension
+    pub(crate) fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Self::Toml,
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Yaml,
+        }
+    }
+
+    /// Deserialize `content` directly, keeping the parser's line numbers in
+    /// errors, and add the paths of keys `T` has no field for to `unknown`
+    pub(crate) fn parse<T: DeserializeOwned>(
+        self,
+        content: &str,
+        unknown: &mut Vec<Vec<String>>,
+    ) -> Result<T> {
+        let track = |path: serde_ignored::Path| unknown.push(unknown_keys::segments(&path));
+        match self {
+            Self::Yaml => {
+                serde_ignored::deserialize(serde_yaml::Deserializer::from_str(content), track)
+                    .map_err(|e| anyhow!(e))
+            }
+            Self::Toml => serde_ignored::deserialize(toml::Deserializer::new(content), track)
+                .map_err(|e| anyhow!(e)),
+            Self::Json => {
+                serde_ignored::deserialize(&mut serde_json::Deserializer::from_str(content), track)
+                    .map_err(|e| anyhow!(e))
+            }
+        }
+    }
+
+    /// Parse `content` into a YAML value, for merging with the global config
+    pub(crate) fn to_yaml(self, content: &str) -> Result<serde_yaml::Value> {
+        match self {
+            Self::Yaml => self.parse(content, &mut Vec::new()),
+            Self::Toml => {
+                let value: toml::Value = self.parse(content, &mut Vec::new())?;
+                serde_yaml::to_value(value).map_err(|e| anyhow!(e))
+            }
+            Self::Json => {
+                let value: serde_json::Value = self.parse(content, &mut Vec::new())?;
+                serde_yaml::to_value(value).map_err(|e| anyhow!(e))
+            }
+        }
+    }
+}
+
+/// Name of the project's config when `--config` isn't given: the first of
+/// [`DEFAULT_NAMES`] that exists, else the YAML one
+pub(crate) fn default_name(project_dir: &Path) -> &'static str {
+    DEFAULT_NAMES
+        .into_iter()
+        .find(|name| project_dir.join(name).is_file())
+        .unwrap_or(DEFAULT_NAMES[0])
+}
+
+/// Nearest of `start` and its parents containing the config named by
+/// `config` (any of [`DEFAULT_NAMES`] when `None`)
+///
+/// Absolute config paths and stdin don't belong to a directory, so nothing
+/// is searched for them.
+pub(crate) fn find_project_root(start: &Path, config: Option<&str>) -> Option<PathBuf> {
+    let names: Vec<&str> = match config {
+        Some(name) if name == crate::STDIN_CONFIG || Path::new(name).is_absolute() => return None,
+        Some(name) => vec![name],
+        None => DEFAULT_NAMES.to_vec(),
+    };
+    start
+        .ancestors()
+        .find(|dir| names.iter().any(|name| dir.join(name).is_file()))
+        .map(Path::to_path_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    /// The smallest useful config in each format
+    const MINIMAL: [(ConfigFormat, &str); 3] = [
+        (ConfigFormat::Yaml, "host: build-box\n"),
+        (ConfigFormat::Toml, "host = \"build-box\"\n"),
+        (ConfigFormat::Json, "{ \"host\": \"build-box\" }\n"),
+    ];
+
+    /// The same config using every kind of value, in each format
+    const FULL: [(ConfigFormat, &str); 3] = [
+        (
+            ConfigFormat::Yaml,
+            r#"host: dev@build-box
+remote_path: ~/builds/app
+ssh_port: 2222
+resilient: true
+keep_remote_logs: 5
+build_command:
+  - cmake -B build
+  - cmake --build build
+env:
+  CC: clang
+  RUST_LOG: debug
+artifacts:
+  - build/app
+  - remote: build/lib/*.so
+    local: ./lib
+exclude_patterns: ["*.o", "build/"]
+targets:
+  release:
+    build_command: cmake --build build --config Release
+    extra_args: ["-j", "8"]
+test:
+  command: ctest --test-dir build
+  format: ctest
+  patterns:
+    failed: ["FAIL: {name}"]
+"#,
+        ),
+        (
+            ConfigFormat::Toml,
+            r#"host = "dev@build-box"
+remote_path = "~/builds/app"
+ssh_port = 2222
+resilient = true
+keep_remote_logs = 5
+build_command = ["cmake -B build", "cmake --build build"]
+exclude_patterns = ["*.o", "build/"]
+artifacts = [
+    "build/app",
+    { remote = "build/lib/*.so", local = "./lib" },
+]
+
+[env]
+CC = "clang"
+RUST_LOG = "debug"
+
+[targets.release]
+build_command = "cmake --build build --config Release"
+extra_args = ["-j", "8"]
+
+[test]
+command = "ctest --test-dir build"
+format = "ctest"
+patterns = { failed = ["FAIL: {name}"] }
+"#,
+        ),
+        (
+            ConfigFormat::Json,
+            r#"{
+    "host": "dev@build-box",
+    "remote_path": "~/builds/app",
+    "ssh_port": 2222,
+    "resilient": true,
+    "keep_remote_logs": 5,
+    "build_command": ["cmake -B build", "cmake --build build"],
+    "env": { "CC": "clang", "RUST_LOG": "debug" },
+    "artifacts": [
+        "build/app",
+        { "remote": "build/lib/*.so", "local": "./lib" }
+    ],
+    "exclude_patterns": ["*.o", "build/"],
+    "targets": {
+        "release": {
+            "build_command": "cmake --build build --config Release",
+            "extra_args": ["-j", "8"]
+        }
+    },
+    "test": {
+        "command": "ctest --test-dir build",
+        "format": "ctest",
+        "patterns": { "failed": ["FAIL: {name}"] }
+    }
+}
+"#,
+        ),
+    ];
+
+    /// Parse a config, failing on unknown keys
+    fn parse(format: ConfigFormat, content: &str) -> Config {
+        let mut unknown = Vec::new();
+        let config = format.parse(content, &mut unknown).unwrap();
+        assert!(
+            unknown.is_empty(),
+            "{:?}: unknown keys {:?}",
+            format,
+            unknown
+        );
+        config
+    }
+
+    /// A config as a value, to compare configs read from different formats
+    fn value(config: &Config) -> serde_json::Value {
+        serde_json::to_value(config).unwrap()
+    }
+
+    /// Every format fills in the same defaults around a host
+    #[test]
+    fn minimal_config() {
+        let expected = value(&parse(ConfigFormat::Yaml, "host: build-box"));
+        for (format, content) in MINIMAL {
+            let config = parse(format, content);
+            assert_eq!(config.host, "build-box");
+            assert_eq!(value(&config), expected, "{:?}", format);
+        }
+    }
+
+    /// Every format reads the full config into the same settings
+    #[test]
+    fn full_config() {
+        let configs: Vec<Config> = FULL.iter().map(|(f, c)| parse(*f, c)).collect();
+        let yaml = &configs[0];
+        assert_eq!(yaml.host, "dev@build-box");
+        assert_eq!(yaml.ssh_port, Some(2222));
+        assert_eq!(yaml.env["RUST_LOG"], "debug");
+        assert_eq!(yaml.artifacts[1].local.as_deref(), Some("./lib"));
+        assert!(yaml.resilient);
+        assert_eq!(value(yaml)["targets"]["release"]["extra_args"][1], "8");
+        assert_eq!(value(yaml)["test"]["patterns"]["failed"][0], "FAIL: {name}");
+        for (config, (format, _)) in configs.iter().zip(FULL) {
+            assert_eq!(value(config), value(yaml), "{:?}", format);
+        }
+    }
+
+    /// The values laid over the global config are the same in every format
+    #[test]
+    fn yaml_values_match() {
+        let values: Vec<serde_yaml::Value> =
+            FULL.iter().map(|(f, c)| f.to_yaml(c).unwrap()).collect();
+        assert_eq!(values[1], values[0]);
+        assert_eq!(values[2], values[0]);
+    }
+
+    /// Unknown keys are reported with their full path in every format
+    #[test]
+    fn unknown_keys() {
+        let typo = [
+            (
+                ConfigFormat::Yaml,
+                "targets:\n  release:\n    extra_arg: [\"-j\"]\n",
+            ),
+            (
+                ConfigFormat::Toml,
+                "[targets.release]\nextra_arg = [\"-j\"]\n",
+            ),
+            (
+                ConfigFormat::Json,
+                r#"{"targets": {"release": {"extra_arg": ["-j"]}}}"#,
+            ),
+        ];
+        for (format, content) in typo {
+            let mut unknown = Vec::new();
+            format.parse::<Config>(content, &mut unknown).unwrap();
+            assert_eq!(
+                unknown,
+                [["targets", "release", "extra_arg"]],
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    /// Syntax errors keep the parser's position
+    #[test]
+    fn errors_have_lines() {
+        let bad = [
+            (ConfigFormat::Yaml, "host: a\nenv: [\n"),
+            (ConfigFormat::Toml, "host = \"a\"\nenv = \n"),
+            (ConfigFormat::Json, "{\"host\": \"a\",\n}"),
+        ];
+        for (format, content) in bad {
+            let error = format
+                .parse::<Config>(content, &mut Vec::new())
+                .unwrap_err();
+            assert!(
+                error.to_string().contains("line"),
+                "{:?}: {}",
+                format,
+                error
+            );
+        }
+    }
+
+    /// The extension picks the format, YAML when there's none
+    #[test]
+    fn format_from_path() {
+        let format = |path: &str| ConfigFormat::from_path(Path::new(path));
+        assert_eq!(format(".remotebuild.toml"), ConfigFormat::Toml);
+        assert_eq!(format("ci/remotebuild.JSON"), ConfigFormat::Json);
+        assert_eq!(format(".remotebuild.yml"), ConfigFormat::Yaml);
+        assert_eq!(format("-"), ConfigFormat::Yaml);
+    }
+
+    /// The nearest directory with a config is the root and its first
+    /// default name is used
+    #[test]
+    fn project_root() {
+        let root =
+            std::env::temp_dir().join(format!("remotebuild-test-root-{}", std::process::id()));
+        let nested = root.join("src/deep");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(root.join(".remotebuild.json"), "{}").unwrap();
+        std::fs::write(root.join(".remotebuild.toml"), "").unwrap();
+
+        assert_eq!(find_project_root(&nested, None), Some(root.clone()));
+        assert_eq!(default_name(&root), ".remotebuild.toml");
+        assert_eq!(default_name(&nested), ".remotebuild.yaml");
+        assert_eq!(find_project_root(&nested, Some("ci.yaml")), None);
+        assert_eq!(find_project_root(&nested, Some("-")), None);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
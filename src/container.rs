@@ -0,0 +1,215 @@
+//! Running the build command inside a Docker or Podman container
+//!
+//! With a `docker` section, the build command runs in a throwaway container
+//! on the remote host. The synced `remote_path` is mounted at `workdir`, which
+//! is also the working directory, and the `env` map and target variables are
+//! exported inside the container. Before anything is synced the runtime is
+//! checked and the image is looked up, and pulled if `pull` is set.
+//!
+//! `user: match-local` runs the container as the SSH user on the remote, who
+//! owns the synced files, so everything the build writes can be overwritten by
+//! the next sync and read by the artifact download. Without `user` the image's
+//! default user (usually root) is kept and the artifacts are handed back to
+//! the SSH user after the build.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+
+use crate::artifact::Artifact;
+use crate::{
+    clear_status, ensure_ssh_connection, print_status, run_ssh_command_output,
+    run_ssh_command_streaming, Config, OutputLevel,
+};
+
+/// `user` value that maps the container user to the remote SSH user
+const MATCH_LOCAL: &str = "match-local";
+
+/// Container settings for the build command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct DockerConfig {
+    /// Image to run the build in
+    image: String,
+
+    /// Extra arguments for `run`, placed before the image
+    #[serde(default)]
+    args: Vec<String>,
+
+    /// `match-local` to run as the remote SSH user, or any `--user` value
+    /// (default: the image's user)
+    #[serde(default)]
+    user: Option<String>,
+
+    /// Mount point of `remote_path` and working directory in the container
+    #[serde(default = "default_workdir")]
+    workdir: String,
+
+    /// Shell running the build command inside the container
+    #[serde(default = "default_shell")]
+    shell: String,
+
+    /// Pull the image when the remote host doesn't have it
+    #[serde(default)]
+    pull: bool,
+
+    /// Container runtime: `docker` or `podman`
+    #[serde(default = "default_runtime")]
+    runtime: String,
+}
+
+/// Default mount point and working directory in the container
+fn default_workdir() -> String {
+    "/work".to_string()
+}
+
+/// Default shell inside the container
+fn default_shell() -> String {
+    "sh".to_string()
+}
+
+/// Default container runtime
+fn default_runtime() -> String {
+    "docker".to_string()
+}
+
+impl DockerConfig {
+    /// The runtime binary, checked against the supported ones
+    fn runtime(&self) -> Result<&str> {
+        match self.runtime.as_str() {
+            runtime @ ("docker" | "podman") => Ok(runtime),
+            other => Err(anyhow!(
+                "Unsupported container runtime: {} (expected docker or podman)",
+                other
+            )),
+        }
+    }
+}
+
+/// Wrap a command so that it runs in the configured container
+///
+/// The result must run in the remote project directory, which is mounted
+/// from `$PWD`. `artifacts` are handed back to the SSH user afterwards when
+/// the container keeps the image's user.
+pub(crate) fn wrap_command(
+    docker: &DockerConfig,
+    command: &str,
+    artifacts: &[Artifact],
+) -> Result<String> {
+    let runtime = docker.runtime()?;
+    let workdir = escape(Cow::Borrowed(docker.workdir.as_str()));
+    let mut wrapped = format!(
+        "{} run --rm -v \"$PWD\":{} -w {}",
+        runtime, workdir, workdir
+    );
+
+    let mut inner = command.to_string();
+    match docker.user.as_deref() {
+        // Rootless podman maps the user itself; `-u` would pick a subuid
+        Some(MATCH_LOCAL) if runtime == "podman" => wrapped.push_str(" --userns=keep-id"),
+        Some(MATCH_LOCAL) => wrapped.push_str(" -u \"$(id -u):$(id -g)\""),
+        Some(user) => {
+            wrapped.push_str(" -u ");
+            wrapped.push_str(&escape(Cow::Borrowed(user)));
+        }
+        None if artifacts.iter().any(|a| !a.remote.starts_with('/')) => {
+            // Absolute artifacts live outside the mount and aren't the container's
+            let owned: Vec<&str> = artifacts
+                .iter()
+                .map(|a| a.remote.as_str())
+                .filter(|a| !a.starts_with('/'))
+                .collect();
+            wrapped.push_str(" -e REMOTEBUILD_OWNER=\"$(id -u):$(id -g)\"");
+            // Artifact patterns stay unquoted so the container shell expands them
+            inner = format!(
+                "{}\nstatus=$?\nchown -R \"$REMOTEBUILD_OWNER\" -- {} 2>/dev/null\nexit $status",
+                command,
+                owned.join(" ")
+            );
+        }
+        None => {}
+    }
+
+    for arg in &docker.args {
+        wrapped.push(' ');
+        wrapped.push_str(&escape(Cow::Borrowed(arg.as_str())));
+    }
+    wrapped.push_str(&format!(
+        " {} {} -c {}",
+        escape(Cow::Borrowed(docker.image.as_str())),
+        escape(Cow::Borrowed(docker.shell.as_str())),
+        escape(Cow::Owned(inner))
+    ));
+    Ok(wrapped)
+}
+
+/// Check the container runtime and image on the remote, pulling the image if
+/// it is missing and `pull` is set
+pub(crate) fn prepare(config: &Config) -> Result<()> {
+    let Some(docker) = &config.docker else {
+        return Ok(());
+    };
+    let runtime = docker.runtime()?;
+    let image = escape(Cow::Borrowed(docker.image.as_str()));
+    let output = config.output_level();
+
+    // Runs before the sync, so the connection isn't up yet
+    ensure_ssh_connection(config)?;
+    let mut spinner = print_status(output, &format!("🐳 Checking {} ", runtime));
+
+    let probe = format!(
+        "if ! command -v {runtime} >/dev/null 2>&1; then echo missing; \
+         elif ! out=$({runtime} info 2>&1 >/dev/null); then echo unreachable; echo \"$out\" | tail -n 5; \
+         elif {runtime} image inspect {image} >/dev/null 2>&1; then echo present; \
+         else echo absent; fi",
+        runtime = runtime,
+        image = image
+    );
+    let result = run_ssh_command_output(config, &probe);
+    clear_status(output, &mut spinner);
+    let report = result?;
+    let mut lines = report.lines();
+
+    match lines.next().unwrap_or_default() {
+        "present" => {}
+        "missing" => {
+            return Err(anyhow!("{} is not installed on {}", runtime, config.host));
+        }
+        "unreachable" => {
+            let detail: Vec<&str> = lines.map(|line| line.trim()).collect();
+            return Err(anyhow!(
+                "{} on {} is not usable: {}",
+                runtime,
+                config.host,
+                detail.join(" ")
+            ));
+        }
+        _ if !docker.pull => {
+            return Err(anyhow!(
+                "Image {} is not available on {}; pull it there or set docker.pull: true",
+                docker.image,
+                config.host
+            ));
+        }
+        _ => {
+            let mut spinner = print_status(output, &format!("🐳 Pulling {} ", docker.image));
+            let result =
+                run_ssh_command_streaming(config, &format!("{} pull {}", runtime, image), "pull");
+            clear_status(output, &mut spinner);
+            let pulled = result?;
+            if !pulled.status.success() {
+                return Err(anyhow!(
+                    "Failed to pull {} ({}){}",
+                    docker.image,
+                    pulled.status,
+                    pulled.tail()
+                ));
+            }
+        }
+    }
+
+    if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) {
+        println!("   ✓ Using {} image {}", runtime, docker.image);
+    }
+    Ok(())
+}
@@ -0,0 +1,364 @@
+//! Excludes detected from the project's build systems
+//!
+//! Build output uploaded by accident makes the first sync take forever, so
+//! marker files in the project root add the output directories of their
+//! ecosystem to the excludes: `Cargo.toml` adds `/target/`, `package.json`
+//! adds `node_modules/`, Python projects their virtualenvs and caches, and
+//! CMake and Meson the build directories named in `build_command` (`-B`,
+//! `--build`, `-C`, `meson setup`).
+//!
+//! Directories listed in `force_include` are never auto-excluded, and
+//! `auto_excludes: false` turns detection off. Like the built-in excludes,
+//! detected ones are protected from `--clean-sync`. `init --detect` writes
+//! them into the generated config instead.
+
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::patterns::ExcludeSet;
+use crate::{Config, OutputLevel};
+
+/// Whether the detected excludes were already printed in this run
+static ANNOUNCED: AtomicBool = AtomicBool::new(false);
+
+/// Excludes added for a marker file in the project root
+struct Rule {
+    /// Files of which any marks the ecosystem
+    markers: &'static [&'static str],
+    /// Patterns excluded when a marker exists
+    excludes: &'static [&'static str],
+}
+
+/// Fixed excludes per ecosystem
+const RULES: &[Rule] = &[
+    Rule {
+        markers: &["Cargo.toml"],
+        excludes: &["/target/"],
+    },
+    Rule {
+        markers: &["package.json"],
+        excludes: &["node_modules/"],
+    },
+    Rule {
+        markers: &[
+            "pyproject.toml",
+            "setup.py",
+            "setup.cfg",
+            "requirements.txt",
+        ],
+        excludes: &[
+            "/.venv/",
+            "__pycache__/",
+            ".pytest_cache/",
+            ".mypy_cache/",
+            "/.tox/",
+        ],
+    },
+    Rule {
+        markers: &["CMakeLists.txt"],
+        excludes: &["/cmake-build-*/"],
+    },
+    Rule {
+        markers: &["build.gradle", "build.gradle.kts", "settings.gradle"],
+        excludes: &["/.gradle/"],
+    },
+    Rule {
+        markers: &["build.zig"],
+        excludes: &["/zig-out/", "/.zig-cache/", "/zig-cache/"],
+    },
+];
+
+/// Meson's conventional build directory, used when the command names none
+const MESON_DEFAULT_BUILD_DIR: &str = "builddir";
+
+/// An exclude found by detection
+#[derive(Debug, Clone)]
+pub(crate) struct Detected {
+    /// rsync exclude pattern
+    pub(crate) pattern: String,
+    /// What it was detected from, e.g. `Cargo.toml`
+    pub(crate) reason: String,
+}
+
+/// Detect the output directories of the project's build systems
+///
+/// Patterns already covered by the built-in excludes or `exclude_patterns`
+/// are left out, as are directories in `force_include`.
+pub(crate) fn detect(
+    project_dir: &Path,
+    build_command: &str,
+    builtin_excludes: &[&str],
+    exclude_patterns: &[String],
+    force_include: &[String],
+) -> Vec<Detected> {
+    let mut found = Vec::new();
+    let mut add = |pattern: String, reason: &str| {
+        found.push(Detected {
+            pattern,
+            reason: reason.to_string(),
+        })
+    };
+
+    for rule in RULES {
+        if let Some(marker) = rule
+            .markers
+            .iter()
+            .find(|marker| project_dir.join(marker).is_file())
+        {
+            for pattern in rule.excludes {
+                add(pattern.to_string(), marker);
+            }
+        }
+    }
+
+    let cmake = project_dir.join("CMakeLists.txt").is_file();
+    let meson = project_dir.join("meson.build").is_file();
+    if cmake || meson {
+        let dirs = build_dirs(build_command);
+        let reason = if cmake {
+            "CMakeLists.txt"
+        } else {
+            "meson.build"
+        };
+        for dir in &dirs {
+            add(format!("/{}/", dir), reason);
+        }
+        if meson && dirs.is_empty() {
+            add(format!("/{}/", MESON_DEFAULT_BUILD_DIR), "meson.build");
+        }
+    }
+
+    // Virtualenvs under any name are recognized by their config file
+    if let Ok(entries) = fs::read_dir(project_dir) {
+        let mut venvs: Vec<String> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("pyvenv.cfg").is_file())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        venvs.sort();
+        for venv in venvs {
+            add(format!("/{}/", venv), "pyvenv.cfg");
+        }
+    }
+
+    let existing = ExcludeSet::new(
+        builtin_excludes
+            .iter()
+            .copied()
+            .chain(exclude_patterns.iter().map(String::as_str)),
+    );
+    let mut kept: Vec<Detected> = Vec::new();
+    for detected in found {
+        let dir = detected.pattern.trim_matches('/');
+        let covered = !dir.contains('*') && existing.excludes_file(&format!("{}/", dir));
+        let forced = force_include
+            .iter()
+            .any(|include| include.trim_matches('/') == dir);
+        if !covered && !forced && !kept.iter().any(|k| k.pattern == detected.pattern) {
+            kept.push(detected);
+        }
+    }
+    kept
+}
+
+/// Detect excludes for the config unless `auto_excludes` is off
+pub(crate) fn apply(project_dir: &Path, config: &mut Config) {
+    if config.auto_excludes {
+        config.detected_excludes = detect(
+            project_dir,
+            &config.build_command.to_string(),
+            &config.builtin_excludes(),
+            &config.exclude_patterns,
+            &config.force_include,
+        );
+    }
+}
+
+/// Print the detected excludes once per run, in normal and verbose output
+pub(crate) fn announce(config: &Config) {
+    if config.detected_excludes.is_empty()
+        || !matches!(
+            config.output_level(),
+            OutputLevel::Normal | OutputLevel::Verbose
+        )
+        || ANNOUNCED.swap(true, Ordering::SeqCst)
+    {
+        return;
+    }
+    let list: Vec<String> = config
+        .detected_excludes
+        .iter()
+        .map(|d| format!("{} ({})", d.pattern, d.reason))
+        .collect();
+    println!("   Auto-excluded: {}", list.join(", "));
+    println!("   (set auto_excludes: false or list directories in force_include to sync them)");
+}
+
+/// Build directories named in the cmake, meson or ninja invocations of
+/// a command
+///
+/// Only plain relative paths count; anything with variables, placeholders or
+/// `..` is left to the user.
+fn build_dirs(command: &str) -> Vec<String> {
+    let mut dirs = Vec::new();
+    for segment in command.split([';', '&', '|', '(', ')']) {
+        let words: Vec<&str> = segment
+            .split_whitespace()
+            .map(|word| word.trim_matches(|c| c == '"' || c == '\''))
+            .skip_while(|word| word.contains('='))
+            .collect();
+        let Some(program) = words.first() else {
+            continue;
+        };
+        let program = program.rsplit('/').next().unwrap_or(program);
+
+        for (i, word) in words.iter().enumerate().skip(1) {
+            let next = words.get(i + 1).copied();
+            let dir = match (program, *word) {
+                ("cmake", "-B" | "--build") => next,
+                ("cmake", _) => word.strip_prefix("-B").filter(|dir| !dir.is_empty()),
+                ("ninja" | "meson", "-C") => next,
+                // Options may come before the build directory
+                ("meson", "setup") if i == 1 => {
+                    words[i + 1..].iter().find(|w| !w.starts_with('-')).copied()
+                }
+                _ => None,
+            };
+            if let Some(dir) = dir.and_then(plain_relative_dir) {
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+    }
+    dirs
+}
+
+/// The directory normalized, if it is a plain path inside the project
+fn plain_relative_dir(dir: &str) -> Option<String> {
+    let dir = dir.trim_start_matches("./").trim_end_matches('/');
+    let plain = !dir.is_empty()
+        && dir != "."
+        && !dir.starts_with(['/', '-', '~'])
+        && !dir.contains(['$', '{', '`', '*'])
+        && !dir.split('/').any(|part| part == "..");
+    plain.then(|| dir.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh project directory containing `files`
+    fn project(name: &str, files: &[&str]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "remotebuild-test-detect-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    /// The detected patterns with their reasons
+    fn detected(found: &[Detected]) -> Vec<(&str, &str)> {
+        found
+            .iter()
+            .map(|d| (d.pattern.as_str(), d.reason.as_str()))
+            .collect()
+    }
+
+    /// Build directories come from cmake, meson and ninja invocations only,
+    /// and only when they are plain paths inside the project
+    #[test]
+    fn build_dirs_from_command() {
+        let cases: &[(&str, &[&str])] = &[
+            ("cmake -B build && cmake --build build", &["build"]),
+            (
+                "cmake -Bout/release . && ninja -C out/release",
+                &["out/release"],
+            ),
+            ("CC=clang /usr/bin/cmake -S . -B './gen/'", &["gen"]),
+            (
+                "meson setup --buildtype=release bdir; meson compile -C bdir",
+                &["bdir"],
+            ),
+            ("(cd src && make) | tee log", &[]),
+            ("make -C build", &[]),
+            ("cmake -B $BUILD_DIR", &[]),
+            ("cmake -B ../outside", &[]),
+            ("cmake -B /abs/build", &[]),
+            ("cmake -B . ", &[]),
+        ];
+        for (command, dirs) in cases {
+            assert_eq!(&build_dirs(command), dirs, "{}", command);
+        }
+    }
+
+    /// Marker files add their ecosystem's output directories, and
+    /// virtualenvs are found by their config file under any name
+    #[test]
+    fn markers_add_excludes() {
+        let dir = project(
+            "markers",
+            &[
+                "Cargo.toml",
+                "requirements.txt",
+                "CMakeLists.txt",
+                "env-3.12/pyvenv.cfg",
+            ],
+        );
+        let found = detect(&dir, "cmake -B build && make -C build", &[], &[], &[]);
+        assert_eq!(
+            detected(&found),
+            [
+                ("/target/", "Cargo.toml"),
+                ("/.venv/", "requirements.txt"),
+                ("__pycache__/", "requirements.txt"),
+                (".pytest_cache/", "requirements.txt"),
+                (".mypy_cache/", "requirements.txt"),
+                ("/.tox/", "requirements.txt"),
+                ("/cmake-build-*/", "CMakeLists.txt"),
+                ("/build/", "CMakeLists.txt"),
+                ("/env-3.12/", "pyvenv.cfg"),
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Meson without a build directory in the command gets its default,
+    /// and nothing is detected without markers
+    #[test]
+    fn meson_default_and_no_markers() {
+        let dir = project("meson", &["meson.build"]);
+        let found = detect(&dir, "ninja", &[], &[], &[]);
+        assert_eq!(detected(&found), [("/builddir/", "meson.build")]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let dir = project("plain", &["main.c", "Makefile"]);
+        assert!(detect(&dir, "cmake -B build", &[], &[], &[]).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Patterns the excludes already cover and `force_include` directories
+    /// are left out
+    #[test]
+    fn covered_and_forced_are_skipped() {
+        let dir = project("skipped", &["Cargo.toml", "package.json", "CMakeLists.txt"]);
+        let found = detect(
+            &dir,
+            "cmake -B build",
+            &["build/"],
+            &["target".to_string()],
+            &["/node_modules/".to_string()],
+        );
+        assert_eq!(detected(&found), [("/cmake-build-*/", "CMakeLists.txt")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
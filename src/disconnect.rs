@@ -0,0 +1,112 @@
+//! Closing control masters on request
+//!
+//! The control master outlives remotebuild for `connection_persist`, keeping
+//! a connection to the host open. `remotebuild disconnect` closes the one of
+//! the configured host (or `--host`) with `ssh -O exit` and says whether it
+//! was running. `--all` closes the master behind every `control_*` socket in
+//! remotebuild's cache directory, whichever project or host it belongs to.
+//! Sockets no master answers on anymore are removed on the way.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+use crate::{control_dir, control_path_option, ssh_control_path, ssh_master, Config};
+
+/// Prefix of the control socket names
+const SOCKET_PREFIX: &str = "control_";
+
+/// What closing one socket's master found
+enum Closed {
+    /// A master was running and was told to exit
+    Master,
+    /// Only a socket left behind was there, now removed
+    Stale,
+    /// Nothing was there
+    Nothing,
+}
+
+/// Close the configured host's control master
+pub(crate) fn run_disconnect(config: &Config) -> Result<()> {
+    let path = ssh_control_path(config);
+    match close(Path::new(&path), &config.host) {
+        Closed::Master => println!("Closed the SSH connection to {}", config.host),
+        Closed::Stale => println!(
+            "No SSH connection to {} was open; removed its stale socket",
+            config.host
+        ),
+        Closed::Nothing => println!("No SSH connection to {} was open", config.host),
+    }
+    Ok(())
+}
+
+/// Close the control masters of every host
+pub(crate) fn run_disconnect_all() -> Result<()> {
+    let dir = control_dir();
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("No SSH connections were open");
+            return Ok(());
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    // The start locks and master logs next to the sockets stay
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|kind| is_socket(&kind)))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with(SOCKET_PREFIX))
+        .collect();
+    names.sort();
+
+    let (mut closed, mut stale) = (0, 0);
+    for name in names {
+        // The host only labels the request; ssh finds the master by its socket
+        let label = &name[SOCKET_PREFIX.len()..];
+        match close(&dir.join(&name), label) {
+            Closed::Master => {
+                println!("   ✓ Closed {}", label);
+                closed += 1;
+            }
+            Closed::Stale => stale += 1,
+            Closed::Nothing => {}
+        }
+    }
+
+    match closed {
+        0 => println!("No SSH connections were open"),
+        1 => println!("Closed 1 SSH connection"),
+        n => println!("Closed {} SSH connections", n),
+    }
+    if stale > 0 {
+        println!("   ℹ Removed {} stale socket(s)", stale);
+    }
+    Ok(())
+}
+
+/// Whether a directory entry is a Unix socket
+#[cfg(unix)]
+fn is_socket(kind: &fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    kind.is_socket()
+}
+
+/// Whether a directory entry is a Unix socket, never outside Unix
+#[cfg(not(unix))]
+fn is_socket(_kind: &fs::FileType) -> bool {
+    false
+}
+
+/// Close the master behind the socket at `path`, removing the socket when
+/// no master answered on it
+fn close(path: &Path, host: &str) -> Closed {
+    if ssh_master::exit(&control_path_option(&path.to_string_lossy()), host) {
+        return Closed::Master;
+    }
+    if fs::symlink_metadata(path).is_ok() && fs::remove_file(path).is_ok() {
+        Closed::Stale
+    } else {
+        Closed::Nothing
+    }
+}
@@ -0,0 +1,113 @@
+//! Previewing a build with `--dry-run`
+//!
+//! A dry run goes through the sync, build and artifact phases without
+//! changing the remote. The sync runs rsync with `--dry-run
+//! --itemize-changes` and lists the paths it would send and delete, the
+//! build and the artifact downloads print what they would run, and the
+//! remote directory is only checked for, not created. Remembered state such
+//! as the git file list and the sync manifest is left as it was, and hooks,
+//! `setup_command`, container checks and Nix shells are skipped, so the
+//! preview can run against a server that has never seen the project.
+
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::process::Output;
+
+use crate::{
+    artifact_source, indented_tail, itemize, remote_build_command, run_remote_build_command,
+    sync_artifacts, sync_back, sync_to_remote, Config, OutputLevel, SyncScope,
+};
+
+/// Preview the sync, the build and the artifact downloads
+pub(crate) fn run(project_dir: &Path, config: &Config, scope: SyncScope) -> Result<()> {
+    sync_to_remote(project_dir, config, scope)?;
+    run_remote_build_command(config)?;
+    sync_back::fetch(config, project_dir)?;
+    sync_artifacts(config, project_dir, &config.artifact_dir(project_dir))?;
+    println!();
+    println!(
+        "🔍 Dry run complete, nothing was changed on {}",
+        config.host
+    );
+    Ok(())
+}
+
+/// Print what the sync's dry run found: the paths rsync would send and
+/// delete, and the files that would be removed with `rm` afterwards
+pub(crate) fn report_sync(
+    config: &Config,
+    rsync: &Output,
+    created: bool,
+    removals: &[String],
+) -> Result<()> {
+    if !rsync.status.success() {
+        return Err(anyhow!(
+            "rsync failed with {}{}",
+            rsync.status,
+            indented_tail(&String::from_utf8_lossy(&rsync.stderr))
+        ));
+    }
+
+    let changes = itemize::Changes::parse(&String::from_utf8_lossy(&rsync.stdout));
+    if created {
+        println!("   Would create remote directory {}", config.remote_path);
+    }
+    if changes.transferred.is_empty() {
+        println!("   Would send nothing, the remote is up to date");
+    } else {
+        println!("   Would send {} path(s):", changes.transferred.len());
+        for path in &changes.transferred {
+            println!("     {}", path);
+        }
+    }
+    let deleted: Vec<&String> = changes.deleted.iter().chain(removals).collect();
+    if !deleted.is_empty() {
+        println!("   Would delete {} remote path(s):", deleted.len());
+        for path in deleted {
+            println!("     {}", path);
+        }
+    }
+    Ok(())
+}
+
+/// Print what a tar sync's dry run found: every file would be sent, as tar
+/// can't compare with the remote, and the files removed with `rm`
+pub(crate) fn report_tar(
+    config: &Config,
+    files: usize,
+    created: bool,
+    removals: &[String],
+) -> Result<()> {
+    if created {
+        println!("   Would create remote directory {}", config.remote_path);
+    }
+    println!("   Would send all {} file(s) with tar", files);
+    if !removals.is_empty() {
+        println!("   Would delete {} remote path(s):", removals.len());
+        for path in removals {
+            println!("     {}", path);
+        }
+    }
+    Ok(())
+}
+
+/// Print the build command that would run, and in verbose output the whole
+/// remote command wrapping it
+pub(crate) fn report_build(config: &Config) -> Result<()> {
+    println!("   Would run: {}", config.build_command);
+    if matches!(config.output_level(), OutputLevel::Verbose) {
+        println!("   Remote command: {}", remote_build_command(config)?);
+    }
+    Ok(())
+}
+
+/// Print the artifacts that would be copied back into `local_dir`
+pub(crate) fn report_artifacts(config: &Config, local_dir: &Path) {
+    for artifact in &config.artifacts {
+        println!(
+            "   Would copy {} to {}",
+            artifact_source(config, &artifact.remote),
+            artifact.destination(local_dir).display()
+        );
+    }
+}
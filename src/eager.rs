@@ -0,0 +1,352 @@
+//! Downloading artifacts while the build is still running
+//!
+//! With `eager_artifacts: true`, a watcher lists the files matching the
+//! artifact patterns every few seconds over the control connection while the
+//! build runs. A file whose size and modification time are the same in two
+//! consecutive listings is no longer being written and is downloaded right
+//! away; one that changes again after that is downloaded again later.
+//!
+//! Once the build has finished, one last listing is compared with what was
+//! downloaded, and only the files that are missing or changed since are
+//! fetched, so even a file rewritten at the very end of the build arrives
+//! complete. Which files were fetched at which state is kept in [`Fetched`]
+//! for the run.
+//!
+//! Artifacts with a `local` destination aren't watched, since rsync may
+//! rename them, and neither are patterns remotebuild expands itself (`**`,
+//! spaces), which the remote shell would get wrong: they are copied after
+//! the build, like without eager downloads.
+
+use anyhow::{Context, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::artifact::Artifact;
+use crate::artifact_glob;
+use crate::{
+    clear_status, compression, copy_artifacts, indented_tail, partial_dir_arg, print_status,
+    remove_partial_dir, rewrite, rsync_command, run_rsync, run_ssh_command_output,
+    ssh_control_path_arg, Config, OutputLevel, REMOTE_META_DIR,
+};
+
+/// Time between two listings of the remote artifacts
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Size and modification time (seconds) of a remote file
+type Stamp = (u64, u64);
+
+/// A remote file matching an artifact
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RemoteFile {
+    /// Index of the artifact in `artifacts`
+    artifact: usize,
+    /// Remote directory the file is downloaded relative to
+    base: String,
+    /// Path below `base`, which is also its path below the local directory
+    relative: String,
+    /// State of the file when listed
+    stamp: Stamp,
+}
+
+/// The artifact files downloaded during a run, with the state they were in
+/// when listed right before their download
+#[derive(Debug, Default)]
+pub(crate) struct Fetched {
+    /// Stamp per remote path (`base/relative`)
+    files: BTreeMap<String, Stamp>,
+    /// Local files written, for rewriting paths at the end
+    local: BTreeSet<PathBuf>,
+}
+
+/// Run `build` while downloading the artifacts it finishes
+///
+/// The watcher never fails the build: listing or download errors only mean
+/// the files are fetched after the build instead.
+pub(crate) fn during_build(
+    config: &Config,
+    local_dir: &Path,
+    build: impl FnOnce() -> Result<()>,
+) -> (Result<()>, Fetched) {
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let watcher = scope.spawn(|| watch(config, local_dir, &done));
+        let result = build();
+        done.store(true, Ordering::SeqCst);
+        let fetched = watcher.join().unwrap_or_default();
+        (result, fetched)
+    })
+}
+
+/// Poll the remote artifacts until `done`, fetching the stable ones
+fn watch(config: &Config, local_dir: &Path, done: &AtomicBool) -> Fetched {
+    let mut fetched = Fetched::default();
+    let mut previous: Vec<RemoteFile> = Vec::new();
+    let verbose = matches!(config.output_level(), OutputLevel::Verbose);
+
+    while !done.load(Ordering::SeqCst) {
+        let started = Instant::now();
+        if let Ok(current) = list(config) {
+            let stable: Vec<RemoteFile> = current
+                .iter()
+                .filter(|file| previous.contains(file))
+                .filter(|file| fetched.files.get(&file.remote_path()) != Some(&file.stamp))
+                .cloned()
+                .collect();
+            if !stable.is_empty() && !done.load(Ordering::SeqCst) {
+                match download(config, local_dir, &stable, &mut fetched) {
+                    Ok(()) if verbose => {
+                        for file in &stable {
+                            println!("   ✓ Fetched early: {}", file.remote_path());
+                        }
+                    }
+                    Ok(()) => {}
+                    // A file may have been removed again; the final pass decides
+                    Err(e) if verbose => {
+                        eprintln!("   ⚠ Early artifact download failed: {:#}", e)
+                    }
+                    Err(_) => {}
+                }
+            }
+            previous = current;
+        }
+
+        // Sleep in small steps, so the end of the build isn't delayed
+        while started.elapsed() < POLL_INTERVAL && !done.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+    fetched
+}
+
+/// Download what the watcher didn't fetch yet, or that changed since, after
+/// the build finished
+pub(crate) fn finish(
+    config: &Config,
+    project_dir: &Path,
+    local_dir: &Path,
+    mut fetched: Fetched,
+) -> Result<()> {
+    let output = config.output_level();
+    let mut spinner = print_status(output, "📥 Copying artifacts ");
+
+    let files = list(config).context("Failed to list the artifacts")?;
+    let missing: Vec<RemoteFile> = files
+        .iter()
+        .filter(|file| fetched.files.get(&file.remote_path()) != Some(&file.stamp))
+        .cloned()
+        .collect();
+    let mapped: Vec<_> = config.artifacts.iter().filter(|a| !watched(a)).collect();
+    let result = download(config, local_dir, &missing, &mut fetched).and_then(|()| {
+        copy_artifacts(config, &mapped, project_dir, local_dir, &mut spinner).map(|_| ())
+    });
+    clear_status(output, &mut spinner);
+    match result {
+        Ok(()) => remove_partial_dir(local_dir),
+        Err(e) => eprintln!("   ⚠ Warning: Could not copy artifacts: {:#}", e),
+    }
+
+    for (i, artifact) in config.artifacts.iter().enumerate() {
+        if watched(artifact) && !files.iter().any(|file| file.artifact == i) {
+            eprintln!(
+                "   ⚠ Warning: Could not copy artifact: {} (no such file)",
+                artifact
+            );
+        }
+    }
+
+    if config.rewrite_paths && !fetched.local.is_empty() {
+        match rewrite::Rewriter::new(config, project_dir) {
+            Ok(rewriter) => {
+                let paths: Vec<PathBuf> = fetched.local.iter().cloned().collect();
+                let verbose = matches!(output, OutputLevel::Verbose);
+                rewriter.rewrite_files(&paths, "artifacts", verbose);
+            }
+            Err(e) => eprintln!(
+                "   ⚠ Warning: Paths in artifacts won't be rewritten: {:#}",
+                e
+            ),
+        }
+    }
+
+    if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) {
+        println!(
+            "   ✓ Artifacts downloaded to {} ({} of {} files already fetched during the build)",
+            local_dir.display(),
+            files.len() - missing.len(),
+            files.len()
+        );
+        println!();
+    }
+    Ok(())
+}
+
+impl RemoteFile {
+    /// The file's remote path, relative to the project unless absolute
+    fn remote_path(&self) -> String {
+        match self.base.as_str() {
+            "." => self.relative.clone(),
+            "/" => format!("/{}", self.relative),
+            base => format!("{}/{}", base, self.relative),
+        }
+    }
+}
+
+/// Whether the watcher lists and downloads `artifact` during the build
+fn watched(artifact: &Artifact) -> bool {
+    artifact.local.is_none() && !artifact_glob::needs_expansion(&artifact.remote)
+}
+
+/// List the regular files matching the artifacts, with their stamps
+fn list(config: &Config) -> Result<Vec<RemoteFile>> {
+    let mut script = format!("cd {} || exit 1; ", config.remote_dir().shell());
+    for (i, artifact) in config.artifacts.iter().enumerate() {
+        if !watched(artifact) {
+            continue;
+        }
+        // The pattern stays unquoted for the remote shell to expand
+        script.push_str(&format!(
+            "for a in {pattern}; do [ -e \"$a\" ] || continue; printf '@ {i} %s\\n' \"$a\"; \
+             find \"$a\" -name {meta} -prune -o -type f -exec sh -c \
+             'stat -c \"%s %Y %n\" \"$@\" 2>/dev/null || stat -f \"%z %m %N\" \"$@\"' sh {{}} +; \
+             done; ",
+            pattern = artifact.remote,
+            i = i,
+            meta = REMOTE_META_DIR
+        ));
+    }
+    script.push_str("true");
+
+    let output = run_ssh_command_output(config, &script)?;
+    let mut files = Vec::new();
+    let mut current: Option<(usize, String, String)> = None;
+    for line in output.lines() {
+        if let Some(header) = line.strip_prefix("@ ") {
+            let Some((index, root)) = header.split_once(' ') else {
+                continue;
+            };
+            let Ok(index) = index.parse::<usize>() else {
+                continue;
+            };
+            let Some(artifact) = config.artifacts.get(index) else {
+                continue;
+            };
+            let trailing = artifact.remote.ends_with('/');
+            let root = root.trim_end_matches('/');
+            // rsync puts the root itself, or with a trailing slash its
+            // contents, into the local directory
+            let base = if trailing {
+                root.to_string()
+            } else {
+                match root.rsplit_once('/') {
+                    Some(("", _)) => "/".to_string(),
+                    Some((parent, _)) => parent.to_string(),
+                    None => ".".to_string(),
+                }
+            };
+            current = Some((index, base, root.to_string()));
+            continue;
+        }
+
+        let mut fields = line.splitn(3, ' ');
+        let (Some(size), Some(mtime), Some(path)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let (Ok(size), Ok(mtime), Some((index, base, _))) =
+            (size.parse(), mtime.parse(), current.as_ref())
+        else {
+            continue;
+        };
+        let relative = if base == "." {
+            Some(path.trim_start_matches("./"))
+        } else {
+            path.strip_prefix(base.as_str())
+                .map(|rest| rest.trim_start_matches('/'))
+        };
+        if let Some(relative) = relative.filter(|r| !r.is_empty()) {
+            let file = RemoteFile {
+                artifact: *index,
+                base: base.clone(),
+                relative: relative.to_string(),
+                stamp: (size, mtime),
+            };
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Download files with one rsync per remote base directory, recording them
+/// in `fetched`
+fn download(
+    config: &Config,
+    local_dir: &Path,
+    files: &[RemoteFile],
+    fetched: &mut Fetched,
+) -> Result<()> {
+    let mut by_base: BTreeMap<&str, Vec<&RemoteFile>> = BTreeMap::new();
+    for file in files {
+        by_base.entry(file.base.as_str()).or_default().push(file);
+    }
+
+    for (base, files) in by_base {
+        let temp_dir = dirs::cache_dir().unwrap_or_else(env::temp_dir);
+        let list_file = temp_dir.join(format!("remotebuild_eager_{}", std::process::id()));
+        let names: Vec<&str> = files.iter().map(|file| file.relative.as_str()).collect();
+        fs::write(&list_file, names.join("\n"))?;
+
+        let source = if base.starts_with('/') {
+            let base = base.trim_end_matches('/');
+            format!("{}:{}/", config.host, escape(Cow::Borrowed(base)))
+        } else {
+            format!(
+                "{}/",
+                config
+                    .remote_dir()
+                    .rsync(&config.host, &escape(Cow::Borrowed(base)))
+            )
+        };
+        let mut rsync_cmd = rsync_command(config);
+        rsync_cmd
+            .arg("-a")
+            .arg("--quiet")
+            .args(config.artifact_compare_arg())
+            .args(compression::current(config).rsync_args())
+            .arg(partial_dir_arg(local_dir))
+            .arg("-e")
+            .arg(ssh_control_path_arg(config))
+            .arg({
+                let mut files_from = OsString::from("--files-from=");
+                files_from.push(&list_file);
+                files_from
+            })
+            .arg(source)
+            .arg(local_dir);
+        let result = run_rsync(&mut rsync_cmd, OutputLevel::Quiet);
+        fs::remove_file(&list_file).ok();
+
+        let (status, stderr) = result.context("Failed to run rsync for artifacts")?;
+        if !status.success() {
+            return Err(anyhow::anyhow!(
+                "rsync failed with {}{}",
+                status,
+                indented_tail(&stderr)
+            ));
+        }
+        for file in files {
+            fetched.files.insert(file.remote_path(), file.stamp);
+            fetched.local.insert(local_dir.join(&file.relative));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,438 @@
+//! Editor task definitions for `remotebuild init --vscode` / `--zed`
+//!
+//! One task is generated for the default build, one per configured target,
+//! and a `check` task for Cargo projects. Tasks are identified by their
+//! `remotebuild: ...` label: running init again replaces those entries in
+//! place and leaves every other task, comment and formatting in the file
+//! alone (see [`crate::jsonc`]).
+//!
+//! VS Code tasks get the `$rustc` or `$gcc` problem matcher. `check` output is
+//! remapped to local paths; build output is matched relative to the workspace
+//! folder, which covers compilers that print project-relative paths.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+
+use crate::jsonc::{self, ArrayEdit};
+use crate::Config;
+
+/// Config file name that doesn't need to be passed to remotebuild
+const DEFAULT_CONFIG_NAME: &str = ".remotebuild.yaml";
+
+/// Prefix of the labels of generated tasks
+const LABEL_PREFIX: &str = "remotebuild: ";
+
+/// Editors that task definitions can be generated for
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Editor {
+    /// `.vscode/tasks.json`
+    VsCode,
+    /// `.zed/tasks.json`
+    Zed,
+}
+
+/// A remotebuild invocation to expose as an editor task
+struct Task {
+    /// Label, starting with [`LABEL_PREFIX`]
+    label: String,
+    /// Arguments passed to remotebuild
+    args: Vec<String>,
+    /// Whether this is the default build
+    default_build: bool,
+    /// Whether the output is rustc diagnostics rather than gcc-style ones
+    rustc: bool,
+}
+
+/// A task in VS Code's `tasks.json`
+#[derive(Serialize)]
+struct VsCodeTask<'a> {
+    /// Name shown in the task picker
+    label: &'a str,
+    /// Always `process`, so arguments need no shell quoting
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Program to run
+    command: &'static str,
+    /// Program arguments
+    args: &'a [String],
+    /// Working directory
+    options: Value,
+    /// Build group membership
+    group: Value,
+    /// Problem matcher turning diagnostics into editor problems
+    #[serde(rename = "problemMatcher")]
+    problem_matcher: Value,
+}
+
+/// A task in Zed's `tasks.json`
+#[derive(Serialize)]
+struct ZedTask<'a> {
+    /// Name shown in the task picker
+    label: &'a str,
+    /// Program to run
+    command: &'static str,
+    /// Program arguments
+    args: &'a [String],
+    /// Working directory
+    cwd: &'static str,
+}
+
+/// Write or merge task definitions for `editor` into the project
+pub(crate) fn write_tasks(
+    project_dir: &Path,
+    config: &Config,
+    config_name: &str,
+    editor: Editor,
+) -> Result<()> {
+    let tasks = remotebuild_tasks(project_dir, config, config_name);
+
+    let (dir, empty) = match editor {
+        Editor::VsCode => (
+            ".vscode",
+            "{\n\t\"version\": \"2.0.0\",\n\t\"tasks\": []\n}\n",
+        ),
+        Editor::Zed => (".zed", "[]\n"),
+    };
+    let path = project_dir.join(dir).join("tasks.json");
+    let existing = path.exists();
+    let text = if existing {
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?
+    } else {
+        empty.to_string()
+    };
+
+    let updated = match editor {
+        Editor::VsCode => merge_vscode(&text, &tasks),
+        Editor::Zed => merge_zed(&text, &tasks),
+    }
+    .with_context(|| format!("Failed to update {}", path.display()))?;
+
+    // Never write back something the editor can't read
+    jsonc::parse(&jsonc::blank(&updated))
+        .with_context(|| format!("Refusing to write invalid {}", path.display()))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    fs::write(&path, updated).with_context(|| format!("Failed to write {}", path.display()))?;
+
+    println!(
+        "✅ {} {} ({} tasks)",
+        if existing { "Updated" } else { "Wrote" },
+        path.display(),
+        tasks.len()
+    );
+    Ok(())
+}
+
+/// The tasks to generate for the project's config
+fn remotebuild_tasks(project_dir: &Path, config: &Config, config_name: &str) -> Vec<Task> {
+    let mut base_args = Vec::new();
+    if project_dir.join(config_name).exists() {
+        if config_name != DEFAULT_CONFIG_NAME {
+            base_args.extend(["--config".to_string(), config_name.to_string()]);
+        }
+    } else {
+        // Running from flags alone, so the task has to pass them too
+        base_args.extend(["--host".to_string(), config.host.clone()]);
+        if !config.build_command.is_empty() {
+            base_args.extend([
+                "--build-command".to_string(),
+                config.build_command.to_string(),
+            ]);
+        }
+        for artifact in &config.artifacts {
+            base_args.extend(["--artifact".to_string(), artifact.remote.clone()]);
+        }
+    }
+    let with_args = |extra: &[&str]| {
+        let mut args = base_args.clone();
+        args.extend(extra.iter().map(|a| a.to_string()));
+        args
+    };
+
+    let rustc = project_dir.join("Cargo.toml").exists();
+    let mut tasks = vec![Task {
+        label: format!("{}build", LABEL_PREFIX),
+        args: base_args.clone(),
+        default_build: true,
+        rustc,
+    }];
+    for name in config.targets.keys() {
+        tasks.push(Task {
+            label: format!("{}build {}", LABEL_PREFIX, name),
+            args: with_args(&["--target", name]),
+            default_build: false,
+            rustc,
+        });
+    }
+    if rustc {
+        tasks.push(Task {
+            label: format!("{}check", LABEL_PREFIX),
+            args: with_args(&["check"]),
+            default_build: false,
+            rustc: true,
+        });
+    }
+    tasks
+}
+
+/// Merge tasks into the text of a VS Code `tasks.json`
+fn merge_vscode(text: &str, tasks: &[Task]) -> Result<String> {
+    let blanked = jsonc::blank(text);
+    let root = jsonc::root(&blanked)?;
+    if !blanked[root.start..].starts_with('{') {
+        return Err(anyhow!("Expected an object at the top level"));
+    }
+
+    let Some(array) = jsonc::object_member(&blanked, root, "tasks")? else {
+        let text = jsonc::insert_member(text, &blanked, root, "tasks", &json!([]))?;
+        return merge_vscode(&text, tasks);
+    };
+    if !blanked[array.start..].starts_with('[') {
+        return Err(anyhow!("Expected \"tasks\" to be an array"));
+    }
+
+    let unit = jsonc::indent_unit(text);
+    let mut edit = ArrayEdit::new(text, &blanked, array, &unit)?;
+    let existing = edit.values(&blanked);
+
+    // Leave a default build task the user picked alone
+    let user_default = existing
+        .iter()
+        .filter(|task| !is_generated(task))
+        .find(|task| {
+            task.pointer("/group/kind").and_then(Value::as_str) == Some("build")
+                && task.pointer("/group/isDefault") == Some(&Value::Bool(true))
+        })
+        .and_then(|task| task.get("label").and_then(Value::as_str));
+    if let Some(label) = user_default {
+        eprintln!(
+            "   ⚠ Warning: {} is already the default build task; not replacing it",
+            label
+        );
+    }
+
+    for task in tasks {
+        let matcher = if task.rustc { "$rustc" } else { "$gcc" };
+        let group = if task.default_build && user_default.is_none() {
+            json!({ "kind": "build", "isDefault": true })
+        } else {
+            json!("build")
+        };
+        let entry = VsCodeTask {
+            label: &task.label,
+            kind: "process",
+            command: "remotebuild",
+            args: &task.args,
+            options: json!({ "cwd": "${workspaceFolder}" }),
+            group,
+            problem_matcher: json!({
+                "base": matcher,
+                "fileLocation": ["autoDetect", "${workspaceFolder}"],
+            }),
+        };
+        match find_label(&existing, &task.label) {
+            Some(index) => edit.replace(index, &entry)?,
+            None => edit.push(&entry)?,
+        }
+    }
+    Ok(edit.finish())
+}
+
+/// Merge tasks into the text of a Zed `tasks.json`
+fn merge_zed(text: &str, tasks: &[Task]) -> Result<String> {
+    let blanked = jsonc::blank(text);
+    let root = jsonc::root(&blanked)?;
+    if !blanked[root.start..].starts_with('[') {
+        return Err(anyhow!("Expected an array at the top level"));
+    }
+
+    let unit = jsonc::indent_unit(text);
+    let mut edit = ArrayEdit::new(text, &blanked, root, &unit)?;
+    let existing = edit.values(&blanked);
+
+    for task in tasks {
+        let entry = ZedTask {
+            label: &task.label,
+            command: "remotebuild",
+            args: &task.args,
+            cwd: "$ZED_WORKTREE_ROOT",
+        };
+        match find_label(&existing, &task.label) {
+            Some(index) => edit.replace(index, &entry)?,
+            None => edit.push(&entry)?,
+        }
+    }
+    Ok(edit.finish())
+}
+
+/// Index of the existing task with `label`
+fn find_label(existing: &[Value], label: &str) -> Option<usize> {
+    existing
+        .iter()
+        .position(|task| task.get("label").and_then(Value::as_str) == Some(label))
+}
+
+/// Whether a task was generated by remotebuild
+fn is_generated(task: &Value) -> bool {
+    task.get("label")
+        .and_then(Value::as_str)
+        .is_some_and(|label| label.starts_with(LABEL_PREFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `tasks.json` with comments, trailing commas and a generated task
+    const VSCODE: &str = r#"{
+    // See https://go.microsoft.com/fwlink/?LinkId=733558
+    "version": "2.0.0",
+    "tasks": [
+        {
+            "label": "lint", /* runs clippy */
+            "type": "shell",
+            "command": "cargo clippy",
+        },
+        {
+            "label": "remotebuild: build",
+            "type": "process",
+            "command": "remotebuild",
+            "args": ["--verbose"],
+        }, // generated
+    ],
+}
+"#;
+
+    /// The tasks of a Cargo project without targets
+    fn tasks() -> Vec<Task> {
+        let task = |label: &str, args: &[&str], default_build| Task {
+            label: format!("{}{}", LABEL_PREFIX, label),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            default_build,
+            rustc: true,
+        };
+        vec![task("build", &[], true), task("check", &["check"], false)]
+    }
+
+    /// The tasks of merged text, parsed
+    fn parsed_tasks(text: &str) -> Vec<Value> {
+        let value = jsonc::parse(&jsonc::blank(text)).unwrap();
+        let tasks = value.get("tasks").unwrap_or(&value);
+        tasks.as_array().unwrap().clone()
+    }
+
+    /// Labels of the tasks in merged text
+    fn labels(text: &str) -> Vec<String> {
+        parsed_tasks(text)
+            .iter()
+            .map(|task| task["label"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    /// Comments and trailing commas survive and the existing task is
+    /// replaced in place
+    #[test]
+    fn vscode_merge_keeps_comments() {
+        let merged = merge_vscode(VSCODE, &tasks()).unwrap();
+        assert_eq!(
+            labels(&merged),
+            ["lint", "remotebuild: build", "remotebuild: check"]
+        );
+        assert!(
+            merged.starts_with("{\n    // See https://go.microsoft.com/fwlink/?LinkId=733558\n")
+        );
+        assert!(merged.contains("\"label\": \"lint\", /* runs clippy */\n"));
+        assert!(merged.contains("        }, // generated\n        {\n"));
+        assert!(merged.ends_with("        },\n    ],\n}\n"));
+
+        let build = &parsed_tasks(&merged)[1];
+        assert_eq!(build["args"], json!([]));
+        assert_eq!(
+            build["group"],
+            json!({ "kind": "build", "isDefault": true })
+        );
+    }
+
+    /// Merging again changes nothing
+    #[test]
+    fn vscode_merge_is_idempotent() {
+        let merged = merge_vscode(VSCODE, &tasks()).unwrap();
+        assert_eq!(merge_vscode(&merged, &tasks()).unwrap(), merged);
+    }
+
+    /// A missing `tasks` member is added after a commented last member
+    #[test]
+    fn vscode_merge_adds_tasks() {
+        let text = "{\n\t\"version\": \"2.0.0\", // no tasks yet\n}\n";
+        let merged = merge_vscode(text, &tasks()).unwrap();
+        assert!(merged
+            .starts_with("{\n\t\"version\": \"2.0.0\", // no tasks yet\n\t\"tasks\": [\n\t\t{\n"));
+        assert!(merged.ends_with("\t\t}\n\t],\n}\n"));
+        assert_eq!(
+            labels(&merged),
+            ["remotebuild: build", "remotebuild: check"]
+        );
+    }
+
+    /// A default build task of the user's keeps being the default
+    #[test]
+    fn vscode_merge_keeps_user_default() {
+        let text = r#"{
+  "tasks": [
+    { "label": "make", "group": { "kind": "build", "isDefault": true } }
+  ]
+}"#;
+        let merged = merge_vscode(text, &tasks()).unwrap();
+        let tasks = parsed_tasks(&merged);
+        assert_eq!(tasks[0]["group"]["isDefault"], json!(true));
+        assert_eq!(tasks[1]["group"], json!("build"));
+    }
+
+    /// Zed tasks are merged into the top-level array
+    #[test]
+    fn zed_merge() {
+        let text = r#"// Zed tasks
+[
+  {
+    "label": "remotebuild: check",
+    "command": "old",
+  },
+  { "label": "fmt", "command": "cargo fmt" }, // mine
+]
+"#;
+        let expected = r#"// Zed tasks
+[
+  {
+    "label": "remotebuild: check",
+    "command": "remotebuild",
+    "args": [
+      "check"
+    ],
+    "cwd": "$ZED_WORKTREE_ROOT"
+  },
+  { "label": "fmt", "command": "cargo fmt" }, // mine
+  {
+    "label": "remotebuild: build",
+    "command": "remotebuild",
+    "args": [],
+    "cwd": "$ZED_WORKTREE_ROOT"
+  },
+]
+"#;
+        assert_eq!(merge_zed(text, &tasks()).unwrap(), expected);
+    }
+
+    /// Files of the wrong shape are refused rather than rewritten
+    #[test]
+    fn merge_rejects_wrong_shapes() {
+        assert!(merge_vscode("[]", &tasks()).is_err());
+        assert!(merge_vscode(r#"{ "tasks": {} }"#, &tasks()).is_err());
+        assert!(merge_zed("{}", &tasks()).is_err());
+    }
+}
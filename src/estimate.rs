@@ -0,0 +1,155 @@
+//! Typical sync and build times, from the build history
+//!
+//! After every recorded build, the recent entries of the remote history are
+//! read back, and the median durations of the last successful runs are kept
+//! in the state file per destination: one for the sync, and one per build
+//! command, so targets and other tasks each get their own. Runs that forced
+//! a full sync or cleaned the tree are left out, since they say little about
+//! the next one.
+//!
+//! The estimates only ever appear in status lines, e.g. `🔨 Building
+//! (typically 2m10s on this host)`, and are simply missing without history.
+//! Reading them needs no remote command, so they are there right away.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::history::{self, HistoryEntry};
+use crate::state::State;
+use crate::Config;
+
+/// Number of recent usable runs the medians are taken over
+const WINDOW: usize = 10;
+
+/// Estimates loaded in this run, keyed by `host:remote_path`
+static LOADED: Mutex<BTreeMap<String, Estimate>> = Mutex::new(BTreeMap::new());
+
+/// Typical durations for one destination
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Estimate {
+    /// Median sync duration in seconds
+    #[serde(default)]
+    sync_secs: Option<f64>,
+    /// Median build duration in seconds, keyed by build command hash
+    #[serde(default)]
+    build_secs: BTreeMap<String, f64>,
+}
+
+/// Key of a destination in the state and in [`LOADED`]
+fn destination(config: &Config) -> String {
+    format!("{}:{}", config.host, config.remote_path)
+}
+
+/// Make the estimates recorded for a project available to this run
+pub(crate) fn load(project_dir: &Path) {
+    let estimates = State::load(project_dir).estimates;
+    if let Ok(mut loaded) = LOADED.lock() {
+        loaded.extend(estimates);
+    }
+}
+
+/// Recompute the estimates of the destination from recent history entries
+/// and save them
+pub(crate) fn update(project_dir: &Path, config: &Config, entries: &[HistoryEntry]) {
+    let usable: Vec<&HistoryEntry> = entries.iter().rev().filter(|e| !e.is_outlier()).collect();
+    let command = history::command_hash(config);
+
+    let mut state = State::load(project_dir);
+    let estimate = state.estimates.entry(destination(config)).or_default();
+    estimate.sync_secs = median(usable.iter().filter_map(|e| e.sync_secs()));
+    let build = median(
+        usable
+            .iter()
+            .filter(|e| e.command_hash() == command)
+            .filter_map(|e| e.successful_build_secs()),
+    );
+    match build {
+        Some(secs) => estimate.build_secs.insert(command, secs),
+        None => estimate.build_secs.remove(&command),
+    };
+    // The state is only a cache of the history, so failing to save it is
+    // left for the next run to retry
+    let _ = state.save(project_dir);
+}
+
+/// Median of the first [`WINDOW`] values
+fn median(values: impl Iterator<Item = f64>) -> Option<f64> {
+    let mut values: Vec<f64> = values.take(WINDOW).collect();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    Some(if values.len() % 2 == 0 {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    })
+}
+
+/// The estimate of the destination, if one was loaded
+fn current(config: &Config) -> Option<Estimate> {
+    LOADED
+        .lock()
+        .ok()
+        .and_then(|loaded| loaded.get(&destination(config)).cloned())
+}
+
+/// Status line of the sync, with the typical duration when known
+pub(crate) fn sync_message(config: &Config) -> String {
+    // Checksum syncs read every file, so they are named
+    let what = match config.checksum_arg() {
+        Some(_) => "📦 Syncing files by checksum",
+        None => "📦 Syncing files",
+    };
+    match current(config).and_then(|estimate| estimate.sync_secs) {
+        Some(secs) => format!("{} (typically {}) ", what, format_secs(secs)),
+        None => format!("{} ", what),
+    }
+}
+
+/// Typical build duration of the configured build command
+fn build_secs(config: &Config) -> Option<f64> {
+    current(config)?
+        .build_secs
+        .get(&history::command_hash(config))
+        .copied()
+}
+
+/// Status line of the build, with the typical duration when known
+pub(crate) fn build_message(config: &Config) -> String {
+    match build_secs(config) {
+        Some(secs) => format!(
+            "🔨 Building (typically {} on this host) ",
+            format_secs(secs)
+        ),
+        None => "🔨 Building ".to_string(),
+    }
+}
+
+/// Line reporting a finished build, comparing it with the typical duration
+/// when known
+pub(crate) fn build_complete(config: &Config, elapsed: Duration) -> String {
+    match build_secs(config) {
+        Some(secs) => format!(
+            "   ✓ Build complete ({}, typically {} on this host)",
+            format_secs(elapsed.as_secs_f64()),
+            format_secs(secs)
+        ),
+        None => "   ✓ Build complete".to_string(),
+    }
+}
+
+/// Format seconds as e.g. `<1s`, `45s`, `2m10s` or `1h05m`
+fn format_secs(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    match secs {
+        0 => "<1s".to_string(),
+        1..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
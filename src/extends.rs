@@ -0,0 +1,145 @@
+//! Configs built on shared base configs
+//!
+//! `extends: ../common/remotebuild-base.yaml` names a config the project's
+//! one is laid over, so projects differing only in a few options share the
+//! rest. The path is relative to the directory of the config naming it, a
+//! base may extend another, and the chain is merged from the deepest base up:
+//! scalars and nested sections work as with the global config, and lists of
+//! files and commands (see [`CONCATENATED`]) are joined, base entries first.
+//! Chains longer than [`MAX_DEPTH`] and cycles are errors.
+
+use anyhow::{anyhow, Context, Result};
+use serde_yaml::Value;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config_format::ConfigFormat;
+use crate::global_config;
+
+/// The key naming the base config
+const KEY: &str = "extends";
+
+/// Longest chain of bases followed
+const MAX_DEPTH: usize = 8;
+
+/// Keys whose lists are joined instead of replaced
+const CONCATENATED: &[&str] = &[
+    "artifacts",
+    "exclude_patterns",
+    "force_include",
+    "include_patterns",
+    "pass_env",
+    "post_artifacts",
+    "pre_sync",
+    "snapshot_commands",
+    "snapshot_env",
+];
+
+/// Whether the config `value` extends a base config
+pub(crate) fn extends(value: &Value) -> bool {
+    value.get(KEY).is_some()
+}
+
+/// Lay `config`, read from `file` (stdin when `None`), over the chain of
+/// configs it extends, returning the merged config and the bases' paths
+///
+/// The bases of a config from stdin are relative to the current directory.
+pub(crate) fn resolve(mut config: Value, file: Option<&Path>) -> Result<(Value, Vec<PathBuf>)> {
+    let (mut dir, mut name) = match file {
+        Some(file) => (
+            file.parent().map(Path::to_path_buf).unwrap_or_default(),
+            file.display().to_string(),
+        ),
+        None => (env::current_dir()?, "config from stdin".to_string()),
+    };
+    let top = name.clone();
+    let mut seen: Vec<PathBuf> = file
+        .into_iter()
+        .filter_map(|f| f.canonicalize().ok())
+        .collect();
+    let mut bases = Vec::new();
+    let mut layers = Vec::new();
+
+    while let Some(base) = take_base(&mut config, &name)? {
+        let path = dir.join(base);
+        let canonical = path.canonicalize().with_context(|| {
+            format!(
+                "Failed to read base config {} (extended by {})",
+                path.display(),
+                name
+            )
+        })?;
+        if seen.contains(&canonical) {
+            return Err(anyhow!(
+                "Config {} extends {}, which is already part of the chain",
+                name,
+                canonical.display()
+            ));
+        }
+        if bases.len() == MAX_DEPTH {
+            return Err(anyhow!(
+                "Config {} extends more than {} base configs",
+                top,
+                MAX_DEPTH
+            ));
+        }
+        let content = fs::read_to_string(&path).with_context(|| {
+            format!(
+                "Failed to read base config {} (extended by {})",
+                path.display(),
+                name
+            )
+        })?;
+        let value = ConfigFormat::from_path(&path)
+            .to_yaml(&content)
+            .map_err(|e| anyhow!("Failed to parse base config: {} - {}", path.display(), e))?;
+        let value = match value {
+            Value::Mapping(_) => value,
+            Value::Null => Value::Mapping(Default::default()),
+            _ => {
+                return Err(anyhow!(
+                    "Base config {} must be a mapping of options",
+                    path.display()
+                ))
+            }
+        };
+
+        layers.push(std::mem::replace(&mut config, value));
+        dir = canonical
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        name = canonical.display().to_string();
+        seen.push(canonical.clone());
+        bases.push(canonical);
+    }
+
+    let merged = layers.into_iter().rev().fold(config, |base, layer| {
+        global_config::merge_with(base, layer, CONCATENATED)
+    });
+    Ok((merged, bases))
+}
+
+/// Remove the `extends` key from `config`, returning the base it names
+fn take_base(config: &mut Value, name: &str) -> Result<Option<PathBuf>> {
+    let Some(mapping) = config.as_mapping_mut() else {
+        return Ok(None);
+    };
+    match mapping.remove(KEY) {
+        None => Ok(None),
+        Some(Value::String(base)) => Ok(Some(expand_home(&base))),
+        Some(_) => Err(anyhow!(
+            "Invalid config file: {} - extends must be a path",
+            name
+        )),
+    }
+}
+
+/// `path` with a leading `~/` replaced by the home directory
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
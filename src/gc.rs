@@ -0,0 +1,844 @@
+//! Pruning old build trees on the remote host
+//!
+//! Every project (and, with `{user}` or per-branch remote paths, every user
+//! and branch) leaves a build tree on the remote that is never cleaned up.
+//! `remotebuild gc` removes the trees below a root directory (by default the
+//! parent of `remote_path`) that haven't been used for a while. With a
+//! `retention` section the same happens automatically after successful
+//! builds, at most once per `interval` and host, according to its limits on
+//! age, number of trees per branch and total size; `gc --policy` applies
+//! those limits by hand. The branch of a tree is that of its last build.
+//!
+//! Only directories holding a `.remotebuild` metadata directory are
+//! considered, and the tree being built, trees locked by a running build and
+//! trees with files modified within the safety window are never removed.
+//! Without `--all-users` only trees owned by the SSH user are touched.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::history::HistoryEntry;
+use crate::remote_path::RemotePath;
+use crate::state::State;
+use crate::{
+    clear_status, ensure_ssh_connection, print_status, run_ssh_command_output, shared, Config,
+    OutputLevel,
+};
+
+/// Safety window used when the config doesn't set one
+const DEFAULT_SAFETY_WINDOW: &str = "1h";
+
+/// Minimum time between automatic runs when the config doesn't set one
+const DEFAULT_INTERVAL: &str = "1d";
+
+/// Retention limits for the build trees on the remote host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RetentionConfig {
+    /// Directory whose subdirectories are pruned (default: the parent of
+    /// `remote_path`; `{user}` expands to the local user name)
+    #[serde(default)]
+    root: Option<String>,
+
+    /// Maximum total size of the trees below the root, e.g. `50G`
+    #[serde(default)]
+    max_total_size: Option<String>,
+
+    /// Remove trees unused for longer than this, e.g. `30d`
+    #[serde(default)]
+    max_age: Option<String>,
+
+    /// Maximum number of trees kept below the root per branch, the one of
+    /// the last build in each tree
+    #[serde(default)]
+    max_dirs: Option<usize>,
+
+    /// Trees with files modified more recently than this are kept (default:
+    /// `1h`)
+    #[serde(default)]
+    safety_window: Option<String>,
+
+    /// Minimum time between automatic runs on a host (default: `1d`)
+    #[serde(default)]
+    interval: Option<String>,
+
+    /// Apply the policy after successful builds
+    #[serde(default = "crate::default_true")]
+    auto: bool,
+}
+
+/// Options of a `gc` run
+pub(crate) struct GcOptions<'a> {
+    /// Apply the configured retention policy instead of `older_than`
+    pub(crate) policy: bool,
+    /// Age limit used without `policy`
+    pub(crate) older_than: &'a str,
+    /// Only print what would be removed
+    pub(crate) dry_run: bool,
+    /// Also consider trees of other users
+    pub(crate) all_users: bool,
+    /// Don't ask before removing other users' trees
+    pub(crate) yes: bool,
+}
+
+/// Limits evaluated against the trees below the root
+struct Policy {
+    /// Maximum total size in KiB
+    max_total_kib: Option<u64>,
+    /// Maximum age in seconds
+    max_age: Option<u64>,
+    /// Maximum number of trees per branch
+    max_dirs: Option<usize>,
+    /// Safety window in seconds
+    safety_window: u64,
+}
+
+/// A build tree found below the root
+struct RemoteDir {
+    /// Absolute path on the remote
+    path: String,
+    /// Disk usage in KiB
+    size_kib: u64,
+    /// Unix timestamp (seconds) of the last build, or of the directory's
+    /// last change when no build was recorded
+    last_used: u64,
+    /// Owned by the SSH user
+    mine: bool,
+    /// Holds files modified within the safety window
+    recent: bool,
+    /// Locked by a running build
+    locked: bool,
+    /// The tree of the current project
+    active: bool,
+    /// Git branch of the last build, if it recorded one
+    branch: Option<String>,
+}
+
+impl RemoteDir {
+    /// Why the tree must be kept regardless of the policy, if it must
+    fn protected(&self, all_users: bool) -> Option<&'static str> {
+        if self.active {
+            Some("current project")
+        } else if self.locked {
+            Some("build running")
+        } else if self.recent {
+            Some("modified recently")
+        } else if !self.mine && !all_users {
+            Some("other user")
+        } else {
+            None
+        }
+    }
+}
+
+/// Run `remotebuild gc`
+pub(crate) fn run_gc(config: &Config, options: &GcOptions) -> Result<()> {
+    let policy = if options.policy {
+        let retention = config.retention.as_ref().ok_or_else(|| {
+            anyhow!("No retention policy configured: add a retention section or drop --policy")
+        })?;
+        Policy::from_config(retention)?
+    } else {
+        Policy {
+            max_total_kib: None,
+            max_age: Some(parse_duration(options.older_than)?),
+            max_dirs: None,
+            safety_window: parse_duration(DEFAULT_SAFETY_WINDOW)?,
+        }
+    };
+    let root = root(config)?;
+
+    ensure_ssh_connection(config)?;
+    let output = config.output_level();
+    let mut spinner = print_status(output, "🧹 Scanning build trees ");
+    let result = list_dirs(config, &root, &policy, options.all_users);
+    clear_status(output, &mut spinner);
+    let dirs = result?;
+
+    let removals = plan(&dirs, &policy, now(), options.all_users);
+    print_plan(config, &root, &dirs, &removals, options.all_users);
+    if removals.is_empty() || options.dry_run {
+        if options.dry_run && !removals.is_empty() {
+            println!("   (dry run, nothing removed)");
+        }
+        return Ok(());
+    }
+
+    if options.all_users && !options.yes {
+        confirm_removal(&dirs, &removals)?;
+    }
+    remove(config, &dirs, &removals)?;
+    println!(
+        "✅ Removed {} build trees, freed {}",
+        removals.len(),
+        format_size(removals.iter().map(|(i, _)| dirs[*i].size_kib).sum())
+    );
+    Ok(())
+}
+
+/// Apply the retention policy after a successful build when it is due
+///
+/// Failures only produce a warning, since the build itself succeeded.
+pub(crate) fn run_after_build(project_dir: &Path, config: &Config) {
+    let Some(retention) = config.retention.as_ref().filter(|r| r.auto) else {
+        return;
+    };
+    if let Err(e) = auto_gc(project_dir, config, retention) {
+        eprintln!("   ⚠ Warning: Retention policy failed: {:#}", e);
+    }
+}
+
+/// Run the retention policy if the last automatic run on the host is older
+/// than the interval
+fn auto_gc(project_dir: &Path, config: &Config, retention: &RetentionConfig) -> Result<()> {
+    let interval = parse_duration(retention.interval.as_deref().unwrap_or(DEFAULT_INTERVAL))?;
+    let now = now();
+    let mut state = State::load(project_dir);
+    if let Some(last) = state.gc_runs.get(&config.host) {
+        if now.saturating_sub(*last) < interval {
+            return Ok(());
+        }
+    }
+    // Recorded up front, so a failing policy isn't retried on every build
+    state.gc_runs.insert(config.host.clone(), now);
+    state.save(project_dir)?;
+
+    let policy = Policy::from_config(retention)?;
+    let root = root(config)?;
+    let dirs = list_dirs(config, &root, &policy, false)?;
+    let removals = plan(&dirs, &policy, now, false);
+    if removals.is_empty() {
+        return Ok(());
+    }
+    remove(config, &dirs, &removals)?;
+
+    let freed = format_size(removals.iter().map(|(i, _)| dirs[*i].size_kib).sum());
+    match config.output_level() {
+        OutputLevel::Quiet => {}
+        OutputLevel::Minimal => {
+            println!(
+                "🧹 Removed {} old build trees ({} freed)",
+                removals.len(),
+                freed
+            );
+        }
+        OutputLevel::Normal | OutputLevel::Verbose => {
+            println!(
+                "🧹 Retention policy removed {} build trees:",
+                removals.len()
+            );
+            for (i, reason) in &removals {
+                print_dir(&dirs[*i], now, reason);
+            }
+            println!("   Freed {}", freed);
+        }
+    }
+    Ok(())
+}
+
+impl Policy {
+    /// Parse the limits of a retention config
+    fn from_config(retention: &RetentionConfig) -> Result<Self> {
+        let policy = Self {
+            max_total_kib: retention
+                .max_total_size
+                .as_deref()
+                .map(parse_size)
+                .transpose()?,
+            max_age: retention
+                .max_age
+                .as_deref()
+                .map(parse_duration)
+                .transpose()?,
+            max_dirs: retention.max_dirs,
+            safety_window: parse_duration(
+                retention
+                    .safety_window
+                    .as_deref()
+                    .unwrap_or(DEFAULT_SAFETY_WINDOW),
+            )?,
+        };
+        if policy.max_total_kib.is_none() && policy.max_age.is_none() && policy.max_dirs.is_none() {
+            return Err(anyhow!(
+                "Retention policy sets no limit: add max_age, max_dirs or max_total_size"
+            ));
+        }
+        Ok(policy)
+    }
+}
+
+/// The directory whose subdirectories are pruned
+fn root(config: &Config) -> Result<String> {
+    let configured = config.retention.as_ref().and_then(|r| r.root.as_deref());
+    let root = match configured {
+        Some(root) => shared::expand_user(root),
+        None => {
+            let path = config.remote_path.trim_end_matches('/');
+            match path.rsplit_once('/') {
+                Some((parent, _)) if !parent.is_empty() => parent.to_string(),
+                _ => {
+                    return Err(anyhow!(
+                        "remote_path {} has no parent directory to prune; set retention.root",
+                        config.remote_path
+                    ))
+                }
+            }
+        }
+    };
+
+    // Pruning the home or root directory would consider every directory in it
+    let trimmed = root.trim_end_matches('/');
+    if trimmed.is_empty() || RemotePath::new(&root).shell() == "." || trimmed == "~" {
+        return Err(anyhow!(
+            "Refusing to prune {}: set retention.root to a directory holding only build trees",
+            root
+        ));
+    }
+    Ok(root)
+}
+
+/// The root as a shell word, with the local user's path component replaced
+/// by `*` when all users are included
+fn root_pattern(root: &str, all_users: bool) -> String {
+    let user = shared::local_username();
+    let segments: Vec<&str> = root.split('/').collect();
+    let position = segments.iter().rposition(|s| *s == user);
+    match position {
+        Some(i) if all_users && i > 0 => {
+            let prefix = RemotePath::new(&segments[..i].join("/")).absolute_shell();
+            let suffix = segments[i + 1..].join("/");
+            if suffix.is_empty() {
+                format!("{}/*", prefix)
+            } else {
+                format!("{}/*/{}", prefix, escape(Cow::Owned(suffix)))
+            }
+        }
+        _ => RemotePath::new(root).absolute_shell(),
+    }
+}
+
+/// List the build trees below the root with their size, age and status
+fn list_dirs(
+    config: &Config,
+    root: &str,
+    policy: &Policy,
+    all_users: bool,
+) -> Result<Vec<RemoteDir>> {
+    // `find -mmin` takes whole minutes
+    let window_minutes = ((policy.safety_window + 59) / 60).max(1);
+    let script = format!(
+        "active=$(cd {active} 2>/dev/null && pwd -P); \
+         for d in {root}/*/; do d=${{d%/}}; [ -d \"$d/.remotebuild\" ] || continue; \
+         size=$(du -sk \"$d\" 2>/dev/null | cut -f1); \
+         used=$(date -r \"$d/.remotebuild/history\" +%s 2>/dev/null || date -r \"$d\" +%s); \
+         mine=0; [ -O \"$d\" ] && mine=1; \
+         recent=0; [ -n \"$(find \"$d\" -mmin -{window} -print 2>/dev/null | head -n 1)\" ] && recent=1; \
+         locked=0; if [ -f \"$d/.remotebuild/lock\" ] \
+         && IFS='\t' read -r lock_user lock_pid lock_rest < \"$d/.remotebuild/lock\" && {alive}; \
+         then locked=1; fi; \
+         current=0; [ \"$(cd \"$d\" && pwd -P)\" = \"$active\" ] && current=1; \
+         last=$(tail -n 1 \"$d/.remotebuild/history\" 2>/dev/null); \
+         printf '%s\\t%s\\t%s\\t%s\\t%s\\t%s\\t%s\\t%s\\n' \"${{size:-0}}\" \"${{used:-0}}\" \
+         $mine $recent $locked $current \"$last\" \"$d\"; \
+         done; true",
+        active = config.remote_dir().shell(),
+        root = root_pattern(root, all_users),
+        window = window_minutes,
+        alive = shared::alive("lock_pid")
+    );
+    let output = run_ssh_command_output(config, &script).context("Failed to list build trees")?;
+
+    let flag = |value: &str| value == "1";
+    Ok(output
+        .lines()
+        .filter_map(|line| {
+            // History lines are JSON, with any tab escaped
+            let fields: Vec<&str> = line.splitn(8, '\t').collect();
+            let [size, used, mine, recent, locked, active, last, path] = fields[..] else {
+                return None;
+            };
+            let branch = serde_json::from_str::<HistoryEntry>(last)
+                .ok()
+                .and_then(|entry| entry.branch().map(str::to_string));
+            Some(RemoteDir {
+                path: path.to_string(),
+                size_kib: size.parse().unwrap_or(0),
+                last_used: used.parse().unwrap_or(0),
+                mine: flag(mine),
+                recent: flag(recent),
+                locked: flag(locked),
+                active: flag(active),
+                branch,
+            })
+        })
+        .collect())
+}
+
+/// Pick the trees to remove, oldest first, with the limit each one exceeds
+fn plan(dirs: &[RemoteDir], policy: &Policy, now: u64, all_users: bool) -> Vec<(usize, String)> {
+    let mut candidates: Vec<usize> = (0..dirs.len())
+        .filter(|&i| dirs[i].protected(all_users).is_none())
+        .collect();
+    candidates.sort_by_key(|&i| dirs[i].last_used);
+
+    // Limits count the trees this run may remove, not other users' trees
+    let managed = (0..dirs.len()).filter(|&i| all_users || dirs[i].mine);
+    let mut removals: Vec<(usize, String)> = Vec::new();
+    let mut removed = BTreeSet::new();
+
+    if let Some(max_age) = policy.max_age {
+        for &i in &candidates {
+            if now.saturating_sub(dirs[i].last_used) > max_age {
+                removed.insert(i);
+                removals.push((i, format!("older than {}", format_duration(max_age))));
+            }
+        }
+    }
+
+    // Trees of one branch stand for its commits; other branches keep theirs
+    if let Some(max_dirs) = policy.max_dirs {
+        let branches: BTreeSet<Option<&str>> =
+            managed.clone().map(|i| dirs[i].branch.as_deref()).collect();
+        for branch in branches {
+            let on_branch = |i: &usize| dirs[*i].branch.as_deref() == branch;
+            let mut kept = managed
+                .clone()
+                .filter(on_branch)
+                .filter(|i| !removed.contains(i))
+                .count();
+            for &i in candidates.iter().filter(|i| on_branch(i)) {
+                if kept <= max_dirs {
+                    break;
+                }
+                if removed.insert(i) {
+                    kept -= 1;
+                    let reason = match branch {
+                        Some(branch) => format!("more than {} trees of {}", max_dirs, branch),
+                        None => format!("more than {} trees", max_dirs),
+                    };
+                    removals.push((i, reason));
+                }
+            }
+        }
+    }
+
+    if let Some(max_total) = policy.max_total_kib {
+        let mut total: u64 = managed
+            .clone()
+            .filter(|i| !removed.contains(i))
+            .map(|i| dirs[i].size_kib)
+            .sum();
+        for &i in &candidates {
+            if total <= max_total {
+                break;
+            }
+            if removed.insert(i) {
+                total = total.saturating_sub(dirs[i].size_kib);
+                removals.push((i, format!("over {} in total", format_size(max_total))));
+            }
+        }
+    }
+
+    removals
+}
+
+/// Print the trees below the root and what happens to each of them
+fn print_plan(
+    config: &Config,
+    root: &str,
+    dirs: &[RemoteDir],
+    removals: &[(usize, String)],
+    all_users: bool,
+) {
+    let now = now();
+    let total: u64 = dirs.iter().map(|d| d.size_kib).sum();
+    println!(
+        "🧹 {} build trees in {}:{}{} ({})",
+        dirs.len(),
+        config.host,
+        root,
+        if all_users { ", all users" } else { "" },
+        format_size(total)
+    );
+
+    let mut order: Vec<usize> = (0..dirs.len()).collect();
+    order.sort_by_key(|&i| dirs[i].last_used);
+    for i in order {
+        let dir = &dirs[i];
+        if let Some((_, reason)) = removals.iter().find(|(r, _)| *r == i) {
+            print_dir(dir, now, reason);
+        } else {
+            let note = dir.protected(all_users).unwrap_or("kept");
+            println!(
+                "   ✓ {}  {}  used {}  ({})",
+                dir.path,
+                format_size(dir.size_kib),
+                crate::history::format_age(now.saturating_sub(dir.last_used)),
+                note
+            );
+        }
+    }
+
+    if removals.is_empty() {
+        println!("   Nothing to remove");
+    } else {
+        println!(
+            "   {} to remove, {} to free",
+            removals.len(),
+            format_size(removals.iter().map(|(i, _)| dirs[*i].size_kib).sum())
+        );
+    }
+}
+
+/// Print one tree that is (or would be) removed
+fn print_dir(dir: &RemoteDir, now: u64, reason: &str) {
+    println!(
+        "   ✗ {}  {}  used {}  ({})",
+        dir.path,
+        format_size(dir.size_kib),
+        crate::history::format_age(now.saturating_sub(dir.last_used)),
+        reason
+    );
+}
+
+/// Ask before removing trees of other users
+fn confirm_removal(dirs: &[RemoteDir], removals: &[(usize, String)]) -> Result<()> {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    let others = removals.iter().filter(|(i, _)| !dirs[*i].mine).count();
+    if others == 0 {
+        return Ok(());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "{} of the trees belong to other users; pass --yes to remove them",
+            others
+        ));
+    }
+
+    print!("Remove {} trees belonging to other users? [y/N] ", others);
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        return Err(anyhow!("gc cancelled"));
+    }
+    Ok(())
+}
+
+/// Remove the planned trees on the remote
+fn remove(config: &Config, dirs: &[RemoteDir], removals: &[(usize, String)]) -> Result<()> {
+    let paths: Vec<String> = removals
+        .iter()
+        .map(|(i, _)| escape(Cow::Borrowed(dirs[*i].path.as_str())).to_string())
+        .collect();
+    run_ssh_command_output(config, &format!("rm -rf -- {}", paths.join(" ")))
+        .context("Failed to remove build trees")?;
+    Ok(())
+}
+
+/// Current Unix time in seconds
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Parse a duration like `90m`, `12h`, `30d` or `2w` into seconds
+pub(crate) fn parse_duration(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid duration: {} (expected e.g. 12h or 30d)", value))?;
+    let factor = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "" | "d" => 86400,
+        "w" => 7 * 86400,
+        _ => {
+            return Err(anyhow!(
+                "Invalid duration: {} (expected e.g. 12h or 30d)",
+                value
+            ))
+        }
+    };
+    number
+        .checked_mul(factor)
+        .ok_or_else(|| anyhow!("Invalid duration: {} (expected e.g. 12h or 30d)", value))
+}
+
+/// Parse a size like `500M` or `20G` into KiB
+pub(crate) fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size: {} (expected e.g. 500M or 20G)", value))?;
+    let unit = unit
+        .trim()
+        .trim_end_matches(['B', 'b'])
+        .to_ascii_uppercase();
+    let factor: u64 = match unit.trim_end_matches('I') {
+        "K" => 1,
+        "M" => 1 << 10,
+        "G" => 1 << 20,
+        "T" => 1 << 30,
+        _ => {
+            return Err(anyhow!(
+                "Invalid size: {} (expected e.g. 500M or 20G)",
+                value
+            ))
+        }
+    };
+    Ok((number * factor as f64) as u64)
+}
+
+/// Format a size in KiB for humans
+pub(crate) fn format_size(kib: u64) -> String {
+    match kib {
+        0..=1023 => format!("{} KB", kib),
+        1024..=1_048_575 => format!("{:.1} MB", kib as f64 / 1024.0),
+        _ => format!("{:.1} GB", kib as f64 / 1_048_576.0),
+    }
+}
+
+/// Format a duration in seconds as it would be written in the config
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A tree of the SSH user on `branch`, last used on `day`
+    fn tree(path: &str, day: u64, size_kib: u64, branch: Option<&str>) -> RemoteDir {
+        RemoteDir {
+            path: path.to_string(),
+            size_kib,
+            last_used: day * 86400,
+            mine: true,
+            recent: false,
+            locked: false,
+            active: false,
+            branch: branch.map(str::to_string),
+        }
+    }
+
+    /// Trees on day 100: two old ones of `main` and one of `feature`, and
+    /// the active, recent and other user's trees, which are older still
+    fn trees() -> Vec<RemoteDir> {
+        let mut active = tree("/b/active", 0, 100, Some("main"));
+        active.active = true;
+        let mut recent = tree("/b/recent", 1, 100, Some("main"));
+        recent.recent = true;
+        let mut other = tree("/b/other", 2, 100, Some("main"));
+        other.mine = false;
+        vec![
+            tree("/b/main-old", 10, 100, Some("main")),
+            tree("/b/main-new", 90, 100, Some("main")),
+            tree("/b/feature", 20, 100, Some("feature")),
+            tree("/b/detached", 30, 100, None),
+            active,
+            recent,
+            other,
+        ]
+    }
+
+    /// A policy with only the given limits
+    fn policy(max_age: Option<&str>, max_dirs: Option<usize>, max_total: Option<&str>) -> Policy {
+        Policy {
+            max_total_kib: max_total.map(|size| parse_size(size).unwrap()),
+            max_age: max_age.map(|age| parse_duration(age).unwrap()),
+            max_dirs,
+            safety_window: 3600,
+        }
+    }
+
+    /// The removed trees' paths with their reasons
+    fn removed(dirs: &[RemoteDir], plan: &[(usize, String)]) -> Vec<(String, String)> {
+        plan.iter()
+            .map(|(i, reason)| (dirs[*i].path.clone(), reason.clone()))
+            .collect()
+    }
+
+    /// Durations take a unit, days by default, and must fit in seconds
+    #[test]
+    fn durations_parse() {
+        for (value, secs) in [
+            ("90m", 5400),
+            (" 12h ", 43_200),
+            ("30d", 2_592_000),
+            ("30", 2_592_000),
+            ("2w", 1_209_600),
+            ("15s", 15),
+        ] {
+            assert_eq!(parse_duration(value).unwrap(), secs, "{}", value);
+        }
+        for value in ["", "d", "-1d", "1.5h", "12x", "30000000000000000w"] {
+            assert_eq!(
+                parse_duration(value).unwrap_err().to_string(),
+                format!("Invalid duration: {} (expected e.g. 12h or 30d)", value)
+            );
+        }
+        assert_eq!(format_duration(2_592_000), "30d");
+        assert_eq!(format_duration(5400), "90m");
+    }
+
+    /// Sizes are in binary units, with an optional `B` or `iB`
+    #[test]
+    fn sizes_parse() {
+        for (value, kib) in [
+            ("64K", 64),
+            ("500M", 512_000),
+            ("20G", 20 << 20),
+            ("1.5GiB", 1_572_864),
+            ("2tb", 2 << 30),
+        ] {
+            assert_eq!(parse_size(value).unwrap(), kib, "{}", value);
+        }
+        for value in ["", "G", "10", "10X"] {
+            assert_eq!(
+                parse_size(value).unwrap_err().to_string(),
+                format!("Invalid size: {} (expected e.g. 500M or 20G)", value)
+            );
+        }
+    }
+
+    /// Trees over the age limit go oldest first, but never the active,
+    /// recently modified or other users' trees
+    #[test]
+    fn plan_keeps_protected_trees() {
+        let dirs = trees();
+        let now = 100 * 86400;
+        let removals = plan(&dirs, &policy(Some("30d"), None, None), now, false);
+        let reason = "older than 30d".to_string();
+        assert_eq!(
+            removed(&dirs, &removals),
+            [
+                ("/b/main-old".to_string(), reason.clone()),
+                ("/b/feature".to_string(), reason.clone()),
+                ("/b/detached".to_string(), reason),
+            ]
+        );
+
+        // Even limits no tree can meet leave them, going branch by branch
+        let removals = plan(&dirs, &policy(None, Some(0), Some("1K")), now, true);
+        let paths: Vec<String> = removed(&dirs, &removals)
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "/b/detached",
+                "/b/feature",
+                "/b/other",
+                "/b/main-old",
+                "/b/main-new"
+            ]
+        );
+    }
+
+    /// `max_dirs` counts the trees of each branch, the kept ones included
+    #[test]
+    fn plan_limits_trees_per_branch() {
+        let dirs = trees();
+        let now = 100 * 86400;
+        let removals = plan(&dirs, &policy(None, Some(3), None), now, false);
+        assert_eq!(
+            removed(&dirs, &removals),
+            [(
+                "/b/main-old".to_string(),
+                "more than 3 trees of main".to_string()
+            )]
+        );
+
+        let removals = plan(&dirs, &policy(None, Some(1), None), now, false);
+        let paths: Vec<String> = removed(&dirs, &removals)
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(paths, ["/b/main-old", "/b/main-new"]);
+
+        // The size limit then takes the oldest trees of any branch
+        let removals = plan(&dirs, &policy(None, Some(3), Some("400K")), now, false);
+        assert_eq!(
+            removed(&dirs, &removals)[1..],
+            [("/b/feature".to_string(), "over 400 KB in total".to_string())]
+        );
+    }
+
+    /// The listing finds the trees with metadata, marks the active one and
+    /// reads the branch of the last build
+    #[test]
+    fn list_dirs_reads_the_last_branch() {
+        crate::resilient::tests::install_fake_ssh();
+        let root =
+            std::env::temp_dir().join(format!("remotebuild-test-gc-list-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for tree in ["app/.remotebuild", "old/.remotebuild", "plain"] {
+            fs::create_dir_all(root.join(tree)).unwrap();
+        }
+        let entry = |branch: &str| {
+            let entry = serde_json::json!({
+                "timestamp": 1,
+                "user": "me@laptop",
+                "branch": branch,
+                "command_hash": "0",
+                "duration_secs": 1.0,
+            });
+            format!("{}\n", entry)
+        };
+        let history = format!("{}{}", entry("main"), entry("fix\tit"));
+        fs::write(root.join("app/.remotebuild/history"), history).unwrap();
+        let config: Config = serde_yaml::from_str(&format!(
+            "host: build-box\nremote_path: {}/app\nremote_shell: login",
+            root.display()
+        ))
+        .unwrap();
+
+        let dirs = list_dirs(
+            &config,
+            &root.to_string_lossy(),
+            &policy(None, Some(1), None),
+            false,
+        )
+        .unwrap();
+        let mut listed: Vec<(&str, bool, bool, Option<&str>)> = dirs
+            .iter()
+            .map(|dir| {
+                let name = dir.path.rsplit('/').next().unwrap();
+                (name, dir.active, dir.mine, dir.branch.as_deref())
+            })
+            .collect();
+        listed.sort_unstable();
+        assert_eq!(
+            listed,
+            [
+                ("app", true, true, Some("fix\tit")),
+                ("old", false, true, None)
+            ]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
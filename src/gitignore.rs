@@ -0,0 +1,103 @@
+//! Excludes read from the project's `.gitignore` files
+//!
+//! A git file list already leaves out what git ignores, but a full sync (no
+//! git repository, `git_aware: false`, `--force-full-sync`) or a manifest
+//! sync used to send everything, including ignored build trees. With
+//! `respect_gitignore` (on by default), the patterns of the root `.gitignore`
+//! and of every nested one are added to the excludes, after the built-in and
+//! detected ones and before `exclude_patterns`. Patterns of a nested file are
+//! rewritten to apply below its directory only, and `!` re-includes work as
+//! in git, with a warning for those an excluded parent directory defeats.
+//!
+//! Directories excluded by the patterns found so far aren't searched, as git
+//! doesn't look inside ignored directories either.
+
+use std::fs;
+use std::path::Path;
+
+use crate::patterns::ExcludeSet;
+use crate::Config;
+
+/// Name of the files read
+const FILE_NAME: &str = ".gitignore";
+
+/// Read the `.gitignore` patterns for the config unless `respect_gitignore`
+/// is off
+pub(crate) fn apply(project_dir: &Path, config: &mut Config) {
+    if config.respect_gitignore {
+        let mut patterns = Vec::new();
+        collect(project_dir, "", config, &mut patterns);
+        config.gitignore_excludes = patterns;
+    }
+}
+
+/// Add the patterns of the `.gitignore` in `dir` (relative path `relative`)
+/// and of the directories below it that aren't excluded
+fn collect(dir: &Path, relative: &str, config: &Config, patterns: &mut Vec<String>) {
+    if let Ok(content) = fs::read_to_string(dir.join(FILE_NAME)) {
+        patterns.extend(content.lines().filter_map(|line| translate(line, relative)));
+    }
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut subdirs: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    subdirs.sort();
+
+    let excludes = ExcludeSet::new(
+        config
+            .builtin_excludes()
+            .into_iter()
+            .chain(config.detected_excludes.iter().map(|d| d.pattern.as_str()))
+            .chain(patterns.iter().map(String::as_str))
+            .chain(config.exclude_patterns.iter().map(String::as_str)),
+    );
+    for name in subdirs {
+        let path = if relative.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        if !excludes.excludes_entry(&path, true) {
+            collect(&dir.join(&name), &path, config, patterns);
+        }
+    }
+}
+
+/// The exclude pattern for a line of the `.gitignore` in the directory
+/// `dir`, relative to the project root, if the line holds a pattern
+fn translate(line: &str, dir: &str) -> Option<String> {
+    // Trailing spaces are ignored unless escaped
+    let line = match line.trim_end() {
+        trimmed if line[trimmed.len()..].starts_with(' ') && trimmed.ends_with('\\') => {
+            &line[..trimmed.len() + 1]
+        }
+        trimmed => trimmed,
+    };
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    if dir.is_empty() {
+        // The root file has the semantics of exclude_patterns already
+        return Some(line.to_string());
+    }
+
+    let (negation, body) = match line.strip_prefix('!') {
+        Some(rest) => ("!", rest),
+        None => ("", line),
+    };
+    let suffix = if body.ends_with('/') { "/" } else { "" };
+    let trimmed = body.trim_end_matches('/');
+    let pattern = if trimmed.contains('/') {
+        // Anchored at the file's directory
+        format!("/{}/{}", dir, trimmed.trim_start_matches('/'))
+    } else {
+        // At any depth below it
+        format!("/{}/**/{}", dir, trimmed)
+    };
+    Some(format!("{}{}{}", negation, pattern, suffix))
+}
@@ -0,0 +1,104 @@
+//! The user-level config shared by all projects
+//!
+//! `$XDG_CONFIG_HOME/remotebuild/config.yaml` (usually
+//! `~/.config/remotebuild/config.yaml`) holds settings such as `host` or
+//! `exclude_patterns` that would otherwise be repeated in every project. It
+//! is read before a project's config, which is laid over it key by key:
+//! values set by the project win, nested sections such as `env` are merged
+//! the same way, and the list keys in [`CONCATENATED`] are joined, global
+//! entries first. `--no-global-config` skips the file.
+
+use anyhow::{anyhow, Result};
+use serde_yaml::{Mapping, Value};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by `--no-global-config`
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// Keys whose lists are joined instead of replaced
+const CONCATENATED: &[&str] = &[
+    "exclude_patterns",
+    "force_include",
+    "include_patterns",
+    "pass_env",
+    "snapshot_commands",
+    "snapshot_env",
+];
+
+/// Stop reading the global config for the rest of the run
+pub(crate) fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+/// Location of the global config
+pub(crate) fn path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+    Some(config_dir.join("remotebuild").join("config.yaml"))
+}
+
+/// The global config as YAML, if it is enabled and exists, with its path
+pub(crate) fn load() -> Result<Option<(Value, PathBuf)>> {
+    if DISABLED.load(Ordering::SeqCst) {
+        return Ok(None);
+    }
+    let Some(path) = path().filter(|path| path.is_file()) else {
+        return Ok(None);
+    };
+    let content = fs::read_to_string(&path)
+        .map_err(|e| anyhow!("Failed to read global config: {} - {}", path.display(), e))?;
+    let value: Value = serde_yaml::from_str(&content)
+        .map_err(|e| anyhow!("Failed to parse global config: {} - {}", path.display(), e))?;
+    match value {
+        Value::Mapping(_) => Ok(Some((value, path))),
+        // An empty file
+        Value::Null => Ok(None),
+        _ => Err(anyhow!(
+            "Global config {} must be a mapping of options",
+            path.display()
+        )),
+    }
+}
+
+/// Lay a project config over the global one
+pub(crate) fn merge(global: Value, project: Value) -> Value {
+    merge_with(global, project, CONCATENATED)
+}
+
+/// Lay the config `layer` over `base`, joining the lists of the top-level
+/// `concatenated` keys
+pub(crate) fn merge_with(base: Value, layer: Value, concatenated: &[&str]) -> Value {
+    match (base, layer) {
+        (Value::Mapping(base), Value::Mapping(layer)) => {
+            Value::Mapping(merge_mappings(base, layer, concatenated))
+        }
+        // An empty config takes everything from the one below
+        (base, Value::Null) => base,
+        (_, layer) => layer,
+    }
+}
+
+/// Merge two mappings key by key, joining the lists of the `concatenated`
+/// keys
+fn merge_mappings(mut global: Mapping, project: Mapping, concatenated: &[&str]) -> Mapping {
+    for (key, value) in project {
+        let joined = key.as_str().is_some_and(|key| concatenated.contains(&key));
+        let merged = match (global.remove(&key), value) {
+            (Some(Value::Sequence(mut first)), Value::Sequence(second)) if joined => {
+                first.extend(second);
+                Value::Sequence(first)
+            }
+            (Some(Value::Mapping(first)), Value::Mapping(second)) => {
+                Value::Mapping(merge_mappings(first, second, &[]))
+            }
+            (_, value) => value,
+        };
+        global.insert(key, merged);
+    }
+    global
+}
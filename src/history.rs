@@ -0,0 +1,273 @@
+//! Build history kept on the remote
+//!
+//! Every build appends one JSON line to `<remote_path>/.remotebuild/history`
+//! so that, on shared servers, it's easy to see who last built in a directory
+//! and with what. Recording is best-effort and never fails a run.
+//!
+//! Entries also carry the sync duration and mark runs that synced the whole
+//! tree on request or cleaned it, which the time estimates in
+//! [`crate::estimate`] leave out as unrepresentative.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::shared::CANCELLED_EXIT_CODE;
+use crate::{estimate, run_ssh_command_output, stable_hash, Config, SyncScope, REMOTE_META_DIR};
+
+/// History file inside the remote metadata directory
+const HISTORY_FILE: &str = "history";
+
+/// Number of entries read back after recording a build, for the estimates
+const RECENT_ENTRIES: usize = 50;
+
+/// One recorded build
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct HistoryEntry {
+    /// Unix timestamp (seconds) when the build finished
+    timestamp: u64,
+    /// Local `user@hostname` that ran the build
+    user: String,
+    /// Git commit of the local project, if it is a git repository
+    #[serde(default)]
+    commit: Option<String>,
+    /// Git branch of the local project, unless its HEAD is detached
+    #[serde(default)]
+    branch: Option<String>,
+    /// Hash of the build command that was run
+    command_hash: String,
+    /// Exit code of the build command (None if it was killed or never ran)
+    #[serde(default)]
+    exit_code: Option<i32>,
+    /// Build duration in seconds
+    duration_secs: f64,
+    /// Duration of the sync before the build in seconds, if it ran directly
+    /// before it
+    #[serde(default)]
+    sync_secs: Option<f64>,
+    /// Whether the whole tree was synced because a full sync was forced
+    #[serde(default)]
+    full_sync: bool,
+    /// Whether the sync was a clean sync
+    #[serde(default)]
+    clean_sync: bool,
+}
+
+/// How the run that recorded a build synced
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RunTags {
+    /// Duration of the sync directly before the build
+    pub(crate) sync: Option<Duration>,
+    /// Whether a full sync was forced
+    pub(crate) full_sync: bool,
+    /// Whether the sync was a clean sync
+    pub(crate) clean_sync: bool,
+}
+
+impl RunTags {
+    /// Tags for a run syncing with `scope`
+    pub(crate) fn new(scope: SyncScope) -> Self {
+        Self {
+            sync: None,
+            full_sync: scope == SyncScope::Full,
+            clean_sync: scope == SyncScope::Clean,
+        }
+    }
+
+    /// The same tags with the duration of the sync before the build
+    pub(crate) fn with_sync(self, sync: Duration) -> Self {
+        Self {
+            sync: Some(sync),
+            ..self
+        }
+    }
+}
+
+impl HistoryEntry {
+    /// Hash of the build command that was run
+    pub(crate) fn command_hash(&self) -> &str {
+        &self.command_hash
+    }
+
+    /// Git branch the build was run from, if recorded
+    pub(crate) fn branch(&self) -> Option<&str> {
+        self.branch.as_deref()
+    }
+
+    /// Build duration in seconds, if the build succeeded
+    pub(crate) fn successful_build_secs(&self) -> Option<f64> {
+        (self.exit_code == Some(0)).then_some(self.duration_secs)
+    }
+
+    /// Sync duration in seconds, if it was recorded
+    pub(crate) fn sync_secs(&self) -> Option<f64> {
+        self.sync_secs
+    }
+
+    /// Whether the run synced or built differently from a usual one
+    pub(crate) fn is_outlier(&self) -> bool {
+        self.full_sync || self.clean_sync
+    }
+}
+
+/// Hash identifying a build command in the history
+pub(crate) fn command_hash(config: &Config) -> String {
+    format!(
+        "{:016x}",
+        stable_hash(config.build_command.to_string().as_bytes())
+    )
+}
+
+/// Append an entry for a finished build to the remote history, and update
+/// the estimates from the recent entries
+///
+/// Failures are reported as warnings only.
+pub(crate) fn record_build(
+    project_dir: &Path,
+    config: &Config,
+    exit_code: Option<i32>,
+    duration: Duration,
+    tags: RunTags,
+) {
+    let entry = HistoryEntry {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        user: local_user(),
+        commit: git_rev_parse(project_dir, &["HEAD"]),
+        branch: git_rev_parse(project_dir, &["--abbrev-ref", "HEAD"])
+            .filter(|branch| branch != "HEAD"),
+        command_hash: command_hash(config),
+        exit_code,
+        duration_secs: duration.as_secs_f64(),
+        sync_secs: tags.sync.map(|sync| sync.as_secs_f64()),
+        full_sync: tags.full_sync,
+        clean_sync: tags.clean_sync,
+    };
+
+    let result = serde_json::to_string(&entry)
+        .map_err(anyhow::Error::from)
+        .and_then(|line| {
+            let cmd = format!(
+                "cd {dir} && mkdir -p {meta} && printf '%s\\n' {line} >> {meta}/{file} && \
+                 tail -n {recent} {meta}/{file}",
+                dir = config.remote_dir().shell(),
+                meta = REMOTE_META_DIR,
+                line = escape(Cow::Owned(line)),
+                file = HISTORY_FILE,
+                recent = RECENT_ENTRIES
+            );
+            run_ssh_command_output(config, &cmd)
+        });
+
+    match result {
+        Ok(output) => estimate::update(project_dir, config, &parse_entries(&output)),
+        Err(e) => eprintln!("   ⚠ Warning: Could not record build history: {}", e),
+    }
+}
+
+/// Fetch the most recent `limit` history entries from the remote
+pub(crate) fn fetch_recent(config: &Config, limit: usize) -> Result<Vec<HistoryEntry>> {
+    let cmd = format!(
+        "cd {} 2>/dev/null && tail -n {} {}/{} 2>/dev/null; true",
+        config.remote_dir().shell(),
+        limit,
+        REMOTE_META_DIR,
+        HISTORY_FILE
+    );
+    let output = run_ssh_command_output(config, &cmd)?;
+    Ok(parse_entries(&output))
+}
+
+/// Parse history lines, skipping those that don't parse, e.g. from a newer
+/// remotebuild version
+fn parse_entries(output: &str) -> Vec<HistoryEntry> {
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Print history entries as a table, newest last
+pub(crate) fn print_entries(entries: &[HistoryEntry]) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let user_width = entries
+        .iter()
+        .map(|e| e.user.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for entry in entries {
+        let (mark, code) = match entry.exit_code {
+            Some(0) => ("✓", "0".to_string()),
+            Some(CANCELLED_EXIT_CODE) => ("⊘", CANCELLED_EXIT_CODE.to_string()),
+            Some(code) => ("✗", code.to_string()),
+            None => ("✗", "-".to_string()),
+        };
+        let commit = entry
+            .commit
+            .as_deref()
+            .map(|c| c.chars().take(10).collect::<String>())
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "   {:>9}  {:<width$}  {:<10}  {} {:<3}  {:.1}s",
+            format_age(now.saturating_sub(entry.timestamp)),
+            entry.user,
+            commit,
+            mark,
+            code,
+            entry.duration_secs,
+            width = user_width
+        );
+    }
+}
+
+/// Format an age in seconds as a short human-readable string
+pub(crate) fn format_age(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// Get the local `user@hostname`
+pub(crate) fn local_user() -> String {
+    let user = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    let host = Command::new("hostname")
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+    format!("{}@{}", user, host)
+}
+
+/// Output of `git rev-parse` with `args` in the project, if it is a git
+/// repository
+fn git_rev_parse(project_dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("rev-parse")
+        .args(args)
+        .current_dir(project_dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
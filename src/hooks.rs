@@ -0,0 +1,499 @@
+//! External hook scripts
+//!
+//! Executables placed in `.remotebuild/hooks/` inside the project are run at
+//! fixed points of the pipeline. Each hook receives information about the run
+//! through `REMOTEBUILD_*` environment variables and, on stdin, the JSON run
+//! report collected so far.
+//!
+//! A failing `pre-sync` hook aborts the run. Failures of the other hooks are
+//! only warnings, unless the hook exits with [`BLOCKING_EXIT_CODE`] or is
+//! listed in the `blocking_hooks` config option.
+//!
+//! The `pre_sync` and `post_artifacts` config options list shell commands
+//! run at the same points, before the hook executable, with the same
+//! environment. Any failing command fails the run. In quiet and minimal
+//! output their output is only shown when they fail.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::{indented_tail, Config, OutputLevel};
+
+/// Directory (relative to the project root) searched for hook executables
+pub(crate) const HOOKS_DIR: &str = ".remotebuild/hooks";
+
+/// Exit code a post-hook uses to fail the run (EX_TEMPFAIL)
+pub(crate) const BLOCKING_EXIT_CODE: i32 = 75;
+
+/// Points in the pipeline where hooks run
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Hook {
+    /// Before any files are synced
+    PreSync,
+    /// After the remote build command succeeded
+    PostBuild,
+    /// After artifacts were downloaded
+    PostArtifacts,
+    /// After any phase failed
+    OnFailure,
+}
+
+impl Hook {
+    /// File name of the hook executable
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Hook::PreSync => "pre-sync",
+            Hook::PostBuild => "post-build",
+            Hook::PostArtifacts => "post-artifacts",
+            Hook::OnFailure => "on-failure",
+        }
+    }
+}
+
+/// Timing and outcome of one pipeline phase
+#[derive(Debug, Serialize)]
+pub(crate) struct PhaseReport {
+    /// Phase name (sync, build, artifacts)
+    pub(crate) name: String,
+    /// Wall-clock duration in seconds
+    pub(crate) duration_secs: f64,
+    /// Whether the phase succeeded
+    pub(crate) success: bool,
+}
+
+/// Report of the run so far, passed to hooks as JSON on stdin
+#[derive(Debug, Serialize)]
+pub(crate) struct RunReport {
+    /// Local project directory
+    pub(crate) project: String,
+    /// SSH host the build runs on
+    pub(crate) host: String,
+    /// Remote directory the project is synced to
+    pub(crate) remote_path: String,
+    /// Configured artifact patterns
+    pub(crate) artifacts: Vec<String>,
+    /// Phases that have finished, in order
+    pub(crate) phases: Vec<PhaseReport>,
+    /// Compression the sync used, once it has run
+    pub(crate) compression: Option<String>,
+    /// Exit code of the remote build command, once known
+    pub(crate) exit_code: Option<i32>,
+    /// Number of times the connection was re-established (resilient mode)
+    pub(crate) reconnects: u32,
+    /// Error message of the failure, if the run failed
+    pub(crate) error: Option<String>,
+    /// When the run started
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl RunReport {
+    /// Start a report for a run of `config` in `project_dir`
+    pub(crate) fn new(project_dir: &Path, config: &Config) -> Self {
+        Self {
+            project: project_dir.display().to_string(),
+            host: config.host.clone(),
+            remote_path: config.remote_path.clone(),
+            artifacts: config.artifacts.iter().map(|a| a.remote.clone()).collect(),
+            phases: Vec::new(),
+            compression: None,
+            exit_code: None,
+            reconnects: 0,
+            error: None,
+            started: Instant::now(),
+        }
+    }
+
+    /// Record a finished phase
+    pub(crate) fn record(&mut self, name: &str, duration: Duration, success: bool) {
+        self.phases.push(PhaseReport {
+            name: name.to_string(),
+            duration_secs: duration.as_secs_f64(),
+            success,
+        });
+    }
+
+    /// Name of the phase currently (or last) running
+    fn current_phase(&self) -> &str {
+        self.phases
+            .iter()
+            .find(|p| !p.success)
+            .or_else(|| self.phases.last())
+            .map(|p| p.name.as_str())
+            .unwrap_or("start")
+    }
+}
+
+/// Run the configured commands and the executable of a hook, if the project
+/// provides them
+///
+/// Returns an error when the hook failed in a way that should stop the run.
+pub(crate) fn run_hook(
+    project_dir: &Path,
+    config: &Config,
+    hook: Hook,
+    report: &RunReport,
+) -> Result<()> {
+    let (option, commands): (&str, &[String]) = match hook {
+        Hook::PreSync => ("pre_sync", &config.pre_sync),
+        Hook::PostArtifacts => ("post_artifacts", &config.post_artifacts),
+        Hook::PostBuild | Hook::OnFailure => ("", &[]),
+    };
+    for command in commands {
+        run_command(project_dir, config, hook, report, option, command)?;
+    }
+
+    let Some(path) = find_hook(project_dir, hook) else {
+        return Ok(());
+    };
+
+    let mut cmd = Command::new(&path);
+    set_environment(&mut cmd, project_dir, hook, report);
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to run hook: {}", path.display()))?;
+    write_report(&mut child, report);
+
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed to wait for hook: {}", path.display()))?;
+    if status.success() {
+        return Ok(());
+    }
+
+    let blocking = matches!(hook, Hook::PreSync)
+        || status.code() == Some(BLOCKING_EXIT_CODE)
+        || config.blocking_hooks.iter().any(|h| h == hook.name());
+
+    if blocking {
+        Err(anyhow!(
+            "Hook {} failed with exit code: {:?}",
+            hook.name(),
+            status
+        ))
+    } else {
+        eprintln!(
+            "   ⚠ Warning: Hook {} failed with exit code: {:?}",
+            hook.name(),
+            status
+        );
+        Ok(())
+    }
+}
+
+/// Run one command of the `option` config list of a hook with `sh -c`
+fn run_command(
+    project_dir: &Path,
+    config: &Config,
+    hook: Hook,
+    report: &RunReport,
+    option: &str,
+    command: &str,
+) -> Result<()> {
+    let show = matches!(
+        config.output_level(),
+        OutputLevel::Normal | OutputLevel::Verbose
+    );
+    if show {
+        println!("🪝 Running {}: {}", option, command);
+    }
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    set_environment(&mut cmd, project_dir, hook, report);
+    if !show {
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    }
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to run {} command: {}", option, command))?;
+    write_report(&mut child, report);
+
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("Failed to wait for {} command: {}", option, command))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let mut captured = String::from_utf8_lossy(&output.stdout).into_owned();
+    captured.push_str(&String::from_utf8_lossy(&output.stderr));
+    Err(anyhow!(
+        "{} command `{}` failed with exit code: {:?}{}",
+        option,
+        command,
+        output.status,
+        indented_tail(&captured)
+    ))
+}
+
+/// Run `cmd` in the project directory with the `REMOTEBUILD_*` variables of
+/// the run and the report on stdin
+fn set_environment(cmd: &mut Command, project_dir: &Path, hook: Hook, report: &RunReport) {
+    cmd.current_dir(project_dir)
+        .env("REMOTEBUILD_HOOK", hook.name())
+        .env("REMOTEBUILD_PROJECT", project_dir)
+        .env("REMOTEBUILD_HOST", &report.host)
+        .env("REMOTEBUILD_REMOTE_PATH", &report.remote_path)
+        .env("REMOTEBUILD_PHASE", report.current_phase())
+        .env("REMOTEBUILD_ARTIFACTS", report.artifacts.join("\n"))
+        .stdin(Stdio::piped());
+
+    if let Some(code) = report.exit_code {
+        cmd.env("REMOTEBUILD_EXIT_CODE", code.to_string());
+    }
+    cmd.env(
+        "REMOTEBUILD_ELAPSED",
+        format!("{:.3}", report.started.elapsed().as_secs_f64()),
+    );
+    for phase in &report.phases {
+        cmd.env(
+            format!("REMOTEBUILD_{}_DURATION", env_name(&phase.name)),
+            format!("{:.3}", phase.duration_secs),
+        );
+    }
+}
+
+/// A phase name as part of an environment variable name, with anything
+/// but letters and digits (the space of `sync back`) made `_`
+fn env_name(phase: &str) -> String {
+    phase
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Pass the run report as JSON to the stdin of a started hook
+fn write_report(child: &mut Child, report: &RunReport) {
+    // The hook may not read stdin at all, so a broken pipe is fine
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = serde_json::to_writer(&mut stdin, report);
+        let _ = stdin.write_all(b"\n");
+    }
+}
+
+/// Locate the executable for a hook, if present
+fn find_hook(project_dir: &Path, hook: Hook) -> Option<PathBuf> {
+    let path = project_dir.join(HOOKS_DIR).join(hook.name());
+    if !path.is_file() {
+        return None;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let executable = path
+            .metadata()
+            .map(|m| m.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+        if !executable {
+            eprintln!(
+                "   ⚠ Warning: Skipping hook that is not executable: {}",
+                path.display()
+            );
+            return None;
+        }
+    }
+
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A fresh project directory with an empty hooks directory
+    fn project(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "remotebuild-test-hooks-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(HOOKS_DIR)).unwrap();
+        dir
+    }
+
+    /// Write the hook executable `hook` running `script`
+    fn write_hook(project: &Path, hook: Hook, script: &str) {
+        let path = project.join(HOOKS_DIR).join(hook.name());
+        fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        set_mode(&path, 0o755);
+    }
+
+    /// Set the permission bits of `path`
+    fn set_mode(path: &Path, mode: u32) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    /// A config for `build-box` with `extra` YAML appended
+    fn config(extra: &str) -> Config {
+        serde_yaml::from_str(&format!("host: build-box\n{}", extra)).unwrap()
+    }
+
+    /// Hooks are found by name, and a file that isn't executable is skipped
+    #[test]
+    fn discovery_skips_non_executable() {
+        let dir = project("discovery");
+        assert_eq!(find_hook(&dir, Hook::PreSync), None);
+
+        write_hook(&dir, Hook::PreSync, "exit 0");
+        let path = dir.join(HOOKS_DIR).join("pre-sync");
+        assert_eq!(find_hook(&dir, Hook::PreSync), Some(path.clone()));
+        assert_eq!(find_hook(&dir, Hook::PostBuild), None);
+
+        set_mode(&path, 0o644);
+        assert_eq!(find_hook(&dir, Hook::PreSync), None);
+        // Skipped, so even a failing script doesn't stop the run
+        fs::write(&path, "#!/bin/sh\nexit 1\n").unwrap();
+        let config = config("");
+        let report = RunReport::new(&dir, &config);
+        run_hook(&dir, &config, Hook::PreSync, &report).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// The commands of `pre_sync` run in order, before the hook executable
+    #[test]
+    fn config_commands_run_before_hook() {
+        let dir = project("order");
+        write_hook(&dir, Hook::PreSync, "echo hook >> order");
+        let config =
+            config("pre_sync: [\"echo first >> order\", \"echo second >> order\"]\noutput: quiet");
+        let report = RunReport::new(&dir, &config);
+
+        run_hook(&dir, &config, Hook::PreSync, &report).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("order")).unwrap(),
+            "first\nsecond\nhook\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A failing `pre-sync` hook stops the run before anything connects
+    #[test]
+    fn failing_pre_sync_aborts_the_run() {
+        let dir = project("pre-sync");
+        write_hook(&dir, Hook::PreSync, "exit 1");
+        write_hook(&dir, Hook::OnFailure, "touch on-failure-ran");
+        let config = config(&format!(
+            "remote_path: {}\noutput: quiet",
+            dir.join("remote").display()
+        ));
+
+        let error = crate::run_cancellable_build(
+            &dir,
+            &config,
+            crate::SyncScope::Full,
+            &crate::supersede::CancelToken::default(),
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().starts_with("Hook pre-sync failed"),
+            "{:#}",
+            error
+        );
+        assert!(!dir.join("on-failure-ran").exists());
+        assert!(!dir.join("remote").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A failing post-hook only warns, unless it exits with 75 or is listed
+    /// in `blocking_hooks`
+    #[test]
+    fn post_hook_failure_blocks_only_when_asked() {
+        let dir = project("blocking");
+        let plain = config("");
+        let report = RunReport::new(&dir, &plain);
+
+        write_hook(&dir, Hook::PostBuild, "exit 1");
+        run_hook(&dir, &plain, Hook::PostBuild, &report).unwrap();
+        let blocking = config("blocking_hooks: [post-build]");
+        let error = run_hook(&dir, &blocking, Hook::PostBuild, &report).unwrap_err();
+        assert!(error.to_string().starts_with("Hook post-build failed"));
+        // Only the listed hook blocks
+        write_hook(&dir, Hook::PostArtifacts, "exit 1");
+        run_hook(&dir, &blocking, Hook::PostArtifacts, &report).unwrap();
+
+        write_hook(
+            &dir,
+            Hook::OnFailure,
+            &format!("exit {}", BLOCKING_EXIT_CODE),
+        );
+        assert!(run_hook(&dir, &plain, Hook::OnFailure, &report).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Hooks get the run's `REMOTEBUILD_*` variables, a duration per phase
+    /// and the report as JSON on stdin
+    #[test]
+    fn hooks_get_environment_and_report() {
+        let dir = project("environment");
+        write_hook(
+            &dir,
+            Hook::PostBuild,
+            "env | grep '^REMOTEBUILD_[A-Z_]*=' | grep -v '^REMOTEBUILD_ARTIFACTS=' \
+             | sort > env; printf '%s' \"$REMOTEBUILD_ARTIFACTS\" > artifacts; \
+             cat > report.json",
+        );
+        let config =
+            config("remote_path: ~/builds/app\nartifacts: [build/app.nds, out/]\noutput: quiet");
+        let mut report = RunReport::new(&dir, &config);
+        report.record("sync", Duration::from_millis(1500), true);
+        report.record("build", Duration::from_millis(250), true);
+        report.record("sync back", Duration::from_millis(20), true);
+        report.exit_code = Some(0);
+
+        run_hook(&dir, &config, Hook::PostBuild, &report).unwrap();
+        let env = fs::read_to_string(dir.join("env")).unwrap();
+        let mut vars: Vec<&str> = env.lines().collect();
+        let elapsed = vars
+            .iter()
+            .position(|var| var.starts_with("REMOTEBUILD_ELAPSED="))
+            .map(|i| vars.remove(i))
+            .unwrap();
+        assert!(elapsed["REMOTEBUILD_ELAPSED=".len()..]
+            .parse::<f64>()
+            .is_ok());
+        let project_var = format!("REMOTEBUILD_PROJECT={}", dir.display());
+        assert_eq!(
+            vars,
+            [
+                "REMOTEBUILD_BUILD_DURATION=0.250",
+                "REMOTEBUILD_EXIT_CODE=0",
+                "REMOTEBUILD_HOOK=post-build",
+                "REMOTEBUILD_HOST=build-box",
+                "REMOTEBUILD_PHASE=sync back",
+                project_var.as_str(),
+                "REMOTEBUILD_REMOTE_PATH=~/builds/app",
+                "REMOTEBUILD_SYNC_BACK_DURATION=0.020",
+                "REMOTEBUILD_SYNC_DURATION=1.500",
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("artifacts")).unwrap(),
+            "build/app.nds\nout/"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+        assert_eq!(json["host"], "build-box");
+        assert_eq!(json["remote_path"], "~/builds/app");
+        assert_eq!(json["exit_code"], 0);
+        assert_eq!(json["phases"][0]["name"], "sync");
+        assert_eq!(json["phases"][0]["duration_secs"], 1.5);
+        assert_eq!(json["phases"][1]["success"], true);
+        assert_eq!(json["artifacts"][1], "out/");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+//! Which project a remote directory belongs to
+//!
+//! The sync deletes remote files that don't exist locally, so two projects
+//! pointed at the same `remote_path` would keep deleting each other. The
+//! first sync writes a `.remotebuild-project` marker into the remote path
+//! with a random ID kept in the project's local state and the absolute local
+//! path, for people reading it. Later syncs read it back in the same SSH
+//! command that creates the directory and stop when it names a different ID,
+//! before anything is sent. `--take-over` claims the directory for this
+//! project instead.
+//!
+//! The ID belongs to the local project directory, so a moved or re-cloned
+//! checkout needs `--take-over` once.
+
+use anyhow::{anyhow, Context, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state::State;
+use crate::{Config, OutputLevel};
+
+/// Marker file in the remote path naming the project it belongs to
+pub(crate) const MARKER: &str = ".remotebuild-project";
+
+/// Line printed by the remote check before the marker of another project
+const OTHER_PROJECT: &str = "remotebuild-other-project";
+
+/// The project's ID, created and saved in its local state on first use
+pub(crate) fn project_id(project_dir: &Path) -> Result<String> {
+    let mut state = State::load(project_dir);
+    if let Some(id) = &state.project_id {
+        return Ok(id.clone());
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(project_dir.to_string_lossy().as_bytes());
+    hasher.update(&nanos.to_le_bytes());
+    hasher.update(&std::process::id().to_le_bytes());
+    let id = hasher.finalize().to_hex()[..32].to_string();
+    state.project_id = Some(id.clone());
+    // Without a saved ID the next sync couldn't recognise its own marker
+    state
+        .save(project_dir)
+        .context("Failed to save the project ID")?;
+    Ok(id)
+}
+
+/// Shell code run in the remote project directory: print the marker if it
+/// names another project, and write ours unless one is there or in a dry run
+///
+/// With `--take-over` ours replaces the other project's.
+pub(crate) fn check_command(config: &Config, project_dir: &Path, id: &str) -> String {
+    let write = format!(
+        "printf '%s\\n%s\\n' {} {} > {}",
+        id,
+        escape(Cow::Owned(project_dir.to_string_lossy().into_owned())),
+        MARKER
+    );
+    let other = format!(
+        "[ -f {m} ] && [ \"$(head -n 1 {m})\" != {id} ]",
+        m = MARKER,
+        id = id
+    );
+    let mismatch = format!("echo {}; cat {}", OTHER_PROJECT, MARKER);
+    if config.dry_run {
+        format!("if {}; then {}; fi", other, mismatch)
+    } else if config.take_over {
+        format!("if {}; then {}; fi; {}", other, mismatch, write)
+    } else {
+        format!(
+            "if {}; then {}; elif [ ! -f {} ]; then {}; fi",
+            other, mismatch, MARKER, write
+        )
+    }
+}
+
+/// The local path in the other project's marker, if the check found one
+pub(crate) fn other_project(stdout: &str) -> Option<String> {
+    let mut lines = stdout.lines().skip_while(|line| *line != OTHER_PROJECT);
+    lines.next()?;
+    Some(lines.nth(1).unwrap_or("an unknown path").to_string())
+}
+
+/// Stop at another project's remote path, unless taking it over
+pub(crate) fn take_over(config: &Config, owner: &str) -> Result<()> {
+    let destination = format!("{}:{}", config.host, config.remote_path);
+    if !config.take_over {
+        return Err(anyhow!(
+            "{} belongs to another project ({}); set a different remote_path, or pass \
+             --take-over to claim it and let the sync delete that project's files",
+            destination,
+            owner
+        ));
+    }
+    if !matches!(config.output_level(), OutputLevel::Quiet) {
+        let verb = if config.dry_run {
+            "Would take over"
+        } else {
+            "Took over"
+        };
+        println!("   ℹ {} {} from {}", verb, destination, owner);
+    }
+    Ok(())
+}
@@ -0,0 +1,682 @@
+//! `remotebuild init`: generate a config file
+//!
+//! Without options a starter config is written from the `--host`,
+//! `--build-command` and `--artifact` flags, asking for the ones not given
+//! when run in a terminal. The build command is prefilled from a `Makefile`,
+//! `CMakeLists.txt` or `Cargo.toml` in the project. Every key of the written
+//! file is commented, and the host is probed once to tell whether the
+//! connection works. With `--from-cmake-preset NAME`, the build is
+//! derived from the configure preset of that name in `CMakePresets.json` or
+//! `CMakeUserPresets.json`: the build command runs the preset, its build and
+//! install directories are kept out of the sync, and the install or output
+//! directories are proposed as artifacts. With `--detect`, the output
+//! directories found by build system detection are written as excludes.
+//!
+//! `cmake --preset` runs on the remote, so most preset fields need no
+//! translation. Environment variables the preset reads from the local
+//! environment (`$env{..}`/`$penv{..}`) are copied into the `env` map, since
+//! the remote won't have them. Anything that can't be translated produces a
+//! warning rather than a broken config.
+
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::Path;
+
+use crate::config_format::ConfigFormat;
+use crate::patterns::ExcludeSet;
+use crate::{
+    default_project_remote_path, detect, ensure_ssh_connection, Config, ALWAYS_EXCLUDED,
+    DEFAULT_EXCLUDES,
+};
+
+/// Preset files searched in the project root, in order
+const PRESET_FILES: &[&str] = &["CMakePresets.json", "CMakeUserPresets.json"];
+
+/// Host written when none is given, to be edited by the user
+const PLACEHOLDER_HOST: &str = "user@hostname";
+
+/// Preset fields that `cmake --preset` handles by itself on the remote
+const PASSTHROUGH_FIELDS: &[&str] = &[
+    "name",
+    "displayName",
+    "description",
+    "inherits",
+    "hidden",
+    "generator",
+    "architecture",
+    "toolset",
+    "toolchainFile",
+    "binaryDir",
+    "installDir",
+    "cacheVariables",
+    "environment",
+    "warnings",
+    "errors",
+    "debug",
+    "trace",
+    "graphviz",
+];
+
+/// Maximum depth of preset inheritance, to stop on cycles
+const MAX_INHERIT_DEPTH: usize = 16;
+
+/// Build files recognized in the project root, with the build command they
+/// suggest, in order of preference
+const BUILD_FILES: &[(&str, &str)] = &[
+    ("Makefile", "make"),
+    ("GNUmakefile", "make"),
+    ("makefile", "make"),
+    (
+        "CMakeLists.txt",
+        "cmake -B build -DCMAKE_BUILD_TYPE=Release && cmake --build build",
+    ),
+    ("Cargo.toml", "cargo build --release"),
+];
+
+/// Comment written above each key of the generated file
+const KEY_COMMENTS: &[(&str, &str)] = &[
+    (
+        "host",
+        "SSH host to build on (user@hostname, or a Host from ~/.ssh/config)",
+    ),
+    (
+        "remote_path",
+        "Directory on the host the project is synced to and built in",
+    ),
+    ("build_command", "Command run in remote_path on the host"),
+    (
+        "env",
+        "Environment variables exported before the build command",
+    ),
+    (
+        "artifacts",
+        "Files and directories copied back after the build, relative to remote_path",
+    ),
+    (
+        "exclude_patterns",
+        "Paths kept out of the sync, in .gitignore syntax",
+    ),
+];
+
+/// Options for `remotebuild init`
+pub(crate) struct InitOptions<'a> {
+    /// Name of the config file to write
+    pub(crate) config_name: &'a str,
+    /// SSH host to write into the config
+    pub(crate) host: Option<&'a str>,
+    /// Build command to write into the config, overriding a preset's
+    pub(crate) build_command: Option<&'a str>,
+    /// Artifacts to write into the config, in addition to a preset's
+    pub(crate) artifacts: &'a [String],
+    /// Configure preset to derive the config from
+    pub(crate) cmake_preset: Option<&'a str>,
+    /// Overwrite an existing config file
+    pub(crate) force: bool,
+    /// Write the excludes detected from the project's build systems
+    pub(crate) detect: bool,
+}
+
+/// The subset of [`Config`] that `init` writes
+#[derive(Debug, Serialize)]
+struct GeneratedConfig {
+    /// SSH host to connect to
+    host: String,
+    /// Remote path where the project will be synced and built
+    remote_path: String,
+    /// Build command to run on the remote server
+    build_command: String,
+    /// Environment variables exported before the build command
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    /// Artifact patterns to copy back
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    artifacts: Vec<String>,
+    /// Extra exclude patterns for the sync
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    exclude_patterns: Vec<String>,
+}
+
+/// Write a new config file for the project
+pub(crate) fn run_init(project_dir: &Path, options: &InitOptions) -> Result<()> {
+    let path = project_dir.join(options.config_name);
+    if path.exists() && !options.force {
+        return Err(anyhow!(
+            "{} already exists (pass --force to overwrite it)",
+            path.display()
+        ));
+    }
+    if ConfigFormat::from_path(&path) != ConfigFormat::Yaml {
+        return Err(anyhow!(
+            "init writes YAML, so {} needs a .yaml or .yml name",
+            options.config_name
+        ));
+    }
+
+    let suggested = BUILD_FILES
+        .iter()
+        .find(|(file, _)| project_dir.join(file).is_file());
+    let mut config = GeneratedConfig {
+        host: options.host.unwrap_or(PLACEHOLDER_HOST).to_string(),
+        remote_path: default_project_remote_path(project_dir),
+        build_command: suggested.map_or("make", |(_, command)| command).to_string(),
+        env: BTreeMap::new(),
+        artifacts: Vec::new(),
+        exclude_patterns: Vec::new(),
+    };
+    if let Some(preset) = options.cmake_preset {
+        apply_cmake_preset(project_dir, preset, &mut config)?;
+    } else if let (Some((file, _)), None) = (suggested, options.build_command) {
+        println!("   Found {}, suggesting: {}", file, config.build_command);
+    }
+    if let Some(build_command) = options.build_command {
+        config.build_command = build_command.to_string();
+    }
+    config.artifacts.extend(options.artifacts.iter().cloned());
+
+    // Ask for what the flags didn't give, if someone is there to answer
+    let mut host_given = options.host.is_some();
+    if std::io::stdin().is_terminal() {
+        if !host_given {
+            if let Some(host) = ask("SSH host of the build server", None)? {
+                config.host = host;
+                host_given = true;
+            }
+        }
+        if options.build_command.is_none() && options.cmake_preset.is_none() {
+            if let Some(command) = ask("Build command", Some(&config.build_command))? {
+                config.build_command = command;
+            }
+        }
+        if options.artifacts.is_empty() && options.cmake_preset.is_none() {
+            if let Some(artifacts) = ask("Artifacts to copy back (space-separated)", None)? {
+                config.artifacts = artifacts.split_whitespace().map(String::from).collect();
+            }
+        }
+    }
+    if options.detect {
+        let detected = detect::detect(
+            project_dir,
+            &config.build_command,
+            &builtin_excludes(),
+            &config.exclude_patterns,
+            &[],
+        );
+        for found in detected {
+            println!("   Excluding {} ({})", found.pattern, found.reason);
+            config.exclude_patterns.push(found.pattern);
+        }
+    }
+
+    let yaml = commented(&serde_yaml::to_string(&config).context("Failed to serialize config")?);
+
+    // The file must load exactly like a hand-written one
+    let loaded = serde_yaml::from_str::<Config>(&yaml).context("Generated config does not load")?;
+
+    fs::write(&path, &yaml)
+        .with_context(|| format!("Failed to write config file: {}", path.display()))?;
+
+    println!("✅ Wrote {}", path.display());
+    if !host_given {
+        println!("   Set host to your build server before running remotebuild");
+        return Ok(());
+    }
+
+    // A broken connection shouldn't lose the config that was just written
+    match ensure_ssh_connection(&loaded) {
+        Ok(()) => println!("   ✓ Connected to {}", loaded.host),
+        Err(e) => warn(&format!("Could not connect to {}: {:#}", loaded.host, e)),
+    }
+    Ok(())
+}
+
+/// Ask a question on the terminal, returning the answer or `default`, or
+/// None when it was left empty without a default
+fn ask(question: &str, default: Option<&str>) -> Result<Option<String>> {
+    match default {
+        Some(default) => print!("{} [{}]: ", question, default),
+        None => print!("{}: ", question),
+    }
+    std::io::stdout().flush().ok();
+    let mut answer = String::new();
+    std::io::stdin().lock().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.map(String::from)
+    } else {
+        Some(answer.to_string())
+    })
+}
+
+/// Put a comment above every top-level key of the generated YAML
+fn commented(yaml: &str) -> String {
+    let mut out = String::from(
+        "# remotebuild configuration, see .remotebuild.yaml.example for all options\n",
+    );
+    for line in yaml.lines() {
+        let key = line.split(':').next().unwrap_or_default();
+        if let Some((_, comment)) = KEY_COMMENTS.iter().find(|(name, _)| *name == key) {
+            out.push('\n');
+            out.push_str(&format!("# {}\n", comment));
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Fill in the build command, env, excludes and artifacts from a preset
+fn apply_cmake_preset(project_dir: &Path, name: &str, config: &mut GeneratedConfig) -> Result<()> {
+    let (configure_presets, build_presets) = load_presets(project_dir)?;
+    let preset = resolve_preset(&configure_presets, name, 0)?;
+
+    for key in preset.keys() {
+        match key.as_str() {
+            "condition" => warn(&format!(
+                "Preset condition of {} is evaluated on the remote host",
+                name
+            )),
+            "vendor" => warn(&format!("Vendor settings of {} are ignored", name)),
+            "cmakeExecutable" => warn(&format!(
+                "cmakeExecutable of {} is a local path; the remote uses cmake from PATH",
+                name
+            )),
+            key if !PASSTHROUGH_FIELDS.contains(&key) => {
+                warn(&format!("Unsupported preset field ignored: {}", key))
+            }
+            _ => {}
+        }
+    }
+
+    let source_dir_name = project_dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let expander = MacroExpander {
+        preset_name: name,
+        source_dir_name: &source_dir_name,
+        generator: preset.get("generator").and_then(Value::as_str),
+    };
+
+    let build_dir = match preset.get("binaryDir").and_then(Value::as_str) {
+        Some(dir) => expander.project_path("binaryDir", dir),
+        None => {
+            warn(&format!("Preset {} has no binaryDir; assuming build", name));
+            Some("build".to_string())
+        }
+    };
+
+    let cache = preset.get("cacheVariables").and_then(Value::as_object);
+    let install_dir = preset
+        .get("installDir")
+        .and_then(Value::as_str)
+        .or_else(|| cache.and_then(|c| cache_string(c, "CMAKE_INSTALL_PREFIX")))
+        .and_then(|dir| expander.project_path("installDir", dir));
+    let output_dirs: Vec<String> = [
+        "CMAKE_RUNTIME_OUTPUT_DIRECTORY",
+        "CMAKE_LIBRARY_OUTPUT_DIRECTORY",
+        "CMAKE_ARCHIVE_OUTPUT_DIRECTORY",
+    ]
+    .iter()
+    .filter_map(|var| {
+        cache
+            .and_then(|c| cache_string(c, var))
+            .map(|dir| (var, dir))
+    })
+    .filter_map(|(var, dir)| {
+        // Relative output directories are relative to the build tree
+        let dir = if dir.starts_with('/') || dir.starts_with("${") || build_dir.is_none() {
+            dir.to_string()
+        } else {
+            format!(
+                "${{sourceDir}}/{}/{}",
+                build_dir.as_deref().unwrap_or("."),
+                dir
+            )
+        };
+        expander.project_path(var, &dir)
+    })
+    .collect();
+
+    // Build command: configure with the preset, then build with a matching
+    // build preset if there is one
+    let quoted_name = escape(Cow::Borrowed(name));
+    config.build_command = match find_build_preset(&build_presets, name) {
+        Some(build_preset) => format!(
+            "cmake --preset {} && cmake --build --preset {}",
+            quoted_name,
+            escape(Cow::Owned(build_preset))
+        ),
+        None => format!(
+            "cmake --preset {} && cmake --build {}",
+            quoted_name,
+            escape(Cow::Owned(
+                build_dir.clone().unwrap_or_else(|| "build".to_string())
+            ))
+        ),
+    };
+
+    // Everything CMake writes stays on the remote, protected from --delete
+    let defaults = ExcludeSet::new(builtin_excludes());
+    for dir in build_dir.iter().chain(&install_dir).chain(&output_dirs) {
+        let pattern = format!("/{}/", dir);
+        if !defaults.excludes_file(&format!("{}/", dir))
+            && !config.exclude_patterns.contains(&pattern)
+        {
+            config.exclude_patterns.push(pattern);
+        }
+    }
+
+    // Artifacts: the install tree if there is one, otherwise the output dirs
+    match &install_dir {
+        Some(dir) => config.artifacts.push(dir.clone()),
+        None => config.artifacts.extend(output_dirs.iter().cloned()),
+    }
+    let exports_compile_commands = cache
+        .and_then(|c| c.get("CMAKE_EXPORT_COMPILE_COMMANDS"))
+        .is_some_and(is_truthy);
+    if let (true, Some(dir)) = (exports_compile_commands, &build_dir) {
+        config
+            .artifacts
+            .push(format!("{}/compile_commands.json", dir));
+    }
+    if config.artifacts.is_empty() {
+        warn(&format!(
+            "Preset {} has no install or output directory; add artifacts by hand",
+            name
+        ));
+    }
+
+    // Variables read from the local environment don't exist on the remote
+    let defined: Vec<&str> = preset
+        .get("environment")
+        .and_then(Value::as_object)
+        .map(|e| e.keys().map(String::as_str).collect())
+        .unwrap_or_default();
+    let mut referenced = Vec::new();
+    collect_env_references(&Value::Object(preset.clone()), &mut referenced);
+    referenced.sort();
+    referenced.dedup();
+    for (var, parent_only) in referenced {
+        if !parent_only && defined.contains(&var.as_str()) {
+            continue;
+        }
+        match env::var(&var) {
+            Ok(value) => {
+                warn(&format!(
+                    "{} was copied from the local environment; check it's right for the remote",
+                    var
+                ));
+                config.env.insert(var, value);
+            }
+            Err(_) => warn(&format!(
+                "Preset {} reads {} from the environment, which isn't set locally; add it to env",
+                name, var
+            )),
+        }
+    }
+
+    Ok(())
+}
+
+/// Load configure and build presets from the preset files in the project
+fn load_presets(project_dir: &Path) -> Result<(Vec<Value>, Vec<Value>)> {
+    let mut configure = Vec::new();
+    let mut build = Vec::new();
+    let mut found = false;
+
+    for file in PRESET_FILES {
+        let path = project_dir.join(file);
+        if !path.exists() {
+            continue;
+        }
+        found = true;
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let presets: Value = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        if presets.get("include").is_some() {
+            warn(&format!("Presets included from {} are not read", file));
+        }
+        if let Some(list) = presets.get("configurePresets").and_then(Value::as_array) {
+            configure.extend(list.iter().cloned());
+        }
+        if let Some(list) = presets.get("buildPresets").and_then(Value::as_array) {
+            build.extend(list.iter().cloned());
+        }
+    }
+
+    if !found {
+        return Err(anyhow!(
+            "No CMakePresets.json or CMakeUserPresets.json in {}",
+            project_dir.display()
+        ));
+    }
+    Ok((configure, build))
+}
+
+/// Find a preset by name and merge in everything it inherits
+///
+/// As in CMake, earlier entries of `inherits` win over later ones, the
+/// preset's own fields win over all of them, and `cacheVariables` and
+/// `environment` are merged key by key.
+fn resolve_preset(presets: &[Value], name: &str, depth: usize) -> Result<Map<String, Value>> {
+    if depth > MAX_INHERIT_DEPTH {
+        return Err(anyhow!(
+            "Preset inheritance is too deep (cycle at {}?)",
+            name
+        ));
+    }
+
+    let preset = presets
+        .iter()
+        .filter_map(Value::as_object)
+        .find(|p| p.get("name").and_then(Value::as_str) == Some(name))
+        .ok_or_else(|| {
+            let available: Vec<&str> = presets
+                .iter()
+                .filter(|p| p.get("hidden").and_then(Value::as_bool) != Some(true))
+                .filter_map(|p| p.get("name").and_then(Value::as_str))
+                .collect();
+            anyhow!(
+                "No preset named {} (available: {})",
+                name,
+                available.join(", ")
+            )
+        })?;
+
+    let parents: Vec<&str> = match preset.get("inherits") {
+        Some(Value::String(parent)) => vec![parent.as_str()],
+        Some(Value::Array(parents)) => parents.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+
+    let mut merged = Map::new();
+    for parent in parents.iter().rev() {
+        let mut resolved = resolve_preset(presets, parent, depth + 1)?;
+        resolved.remove("hidden");
+        overlay(&mut merged, resolved);
+    }
+    overlay(&mut merged, preset.clone());
+    Ok(merged)
+}
+
+/// Apply the fields of `over` on top of `base`
+fn overlay(base: &mut Map<String, Value>, over: Map<String, Value>) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(inner)), Value::Object(value))
+                if key == "cacheVariables" || key == "environment" =>
+            {
+                inner.extend(value);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Find the build preset to use with a configure preset
+///
+/// A build preset with the same name is preferred, otherwise the first one
+/// pointing at the configure preset is used.
+fn find_build_preset(presets: &[Value], configure_preset: &str) -> Option<String> {
+    let names: Vec<&str> = presets
+        .iter()
+        .filter(|p| p.get("hidden").and_then(Value::as_bool) != Some(true))
+        .filter_map(|p| p.get("name").and_then(Value::as_str))
+        .collect();
+    let matching: Vec<&str> = names
+        .into_iter()
+        .filter(|name| {
+            resolve_preset(presets, name, 0).ok().and_then(|p| {
+                p.get("configurePreset")
+                    .and_then(Value::as_str)
+                    .map(|c| c == configure_preset)
+            }) == Some(true)
+        })
+        .collect();
+
+    matching
+        .iter()
+        .find(|name| **name == configure_preset)
+        .or_else(|| matching.first())
+        .map(|name| name.to_string())
+}
+
+/// Get a cache variable's value as a string, in either the plain or the
+/// `{ "type": .., "value": .. }` form
+fn cache_string<'a>(cache: &'a Map<String, Value>, var: &str) -> Option<&'a str> {
+    match cache.get(var)? {
+        Value::String(value) => Some(value),
+        Value::Object(entry) => entry.get("value").and_then(Value::as_str),
+        _ => None,
+    }
+}
+
+/// Check whether a cache value is true in CMake's sense
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Bool(b) => *b,
+        Value::String(s) => matches!(s.to_uppercase().as_str(), "ON" | "TRUE" | "YES" | "Y" | "1"),
+        Value::Object(entry) => entry.get("value").is_some_and(is_truthy),
+        _ => false,
+    }
+}
+
+/// Collect `$env{NAME}` and `$penv{NAME}` references in every string
+///
+/// Each reference is returned with whether it can only come from the parent
+/// environment (`$penv`).
+fn collect_env_references(value: &Value, found: &mut Vec<(String, bool)>) {
+    match value {
+        Value::String(s) => {
+            for (prefix, parent_only) in [("$env{", false), ("$penv{", true)] {
+                let mut rest = s.as_str();
+                while let Some(start) = rest.find(prefix) {
+                    rest = &rest[start + prefix.len()..];
+                    if let Some(end) = rest.find('}') {
+                        found.push((rest[..end].to_string(), parent_only));
+                        rest = &rest[end..];
+                    }
+                }
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|v| collect_env_references(v, found)),
+        Value::Object(map) => map.values().for_each(|v| collect_env_references(v, found)),
+        _ => {}
+    }
+}
+
+/// Expands CMake preset macros in paths
+struct MacroExpander<'a> {
+    /// Name of the configure preset
+    preset_name: &'a str,
+    /// Name of the project directory
+    source_dir_name: &'a str,
+    /// Generator of the preset, if set
+    generator: Option<&'a str>,
+}
+
+impl MacroExpander<'_> {
+    /// Expand a preset path into a path relative to the project root
+    ///
+    /// Returns `None`, with a warning, for paths outside the project or with
+    /// macros that can only be resolved on the machine running CMake.
+    fn project_path(&self, field: &str, value: &str) -> Option<String> {
+        let mut expanded = value
+            .replace("${sourceDir}", ".")
+            .replace("${fileDir}", ".")
+            .replace("${sourceParentDir}", "..")
+            .replace("${sourceDirName}", self.source_dir_name)
+            .replace("${presetName}", self.preset_name)
+            .replace("${dollar}", "$");
+        if let Some(generator) = self.generator {
+            expanded = expanded.replace("${generator}", generator);
+        }
+
+        if expanded.contains("${") || expanded.contains("$env{") || expanded.contains("$penv{") {
+            warn(&format!(
+                "{} {} uses macros that can't be resolved locally; not used",
+                field, value
+            ));
+            return None;
+        }
+
+        let mut parts = Vec::new();
+        for part in expanded.split('/') {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    if parts.pop().is_none() {
+                        warn(&format!(
+                            "{} {} is outside the project; not used",
+                            field, value
+                        ));
+                        return None;
+                    }
+                }
+                part => parts.push(part),
+            }
+        }
+        if expanded.starts_with('/') {
+            warn(&format!(
+                "{} {} is an absolute path; not used",
+                field, value
+            ));
+            return None;
+        }
+        if parts.is_empty() {
+            warn(&format!(
+                "{} {} is the project root; not used",
+                field, value
+            ));
+            return None;
+        }
+        Some(parts.join("/"))
+    }
+}
+
+/// Print an init warning
+fn warn(message: &str) {
+    eprintln!("   ⚠ Warning: {}", message);
+}
+
+/// The built-in excludes of a generated config, which keeps the default
+/// `default_excludes`
+fn builtin_excludes() -> Vec<&'static str> {
+    ALWAYS_EXCLUDED
+        .iter()
+        .chain(DEFAULT_EXCLUDES)
+        .copied()
+        .collect()
+}
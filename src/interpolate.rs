@@ -0,0 +1,238 @@
+//! Local environment variables in config values
+//!
+//! `host`, `remote_path` and `artifacts`, at the top level and in profiles,
+//! may refer to variables of the local environment as `$VAR` or `${VAR}`, so
+//! one checked-in config works for everyone (`host: ${BUILD_HOST}`,
+//! `remote_path: ~/builds/${USER}/app`). An unset variable is an error unless
+//! a fallback is given as `${VAR:-default}`, and `$$` is a literal dollar.
+//!
+//! The build command is left alone: it runs in the remote shell, which
+//! already expands its variables, including those set by `env`. Variables
+//! the build needs from the local environment are named in `pass_env`
+//! instead, and added to `env` with their local values.
+
+use anyhow::{anyhow, Context, Result};
+use std::env;
+
+use crate::Config;
+
+/// Expand the variables in the values of `config` that take them
+pub(crate) fn apply(config: &mut Config) -> Result<()> {
+    config.host = expand(&config.host).context("Invalid host")?;
+    config.remote_path = expand(&config.remote_path).context("Invalid remote_path")?;
+    config.artifacts = config
+        .artifacts
+        .iter()
+        .map(|artifact| {
+            artifact
+                .try_map(expand)
+                .with_context(|| format!("Invalid artifact {}", artifact))
+        })
+        .collect::<Result<_>>()?;
+    pass_env(config);
+    Ok(())
+}
+
+/// Add the local values of the `pass_env` variables to `env`, warning about
+/// unset ones instead of exporting them empty
+fn pass_env(config: &mut Config) {
+    for name in &config.pass_env {
+        if config.env.contains_key(name) {
+            continue;
+        }
+        match env::var(name) {
+            Ok(value) => {
+                config.env.insert(name.clone(), value);
+            }
+            Err(_) => eprintln!(
+                "   ⚠ Warning: pass_env variable {} is not set locally, not passing it",
+                name
+            ),
+        }
+    }
+}
+
+/// Replace the variable references in `value` with their values
+pub(crate) fn expand(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated ${{ in {}", value))?;
+            let (name, default) = match braced[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&braced[..end], None),
+            };
+            if !is_name(name) {
+                return Err(anyhow!("Invalid variable name ${{{}}} in {}", name, value));
+            }
+            expanded.push_str(&lookup(name, default)?);
+            rest = &braced[end + 1..];
+        } else {
+            let len = after
+                .find(|c: char| !(c == '_' || c.is_ascii_alphanumeric()))
+                .unwrap_or(after.len());
+            if is_name(&after[..len]) {
+                expanded.push_str(&lookup(&after[..len], None)?);
+                rest = &after[len..];
+            } else {
+                // A dollar not starting a name stands for itself
+                expanded.push('$');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Whether `name` is a valid variable name
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Value of the variable `name`, or `default` when it is unset or empty
+fn lookup(name: &str, default: Option<&str>) -> Result<String> {
+    match (env::var(name), default) {
+        (Ok(value), Some(default)) if value.is_empty() => Ok(default.to_string()),
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some(default)) => Ok(default.to_string()),
+        (Err(_), None) => Err(anyhow!(
+            "Environment variable {} is not set (write ${{{}:-default}} for a fallback)",
+            name,
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A config with `env` and `pass_env` entries
+    fn config(yaml: &str) -> Config {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// Passed variables keep their local values, however they need quoting
+    #[test]
+    fn pass_env_values() {
+        env::set_var("REMOTEBUILD_TEST_PASS_TRICKY", "it's $HOME\nand more");
+        env::set_var("REMOTEBUILD_TEST_PASS_EMPTY", "");
+        let mut config =
+            config("pass_env: [REMOTEBUILD_TEST_PASS_TRICKY, REMOTEBUILD_TEST_PASS_EMPTY]");
+        pass_env(&mut config);
+        assert_eq!(
+            config.env["REMOTEBUILD_TEST_PASS_TRICKY"],
+            "it's $HOME\nand more"
+        );
+        assert_eq!(config.env["REMOTEBUILD_TEST_PASS_EMPTY"], "");
+    }
+
+    /// `env` entries win over passed variables, and unset ones are skipped
+    #[test]
+    fn pass_env_precedence() {
+        env::set_var("REMOTEBUILD_TEST_PASS_SET", "local");
+        env::remove_var("REMOTEBUILD_TEST_PASS_UNSET");
+        let mut config = config(
+            "env: {REMOTEBUILD_TEST_PASS_SET: configured}\n\
+             pass_env: [REMOTEBUILD_TEST_PASS_SET, REMOTEBUILD_TEST_PASS_UNSET]",
+        );
+        pass_env(&mut config);
+        assert_eq!(config.env["REMOTEBUILD_TEST_PASS_SET"], "configured");
+        assert!(!config.env.contains_key("REMOTEBUILD_TEST_PASS_UNSET"));
+    }
+
+    /// Both reference forms expand, and fallbacks cover unset and empty values
+    #[test]
+    fn expand_references() {
+        env::set_var("REMOTEBUILD_TEST_EXPAND_HOST", "build-box");
+        env::set_var("REMOTEBUILD_TEST_EXPAND_EMPTY", "");
+        env::remove_var("REMOTEBUILD_TEST_EXPAND_UNSET");
+        let cases = [
+            ("$REMOTEBUILD_TEST_EXPAND_HOST", "build-box"),
+            ("${REMOTEBUILD_TEST_EXPAND_HOST}.lan", "build-box.lan"),
+            ("$REMOTEBUILD_TEST_EXPAND_HOST/out", "build-box/out"),
+            ("${REMOTEBUILD_TEST_EXPAND_UNSET:-fallback}", "fallback"),
+            ("${REMOTEBUILD_TEST_EXPAND_EMPTY:-fallback}", "fallback"),
+            ("${REMOTEBUILD_TEST_EXPAND_HOST:-fallback}", "build-box"),
+            ("${REMOTEBUILD_TEST_EXPAND_UNSET:-}", ""),
+            ("~/builds/plain", "~/builds/plain"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(expand(value).unwrap(), expected, "{}", value);
+        }
+    }
+
+    /// `$$` is a literal dollar, as is a dollar not starting a name
+    #[test]
+    fn expand_dollars() {
+        env::set_var("REMOTEBUILD_TEST_EXPAND_DOLLAR", "value");
+        let cases = [
+            ("$$", "$"),
+            ("cost$$5", "cost$5"),
+            (
+                "$$REMOTEBUILD_TEST_EXPAND_DOLLAR",
+                "$REMOTEBUILD_TEST_EXPAND_DOLLAR",
+            ),
+            ("$$$REMOTEBUILD_TEST_EXPAND_DOLLAR", "$value"),
+            ("$$${REMOTEBUILD_TEST_EXPAND_DOLLAR}", "$value"),
+            ("$", "$"),
+            ("a $ b", "a $ b"),
+            ("$1", "$1"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(expand(value).unwrap(), expected, "{}", value);
+        }
+    }
+
+    /// Unset variables without fallback and malformed references are errors
+    #[test]
+    fn expand_errors() {
+        env::remove_var("REMOTEBUILD_TEST_EXPAND_MISSING");
+        let unset = expand("$REMOTEBUILD_TEST_EXPAND_MISSING").unwrap_err();
+        assert!(unset
+            .to_string()
+            .contains("REMOTEBUILD_TEST_EXPAND_MISSING is not set"));
+        assert!(expand("${REMOTEBUILD_TEST_EXPAND_MISSING")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Unterminated"));
+        assert!(expand("${1X}")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid variable name"));
+    }
+
+    /// Host, remote path and artifacts expand; the build command is left alone
+    #[test]
+    fn apply_leaves_build_command() {
+        env::set_var("REMOTEBUILD_TEST_APPLY_HOST", "build-box");
+        env::set_var("REMOTEBUILD_TEST_APPLY_DIR", "app");
+        let mut config = config(
+            "host: ${REMOTEBUILD_TEST_APPLY_HOST}\n\
+             remote_path: ~/builds/$REMOTEBUILD_TEST_APPLY_DIR\n\
+             artifacts: [out/$REMOTEBUILD_TEST_APPLY_DIR.bin]\n\
+             build_command: make -j${JOBS} APP=$$REMOTEBUILD_TEST_APPLY_DIR",
+        );
+        apply(&mut config).unwrap();
+        assert_eq!(config.host, "build-box");
+        assert_eq!(config.remote_path, "~/builds/app");
+        assert_eq!(config.artifacts[0].to_string(), "out/app.bin");
+        assert_eq!(
+            config.build_command.to_string(),
+            "make -j${JOBS} APP=$$REMOTEBUILD_TEST_APPLY_DIR"
+        );
+    }
+}
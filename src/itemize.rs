@@ -0,0 +1,41 @@
+//! rsync's `--itemize-changes` output
+//!
+//! Each changed path gets a line starting with an update code such as
+//! `>f.st......` (a file received) or `<f+++++++++` (a file sent), and
+//! deletions are listed as `*deleting`. Entries whose attributes alone differ
+//! start with `.` and transfer nothing.
+
+/// What rsync's itemized output says it did, or would do in a dry run
+#[derive(Debug, Default)]
+pub(crate) struct Changes {
+    /// Paths transferred, directories with a trailing `/`
+    pub(crate) transferred: Vec<String>,
+    /// Paths deleted
+    pub(crate) deleted: Vec<String>,
+}
+
+impl Changes {
+    /// Collect the changes from rsync's output, skipping its other messages
+    /// and entries whose attributes alone differ
+    pub(crate) fn parse(stdout: &str) -> Self {
+        let mut changes = Self::default();
+        for line in stdout.lines() {
+            let Some((code, path)) = line.split_once(' ') else {
+                continue;
+            };
+            let mut chars = code.chars();
+            let kind = (chars.next(), chars.next());
+            let path = path.trim_start().to_string();
+            match kind {
+                (Some('*'), _) if code == "*deleting" => changes.deleted.push(path),
+                (Some('<' | '>' | 'c' | 'h'), Some('f' | 'd' | 'L' | 'D' | 'S'))
+                    if code.len() >= 9 =>
+                {
+                    changes.transferred.push(path)
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,585 @@
+//! Minimal editing of JSON-with-comments files
+//!
+//! Editor config files allow `//` and `/* */` comments and trailing commas,
+//! which `serde_json` rejects. Rather than parsing and reformatting a user's
+//! file, [`blank`] overwrites comments and trailing commas with spaces, which
+//! keeps every byte offset intact. The blanked text parses as plain JSON and
+//! is scanned for the spans of values, and edits are spliced into the
+//! original text at those spans, so comments and layout elsewhere survive.
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::ser::PrettyFormatter;
+use serde_json::Value;
+
+/// Byte range of a value in the text
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Span {
+    /// Offset of the first byte
+    pub(crate) start: usize,
+    /// Offset just past the last byte
+    pub(crate) end: usize,
+}
+
+/// A text replacement to apply
+struct Splice {
+    /// Range replaced (empty for an insertion)
+    span: Span,
+    /// Replacement text
+    text: String,
+}
+
+/// Replace comments and trailing commas with spaces, keeping newlines so
+/// offsets and line structure are unchanged
+pub(crate) fn blank(text: &str) -> String {
+    let mut bytes = text.as_bytes().to_vec();
+
+    let mut i = 0;
+    let mut in_string = false;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if in_string {
+            match b {
+                b'\\' => i += 1,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else if b == b'"' {
+            in_string = true;
+        } else if b == b'/' && bytes.get(i + 1) == Some(&b'/') {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                bytes[i] = b' ';
+                i += 1;
+            }
+            continue;
+        } else if b == b'/' && bytes.get(i + 1) == Some(&b'*') {
+            let end = text[i + 2..]
+                .find("*/")
+                .map_or(bytes.len(), |end| i + 2 + end + 2);
+            for byte in &mut bytes[i..end] {
+                if *byte != b'\n' {
+                    *byte = b' ';
+                }
+            }
+            i = end;
+            continue;
+        }
+        i += 1;
+    }
+
+    // Trailing commas, now that comments can't hide what follows them
+    let mut in_string = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if in_string => i += 1,
+            b'"' => in_string = !in_string,
+            b',' if !in_string => {
+                let next = bytes[i + 1..]
+                    .iter()
+                    .find(|b| !b.is_ascii_whitespace())
+                    .copied();
+                if matches!(next, Some(b']') | Some(b'}')) {
+                    bytes[i] = b' ';
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    // Only ASCII bytes outside strings were replaced, by ASCII spaces
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Parse blanked text (or a blanked slice) as JSON
+pub(crate) fn parse(blanked: &str) -> Result<Value> {
+    serde_json::from_str(blanked).map_err(|e| anyhow!("Invalid JSON: {}", e))
+}
+
+/// Offset of the first non-whitespace byte at or after `i`
+fn skip_whitespace(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// Offset just past the value starting at `start`
+fn value_end(bytes: &[u8], start: usize) -> Result<usize> {
+    match bytes.get(start) {
+        Some(b'"') => {
+            let mut i = start + 1;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'\\' => i += 1,
+                    b'"' => return Ok(i + 1),
+                    _ => {}
+                }
+                i += 1;
+            }
+            Err(anyhow!("Unterminated string"))
+        }
+        Some(b'{') | Some(b'[') => {
+            let mut depth = 0;
+            let mut i = start;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'"' => {
+                        i = value_end(bytes, i)?;
+                        continue;
+                    }
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Ok(i + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+            Err(anyhow!("Unterminated object or array"))
+        }
+        Some(_) => {
+            let mut i = start;
+            while i < bytes.len() && !matches!(bytes[i], b',' | b'}' | b']') {
+                i += 1;
+            }
+            // Blanked comments before the delimiter aren't part of the value
+            while i > start && bytes[i - 1].is_ascii_whitespace() {
+                i -= 1;
+            }
+            Ok(i)
+        }
+        None => Err(anyhow!("Unexpected end of input")),
+    }
+}
+
+/// Spans of the elements of the array at `span`
+pub(crate) fn array_elements(blanked: &str, span: Span) -> Result<Vec<Span>> {
+    let bytes = blanked.as_bytes();
+    let mut elements = Vec::new();
+    let mut i = skip_whitespace(bytes, span.start + 1);
+    while i < span.end - 1 {
+        let end = value_end(bytes, i)?;
+        elements.push(Span { start: i, end });
+        i = skip_whitespace(bytes, end);
+        if bytes.get(i) == Some(&b',') {
+            i = skip_whitespace(bytes, i + 1);
+        }
+    }
+    Ok(elements)
+}
+
+/// Span of the value of `key` in the object at `span`, if it has one
+pub(crate) fn object_member(blanked: &str, span: Span, key: &str) -> Result<Option<Span>> {
+    let bytes = blanked.as_bytes();
+    let mut i = skip_whitespace(bytes, span.start + 1);
+    while i < span.end - 1 {
+        let key_end = value_end(bytes, i)?;
+        let name: String = serde_json::from_str(&blanked[i..key_end])?;
+        i = skip_whitespace(bytes, key_end);
+        if bytes.get(i) != Some(&b':') {
+            return Err(anyhow!("Expected ':' after object key"));
+        }
+        let value_start = skip_whitespace(bytes, i + 1);
+        let end = value_end(bytes, value_start)?;
+        if name == key {
+            return Ok(Some(Span {
+                start: value_start,
+                end,
+            }));
+        }
+        i = skip_whitespace(bytes, end);
+        if bytes.get(i) == Some(&b',') {
+            i = skip_whitespace(bytes, i + 1);
+        }
+    }
+    Ok(None)
+}
+
+/// Span of the top-level value
+pub(crate) fn root(blanked: &str) -> Result<Span> {
+    let bytes = blanked.as_bytes();
+    let start = skip_whitespace(bytes, 0);
+    Ok(Span {
+        start,
+        end: value_end(bytes, start)?,
+    })
+}
+
+/// Indentation unit used by the file: a tab, or the smallest space indent
+pub(crate) fn indent_unit(text: &str) -> String {
+    let indents = text.lines().map(|line| {
+        let trimmed = line.trim_start();
+        &line[..line.len() - trimmed.len()]
+    });
+    let mut spaces = None;
+    for indent in indents.filter(|i| !i.is_empty()) {
+        if indent.starts_with('\t') {
+            return "\t".to_string();
+        }
+        spaces = Some(spaces.map_or(indent.len(), |s: usize| s.min(indent.len())));
+    }
+    " ".repeat(spaces.unwrap_or(4))
+}
+
+/// Whitespace before the content of the line containing `offset`
+fn line_indent(text: &str, offset: usize) -> &str {
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = &text[line_start..];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Where to append after the value ending at `end`, and whether a comma
+/// already follows it
+///
+/// A comma and comment on the rest of the value's line belong to it, so new
+/// entries go on the next line instead of between them and the value.
+fn append_point(text: &str, blanked: &str, end: usize) -> (usize, bool) {
+    let comma = text[end..].trim_start().starts_with(',');
+    let Some(newline) = blanked[end..].find('\n').map(|i| end + i) else {
+        return (end, comma);
+    };
+    let rest = &text[end..newline];
+    let open_comment = rest
+        .rfind("/*")
+        .is_some_and(|open| !rest[open..].contains("*/"));
+    if blanked[end..newline].trim().is_empty() && !open_comment {
+        (newline, comma)
+    } else {
+        (end, comma)
+    }
+}
+
+/// Splices appending `joined` after the value ending at `end`, with `indent`
+/// before it
+fn append_after(text: &str, blanked: &str, end: usize, indent: &str, joined: &str) -> Vec<Splice> {
+    let at = |offset: usize, text: String| Splice {
+        span: Span {
+            start: offset,
+            end: offset,
+        },
+        text,
+    };
+    match append_point(text, blanked, end) {
+        (offset, _) if offset == end => {
+            // A trailing comma stays after the new entries
+            vec![at(end, format!(",\n{}{}", indent, joined))]
+        }
+        (offset, true) => vec![at(offset, format!("\n{}{},", indent, joined))],
+        (offset, false) => vec![
+            at(end, ",".to_string()),
+            at(offset, format!("\n{}{}", indent, joined)),
+        ],
+    }
+}
+
+/// Apply splices to the text
+fn apply(text: &str, mut splices: Vec<Splice>) -> String {
+    splices.sort_by_key(|s| s.span.start);
+    let mut result = text.to_string();
+    for splice in splices.iter().rev() {
+        result.replace_range(splice.span.start..splice.span.end, &splice.text);
+    }
+    result
+}
+
+/// Offset past the comments inside an empty object or array at `span`
+fn comments_end(text: &str, span: Span) -> usize {
+    span.start + 1 + text[span.start + 1..span.end - 1].trim_end().len()
+}
+
+/// Render a value as pretty JSON indented to sit at `indent`
+pub(crate) fn render(value: &impl Serialize, unit: &str, indent: &str) -> Result<String> {
+    let mut out = Vec::new();
+    let formatter = PrettyFormatter::with_indent(unit.as_bytes());
+    let mut serializer = serde_json::Serializer::with_formatter(&mut out, formatter);
+    value.serialize(&mut serializer)?;
+    let rendered = String::from_utf8_lossy(&out).into_owned();
+    Ok(rendered.replace('\n', &format!("\n{}", indent)))
+}
+
+/// Editor for the elements of one array in a JSON-with-comments file
+pub(crate) struct ArrayEdit<'a> {
+    /// Original text
+    text: &'a str,
+    /// Text with comments and trailing commas blanked
+    blanked: String,
+    /// Span of the array
+    array: Span,
+    /// Spans of the existing elements
+    elements: Vec<Span>,
+    /// Indentation unit of the file
+    unit: String,
+    /// Pending replacements of existing elements
+    replacements: Vec<Splice>,
+    /// Rendered elements to append
+    appended: Vec<String>,
+}
+
+impl<'a> ArrayEdit<'a> {
+    /// Start editing the array at `array` in `text`
+    pub(crate) fn new(text: &'a str, blanked: &str, array: Span, unit: &str) -> Result<Self> {
+        Ok(Self {
+            text,
+            blanked: blanked.to_string(),
+            array,
+            elements: array_elements(blanked, array)?,
+            unit: unit.to_string(),
+            replacements: Vec::new(),
+            appended: Vec::new(),
+        })
+    }
+
+    /// The existing elements, parsed from the blanked text
+    pub(crate) fn values(&self, blanked: &str) -> Vec<Value> {
+        self.elements
+            .iter()
+            .map(|span| parse(&blanked[span.start..span.end]).unwrap_or(Value::Null))
+            .collect()
+    }
+
+    /// Indentation of the array's elements
+    fn element_indent(&self) -> String {
+        match self.elements.first() {
+            Some(first) => line_indent(self.text, first.start).to_string(),
+            None => format!("{}{}", line_indent(self.text, self.array.start), self.unit),
+        }
+    }
+
+    /// Replace the existing element at `index`
+    pub(crate) fn replace(&mut self, index: usize, value: &impl Serialize) -> Result<()> {
+        let span = self.elements[index];
+        let indent = line_indent(self.text, span.start).to_string();
+        self.replacements.push(Splice {
+            span,
+            text: render(value, &self.unit, &indent)?,
+        });
+        Ok(())
+    }
+
+    /// Append a new element
+    pub(crate) fn push(&mut self, value: &impl Serialize) -> Result<()> {
+        let indent = self.element_indent();
+        self.appended.push(render(value, &self.unit, &indent)?);
+        Ok(())
+    }
+
+    /// Apply the edits, returning the new text
+    pub(crate) fn finish(self) -> String {
+        let indent = self.element_indent();
+        let mut splices = self.replacements;
+
+        if !self.appended.is_empty() {
+            let joined = self.appended.join(&format!(",\n{}", indent));
+            let inline = !self.text[self.array.start..self.array.end].contains('\n')
+                && !self.appended.iter().any(|value| value.contains('\n'));
+            match self.elements.last() {
+                Some(last) if inline => splices.push(Splice {
+                    span: Span {
+                        start: last.end,
+                        end: last.end,
+                    },
+                    text: format!(", {}", self.appended.join(", ")),
+                }),
+                Some(last) => {
+                    splices.extend(append_after(
+                        self.text,
+                        &self.blanked,
+                        last.end,
+                        &indent,
+                        &joined,
+                    ));
+                }
+                None => splices.push(Splice {
+                    // Everything between the brackets is whitespace or comments
+                    span: Span {
+                        start: comments_end(self.text, self.array),
+                        end: self.array.end - 1,
+                    },
+                    text: format!(
+                        "\n{}{}\n{}",
+                        indent,
+                        joined,
+                        line_indent(self.text, self.array.start)
+                    ),
+                }),
+            }
+        }
+
+        apply(self.text, splices)
+    }
+}
+
+/// Insert `"key": value` as the last member of the object at `span`
+pub(crate) fn insert_member(
+    text: &str,
+    blanked: &str,
+    span: Span,
+    key: &str,
+    value: &impl Serialize,
+) -> Result<String> {
+    let unit = indent_unit(text);
+    let outer = line_indent(text, span.start).to_string();
+    let indent = format!("{}{}", outer, unit);
+    let member = format!(
+        "{}: {}",
+        serde_json::to_string(key)?,
+        render(value, &unit, &indent)?
+    );
+
+    let bytes = blanked.as_bytes();
+    let close = span.end - 1;
+    let last = bytes[span.start + 1..close]
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map(|i| span.start + 1 + i);
+
+    let splices = match last {
+        Some(last) => append_after(text, blanked, last + 1, &indent, &member),
+        None => vec![Splice {
+            span: Span {
+                start: comments_end(text, span),
+                end: close,
+            },
+            text: format!("\n{}{}\n{}", indent, member, outer),
+        }],
+    };
+    Ok(apply(text, splices))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Append values to the array at the top level of `text`
+    fn append(text: &str, values: &[Value]) -> String {
+        let blanked = blank(text);
+        let mut edit = ArrayEdit::new(text, &blanked, root(&blanked).unwrap(), "  ").unwrap();
+        for value in values {
+            edit.push(value).unwrap();
+        }
+        edit.finish()
+    }
+
+    /// Comments and trailing commas become spaces at the same offsets
+    #[test]
+    fn blank_keeps_offsets() {
+        let text = "{\n  \"a\": 1, // one\n  /* two\n  lines */ \"b\": [2,],\n}";
+        let blanked = blank(text);
+        assert_eq!(blanked.len(), text.len());
+        assert_eq!(blanked.lines().count(), text.lines().count());
+        assert_eq!(
+            parse(&blanked).unwrap(),
+            serde_json::json!({ "a": 1, "b": [2] })
+        );
+    }
+
+    /// Comment markers and commas inside strings are left alone
+    #[test]
+    fn blank_skips_strings() {
+        let text = r#"{ "url": "https://example.com/*x*/", "s": "a,]", "q": "\"//" }"#;
+        assert_eq!(blank(text), text);
+    }
+
+    /// Members are found past comments, with spans into the original text
+    #[test]
+    fn member_spans() {
+        let text = "{ /* \"b\": 0 */ \"a\": [1, 2], // \"b\": 0\n \"b\": {\"c\": true}, }";
+        let blanked = blank(text);
+        let top = root(&blanked).unwrap();
+        let b = object_member(&blanked, top, "b").unwrap().unwrap();
+        assert_eq!(&text[b.start..b.end], "{\"c\": true}");
+        let a = object_member(&blanked, top, "a").unwrap().unwrap();
+        let elements = array_elements(&blanked, a).unwrap();
+        let found: Vec<&str> = elements.iter().map(|s| &text[s.start..s.end]).collect();
+        assert_eq!(found, ["1", "2"]);
+        assert!(object_member(&blanked, top, "c").unwrap().is_none());
+    }
+
+    /// Appending after a trailing comma and comment keeps both with their
+    /// element and the trailing comma style
+    #[test]
+    fn append_after_comment() {
+        let text = "[\n  1, // one\n]\n";
+        assert_eq!(
+            append(text, &[2.into(), 3.into()]),
+            "[\n  1, // one\n  2,\n  3,\n]\n"
+        );
+    }
+
+    /// Without a trailing comma the comma goes right after the element
+    #[test]
+    fn append_without_trailing_comma() {
+        let text = "[\n  1 // one\n]\n";
+        assert_eq!(append(text, &[2.into()]), "[\n  1, // one\n  2\n]\n");
+    }
+
+    /// A block comment continuing on the next line isn't split
+    #[test]
+    fn append_before_open_block_comment() {
+        let text = "[\n  1 /* one\n  more */\n]\n";
+        assert_eq!(
+            append(text, &[2.into()]),
+            "[\n  1,\n  2 /* one\n  more */\n]\n"
+        );
+    }
+
+    /// One-line arrays stay on one line; empty ones keep their comments
+    #[test]
+    fn append_inline() {
+        assert_eq!(append("[1, 2]", &[3.into()]), "[1, 2, 3]");
+        assert_eq!(
+            append("[ /* none */ ]", &[1.into()]),
+            "[ /* none */\n  1\n]"
+        );
+    }
+
+    /// Replacing an element keeps the comments around it
+    #[test]
+    fn replace_element() {
+        let text = "[\n  // first\n  {\"a\": 1}, /* second */ 2,\n]";
+        let blanked = blank(text);
+        let mut edit = ArrayEdit::new(text, &blanked, root(&blanked).unwrap(), "  ").unwrap();
+        assert_eq!(
+            edit.values(&blanked),
+            [serde_json::json!({"a": 1}), 2.into()]
+        );
+        edit.replace(0, &serde_json::json!({"a": 2})).unwrap();
+        assert_eq!(
+            edit.finish(),
+            "[\n  // first\n  {\n    \"a\": 2\n  }, /* second */ 2,\n]"
+        );
+    }
+
+    /// A new member follows a commented last member or an empty object's
+    /// comment
+    #[test]
+    fn insert_members() {
+        let insert = |text: &str| {
+            let blanked = blank(text);
+            insert_member(text, &blanked, root(&blanked).unwrap(), "b", &2).unwrap()
+        };
+        assert_eq!(
+            insert("{\n    \"a\": 1, // one\n}"),
+            "{\n    \"a\": 1, // one\n    \"b\": 2,\n}"
+        );
+        assert_eq!(
+            insert("{\n    \"a\": 1\n}"),
+            "{\n    \"a\": 1,\n    \"b\": 2\n}"
+        );
+        assert_eq!(insert("{ /* empty */ }"), "{ /* empty */\n    \"b\": 2\n}");
+        assert_eq!(insert("{}"), "{\n    \"b\": 2\n}");
+    }
+
+    /// The indentation unit is a tab or the smallest space indent
+    #[test]
+    fn indent_units() {
+        assert_eq!(indent_unit("{\n\t\"a\": 1\n}"), "\t");
+        assert_eq!(indent_unit("{\n  \"a\": {\n    \"b\": 1\n  }\n}"), "  ");
+        assert_eq!(indent_unit("{}"), "    ");
+    }
+}
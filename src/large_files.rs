@@ -0,0 +1,192 @@
+//! Files too large to sync by accident
+//!
+//! `max_file_size` (e.g. `100M`) flags files above that size before each
+//! sync, such as a trace or core dump left in the tree. The candidates are
+//! only stat'ed: the git file list of a git-aware sync, or else a walk of the
+//! project that skips excluded files and directories. `large_file_action`
+//! picks what happens to them:
+//!
+//! - `warn` (the default) lists them with their sizes and syncs them anyway.
+//! - `skip` lists them and leaves them out with rsync's `--max-size`, which
+//!   also applies to files the walk didn't see.
+//! - `fail` lists them and stops before anything is sent.
+
+use anyhow::{anyhow, Result};
+use std::fs;
+use std::path::Path;
+
+use crate::gc::{format_size, parse_size};
+use crate::patterns::ExcludeSet;
+use crate::{sync_excludes, Config, OutputLevel};
+
+/// What a sync does with files over `max_file_size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Sync them after a warning
+    Warn,
+    /// Leave them out
+    Skip,
+    /// Stop the sync
+    Fail,
+}
+
+/// Default `large_file_action`
+pub(crate) fn default_action() -> String {
+    "warn".to_string()
+}
+
+impl Action {
+    /// Parse a configured `large_file_action`
+    pub(crate) fn parse(name: &str) -> Result<Self> {
+        match name {
+            "warn" => Ok(Self::Warn),
+            "skip" => Ok(Self::Skip),
+            "fail" => Ok(Self::Fail),
+            other => Err(anyhow!(
+                "Invalid large_file_action: {} (expected warn, skip or fail)",
+                other
+            )),
+        }
+    }
+}
+
+/// Check `max_file_size` and `large_file_action` when the config is loaded
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    if let Some(size) = &config.max_file_size {
+        parse_size(size)
+            .map_err(|_| anyhow!("Invalid max_file_size: {} (expected e.g. 500M or 2G)", size))?;
+    }
+    Action::parse(&config.large_file_action)?;
+    Ok(())
+}
+
+/// The configured limit in KiB and the action for files above it, if
+/// `max_file_size` is set
+pub(crate) fn limit(config: &Config) -> Result<Option<(u64, Action)>> {
+    let Some(size) = &config.max_file_size else {
+        return Ok(None);
+    };
+    Ok(Some((
+        parse_size(size)?,
+        Action::parse(&config.large_file_action)?,
+    )))
+}
+
+/// The files over `limit_kib` that the sync would send, largest first, with
+/// their sizes in bytes
+///
+/// `files` is the git file list, relative to the project directory; without
+/// one the project is walked.
+pub(crate) fn find(
+    project_dir: &Path,
+    config: &Config,
+    files: Option<&[String]>,
+    limit_kib: u64,
+) -> Vec<(String, u64)> {
+    let limit = limit_kib.saturating_mul(1024);
+    let mut large: Vec<(String, u64)> = match files {
+        Some(files) => files
+            .iter()
+            .filter_map(|file| {
+                let meta = fs::symlink_metadata(project_dir.join(file)).ok()?;
+                (meta.is_file() && meta.len() > limit).then(|| (file.clone(), meta.len()))
+            })
+            .collect(),
+        None => {
+            let mut large = Vec::new();
+            walk(
+                project_dir,
+                "",
+                &sync_excludes(config, true),
+                limit,
+                &mut large,
+            );
+            large
+        }
+    };
+    large.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    large
+}
+
+/// Recursively collect the non-excluded files over `limit` bytes
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    excludes: &ExcludeSet,
+    limit: u64,
+    large: &mut Vec<(String, u64)>,
+) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let rel = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if excludes.excludes_entry(&rel, file_type.is_dir()) {
+            continue;
+        }
+        if file_type.is_dir() {
+            walk(&entry.path(), &rel, excludes, limit, large);
+        } else if file_type.is_file() {
+            match entry.metadata() {
+                Ok(meta) if meta.len() > limit => large.push((rel, meta.len())),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// List the files found over the limit, failing under `fail`
+pub(crate) fn report(
+    config: &Config,
+    limit_kib: u64,
+    action: Action,
+    large: &[(String, u64)],
+) -> Result<()> {
+    let listing: String = large
+        .iter()
+        .map(|(path, bytes)| format!("\n     {:>9}  {}", format_size(bytes / 1024), path))
+        .collect();
+    let limit = format_size(limit_kib);
+    match action {
+        Action::Warn => eprintln!(
+            "   ⚠ Warning: Syncing {} file(s) over max_file_size ({}); add them to \
+             exclude_patterns or set large_file_action: skip{}",
+            large.len(),
+            limit,
+            listing
+        ),
+        Action::Skip => {
+            if !matches!(config.output_level(), OutputLevel::Quiet) {
+                println!(
+                    "   ℹ Skipping {} file(s) over max_file_size ({}){}",
+                    large.len(),
+                    limit,
+                    listing
+                );
+            }
+        }
+        Action::Fail => {
+            return Err(anyhow!(
+                "{} file(s) over max_file_size ({}); add them to exclude_patterns or raise the \
+                 limit{}",
+                large.len(),
+                limit,
+                listing
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// The rsync argument leaving out files over the limit under `skip`
+pub(crate) fn rsync_arg(limit_kib: u64, action: Action) -> Option<String> {
+    (action == Action::Skip).then(|| format!("--max-size={}K", limit_kib))
+}
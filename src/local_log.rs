@@ -0,0 +1,194 @@
+//! Build logs kept locally
+//!
+//! Next to the remote logs of [`crate::remote_log`], the output of a build is
+//! written to `<timestamp>.log` in a per-project directory of the state
+//! directory while it is forwarded to the terminal, so it can be read without
+//! a connection, and for hosts where `keep_remote_logs` is `0`. Keeping them
+//! out of the project keeps them out of `git status`. Both are
+//! named after the UTC start of the build, so the two logs of one build sort
+//! next to each other. Only the newest `keep_local_logs` files are kept; `0`
+//! turns the local logs off.
+//!
+//! Builds whose output lines are prefixed (parallel batches, matrix
+//! platforms) and detached resilient builds are only logged remotely.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state;
+
+/// Default number of logs kept locally
+pub(crate) fn default_keep() -> usize {
+    10
+}
+
+/// Directory of the local logs of the project at `project_dir`
+pub(crate) fn dir(project_dir: &Path) -> PathBuf {
+    state::project_state_path(project_dir, "logs")
+}
+
+/// A log file kept locally
+pub(crate) struct LocalLog {
+    /// Name without the `.log` extension
+    pub(crate) name: String,
+    /// Size in bytes
+    pub(crate) size: u64,
+    /// Seconds since it was last written, if known
+    pub(crate) age: Option<u64>,
+}
+
+/// The logs in `dir`, newest first
+pub(crate) fn list(dir: &Path) -> Vec<LocalLog> {
+    let now = SystemTime::now();
+    let mut logs: Vec<LocalLog> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let name = file_name.strip_suffix(".log")?.to_string();
+            let meta = entry.metadata().ok().filter(|meta| meta.is_file())?;
+            let age = meta
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .map(|age| age.as_secs());
+            Some(LocalLog {
+                name,
+                size: meta.len(),
+                age,
+            })
+        })
+        .collect();
+    logs.sort_by(|a, b| b.name.cmp(&a.name));
+    logs
+}
+
+/// Create the log of a new build in `dir`, first removing the oldest logs so
+/// that `keep` remain with it
+pub(crate) fn create(dir: &Path, keep: usize) -> Result<fs::File> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    for old in list(dir).iter().skip(keep.saturating_sub(1)) {
+        let _ = fs::remove_file(dir.join(format!("{}.log", old.name)));
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let path = dir.join(format!("{}.log", log_name(now, std::process::id())));
+    fs::File::create(&path).with_context(|| format!("Failed to create {}", path.display()))
+}
+
+/// Copy what `pipe` delivers to `out` and to `log` as it arrives
+///
+/// Chunks rather than lines are copied, so progress lines redrawn with `\r`
+/// still show up as they change.
+pub(crate) fn tee(pipe: Option<impl Read>, mut out: impl Write, log: &Mutex<fs::File>) {
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    let mut buffer = [0; 8192];
+    let mut logging = true;
+    while let Ok(read) = pipe.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        let _ = out.write_all(&buffer[..read]);
+        let _ = out.flush();
+        if logging {
+            // A full disk stops the log, not the build
+            logging = log
+                .lock()
+                .is_ok_and(|mut log| log.write_all(&buffer[..read]).is_ok());
+        }
+    }
+}
+
+/// Name of a log started at `unix_secs` by process `pid`, in the format of
+/// the remote logs: `YYYYMMDD-HHMMSS-PID` in UTC
+fn log_name(unix_secs: u64, pid: u32) -> String {
+    let days = unix_secs / 86_400;
+    let secs = unix_secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        pid
+    )
+}
+
+/// Year, month and day of the date `days` days after 1970-01-01
+///
+/// Howard Hinnant's `civil_from_days`, for dates after the epoch only.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Names match `date -u +%Y%m%d-%H%M%S` followed by the process ID
+    #[test]
+    fn log_names() {
+        assert_eq!(log_name(0, 7), "19700101-000000-7");
+        assert_eq!(log_name(951_782_400, 1), "20000229-000000-1");
+        assert_eq!(log_name(1_790_000_000, 4242), "20260921-141320-4242");
+        assert_eq!(log_name(4_102_444_799, 9), "20991231-235959-9");
+    }
+
+    /// New logs rotate out the oldest, and the output is copied unchanged
+    #[test]
+    fn create_rotates_and_tee_copies() {
+        let dir = std::env::temp_dir().join(format!(
+            "remotebuild-test-local-logs-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "20260101-000000-1",
+            "20260102-000000-1",
+            "20260103-000000-1",
+        ] {
+            fs::write(dir.join(format!("{}.log", name)), name).unwrap();
+        }
+        fs::write(dir.join("notes.txt"), "not a log").unwrap();
+
+        let log = Mutex::new(create(&dir, 3).unwrap());
+        let mut terminal = Vec::new();
+        tee(
+            Some(&b"building\r50%\r100%\ndone\n"[..]),
+            &mut terminal,
+            &log,
+        );
+        assert_eq!(terminal, b"building\r50%\r100%\ndone\n");
+
+        let names: Vec<String> = list(&dir).into_iter().map(|log| log.name).collect();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[1..], ["20260103-000000-1", "20260102-000000-1"]);
+        let newest = fs::read_to_string(dir.join(format!("{}.log", names[0]))).unwrap();
+        assert_eq!(newest, "building\r50%\r100%\ndone\n");
+        assert!(dir.join("notes.txt").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
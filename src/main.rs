@@ -1,56 +1,639 @@
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use shell_escape::escape;
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
 use std::env;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
+
+mod abort;
+mod artifact;
+mod artifact_glob;
+mod auth;
+mod batch;
+mod build_command;
+mod bwlimit;
+mod cache_archive;
+mod cancel;
+mod check;
+mod compression;
+mod config_format;
+mod container;
+mod detect;
+mod disconnect;
+mod dry_run;
+mod eager;
+mod editor;
+mod estimate;
+mod extends;
+mod gc;
+mod gitignore;
+mod global_config;
+mod history;
+mod hooks;
+mod identity;
+mod init;
+mod interpolate;
+mod itemize;
+mod jsonc;
+mod large_files;
+mod local_log;
+mod manifest;
+mod matrix;
+mod nix;
+mod patterns;
+mod permissions;
+mod profiles;
+mod progress;
+mod remote_log;
+mod remote_path;
+mod remote_shell;
+mod resilient;
+mod resumable;
+mod rewrite;
+mod rsync_version;
+mod selftest;
+mod setup;
+mod shared;
+mod snapshot;
+mod ssh_master;
+mod ssh_options;
+mod state;
+mod stats;
+mod supersede;
+mod sync_back;
+mod sync_delete;
+mod targets;
+mod test_runner;
+mod transport;
+mod unknown_keys;
+mod verify;
+mod watch;
+mod workspace;
+
+use artifact::Artifact;
+use build_command::BuildCommand;
+use config_format::ConfigFormat;
+use hooks::{Hook, RunReport};
+use manifest::Manifest;
+use patterns::ExcludeSet;
+use progress::Progress;
+use remote_path::{RemotePath, RSYNC_OLD_ARGS};
+use stats::Stats;
+use supersede::{CancelToken, Cancellation, Superseded};
+use sync_delete::DeleteMode;
+
+/// Metadata directory remotebuild keeps inside the remote path
+const REMOTE_META_DIR: &str = ".remotebuild";
+
+/// Directory in the local artifact destination keeping partially downloaded
+/// files, so an interrupted download resumes where it stopped
+const PARTIAL_DIR: &str = ".remotebuild-partial";
+
+/// Patterns that are always excluded from the sync
+///
+/// Excluding the metadata directory and the project marker also protects
+/// them from `--delete`.
+const ALWAYS_EXCLUDED: &[&str] = &[
+    ".git",
+    ".remotebuild/",
+    ".remotebuild-partial/",
+    "/.remotebuild-project",
+];
+
+/// Default of `default_excludes`, excluded after [`ALWAYS_EXCLUDED`] unless
+/// `use_default_excludes` is off
+const DEFAULT_EXCLUDES: &[&str] = &[
+    "*.nds",
+    "*.elf",
+    "build/",
+    ".ninja_*",
+    "compile_commands.json",
+];
+
+/// Artifact downloads running at once over the shared connection
+const ARTIFACT_JOBS: usize = 4;
+
+/// Line printed by the remote `mkdir` step when the project directory didn't
+/// exist yet
+const CREATED_MARKER: &str = "remotebuild-created";
+
+/// Longest control socket path used as is: the 104 bytes macOS allows,
+/// less ssh's temporary suffix and the terminating NUL
+const MAX_CONTROL_PATH: usize = 104 - 17 - 1;
+
+/// `--config` value reading the configuration from stdin
+const STDIN_CONFIG: &str = "-";
 
 /// Remote build configuration file
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Config {
     /// SSH host to connect to (e.g., "user@host" or just "host")
+    #[serde(default)]
     host: String,
 
-    /// Remote path where the project will be synced and built
-    #[serde(default = "default_remote_path")]
+    /// Remote path where the project will be synced and built (`{user}`
+    /// expands to the local user name); defaults to a directory of its own
+    /// per project, see [`default_project_remote_path`]
+    #[serde(default)]
     remote_path: String,
 
-    /// Build command to run on the remote server
-    build_command: String,
+    /// Remote compiler cache directory shared by all users of the host
+    #[serde(default)]
+    cache_path: Option<String>,
 
-    /// List of artifact patterns to copy back (relative to project root)
-    artifacts: Vec<String>,
+    /// Build command to run on the remote server, or a list of steps
+    #[serde(default)]
+    build_command: BuildCommand,
+
+    /// Environment variables exported before the build command
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+
+    /// Names of local environment variables exported to the build with
+    /// their local values (`env` entries take precedence)
+    #[serde(default)]
+    pass_env: Vec<String>,
+
+    /// List of artifact patterns to copy back (relative to project root, or
+    /// absolute remote paths when starting with `/`), each optionally with a
+    /// local destination
+    #[serde(default)]
+    artifacts: Vec<Artifact>,
+
+    /// Local directory artifacts are copied into, relative to the project
+    /// directory unless absolute (default: the project directory)
+    #[serde(default)]
+    artifact_dir: Option<String>,
+
+    /// Download artifacts while the build still runs, as soon as they stop
+    /// changing
+    #[serde(default)]
+    eager_artifacts: bool,
+
+    /// Replace the remote project path with the local one in downloaded text
+    /// artifacts such as coverage data and test reports
+    #[serde(default)]
+    rewrite_paths: bool,
+
+    /// Generated files copied back into the project after the build, such as
+    /// `compile_commands.json`, and left out of the sync
+    #[serde(default)]
+    sync_back: Vec<String>,
 
     /// Files/directories to exclude from sync (gitignore-style patterns)
     #[serde(default)]
     exclude_patterns: Vec<String>,
 
+    /// Files/directories synced even when excluded (gitignore-style
+    /// patterns)
+    #[serde(default)]
+    include_patterns: Vec<String>,
+
+    /// What a sync deletes on the remote: `always`, `never` or
+    /// `excluded-too`
+    #[serde(default = "sync_delete::default_mode")]
+    sync_delete: String,
+
+    /// Built-in excludes applied before everything else
+    #[serde(default = "default_excludes")]
+    default_excludes: Vec<String>,
+
+    /// Whether `default_excludes` are applied
+    #[serde(default = "default_true")]
+    use_default_excludes: bool,
+
+    /// Whether to exclude the output directories of detected build systems
+    /// (e.g. `target/` next to `Cargo.toml`)
+    #[serde(default = "default_true")]
+    auto_excludes: bool,
+
+    /// Directories synced even when detected as build output
+    #[serde(default)]
+    force_include: Vec<String>,
+
+    /// Excludes detected at startup, applied after the built-in ones
+    #[serde(skip)]
+    detected_excludes: Vec<detect::Detected>,
+
+    /// Whether the patterns of the project's `.gitignore` files are excluded
+    /// from syncs not using git's file list
+    #[serde(default = "default_true")]
+    respect_gitignore: bool,
+
+    /// Excludes read from `.gitignore` files at startup, applied after the
+    /// detected ones
+    #[serde(skip)]
+    gitignore_excludes: Vec<String>,
+
+    /// Prefix for the lines of build output, set when several builds share
+    /// the terminal
+    #[serde(skip)]
+    output_prefix: Option<String>,
+
+    /// Host from the config file, when `--host` replaced it
+    #[serde(skip)]
+    replaced_host: Option<String>,
+
     /// Whether to use git to detect changed files for faster sync
     #[serde(default = "default_true")]
     git_aware: bool,
 
+    /// Whether the git file list includes the files of submodules
+    #[serde(default = "default_true")]
+    sync_submodules: bool,
+
+    /// Whether to detect changed files with a local content-hash manifest
+    /// when no git file list is available
+    #[serde(default)]
+    manifest_sync: bool,
+
+    /// Transfer compression: `auto` to choose from the measured link speed,
+    /// `off`, or an rsync compression level from 1 to 9
+    #[serde(default = "compression::default_mode")]
+    compression: String,
+
+    /// Thresholds used by `compression: auto`
+    #[serde(default)]
+    compression_auto: compression::AutoThresholds,
+
+    /// Local command printing the SSH password, for hosts without key
+    /// authentication (the password itself is never stored)
+    #[serde(default)]
+    password_command: Option<String>,
+
+    /// SSH port of the host, instead of the default or `~/.ssh/config`
+    #[serde(default)]
+    ssh_port: Option<u16>,
+
+    /// Private key to authenticate with, offered alone
+    #[serde(default)]
+    ssh_identity: Option<String>,
+
+    /// How long the SSH control master stays after the last command, in
+    /// ssh's time format; `0` or `no` closes it when remotebuild exits
+    #[serde(default = "ssh_master::default_persist")]
+    connection_persist: String,
+
+    /// Seconds a newly started control master may take to answer
+    #[serde(default = "ssh_master::default_ready_timeout")]
+    ssh_ready_timeout: u64,
+
+    /// Bastion to reach the host through (`ProxyJump`), e.g.
+    /// `user@bastion` or `bastion1,bastion2`
+    #[serde(default)]
+    jump_host: Option<String>,
+
+    /// Extra ssh `-o` options, e.g. `StrictHostKeyChecking=accept-new`
+    #[serde(default)]
+    ssh_options: Vec<String>,
+
+    /// Seconds between ssh keepalives on an idle connection, 0 for none
+    #[serde(default = "ssh_options::default_keepalive_interval")]
+    keepalive_interval: u64,
+
+    /// Keep the build running through dropped connections: keepalives,
+    /// retried transfers and a detached build whose output is resumed
+    #[serde(default)]
+    resilient: bool,
+
+    /// Keep partially transferred files and retry syncs and artifact
+    /// downloads cut off by the connection
+    #[serde(default = "default_true")]
+    resumable: bool,
+
+    /// Retries of an interrupted sync or artifact download
+    #[serde(default = "resumable::default_retries")]
+    transfer_retries: u32,
+
+    /// Retries of a step that lost the SSH connection, after reconnecting
+    #[serde(default = "resilient::default_connection_retries")]
+    connection_retries: u32,
+
+    /// Seconds before the first retry, doubled for each further one
+    #[serde(default = "resumable::default_backoff")]
+    retry_backoff: u64,
+
+    /// Remote command run in the project directory once per host and path,
+    /// and again whenever it changes, before the build
+    #[serde(default)]
+    setup_command: Option<String>,
+
+    /// Run `setup_command` even if it already completed, set by
+    /// `--rerun-setup`
+    #[serde(skip)]
+    rerun_setup: bool,
+
+    /// Only show what the build would sync and run, set by `--dry-run`
+    #[serde(skip)]
+    dry_run: bool,
+
+    /// Claim a remote path whose marker names another project, set by
+    /// `--take-over`
+    #[serde(skip)]
+    take_over: bool,
+
+    /// Skip the artifact download, set by `--no-artifacts`
+    #[serde(skip)]
+    no_artifacts: bool,
+
+    /// Only download the artifacts of the last build, set by
+    /// `--artifacts-only`
+    #[serde(skip)]
+    artifacts_only: bool,
+
+    /// Skip the sync and setup, set for workspace components, whose tree the
+    /// workspace synced and set up before building them
+    #[serde(skip)]
+    presynced: bool,
+
+    /// Commands whose output is recorded in the environment snapshot taken
+    /// after each build (e.g. `cc --version`)
+    #[serde(default)]
+    snapshot_commands: Vec<String>,
+
+    /// Remote environment variables recorded in the snapshot
+    #[serde(default)]
+    snapshot_env: Vec<String>,
+
+    /// Shell running remote commands: `auto` detects whether the login shell
+    /// is POSIX, `login` trusts it, anything else (`bash`, `/bin/sh`) always
+    /// runs commands with `<shell> -c`
+    #[serde(default = "remote_shell::default_remote_shell")]
+    remote_shell: String,
+
+    /// umask of the remote tree, e.g. `022`: applied to uploads with rsync
+    /// `--chmod` and to the build
+    #[serde(default)]
+    remote_umask: Option<String>,
+
+    /// Mode of `remote_path` itself, e.g. `2775`
+    #[serde(default)]
+    remote_dir_mode: Option<String>,
+
+    /// Extra rsync `--chmod` rules for uploads, e.g. `Fu+x` or `D2775`
+    #[serde(default)]
+    chmod: Option<String>,
+
+    /// Whether tracked files get the execute bits git records on the remote,
+    /// whatever their mode on disk
+    #[serde(default)]
+    fix_permissions: bool,
+
+    /// How files travel: `auto` (rsync, or tar where the remote has no
+    /// rsync), `rsync` or `tar`
+    #[serde(default = "transport::default_transport")]
+    transport: String,
+
+    /// Local rsync to run, e.g. a Homebrew one on macOS (default: `rsync`
+    /// from PATH)
+    #[serde(default = "rsync_version::default_rsync_binary")]
+    rsync_binary: String,
+
+    /// Throughput limit of rsync transfers, e.g. `500k` or `2m` (KiB/s)
+    #[serde(default)]
+    bwlimit: Option<String>,
+
+    /// How rsync finds changed files: `fast` compares size and mtime,
+    /// `checksum` the contents
+    #[serde(default = "default_sync_mode")]
+    sync_mode: String,
+
+    /// Size above which files are flagged before a sync, e.g. `100M`
+    #[serde(default)]
+    max_file_size: Option<String>,
+
+    /// What happens to files over `max_file_size`: `warn`, `skip` or `fail`
+    #[serde(default = "large_files::default_action")]
+    large_file_action: String,
+
+    /// How artifact downloads find changed files: `checksum` compares the
+    /// contents, `size` the size only, `mtime` size and mtime (default:
+    /// follows `sync_mode`)
+    #[serde(default)]
+    artifact_compare: Option<String>,
+
+    /// Number of build logs kept in `.remotebuild/logs` on the remote
+    /// (default: 10, 0 disables them)
+    #[serde(default = "remote_log::default_keep")]
+    keep_remote_logs: usize,
+
+    /// Number of build logs kept locally, in the state directory (default:
+    /// 10, 0 disables them)
+    #[serde(default = "local_log::default_keep")]
+    keep_local_logs: usize,
+
+    /// Local directory the build output is also logged to, set for the
+    /// project a run was started in
+    #[serde(skip)]
+    local_log_dir: Option<PathBuf>,
+
     /// Output level: minimal, normal, or verbose (default: minimal)
     #[serde(default)]
     output: String,
+
+    /// Hooks (by name) whose failure should fail the run
+    #[serde(default)]
+    blocking_hooks: Vec<String>,
+
+    /// Local shell commands run in the project directory before the sync
+    #[serde(default)]
+    pre_sync: Vec<String>,
+
+    /// Local shell commands run in the project directory after the artifacts
+    /// were downloaded
+    #[serde(default)]
+    post_artifacts: Vec<String>,
+
+    /// Extra paths (relative to the workspace root) whose changes should
+    /// trigger a rebuild of this component in workspace mode
+    #[serde(default)]
+    depends_on: Vec<String>,
+
+    /// Named cross-compilation targets, selected with `--target`
+    #[serde(default)]
+    targets: BTreeMap<String, targets::TargetConfig>,
+
+    /// Target built when none is named on the command line, instead of the
+    /// top-level build command
+    #[serde(default)]
+    default_target: Option<String>,
+
+    /// Container to run the build command in
+    #[serde(default)]
+    docker: Option<container::DockerConfig>,
+
+    /// Nix shell to run the build command in
+    #[serde(default)]
+    nix: Option<nix::NixConfig>,
+
+    /// Platforms built on their own hosts with `--matrix`
+    #[serde(default)]
+    matrix: BTreeMap<String, matrix::PlatformConfig>,
+
+    /// Named overrides of host, remote path and build command, selected with
+    /// `--profile`
+    #[serde(default)]
+    profiles: BTreeMap<String, profiles::ProfileConfig>,
+
+    /// Profile used when `--profile` isn't given
+    #[serde(default)]
+    default_profile: Option<String>,
+
+    /// Limits for the build trees kept on the remote host
+    #[serde(default)]
+    retention: Option<gc::RetentionConfig>,
+
+    /// Test command and result parsing for `remotebuild test`
+    #[serde(default)]
+    test: Option<test_runner::TestConfig>,
+
+    /// Settings of `remotebuild verify`
+    #[serde(default)]
+    verify: Option<verify::VerifyConfig>,
+
+    /// Build caches moved between hosts with `remotebuild cache`
+    #[serde(default)]
+    cache_archive: Option<cache_archive::CacheArchiveConfig>,
 }
 
 impl Config {
+    /// The resolved remote path, for use in remote commands and rsync
+    fn remote_dir(&self) -> RemotePath {
+        RemotePath::new(&self.remote_path)
+    }
+
+    /// The local directory artifacts are copied into, `artifact_dir`
+    /// resolved against `project_dir`
+    fn artifact_dir(&self, project_dir: &Path) -> PathBuf {
+        match &self.artifact_dir {
+            Some(dir) => project_dir.join(dir),
+            None => project_dir.to_path_buf(),
+        }
+    }
+
+    /// Whether transfers keep partial files, for `resumable` or
+    /// `--resilient`
+    fn resumes(&self) -> bool {
+        self.resumable || self.resilient
+    }
+
+    /// The excludes applied before detected ones: [`ALWAYS_EXCLUDED`], then
+    /// `default_excludes` unless `use_default_excludes` is off
+    fn builtin_excludes(&self) -> Vec<&str> {
+        let defaults: &[String] = if self.use_default_excludes {
+            &self.default_excludes
+        } else {
+            &[]
+        };
+        ALWAYS_EXCLUDED
+            .iter()
+            .copied()
+            .chain(defaults.iter().map(String::as_str))
+            .collect()
+    }
+
+    /// The rsync argument comparing file contents, with `sync_mode: checksum`
+    fn checksum_arg(&self) -> Option<&'static str> {
+        (self.sync_mode == "checksum").then_some("--checksum")
+    }
+
+    /// The rsync argument picking how artifact downloads compare files, per
+    /// `artifact_compare`
+    fn artifact_compare_arg(&self) -> Option<&'static str> {
+        match self.artifact_compare.as_deref() {
+            Some("checksum") => Some("--checksum"),
+            Some("size") => Some("--size-only"),
+            Some(_) => None,
+            None => self.checksum_arg(),
+        }
+    }
+
     /// Parse the output level from the configuration string
     fn output_level(&self) -> OutputLevel {
         match self.output.to_lowercase().as_str() {
             "verbose" | "v" => OutputLevel::Verbose,
             "normal" | "n" => OutputLevel::Normal,
+            "quiet" | "q" => OutputLevel::Quiet,
             _ => OutputLevel::Minimal,
         }
     }
 }
 
+/// Error returned when the remote build command exits unsuccessfully
+#[derive(Debug)]
+struct BuildFailed {
+    /// Exit status of the ssh process running the build
+    status: ExitStatus,
+    /// Build command that was run
+    command: BuildCommand,
+}
+
+impl BuildFailed {
+    /// Whether `remotebuild cancel` stopped the build
+    fn cancelled(&self) -> bool {
+        self.status.code() == Some(shared::CANCELLED_EXIT_CODE)
+    }
+}
+
+impl fmt::Display for BuildFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.cancelled() {
+            return write!(f, "Remote build was cancelled");
+        }
+        match &self.command {
+            BuildCommand::Single(command) => write!(
+                f,
+                "Remote build command `{}` failed with exit code: {:?}",
+                command, self.status
+            ),
+            // The remote shell already named the failed step
+            BuildCommand::Steps { .. } => write!(
+                f,
+                "Remote build step failed with exit code: {:?}",
+                self.status
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildFailed {}
+
+/// Which files a sync considers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyncScope {
+    /// Only changed files, from the git file list or manifest when available
+    Changed,
+    /// The whole tree, deleting remote files that no longer exist locally
+    Full,
+    /// Like `Full`, but also deleting remote files matched by
+    /// `exclude_patterns`, after showing what will be deleted
+    Clean,
+}
+
+impl SyncScope {
+    /// `Full` when a full sync was forced, `Changed` otherwise
+    fn full_if(force_full_sync: bool) -> Self {
+        if force_full_sync {
+            Self::Full
+        } else {
+            Self::Changed
+        }
+    }
+}
+
 /// Output verbosity level for the CLI
 #[derive(Clone, Copy)]
 enum OutputLevel {
+    /// No progress output at all, only warnings and errors
+    Quiet,
     /// Single \r-overwriting lines with spinner
     Minimal,
     /// Multi-line status with clear start/end
@@ -59,7 +642,13 @@ enum OutputLevel {
     Verbose,
 }
 
+/// Erases the status line, whatever its display width
+const CLEAR_LINE: &str = "\r\x1b[2K";
+
 /// Simple spinner for minimal mode
+///
+/// When stdout isn't a terminal, such as in CI logs, the message is printed
+/// once as a plain line and nothing is animated or erased.
 struct Spinner {
     /// The message to display
     message: String,
@@ -69,47 +658,95 @@ struct Spinner {
     current_frame: usize,
     /// Whether the spinner has been stopped
     stopped: bool,
+    /// Shown after the animation, such as the transfer progress
+    detail: String,
+    /// Whether stdout is a terminal that can redraw the line
+    terminal: bool,
 }
 
 impl Spinner {
     /// Create a new spinner with the given message
     fn new(message: &str) -> Self {
+        use std::io::IsTerminal;
+        Self::with_terminal(message, std::io::stdout().is_terminal())
+    }
+
+    /// Create a new spinner, animated only when `terminal` is set
+    fn with_terminal(message: &str, terminal: bool) -> Self {
         Self {
             message: message.to_string(),
             frames: &["⣾", "⣽", "⣻", "⢿", "⡿", "⣟", "⣯", "⣷"],
             current_frame: 0,
             stopped: false,
+            detail: String::new(),
+            terminal,
         }
     }
 
+    /// Show `detail` after the animation from the next frame on
+    fn set_detail(&mut self, detail: String) {
+        self.detail = detail;
+    }
+
     /// Advance the spinner by one frame
     fn tick(&mut self) {
-        if self.stopped {
-            return;
-        }
+        let text = self.next_frame();
+        write_status(&text);
+    }
 
-        let frame = self.frames[self.current_frame % self.frames.len()];
-        // Bold the entire line including spinner
-        print!("\r\x1b[1m{}{}\x1b[0m", self.message, frame);
-        use std::io::Write;
-        std::io::stdout().flush().ok();
+    /// Stop the spinner and clear the line
+    fn stop(&mut self) {
+        let text = self.finish();
+        write_status(&text);
+    }
 
+    /// What showing the next frame writes, advancing the animation
+    fn next_frame(&mut self) -> String {
+        if self.stopped {
+            return String::new();
+        }
         self.current_frame += 1;
+        if !self.terminal {
+            return match self.current_frame {
+                1 => format!("{}\n", self.message.trim_end()),
+                _ => String::new(),
+            };
+        }
+
+        let frame = self.frames[(self.current_frame - 1) % self.frames.len()];
+        // Bold the entire line including spinner, erasing anything left
+        // over from a longer previous line
+        let separator = if self.detail.is_empty() { "" } else { " " };
+        format!(
+            "\r\x1b[1m{}{}{}{}\x1b[0m\x1b[K",
+            self.message, frame, separator, self.detail
+        )
     }
 
-    /// Stop the spinner and clear the line
-    fn stop(&mut self) {
+    /// What stopping writes
+    fn finish(&mut self) -> String {
         if self.stopped {
-            return;
+            return String::new();
         }
         self.stopped = true;
+        // Erase the whole line with ANSI EL rather than padding with spaces,
+        // which depends on the display width of the emoji prefix
+        if self.terminal {
+            CLEAR_LINE.to_string()
+        } else {
+            String::new()
+        }
+    }
+}
 
-        // Clear the line
-        let line_width = self.message.len() + 2; // message + spinner frame
-        print!("\r{: <width$}\r", ' ', width = line_width);
-        use std::io::Write;
-        std::io::stdout().flush().ok();
+/// Print status text right away
+fn write_status(text: &str) {
+    if text.is_empty() {
+        return;
     }
+    use std::io::Write;
+    print!("{}", text);
+    std::io::stdout().flush().ok();
 }
 
 impl Drop for Spinner {
@@ -118,7 +755,7 @@ impl Drop for Spinner {
     }
 }
 
-/// Default value for the remote_path configuration field
+/// Directory holding the default remote paths of projects
 fn default_remote_path() -> String {
     "~/remotebuild-cache".to_string()
 }
@@ -128,35 +765,118 @@ fn default_true() -> bool {
     true
 }
 
+/// Default value for `sync_mode`
+fn default_sync_mode() -> String {
+    "fast".to_string()
+}
+
+/// Default value for `default_excludes`
+fn default_excludes() -> Vec<String> {
+    DEFAULT_EXCLUDES.iter().map(|p| p.to_string()).collect()
+}
+
+/// Stable 64-bit FNV-1a hash, used for cache keys that must survive upgrades
+fn stable_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Directory holding the control sockets, in the XDG cache directory or
+/// else the temp directory
+fn control_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join("remotebuild")
+}
+
 /// Get the SSH control socket path for connection sharing
-fn ssh_control_path(host: &str) -> String {
-    // Use XDG cache directory or fallback to temp
-    let cache_dir = dirs::cache_dir().unwrap_or_else(env::temp_dir);
-    let control_dir = cache_dir.join("remotebuild");
+///
+/// Socket paths are limited to 104 bytes on macOS (108 on Linux), and the
+/// master first binds a temporary name 17 characters longer, so names that
+/// would get too long are shortened to a prefix of the host plus a hash.
+/// Paths that fit keep their full name, so live masters are still found.
+/// The shortened name takes 26 bytes, which leaves 60 for the directory.
+fn ssh_control_path(config: &Config) -> String {
+    let control_dir = control_dir();
     let _ = fs::create_dir_all(&control_dir);
+    control_socket_path(&control_dir, &config.host, config.ssh_port)
+}
 
+/// The control socket path for `host` and `port` in `control_dir`
+fn control_socket_path(control_dir: &Path, host: &str, port: Option<u16>) -> String {
     // Sanitize hostname for use in filename
     let safe_host = host.replace(|c: char| !c.is_alphanumeric() && c != '-' && c != '.', "_");
-    control_dir
-        .join(format!("control_{}", safe_host))
-        .to_string_lossy()
-        .to_string()
+    // Servers on different ports of one host get their own sockets
+    let name = match port {
+        Some(port) => format!("control_{}_{}", safe_host, port),
+        None => format!("control_{}", safe_host),
+    };
+    let path = control_dir.join(&name).to_string_lossy().to_string();
+    if path.len() <= MAX_CONTROL_PATH {
+        return path;
+    }
+    // 32 bits of the hash are plenty to tell a user's hosts apart
+    let prefix: String = safe_host.chars().take(8).collect();
+    let hash = stable_hash(name.as_bytes()) & 0xffff_ffff;
+    let short = format!("control_{}_{:08x}", prefix, hash);
+    control_dir.join(short).to_string_lossy().to_string()
+}
+
+/// The `ControlPath=...` option for the host's control socket, quoted for
+/// ssh's option parser, which splits on whitespace and expands `%` tokens
+fn ssh_control_option(config: &Config) -> String {
+    control_path_option(&ssh_control_path(config))
+}
+
+/// The `ControlPath=...` option for the socket at `path`
+fn control_path_option(path: &str) -> String {
+    let path = path.replace('%', "%%");
+    if path.contains(char::is_whitespace) {
+        format!("ControlPath=\"{}\"", path)
+    } else {
+        format!("ControlPath={}", path)
+    }
 }
 
 /// Ensure SSH control master connection is established
+///
+/// The master is started with `-f`, so the ssh process we spawn exits (and is
+/// reaped here) as soon as authentication finished, after forking the master
+/// into the background. The detached master belongs to no remotebuild process
+/// and outlives it for `ControlPersist`. The exit status of the spawned ssh
+/// tells whether the connection could be established, and the run continues
+/// once the master answers on its socket.
+///
+/// A connection [`connect_early`] started for the same socket is joined
+/// instead, returning its result.
 fn ensure_ssh_connection(config: &Config) -> Result<()> {
-    let control_path = ssh_control_path(&config.host);
+    let early = EARLY_CONNECTIONS
+        .lock()
+        .ok()
+        .and_then(|mut early| early.remove(&ssh_control_path(config)));
+    match early {
+        Some(pending) => pending.wait(),
+        None => establish_ssh_connection(config),
+    }
+}
+
+/// Connect to the host through the control master, starting it if needed
+fn establish_ssh_connection(config: &Config) -> Result<()> {
+    let control_path = ssh_control_path(config);
+    ssh_master::close_at_exit(config);
 
     // Check if control socket already exists and is valid
     if Path::new(&control_path).exists() {
         // Test connection with a simple command
         let test_result = Command::new("ssh")
+            .args(ssh_options::args(config))
             .arg("-o")
             .arg("ControlMaster=no")
             .arg("-o")
-            .arg(format!("ControlPath={}", control_path))
+            .arg(ssh_control_option(config))
             .arg("-o")
-            .arg("ConnectTimeout=2")
+            .arg(ssh_options::check_timeout(config))
             .arg(&config.host)
             .arg("true")
             .output();
@@ -169,30 +889,175 @@ fn ensure_ssh_connection(config: &Config) -> Result<()> {
         }
     }
 
+    // Another process may be starting the same master
+    let Some(_lock) = ssh_master::lock(config, &control_path)? else {
+        return Ok(());
+    };
+
+    // A socket left behind by a dead master would stop the new one from listening
+    let _ = fs::remove_file(&control_path);
+
+    // Without a way to answer prompts, ssh must fail rather than wait
+    let (status, stderr) = start_master(config, &control_path, None)?;
+    if status.success() {
+        return master_ready(config, &control_path);
+    }
+    if !auth::needs_interaction(&stderr) {
+        return Err(connect_error(config, status, &stderr));
+    }
+
+    let Some(interaction) = auth::Interaction::for_config(config) else {
+        return Err(auth::interaction_required(config, &stderr));
+    };
+    if let auth::Interaction::Terminal = interaction {
+        eprintln!("🔑 {} requires interactive authentication", config.host);
+    }
+    let (status, stderr) = start_master(config, &control_path, Some(&interaction))?;
+    if !status.success() {
+        return Err(connect_error(config, status, &stderr));
+    }
+    master_ready(config, &control_path)
+}
+
+/// Wait for a started master to answer on its socket, failing with what it
+/// logged if it doesn't
+fn master_ready(config: &Config, control_path: &str) -> Result<()> {
+    if ssh_master::wait_ready(config) {
+        return Ok(());
+    }
+    let log = fs::read_to_string(format!("{}.log", control_path)).unwrap_or_default();
+    Err(anyhow!(
+        "SSH control master for {} didn't answer within {}s{}",
+        config.host,
+        config.ssh_ready_timeout,
+        indented_tail(&log)
+    ))
+}
+
+/// Start the control master in the background, returning ssh's exit status
+/// and error output
+///
+/// Without `interaction` the master runs in batch mode and fails instead of
+/// prompting.
+fn start_master(
+    config: &Config,
+    control_path: &str,
+    interaction: Option<&auth::Interaction>,
+) -> Result<(ExitStatus, String)> {
+    // The backgrounded master may keep the spawned process's stderr open, so
+    // it goes to a file rather than a pipe that would never reach EOF
+    let log_path = format!("{}.log", control_path);
+    let log = fs::File::create(&log_path)
+        .with_context(|| format!("Failed to create SSH log file: {}", log_path))?;
+
     // Start new control master connection in background
-    Command::new("ssh")
+    let mut master = Command::new("ssh");
+    match interaction {
+        Some(interaction) => interaction.apply(&mut master)?,
+        None => {
+            master.arg("-o").arg("BatchMode=yes");
+        }
+    }
+    if compression::ssh_compression(&config.host) {
+        master.arg("-C");
+    }
+    master.args(ssh_options::args(config));
+    if config.resilient {
+        master.args(ssh_options::unless_set(
+            config,
+            &[resilient::CONNECT_TIMEOUT.to_string()],
+        ));
+    }
+    let status = master
+        .arg("-f")
         .arg("-N")
         .arg("-M")
         .arg("-o")
         .arg("ControlMaster=auto")
         .arg("-o")
-        .arg("ControlPersist=10m")
+        .arg(ssh_master::persist_option(config))
         .arg("-o")
-        .arg(format!("ControlPath={}", control_path))
+        .arg(ssh_control_option(config))
         .arg(&config.host)
-        .spawn()
+        .stdout(std::process::Stdio::null())
+        .stderr(log)
+        .status()
         .context("Failed to start SSH control master")?;
 
-    // Give it a moment to establish
-    std::thread::sleep(std::time::Duration::from_millis(100));
+    let stderr = if status.success() {
+        String::new()
+    } else {
+        fs::read_to_string(&log_path).unwrap_or_default()
+    };
+    Ok((status, stderr))
+}
+
+/// The error for a control master that couldn't connect
+fn connect_error(config: &Config, status: ExitStatus, stderr: &str) -> anyhow::Error {
+    if let Some(failure) = ssh_options::jump_failure(config, stderr) {
+        return anyhow!(
+            "Failed to connect to {} (ssh {}): {}: {}",
+            config.host,
+            status,
+            failure,
+            stderr.trim()
+        );
+    }
+    anyhow!(
+        "Failed to connect to {} (ssh {}): {}",
+        config.host,
+        status,
+        stderr.trim()
+    )
+}
+
+/// SSH connection being established on a background thread
+struct PendingConnection {
+    /// Thread running `ensure_ssh_connection`
+    handle: std::thread::JoinHandle<Result<()>>,
+}
 
-    Ok(())
+impl PendingConnection {
+    /// Wait for the connection attempt, returning its error unchanged
+    fn wait(self) -> Result<()> {
+        self.handle
+            .join()
+            .map_err(|_| anyhow!("SSH connection thread panicked"))?
+    }
+}
+
+/// Connections started by [`connect_early`], by control socket path
+static EARLY_CONNECTIONS: Mutex<BTreeMap<String, PendingConnection>> = Mutex::new(BTreeMap::new());
+
+/// Start `ensure_ssh_connection` on a background thread
+fn connect_in_background(config: &Config) -> PendingConnection {
+    let config = config.clone();
+    PendingConnection {
+        handle: std::thread::spawn(move || ensure_ssh_connection(&config)),
+    }
+}
+
+/// Start connecting to the host as soon as it is known, for the first
+/// `ensure_ssh_connection` to join
+///
+/// Its error is only reported there, so runs that end up not using the
+/// connection don't fail because of it.
+fn connect_early(config: &Config) {
+    let path = ssh_control_path(config);
+    let config = config.clone();
+    let pending = PendingConnection {
+        handle: std::thread::spawn(move || establish_ssh_connection(&config)),
+    };
+    if let Ok(mut early) = EARLY_CONNECTIONS.lock() {
+        early.insert(path, pending);
+    }
 }
 
-/// Helper to add SSH control options to a command
+/// Helper to add SSH control options to a command, after the configured
+/// port, identity and options
 fn add_ssh_control_args(cmd: &mut Command, config: &Config) {
-    let control_path = ssh_control_path(&config.host);
-    cmd.arg("-o").arg(format!("ControlPath={}", control_path));
+    cmd.args(ssh_options::args(config));
+    cmd.arg("-o").arg(ssh_control_option(config));
 }
 
 /// Create a Command with SSH control path pre-configured
@@ -203,9 +1068,32 @@ fn ssh_command(config: &Config) -> Command {
     cmd
 }
 
-/// Get the SSH control path as a string (for rsync -e flag)
+/// Create an ssh Command running the POSIX shell code `cmd` on the remote,
+/// through a POSIX shell when the login shell isn't one
+///
+/// Every remote command goes through here, so env exports, wrappers and the
+/// log tee all reach the same shell.
+fn remote_command(config: &Config, cmd: &str) -> Command {
+    let mut ssh = ssh_command(config);
+    ssh.arg(remote_shell::wrap(config, cmd));
+    ssh
+}
+
+/// Get the SSH control path as a string (for rsync -e flag), with the
+/// configured port, identity and options
+///
+/// rsync splits the command on spaces but keeps quoted parts together, so
+/// arguments are single-quoted piecewise around any single quotes in them.
 fn ssh_control_path_arg(config: &Config) -> String {
-    format!("ssh -o ControlPath={}", ssh_control_path(&config.host))
+    let mut command = vec!["ssh".to_string()];
+    command.extend(
+        ssh_options::args(config)
+            .iter()
+            .map(|arg| ssh_options::rsync_quote(arg)),
+    );
+    command.push("-o".to_string());
+    command.push(ssh_options::rsync_quote(&ssh_control_option(config)));
+    command.join(" ")
 }
 
 /// Command line arguments
@@ -213,31 +1101,362 @@ fn ssh_control_path_arg(config: &Config) -> String {
 #[command(name = "remotebuild")]
 #[command(about = "Proxy builds to a remote server via SSH", long_about = None)]
 struct Args {
+    /// Subcommand to run (defaults to the full sync/build/fetch pipeline)
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Target from the config's `targets` to build, like `--target`
+    /// (defaults to `default_target`)
+    #[arg(value_name = "TARGET", conflicts_with = "all")]
+    target_name: Option<String>,
+
     /// Path to project directory (defaults to current directory)
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     path: Option<PathBuf>,
 
-    /// Config file name (defaults to .remotebuild.yaml)
-    #[arg(short, long, default_value = ".remotebuild.yaml")]
-    config: String,
+    /// Config file, relative to the project directory or absolute; `-`
+    /// reads it from stdin (defaults to the first of .remotebuild.yaml,
+    /// .remotebuild.toml and .remotebuild.json that exists)
+    #[arg(short, long, global = true)]
+    config: Option<String>,
+
+    /// SSH host to build on. Overrides config file; without a config file,
+    /// the whole configuration comes from flags
+    #[arg(long, global = true)]
+    host: Option<String>,
+
+    /// Profile from the config's `profiles` to merge over its top-level
+    /// values (defaults to `default_profile`)
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Build command to run remotely. Overrides config file
+    #[arg(long, global = true)]
+    build_command: Option<String>,
+
+    /// Artifact to copy back (repeatable). Overrides config file artifacts
+    #[arg(long = "artifact", value_name = "PATTERN", global = true)]
+    artifacts: Vec<String>,
+
+    /// Artifact to copy back in addition to the configured ones (repeatable)
+    #[arg(long = "extra-artifact", value_name = "PATTERN", global = true)]
+    extra_artifacts: Vec<String>,
+
+    /// Local directory to copy artifacts into, relative to the project
+    /// directory. Overrides config file
+    #[arg(long, value_name = "DIR", global = true)]
+    artifact_dir: Option<String>,
 
     /// Force full sync (ignore git change detection)
-    #[arg(long)]
+    #[arg(long, global = true)]
     force_full_sync: bool,
 
+    /// Sync into a remote path that belongs to another project, making it
+    /// this project's
+    #[arg(long, global = true)]
+    take_over: bool,
+
+    /// Sync the whole tree and delete every remote file the current settings
+    /// wouldn't sync, including stale and newly excluded files
+    #[arg(long, conflicts_with_all = ["all", "targets", "target_name"])]
+    clean_sync: bool,
+
+    /// Find changed files by their contents instead of size and mtime, like
+    /// `sync_mode: checksum`
+    #[arg(long, global = true)]
+    checksum: bool,
+
+    /// Keep remote files that no longer exist locally, like
+    /// `sync_delete: never`
+    #[arg(long, conflicts_with = "clean_sync")]
+    no_delete: bool,
+
     /// Output level (minimal, normal, verbose). Overrides config file
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     output: Option<String>,
+
+    /// Build every workspace component (subdirectories with their own config)
+    #[arg(long)]
+    all: bool,
+
+    /// With --all, stop at the first failing component
+    #[arg(long, requires = "all")]
+    fail_fast: bool,
+
+    /// With --all, also build components whose inputs are unchanged
+    #[arg(long, requires = "all")]
+    rebuild_unchanged: bool,
+
+    /// Rebuild whenever a synced file changes, cancelling a running build
+    #[arg(long, conflicts_with = "all")]
+    watch: bool,
+
+    /// Build the named target from the config's `targets` (repeatable)
+    #[arg(long = "target", value_name = "NAME", conflicts_with = "all")]
+    targets: Vec<String>,
+
+    /// Build every platform of the config's `matrix` concurrently
+    #[arg(long, conflicts_with_all = ["all", "targets", "target_name", "clean_sync"])]
+    matrix: bool,
+
+    /// With `--matrix`, let the other platforms finish after one fails
+    #[arg(long, requires = "matrix")]
+    matrix_continue: bool,
+
+    /// Survive dropped connections: retry transfers, run the build detached
+    /// and resume its output after reconnecting
+    #[arg(long)]
+    resilient: bool,
+
+    /// Run setup_command again even if it already completed on the host
+    #[arg(long, global = true)]
+    rerun_setup: bool,
+
+    /// Show the files the sync would send and delete and the commands the
+    /// build would run, without changing the remote
+    #[arg(long, conflicts_with_all = ["all", "matrix", "targets", "target_name"])]
+    dry_run: bool,
+
+    /// Skip copying artifacts back, e.g. to only check that the code builds
+    #[arg(long)]
+    no_artifacts: bool,
+
+    /// Only copy back the artifacts the last build left on the remote,
+    /// skipping the sync and the build
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "no_artifacts", "dry_run", "clean_sync", "force_full_sync",
+            "all", "matrix", "targets", "target_name",
+        ]
+    )]
+    artifacts_only: bool,
+
+    /// Ignore config keys remotebuild doesn't use instead of failing
+    #[arg(long, global = true)]
+    lax_config: bool,
+
+    /// Don't merge ~/.config/remotebuild/config.yaml into the config
+    #[arg(long, global = true)]
+    no_global_config: bool,
+
+    /// Limit rsync transfers to RATE KiB/s, e.g. 500k or 2m (0 for no
+    /// limit). Overrides config file
+    #[arg(long, value_name = "RATE", global = true)]
+    bwlimit: Option<String>,
+}
+
+/// Subcommands besides the default build pipeline
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Sync the project to the remote without building or fetching artifacts
+    Sync,
+
+    /// Download the artifacts the last build left on the remote, without
+    /// syncing or building
+    Fetch,
+
+    /// Verify a full sync/build/fetch round trip against the configured host
+    /// using a throwaway remote directory
+    SelfTest,
+
+    /// Run `cargo check` remotely and print its diagnostics with local paths
+    Check {
+        /// `human` to render diagnostics, or a cargo JSON format (e.g. `json`)
+        /// to re-emit the messages for tools like rust-analyzer
+        #[arg(long, default_value = "human")]
+        message_format: String,
+
+        /// Extra arguments passed to `cargo check`
+        #[arg(last = true)]
+        cargo_args: Vec<String>,
+    },
+
+    /// Run the configured tests remotely and summarize the failures
+    Test {
+        /// Extra arguments appended to the test command
+        #[arg(last = true)]
+        test_args: Vec<String>,
+    },
+
+    /// Compare the remote environment with the last successful build's
+    EnvDiff,
+
+    /// List or print the build logs kept locally and on the remote host
+    Logs {
+        /// Show the logs kept on the remote host
+        #[arg(long)]
+        remote: bool,
+
+        /// Log to print, or `latest` for the newest
+        name: Option<String>,
+
+        /// Keep printing what is appended to the log (the newest by default)
+        #[arg(short, long)]
+        follow: bool,
+    },
+
+    /// Build remotely and locally and compare the artifacts
+    Verify {
+        /// Exit with an error when any artifact differs
+        #[arg(long)]
+        strict: bool,
+    },
+
+    /// Write a new config file, optionally derived from a CMake preset
+    Init {
+        /// Configure preset (from CMakePresets.json) to derive the config from
+        #[arg(long, value_name = "PRESET")]
+        from_cmake_preset: Option<String>,
+
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+
+        /// Add remotebuild tasks to `.vscode/tasks.json`
+        #[arg(long)]
+        vscode: bool,
+
+        /// Add remotebuild tasks to `.zed/tasks.json`
+        #[arg(long)]
+        zed: bool,
+
+        /// Write the excludes detected from the project's build systems
+        #[arg(long)]
+        detect: bool,
+    },
+
+    /// Remove old build trees from the remote host
+    Gc {
+        /// Apply the `retention` policy from the config
+        #[arg(long)]
+        policy: bool,
+
+        /// Remove trees unused for longer than this (without --policy)
+        #[arg(long, value_name = "AGE", default_value = "30d")]
+        older_than: String,
+
+        /// Only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Include build trees of other users
+        #[arg(long)]
+        all_users: bool,
+
+        /// Don't ask before removing other users' trees
+        #[arg(long)]
+        yes: bool,
+    },
+
+    /// Build several projects, each with its own config, and summarize them
+    Batch {
+        /// Project directories
+        #[arg(required = true)]
+        dirs: Vec<PathBuf>,
+
+        /// Number of projects building at the same time, with their build
+        /// output prefixed instead of progress output
+        #[arg(long, default_value_t = 1)]
+        parallel: usize,
+    },
+
+    /// Move the remote tree's build caches to or from an archive
+    Cache {
+        /// Whether to push or pull
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+
+    /// Stop the build running in the project's remote tree
+    Cancel {
+        /// Also cancel builds started by other users
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Close the SSH connection remotebuild keeps open to the host
+    Disconnect {
+        /// Close the connections to every host, removing stale sockets
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Show the most recent builds recorded on the remote
+    Status {
+        /// Number of history entries to show
+        #[arg(short = 'n', long, default_value_t = 5)]
+        limit: usize,
+    },
+}
+
+/// Actions of `remotebuild cache`
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Archive the configured cache paths of the remote tree under a name
+    Push {
+        /// Name of the archive
+        name: String,
+    },
+
+    /// Unpack a named archive into the remote tree
+    Pull {
+        /// Name of the archive
+        name: String,
+
+        /// Unpack even if the tree was built after the archive was pushed
+        #[arg(long)]
+        force: bool,
+    },
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    // ssh runs remotebuild as its askpass program for `password_command`
+    if let Some(code) = auth::run_as_askpass() {
+        std::process::exit(code);
+    }
+
+    let result = run(Args::parse());
+    ssh_master::close_unpersisted();
+    // A cancelled build gets its own exit code instead of a generic failure
+    if let Err(e) = &result {
+        if e.downcast_ref::<BuildFailed>()
+            .is_some_and(BuildFailed::cancelled)
+        {
+            eprintln!("🛑 {}", e);
+            std::process::exit(shared::CANCELLED_EXIT_CODE);
+        }
+        if e.downcast_ref::<Superseded>().is_some() {
+            eprintln!("🛑 {}", e);
+            std::process::exit(shared::CANCELLED_EXIT_CODE);
+        }
+    }
+    result
+}
+
+/// Run remotebuild with the parsed arguments
+fn run(args: Args) -> Result<()> {
+    if args.no_global_config {
+        global_config::disable();
+    }
+    if args.lax_config {
+        unknown_keys::allow();
+    }
 
-    // Determine project directory
+    // Determine project directory: the given path, or the nearest directory
+    // with a config, searching upwards from the current one
+    let searched = !matches!(
+        args.command,
+        Some(Commands::Init { .. } | Commands::Batch { .. })
+    );
     let project_dir = if let Some(path) = args.path {
         fs::canonicalize(path)?
     } else {
-        env::current_dir()?
+        let current = env::current_dir()?;
+        match config_format::find_project_root(&current, args.config.as_deref()) {
+            Some(root) if searched => root,
+            _ => current,
+        }
     };
 
     if !project_dir.is_dir() {
@@ -246,58 +1465,607 @@ fn main() -> Result<()> {
             project_dir.display()
         ));
     }
+    let config_name = args
+        .config
+        .clone()
+        .unwrap_or_else(|| config_format::default_name(&project_dir).to_string());
+
+    // These need a config file of their own to point at
+    if config_name == STDIN_CONFIG {
+        if matches!(args.command, Some(Commands::Init { .. })) {
+            return Err(anyhow!("init can't write to a config read from stdin"));
+        }
+        if args.all {
+            return Err(anyhow!(
+                "--all reads each component's own config file, so it can't use --config -"
+            ));
+        }
+    }
+
+    // Every project of a batch has its own config
+    if let Some(Commands::Batch { dirs, parallel }) = &args.command {
+        let options = batch::BatchOptions {
+            dirs,
+            config_name: args.config.as_deref(),
+            profile: args.profile.as_deref(),
+            output: args.output.as_deref(),
+            scope: if args.clean_sync {
+                SyncScope::Clean
+            } else {
+                SyncScope::full_if(args.force_full_sync)
+            },
+            parallel: *parallel,
+        };
+        return batch::run_batch(&options);
+    }
+
+    // Closing every connection needs no project
+    if let Some(Commands::Disconnect { all: true }) = &args.command {
+        return disconnect::run_disconnect_all();
+    }
+
+    if let Some(Commands::Init {
+        from_cmake_preset,
+        force,
+        vscode,
+        zed,
+        detect,
+    }) = &args.command
+    {
+        // Editor tasks alone are added to the existing config
+        let editors = *vscode || *zed;
+        if !editors || from_cmake_preset.is_some() || *detect {
+            let options = init::InitOptions {
+                config_name: &config_name,
+                host: args.host.as_deref(),
+                build_command: args.build_command.as_deref(),
+                artifacts: &args.artifacts,
+                cmake_preset: from_cmake_preset.as_deref(),
+                force: *force,
+                detect: *detect,
+            };
+            init::run_init(&project_dir, &options)?;
+        }
+        if !editors {
+            return Ok(());
+        }
+    }
+
+    // Load config, or synthesize one when everything is given as flags
+    let config_path = project_dir.join(&config_name);
+    let mut config: Config = if config_name == STDIN_CONFIG {
+        let content = std::io::read_to_string(std::io::stdin())
+            .context("Failed to read config from stdin")?;
+        parse_config(&content, "from stdin", ConfigFormat::Yaml, None)?
+    } else if config_path.exists() {
+        let mut config = load_config(&config_path)?;
+        exclude_explicit_config(&project_dir, &config_name, &config_path, &mut config);
+        config
+    } else if args.host.is_some() {
+        zero_config()?
+    } else if args.all {
+        workspace::root_config(&project_dir, &config_name)?
+    } else {
+        return Err(anyhow!(
+            "No config file found at {}. Create one with `remotebuild init` \
+             or pass --host and --build-command",
+            config_path.display()
+        ));
+    };
 
-    // Load config
-    let config_path = project_dir.join(&args.config);
-    let mut config: Config = load_config(&config_path)?;
+    profiles::apply(&mut config, args.profile.as_deref())?;
+    if config.remote_path.is_empty() {
+        config.remote_path = default_project_remote_path(&project_dir);
+    }
 
-    // Override output level if specified on CLI
+    // Override config values specified on CLI
     if let Some(output) = args.output {
         config.output = output;
     }
+    if let Some(host) = args.host {
+        if !config.host.is_empty() && config.host != host {
+            config.replaced_host = Some(std::mem::replace(&mut config.host, host));
+        } else {
+            config.host = host;
+        }
+    }
+    if let Some(build_command) = args.build_command {
+        config.build_command = build_command.into();
+    }
+    if !args.artifacts.is_empty() {
+        config.artifacts = args
+            .artifacts
+            .into_iter()
+            .map(|pattern| Artifact::from_flag(pattern, "--artifact"))
+            .collect();
+    }
+    config.artifacts.extend(
+        args.extra_artifacts
+            .into_iter()
+            .map(|pattern| Artifact::from_flag(pattern, "--extra-artifact")),
+    );
+    if let Some(dir) = args.artifact_dir {
+        config.artifact_dir = Some(dir);
+    }
+    if args.resilient {
+        config.resilient = true;
+    }
+    if args.no_delete {
+        config.sync_delete = "never".to_string();
+    }
+    if args.checksum {
+        config.sync_mode = "checksum".to_string();
+    }
+    if let Some(rate) = args.bwlimit {
+        bwlimit::parse(&rate)?;
+        config.bwlimit = Some(rate);
+    }
+    config.rerun_setup = args.rerun_setup;
+    config.dry_run = args.dry_run;
+    config.take_over = args.take_over;
+    config.no_artifacts = args.no_artifacts;
+    config.artifacts_only = args.artifacts_only;
+    if config.keep_local_logs > 0 {
+        config.local_log_dir = Some(local_log::dir(&project_dir));
+    }
+    detect::apply(&project_dir, &mut config);
+    gitignore::apply(&project_dir, &mut config);
+    lint_artifacts(&project_dir, &config);
+    sync_excludes(&config, true).lint();
+
+    // Matrix platforms each name their own host
+    if config.host.is_empty() && !args.matrix {
+        return Err(anyhow!(
+            "No host configured: set host in {} or pass --host",
+            config_path.display()
+        ));
+    }
+    compression::load_cached(&project_dir, &config)?;
+
+    // Connect while the rest of the run is prepared locally, unless the run
+    // only previews, works locally or connects to the platforms' hosts
+    let local = matches!(
+        args.command,
+        Some(Commands::Init { .. } | Commands::Disconnect { .. })
+            | Some(Commands::Logs {
+                remote: false,
+                name: Some(_),
+                ..
+            })
+    );
+    if !config.dry_run && !local && !args.matrix {
+        connect_early(&config);
+    }
+
+    match args.command {
+        Some(Commands::Sync) => {
+            let scope = if args.clean_sync {
+                SyncScope::Clean
+            } else {
+                SyncScope::full_if(args.force_full_sync)
+            };
+            return run_sync(&project_dir, &config, scope);
+        }
+        Some(Commands::Fetch) => {
+            if config.artifacts.is_empty() {
+                return Err(anyhow!(
+                    "No artifacts configured: set artifacts in {} or pass --artifact",
+                    config_path.display()
+                ));
+            }
+            return fetch_artifacts(&project_dir, &config);
+        }
+        Some(Commands::SelfTest) => return selftest::run_self_test(&config),
+        Some(Commands::Status { limit }) => return show_status(&config, limit),
+        Some(Commands::Cancel { force }) => {
+            ensure_ssh_connection(&config)?;
+            return cancel::run_cancel(&config, force);
+        }
+        Some(Commands::Disconnect { .. }) => return disconnect::run_disconnect(&config),
+        Some(Commands::Cache { action }) => {
+            let action = match &action {
+                CacheCommand::Push { name } => cache_archive::CacheAction::Push(name),
+                CacheCommand::Pull { name, force } => {
+                    cache_archive::CacheAction::Pull(name, *force)
+                }
+            };
+            return cache_archive::run_cache(&project_dir, &config, action);
+        }
+        Some(Commands::EnvDiff) => {
+            ensure_ssh_connection(&config)?;
+            return snapshot::run_env_diff(&project_dir, &config);
+        }
+        Some(Commands::Logs {
+            remote,
+            name,
+            follow,
+        }) => {
+            let options = remote_log::LogsOptions {
+                remote,
+                name: name.as_deref(),
+                follow,
+            };
+            if remote {
+                ensure_ssh_connection(&config)?;
+            }
+            return remote_log::run_logs(&project_dir, &config, &options);
+        }
+        Some(Commands::Gc {
+            policy,
+            older_than,
+            dry_run,
+            all_users,
+            yes,
+        }) => {
+            let options = gc::GcOptions {
+                policy,
+                older_than: &older_than,
+                dry_run,
+                all_users,
+                yes,
+            };
+            return gc::run_gc(&config, &options);
+        }
+        Some(Commands::Check {
+            message_format,
+            cargo_args,
+        }) => {
+            let options = check::CheckOptions {
+                message_format: &message_format,
+                cargo_args: &cargo_args,
+                scope: SyncScope::full_if(args.force_full_sync),
+            };
+            let code = check::run_check(&project_dir, &config, &options)?;
+            ssh_master::close_unpersisted();
+            std::process::exit(code);
+        }
+        Some(Commands::Test { test_args }) => {
+            let options = test_runner::TestOptions {
+                args: &test_args,
+                scope: SyncScope::full_if(args.force_full_sync),
+            };
+            let code = test_runner::run_tests(&project_dir, &config, &options)?;
+            ssh_master::close_unpersisted();
+            std::process::exit(code);
+        }
+        Some(Commands::Verify { strict }) => {
+            let options = verify::VerifyOptions {
+                strict,
+                scope: SyncScope::full_if(args.force_full_sync),
+            };
+            let code = verify::run_verify(&project_dir, &config, &options)?;
+            ssh_master::close_unpersisted();
+            std::process::exit(code);
+        }
+        Some(Commands::Init { vscode, zed, .. }) => {
+            let editors = [(vscode, editor::Editor::VsCode), (zed, editor::Editor::Zed)];
+            for (_, editor) in editors.into_iter().filter(|(wanted, _)| *wanted) {
+                editor::write_tasks(&project_dir, &config, &config_name, editor)?;
+            }
+            return Ok(());
+        }
+        // Batches return before a config is loaded
+        Some(Commands::Batch { .. }) | None => {}
+    }
+
+    if args.all {
+        let options = workspace::WorkspaceOptions {
+            config_name: &config_name,
+            scope: SyncScope::full_if(args.force_full_sync),
+            fail_fast: args.fail_fast,
+            rebuild_unchanged: args.rebuild_unchanged,
+        };
+        return workspace::run_workspace_build(&project_dir, &config, &options);
+    }
+
+    if args.matrix {
+        return matrix::run_matrix(
+            &project_dir,
+            &config,
+            SyncScope::full_if(args.force_full_sync),
+            args.matrix_continue,
+        );
+    }
+
+    let mut target_names = args.targets;
+    target_names.splice(0..0, args.target_name);
+    if target_names.is_empty() {
+        if let Some(default) = &config.default_target {
+            let exclusive = [
+                (args.clean_sync, "--clean-sync"),
+                (args.dry_run, "--dry-run"),
+                (args.artifacts_only, "--artifacts-only"),
+            ];
+            if let Some((_, flag)) = exclusive.iter().find(|(set, _)| *set) {
+                return Err(anyhow!(
+                    "{} can't be used with targets, and default_target selects {}",
+                    flag,
+                    default
+                ));
+            }
+            target_names.push(default.clone());
+        }
+    }
+    if !target_names.is_empty() {
+        return targets::run_target_builds(
+            &project_dir,
+            &config,
+            &target_names,
+            SyncScope::full_if(args.force_full_sync),
+        );
+    }
+
+    if config.build_command.is_empty() && !args.artifacts_only {
+        return Err(anyhow!(
+            "No build_command configured: set build_command in {} or pass --build-command",
+            config_path.display()
+        ));
+    }
 
     // Run the remote build
-    run_remote_build(&project_dir, &config, args.force_full_sync)?;
+    let scope = if args.clean_sync {
+        SyncScope::Clean
+    } else {
+        SyncScope::full_if(args.force_full_sync)
+    };
+    if args.watch {
+        return watch::run_watch(&project_dir, &config, scope);
+    }
+    run_remote_build(&project_dir, &config, scope)?;
+
+    Ok(())
+}
+
+/// Sync the project to the remote and say where it went, for `remotebuild
+/// sync`
+fn run_sync(project_dir: &Path, config: &Config, scope: SyncScope) -> Result<()> {
+    ensure_ssh_connection(config)?;
+    sync_to_remote(project_dir, config, scope)?;
+    if !matches!(config.output_level(), OutputLevel::Quiet) && !config.dry_run {
+        println!("✅ Synced to {}:{}", config.host, config.remote_path);
+    }
+    Ok(())
+}
+
+/// Show the recent build history recorded on the remote
+fn show_status(config: &Config, limit: usize) -> Result<()> {
+    ensure_ssh_connection(config)?;
+
+    match shared::active_builds(config) {
+        Ok(builds) if !builds.is_empty() => {
+            println!("🔒 Running on {}", config.host);
+            shared::print_active(&builds);
+            println!();
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("   ⚠ Warning: Could not check running builds: {}", e),
+    }
+
+    let entries = history::fetch_recent(config, limit)?;
+
+    if entries.is_empty() {
+        println!(
+            "No builds recorded in {}:{} yet",
+            config.host, config.remote_path
+        );
+        return Ok(());
+    }
+
+    println!("📊 Recent builds in {}:{}", config.host, config.remote_path);
+    history::print_entries(&entries);
 
     Ok(())
 }
 
+/// Build the config used when there is no config file
+///
+/// Options not given as flags get the same defaults as in a config file.
+fn zero_config() -> Result<Config> {
+    serde_yaml::from_str("{}").context("Failed to build default config")
+}
+
+/// Remote directory for a project without a configured `remote_path`
+///
+/// Named after the project directory plus a hash of its absolute path, so
+/// projects sharing a host never sync over each other.
+pub(crate) fn default_project_remote_path(project_dir: &Path) -> String {
+    format!(
+        "{}/{}",
+        default_remote_path(),
+        state::project_key(project_dir)
+    )
+}
+
 /// Load and parse the configuration file from the given path
 fn load_config(path: &Path) -> Result<Config> {
     let content = fs::read_to_string(path)
         .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+    parse_config(
+        &content,
+        &path.display().to_string(),
+        ConfigFormat::from_path(path),
+        Some(path),
+    )
+}
+
+/// Parse a configuration read from `file` (stdin when `None`), laid over the
+/// configs it extends and the global config, `source` naming where it came
+/// from in errors
+fn parse_config(
+    content: &str,
+    source: &str,
+    format: ConfigFormat,
+    file: Option<&Path>,
+) -> Result<Config> {
+    let parse_error = |e: anyhow::Error| anyhow!("Failed to parse config file: {} - {}", source, e);
+    let mut unknown = Vec::new();
+    let global = global_config::load()?;
+    let project = format.to_yaml(content).map_err(parse_error)?;
+    let (mut config, source): (Config, Cow<str>) =
+        if global.is_none() && !extends::extends(&project) {
+            // Parsing the text directly keeps line numbers in errors
+            (
+                format.parse(content, &mut unknown).map_err(parse_error)?,
+                source.into(),
+            )
+        } else {
+            let (mut merged, bases) = extends::resolve(project, file)?;
+            let mut source = source.to_string();
+            if !bases.is_empty() {
+                let bases: Vec<String> = bases
+                    .iter()
+                    .map(|base| base.display().to_string())
+                    .collect();
+                source = format!("{} (extending {})", source, bases.join(", "));
+            }
+            if let Some((global, global_path)) = global {
+                source = format!("{} (merged with {})", source, global_path.display());
+                merged = global_config::merge(global, merged);
+            }
+            let config = serde_ignored::deserialize(merged, |path| {
+                unknown.push(unknown_keys::segments(&path));
+            })
+            .map_err(|e| anyhow!("Failed to parse config file: {} - {}", source, e))?;
+            (config, source.into())
+        };
+    unknown_keys::check(&config, &unknown, &source)?;
+    interpolate::apply(&mut config).with_context(|| format!("Invalid config file: {}", source))?;
+    config.remote_path = shared::expand_user(&config.remote_path);
+    config.cache_path = config.cache_path.as_deref().map(shared::expand_user);
+    permissions::validate(&config).with_context(|| format!("Invalid config file: {}", source))?;
+    large_files::validate(&config).with_context(|| format!("Invalid config file: {}", source))?;
+    sync_back::validate(&config).with_context(|| format!("Invalid config file: {}", source))?;
+    transport::validate(&config).with_context(|| format!("Invalid config file: {}", source))?;
+    ssh_options::validate(&config).with_context(|| format!("Invalid config file: {}", source))?;
+    ssh_master::validate(&config).with_context(|| format!("Invalid config file: {}", source))?;
+    if let Some(rate) = &config.bwlimit {
+        bwlimit::parse(rate).with_context(|| format!("Invalid config file: {}", source))?;
+    }
+    if !matches!(config.sync_mode.as_str(), "fast" | "checksum") {
+        return Err(anyhow!(
+            "Invalid config file: {} - invalid sync_mode: {} (expected fast or checksum)",
+            source,
+            config.sync_mode
+        ));
+    }
+    if let Some(compare) = &config.artifact_compare {
+        if !matches!(compare.as_str(), "checksum" | "size" | "mtime") {
+            return Err(anyhow!(
+                "Invalid config file: {} - invalid artifact_compare: {} (expected checksum, size \
+                 or mtime)",
+                source,
+                compare
+            ));
+        }
+    }
+    Ok(config)
+}
 
-    serde_yaml::from_str(&content)
-        .map_err(|e| anyhow!("Failed to parse config file: {} - {}", path.display(), e))
+/// Keep a config given by absolute path out of the sync if it lies inside
+/// the project, since it belongs to the caller rather than the project
+fn exclude_explicit_config(
+    project_dir: &Path,
+    config_arg: &str,
+    config_path: &Path,
+    config: &mut Config,
+) {
+    if !Path::new(config_arg).is_absolute() {
+        return;
+    }
+    let config_path = config_path
+        .canonicalize()
+        .unwrap_or_else(|_| config_path.to_path_buf());
+    if let Ok(relative) = config_path.strip_prefix(project_dir) {
+        config
+            .exclude_patterns
+            .push(format!("/{}", relative.to_string_lossy()));
+    }
 }
 
 /// Main entry point for running a remote build
-fn run_remote_build(project_dir: &Path, config: &Config, force_full_sync: bool) -> Result<()> {
+fn run_remote_build(project_dir: &Path, config: &Config, scope: SyncScope) -> Result<()> {
+    run_cancellable_build(project_dir, config, scope, &CancelToken::default())
+}
+
+/// Run a remote build that `token` or a newer build of the same tree cancels
+fn run_cancellable_build(
+    project_dir: &Path,
+    config: &Config,
+    scope: SyncScope,
+    token: &CancelToken,
+) -> Result<()> {
     let output = config.output_level();
 
     match output {
-        OutputLevel::Minimal => {
+        OutputLevel::Quiet | OutputLevel::Minimal => {
             // No initial message for minimal mode
         }
         OutputLevel::Normal | OutputLevel::Verbose => {
             println!("🚀 Remote Build Proxy");
-            println!("   Host: {}", config.host);
+            match &config.replaced_host {
+                Some(replaced) => {
+                    println!("   Host: {} (--host, instead of {})", config.host, replaced)
+                }
+                None => println!("   Host: {}", config.host),
+            }
+            println!("   Remote path: {}", config.remote_path);
             println!("   Project: {}", project_dir.display());
             println!();
         }
     }
 
-    // Step 1: Sync files to remote
-    sync_to_remote(project_dir, config, force_full_sync)?;
+    // Hooks, setup and the build history are skipped along with the build
+    if config.dry_run {
+        return dry_run::run(project_dir, config, scope);
+    }
+    if config.artifacts_only {
+        return run_artifacts_only(project_dir, config);
+    }
 
-    // Step 2: Run build command on remote and stream output
-    run_remote_build_command(config)?;
+    // Released on return, once the previous build of the tree has stopped
+    let cancel = Cancellation::start(project_dir, config, token);
+
+    let guard = abort::RunGuard::new(config.output_prefix.clone());
+    estimate::load(project_dir);
+    let mut report = RunReport::new(project_dir, config);
+    guard.enter("pre-sync hook");
+    hooks::run_hook(project_dir, config, Hook::PreSync, &report)?;
+
+    let result = run_build_phases(project_dir, config, scope, &mut report, &guard, &cancel);
+    if let Err(e) = &result {
+        report.exit_code = e
+            .downcast_ref::<BuildFailed>()
+            .and_then(|b| b.status.code());
+    }
+
+    // Record the build on the remote once the build command has run
+    if let Some(build) = report.phases.iter().find(|p| p.name == "build") {
+        let duration = Duration::from_secs_f64(build.duration_secs);
+        let mut tags = history::RunTags::new(scope);
+        if let Some(sync) = report.phases.iter().find(|p| p.name == "sync") {
+            tags = tags.with_sync(Duration::from_secs_f64(sync.duration_secs));
+        }
+        history::record_build(project_dir, config, report.exit_code, duration, tags);
+        snapshot::after_build(project_dir, config, report.exit_code == Some(0));
+        if report.exit_code == Some(0) {
+            cache_archive::record_build(project_dir, config);
+        }
+    }
 
-    // Step 3: Copy artifacts back
-    sync_artifacts(config)?;
+    if let Err(e) = result {
+        // A superseded run didn't fail; the newer one reports on the tree
+        if e.downcast_ref::<Superseded>().is_some() {
+            return Err(e);
+        }
+        report.error = Some(e.to_string());
+        if let Err(hook_error) = hooks::run_hook(project_dir, config, Hook::OnFailure, &report) {
+            eprintln!("   ⚠ Warning: {}", hook_error);
+        }
+        return Err(e);
+    }
 
     match output {
-        OutputLevel::Minimal => {
+        OutputLevel::Quiet | OutputLevel::Minimal => {
             // No final message for minimal mode - spinner cleanup is enough
         }
         OutputLevel::Normal | OutputLevel::Verbose => {
@@ -306,12 +2074,190 @@ fn run_remote_build(project_dir: &Path, config: &Config, force_full_sync: bool)
         }
     }
 
+    gc::run_after_build(project_dir, config);
+    Ok(())
+}
+
+/// Download the artifacts the last build left on the remote, without the
+/// sync, the build or their hooks
+fn run_artifacts_only(project_dir: &Path, config: &Config) -> Result<()> {
+    let output = config.output_level();
+    if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) {
+        println!("   ℹ Skipping sync and build (--artifacts-only)");
+        println!();
+    }
+    fetch_artifacts(project_dir, config)
+}
+
+/// Download the configured artifacts from the remote as the last build left
+/// them, for `--artifacts-only` and `remotebuild fetch`
+fn fetch_artifacts(project_dir: &Path, config: &Config) -> Result<()> {
+    let output = config.output_level();
+    ensure_ssh_connection(config)?;
+    check_remote_path(config)?;
+    sync_back::fetch(config, project_dir)?;
+    let mut reconnects = resilient::Reconnects::default();
+    resilient::retry(config, &mut reconnects, "Artifact download", || {
+        sync_artifacts(config, project_dir, &config.artifact_dir(project_dir))
+    })?;
+
+    if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) && !config.dry_run {
+        println!("✅ Artifact download complete!");
+    }
+    Ok(())
+}
+
+/// Fail when the remote path is missing, rather than warning about every
+/// artifact relative to it
+///
+/// Artifacts with absolute or home-relative paths don't need it.
+fn check_remote_path(config: &Config) -> Result<()> {
+    let relative = config
+        .artifacts
+        .iter()
+        .any(|artifact| !artifact.remote.starts_with(['/', '~']));
+    if !relative {
+        return Ok(());
+    }
+    let script = format!("[ -d {} ] || echo missing", config.remote_dir().shell());
+    if run_ssh_command_output(config, &script)?.trim() == "missing" {
+        return Err(anyhow!(
+            "Remote path {} does not exist on {}; has a build ever run there?",
+            config.remote_path,
+            config.host
+        ));
+    }
+    Ok(())
+}
+
+/// Run the sync, build and artifact phases, recording each in the report and
+/// stopping between them once `cancel` says so
+fn run_build_phases(
+    project_dir: &Path,
+    config: &Config,
+    scope: SyncScope,
+    report: &mut RunReport,
+    guard: &abort::RunGuard,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let mut reconnects = resilient::Reconnects::default();
+
+    // Step 0: Make sure the build container can run before syncing
+    if config.docker.is_some() {
+        guard.enter("container check");
+        let start = Instant::now();
+        let result = resilient::retry(config, &mut reconnects, "Container check", || {
+            container::prepare(config)
+        });
+        report.record("container", start.elapsed(), result.is_ok());
+        result?;
+    }
+    nix::check_installed(config)?;
+
+    // Step 1: Sync files to remote
+    if !config.presynced {
+        cancel.check()?;
+        guard.enter("sync");
+        let start = Instant::now();
+        let result = resilient::retry(config, &mut reconnects, "Sync", || {
+            sync_to_remote(project_dir, config, scope)
+        });
+        report.record("sync", start.elapsed(), result.is_ok());
+        report.reconnects = reconnects.count;
+        result?;
+        report.compression = Some(compression::current(config).describe());
+
+        if config.setup_command.is_some() {
+            guard.enter("setup");
+            let start = Instant::now();
+            let result = setup::run_setup(config);
+            report.record("setup", start.elapsed(), result.is_ok());
+            result?;
+        }
+    }
+
+    // The nix shell is evaluated from the synced flake or shell file
+    if config.nix.is_some() {
+        guard.enter("nix shell");
+        let start = Instant::now();
+        let result = nix::enter_shell(config);
+        report.record("nix", start.elapsed(), result.is_ok());
+        result?;
+    }
+
+    // Step 2: Run build command on remote and stream output
+    cancel.check()?;
+    guard.enter("build");
+    let start = Instant::now();
+    let mut build = || {
+        cancel.during_build(config, || {
+            if config.resilient {
+                resilient::run_build(config, &mut reconnects)
+            } else {
+                resilient::retry_lost(config, &mut reconnects, "Build", || {
+                    run_remote_build_command(config)
+                })
+            }
+        })
+    };
+    let local_dir = config.artifact_dir(project_dir);
+    // Eager downloads rely on rsync skipping what it already copied
+    let eager = config.eager_artifacts
+        && !config.artifacts.is_empty()
+        && !config.no_artifacts
+        && !transport::uses_tar(config);
+    let (result, fetched) = if eager {
+        fs::create_dir_all(&local_dir)
+            .with_context(|| format!("Failed to create artifact dir: {}", local_dir.display()))?;
+        let (result, fetched) = eager::during_build(config, &local_dir, build);
+        (result, Some(fetched))
+    } else {
+        (build(), None)
+    };
+    report.record("build", start.elapsed(), result.is_ok());
+    report.reconnects = reconnects.count;
+    result?;
+    report.exit_code = Some(0);
+    guard.enter("post-build hook");
+    hooks::run_hook(project_dir, config, Hook::PostBuild, report)?;
+
+    // Generated files for local tools come back even without artifacts
+    if !config.sync_back.is_empty() {
+        guard.enter("sync back");
+        let start = Instant::now();
+        let result = sync_back::fetch(config, project_dir);
+        report.record("sync back", start.elapsed(), result.is_ok());
+        result?;
+    }
+
+    // Step 3: Copy artifacts back; the post-artifacts hook would find stale
+    // ones without the download
+    if config.no_artifacts {
+        return sync_artifacts(config, project_dir, &local_dir);
+    }
+    cancel.check()?;
+    guard.enter("artifact download");
+    let start = Instant::now();
+    let result = match fetched {
+        // Only what wasn't fetched during the build, or changed since
+        Some(fetched) => eager::finish(config, project_dir, &local_dir, fetched),
+        None => resilient::retry(config, &mut reconnects, "Artifact download", || {
+            sync_artifacts(config, project_dir, &local_dir)
+        }),
+    };
+    report.record("artifacts", start.elapsed(), result.is_ok());
+    report.reconnects = reconnects.count;
+    result?;
+    guard.enter("post-artifacts hook");
+    hooks::run_hook(project_dir, config, Hook::PostArtifacts, report)?;
+
     Ok(())
 }
 
 /// Print a status message that can be overwritten
 fn print_status(level: OutputLevel, message: &str) -> Option<Spinner> {
     match level {
+        OutputLevel::Quiet => None,
         OutputLevel::Minimal => {
             let mut spinner = Spinner::new(message);
             spinner.tick(); // Show first frame immediately
@@ -336,109 +2282,631 @@ fn clear_status(_level: OutputLevel, spinner: &mut Option<Spinner>) {
 }
 
 /// Sync project files to the remote server using rsync
-fn sync_to_remote(project_dir: &Path, config: &Config, force_full_sync: bool) -> Result<()> {
+fn sync_to_remote(project_dir: &Path, config: &Config, scope: SyncScope) -> Result<()> {
     let output = config.output_level();
 
-    let mut spinner = print_status(output, "📦 Syncing files ");
-
-    // Ensure SSH connection is established for reuse
-    ensure_ssh_connection(config)?;
+    detect::announce(config);
+    let mut spinner = print_status(output, &estimate::sync_message(config));
 
-    // Use remote_path as-is (it should be the full destination path)
-    let remote_full_path = &config.remote_path;
+    // Establish the SSH connection in the background while the file list is
+    // prepared locally; it is joined before the first remote command
+    let connection = connect_in_background(config);
 
-    // Create remote directory if it doesn't exist
-    let mkdir_cmd = format!(
-        "mkdir -p {}",
-        escape(Cow::Borrowed(remote_full_path.as_str()))
-    );
-    run_ssh_command(config, &mkdir_cmd)?;
+    let remote_dir = config.remote_dir();
 
     // Build rsync command
-    let mut rsync_cmd = Command::new("rsync");
-    rsync_cmd.arg("-avz");
+    let mut rsync_cmd = rsync_command(config);
+    rsync_cmd.arg("-av");
 
+    // Minimal and normal mode read the transfer progress instead of the
+    // file names, which old rsyncs can't report
+    let show_progress = matches!(output, OutputLevel::Minimal | OutputLevel::Normal)
+        && !config.dry_run
+        && rsync_version::supported_locally(config, rsync_version::Feature::InfoProgress2);
     match output {
+        _ if config.dry_run => rsync_cmd.args(["--dry-run", "--itemize-changes"]),
         OutputLevel::Verbose => rsync_cmd.arg("-v"),
+        _ if show_progress => rsync_cmd.arg("--info=progress2,name0"),
         _ => rsync_cmd.arg("--quiet"),
     };
+    // --quiet would hide the statistics anyway
+    if show_progress || (matches!(output, OutputLevel::Verbose) && !config.dry_run) {
+        rsync_cmd.arg("--stats");
+    }
 
-    // Add delete flag to keep remote in sync
-    rsync_cmd.arg("--delete");
+    rsync_cmd.args(config.checksum_arg());
 
-    // Add SSH control path for connection reuse
+    // Add delete flag to keep remote in sync, unless configured otherwise;
+    // a clean sync always deletes
+    let delete_mode = DeleteMode::parse(&config.sync_delete)?;
+    if delete_mode.deletes() || scope == SyncScope::Clean {
+        rsync_cmd.arg("--delete");
+    }
+    // Relative, so every receiving directory keeps its own; the built-in
+    // excludes keep --delete away from it
+    if config.resumes() {
+        rsync_cmd.arg(format!("--partial-dir={}", PARTIAL_DIR));
+    }
+
+    // Add SSH control path for connection reuse
     rsync_cmd.arg("-e").arg(ssh_control_path_arg(config));
 
-    // Add exclusions
-    rsync_cmd.arg("--exclude=.git");
-    rsync_cmd.arg("--exclude=.gitignore");
-    rsync_cmd.arg("--exclude=*.nds");
-    rsync_cmd.arg("--exclude=*.elf");
-    rsync_cmd.arg("--exclude=build/");
-    rsync_cmd.arg("--exclude=.ninja_*");
-    rsync_cmd.arg("--exclude=compile_commands.json");
-
-    for pattern in &config.exclude_patterns {
-        rsync_cmd.arg(format!("--exclude={}", pattern));
-    }
-
-    // If git-aware and not forcing full sync, only sync tracked and new files
-    let temp_file = if config.git_aware && !force_full_sync {
-        if let Ok(tracked_files) = get_git_files(project_dir) {
-            if !tracked_files.is_empty() {
-                // Use --files-from to sync only tracked files
-                // We need to write the list to a temp file
-                let temp_dir = dirs::cache_dir().unwrap_or_else(env::temp_dir);
-                let temp_file = temp_dir.join(format!("remotebuild_{}", std::process::id()));
+    // Add exclusions. A clean sync also deletes excluded files remotely, except
+    // for the built-in, detected and ignored excludes, which cover build
+    // output and metadata
+    let exclude_patterns = sync_exclude_patterns(config, true);
+    if matches!(output, OutputLevel::Verbose) {
+        println!(
+            "   Excludes, last match wins: {}",
+            exclude_patterns.join(" ")
+        );
+        if !config.include_patterns.is_empty() {
+            println!(
+                "   Includes, overriding them: {}",
+                config.include_patterns.join(" ")
+            );
+        }
+        if scope == SyncScope::Clean {
+            println!("   Remote deletion: clean sync (files missing locally or excluded)");
+        } else {
+            println!("   Remote deletion: {}", delete_mode.describe());
+        }
+    }
+    let mut filter_args = Vec::new();
+    if scope == SyncScope::Clean || delete_mode == DeleteMode::ExcludedToo {
+        filter_args.push("--delete-excluded".to_string());
+        let detected = config.detected_excludes.iter().map(|d| d.pattern.as_str());
+        let ignored = config
+            .gitignore_excludes
+            .iter()
+            .map(String::as_str)
+            .filter(|p| !p.starts_with('!'));
+        for pattern in config
+            .builtin_excludes()
+            .into_iter()
+            .chain(detected)
+            .chain(ignored)
+        {
+            filter_args.push(format!("--filter=P {}", pattern));
+        }
+        for pattern in sync_back::excludes(config) {
+            filter_args.push(format!("--filter=P {}", pattern));
+        }
+    }
+    filter_args.extend(sync_excludes(config, true).rsync_args());
+    rsync_cmd.args(&filter_args);
+    rsync_cmd.args(permissions::rsync_args(config)?);
 
-                fs::write(&temp_file, tracked_files.join("\n"))?;
+    // If git-aware and not forcing full sync, only sync tracked and new files.
+    // Files deleted without `git rm` are still tracked, and rsync fails on
+    // listed paths that don't exist, so they are taken out of the list
+    let mut listed_gone = Vec::new();
+    let mut file_list: Option<Vec<String>> = if config.git_aware && scope == SyncScope::Changed {
+        // rsync doesn't apply --exclude to paths listed explicitly in
+        // --files-from, so the list is filtered with the same rules first.
+        // Git already left out what it ignores, and tracked files are synced
+        // even if ignored. Included files git doesn't list are added.
+        let excludes = sync_excludes(config, false);
+        get_git_files(project_dir, config.sync_submodules)
+            .ok()
+            .filter(|tracked_files| !tracked_files.is_empty())
+            .map(|tracked_files| {
+                let mut files: Vec<String> = tracked_files
+                    .into_iter()
+                    .filter(|file| !excludes.excludes_file(file))
+                    .collect();
+                if !config.include_patterns.is_empty() {
+                    let mut included = Vec::new();
+                    collect_included_files(
+                        project_dir,
+                        "",
+                        &sync_excludes(config, true),
+                        &mut included,
+                    );
+                    let listed: HashSet<&String> = files.iter().collect();
+                    included.retain(|file| !listed.contains(file));
+                    files.extend(included);
+                }
+                listed_gone = sync_delete::take_gone(project_dir, &mut files);
+                files
+            })
+    } else {
+        None
+    };
 
-                rsync_cmd.arg(format!("--files-from={}", temp_file.display()));
+    // The git file list, remembered so the next sync can delete what
+    // disappears from it
+    let git_list = file_list.clone();
 
-                Some(temp_file)
-            } else {
-                None
+    if let Some((limit, action)) = large_files::limit(config)? {
+        let large = large_files::find(project_dir, config, git_list.as_deref(), limit);
+        if !large.is_empty() {
+            clear_status(output, &mut spinner);
+            large_files::report(config, limit, action, &large)?;
+            spinner = print_status(output, &estimate::sync_message(config));
+        }
+        rsync_cmd.args(large_files::rsync_arg(limit, action));
+    }
+
+    // Without a git file list, fall back to the content-hash manifest if enabled
+    let mut new_manifest = None;
+    let mut delete_missing = false;
+    if file_list.is_none() && config.manifest_sync {
+        let excludes = sync_excludes(config, true);
+        let options = format!(
+            "{:016x}",
+            stable_hash(
+                format!(
+                    "{}\n{}\n{}\n{}",
+                    config.host,
+                    config.remote_path,
+                    exclude_patterns.join("\n"),
+                    config.include_patterns.join("\n")
+                )
+                .as_bytes()
+            )
+        );
+        // --force-full-sync rehashes everything and compares the whole tree
+        let previous = Manifest::load(project_dir, config)
+            .filter(|m| m.has_options(&options) && scope == SyncScope::Changed);
+        let manifest = Manifest::build(project_dir, &excludes, options, previous.as_ref())?;
+        if let Some(previous) = previous {
+            let diff = manifest.diff(&previous);
+            if matches!(output, OutputLevel::Verbose) {
+                println!(
+                    "   Manifest: {} changed, {} deleted ({} files total)",
+                    diff.changed.len(),
+                    diff.deleted.len(),
+                    manifest.len()
+                );
             }
+
+            // Deleted paths are listed too so rsync removes them remotely
+            let deleted = if delete_mode.deletes() {
+                diff.deleted
+            } else {
+                Vec::new()
+            };
+            delete_missing = !deleted.is_empty();
+            file_list = Some(diff.changed.into_iter().chain(deleted).collect());
+        }
+
+        new_manifest = Some(manifest);
+    }
+
+    // The connection is needed from here on
+    connection.wait()?;
+    let tar = transport::uses_tar(config);
+    if tar {
+        if scope == SyncScope::Clean {
+            clear_status(output, &mut spinner);
+            return Err(anyhow!(
+                "--clean-sync needs rsync, as tar can't find stale files on {}",
+                config.host
+            ));
+        }
+        clear_status(output, &mut spinner);
+        transport::announce(config);
+        spinner = print_status(output, &estimate::sync_message(config));
+    } else {
+        rsync_cmd.args(compression::choose(project_dir, config)?.rsync_args());
+    }
+    if delete_missing {
+        if !tar
+            && rsync_version::supports(
+                config,
+                rsync_version::Feature::DeleteMissingArgs,
+                "manifest_sync transfers the whole tree instead",
+            )
+        {
+            rsync_cmd.arg("--delete-missing-args");
         } else {
-            None
+            file_list = None;
+        }
+    }
+    let mut remote_removals = Vec::new();
+    if let (Some(files), true) = (&mut file_list, git_list.is_some()) {
+        if delete_mode.deletes() {
+            let mut deleted = sync_delete::missing(project_dir, config, files);
+            listed_gone.retain(|path| !deleted.contains(path));
+            deleted.extend(listed_gone);
+            if !deleted.is_empty() {
+                if matches!(output, OutputLevel::Verbose) {
+                    println!("   Deleting {} file(s) missing locally", deleted.len());
+                }
+                if !tar
+                    && rsync_version::supports(
+                        config,
+                        rsync_version::Feature::DeleteMissingArgs,
+                        "files deleted locally are removed with rm instead",
+                    )
+                {
+                    files.extend(deleted);
+                    rsync_cmd.arg("--delete-missing-args");
+                } else {
+                    remote_removals = deleted;
+                }
+            }
+        } else if !listed_gone.is_empty() && matches!(output, OutputLevel::Verbose) {
+            println!(
+                "   Skipping {} file(s) git lists that don't exist locally: {}",
+                listed_gone.len(),
+                listed_gone.join(" ")
+            );
         }
+    }
+
+    // Create remote directory if it doesn't exist; a dry run only checks
+    let mut mkdir_cmd = format!(
+        "[ -d {dir} ] || echo {marker}",
+        dir = remote_dir.shell(),
+        marker = CREATED_MARKER
+    );
+    let check = identity::check_command(config, project_dir, &identity::project_id(project_dir)?);
+    if config.dry_run {
+        mkdir_cmd = format!(
+            "{}; if cd {} 2>/dev/null; then {}; fi",
+            mkdir_cmd,
+            remote_dir.shell(),
+            check
+        );
     } else {
-        None
+        mkdir_cmd = format!(
+            "{{ {}; }} && {} && cd {} && {}",
+            mkdir_cmd,
+            permissions::mkdir_command(config, &remote_dir)?,
+            remote_dir.shell(),
+            check
+        );
+    }
+    // Buffered rather than streamed: stdout is only the created marker and the
+    // identity check's few lines, which are parsed here and not meant to be
+    // shown as command output
+    let mkdir = remote_command(config, &mkdir_cmd)
+        .output()
+        .context("Failed to run SSH command")?;
+    if !mkdir.status.success() {
+        clear_status(output, &mut spinner);
+        return Err(resilient::ssh_error(
+            mkdir.status,
+            format!(
+                "Failed to create remote directory {} ({}){}",
+                config.remote_path,
+                mkdir.status,
+                indented_tail(&String::from_utf8_lossy(&mkdir.stderr))
+            ),
+        ));
+    }
+    let mkdir_stdout = String::from_utf8_lossy(&mkdir.stdout);
+    let created = mkdir_stdout.lines().any(|line| line == CREATED_MARKER);
+    if let Some(owner) = identity::other_project(&mkdir_stdout) {
+        clear_status(output, &mut spinner);
+        identity::take_over(config, &owner)?;
+        spinner = print_status(output, &estimate::sync_message(config));
+    }
+
+    if scope == SyncScope::Clean && !config.dry_run {
+        clear_status(output, &mut spinner);
+        confirm_clean_sync(project_dir, config, &filter_args)?;
+    }
+
+    // tar sends the git file list or the whole tree
+    let mode = match (&file_list, &new_manifest) {
+        _ if tar && git_list.is_none() => "full",
+        (None, _) => "full",
+        (Some(_), None) => "git",
+        (Some(_), Some(_)) => "manifest",
     };
 
-    // Add source and destination
-    rsync_cmd.arg(format!("{}/", project_dir.display()));
-    rsync_cmd.arg(format!("{}:{}/", config.host, remote_full_path));
+    // Without rsync on the remote, everything goes over in one tar stream
+    let start = Instant::now();
+    let (progress, stats) = if tar {
+        let files = transport::files_to_send(project_dir, config, git_list.as_deref());
+        if config.dry_run {
+            clear_status(output, &mut spinner);
+            return dry_run::report_tar(config, files.len(), created, &remote_removals);
+        }
+        if let Err(e) = transport::upload(config, project_dir, &files) {
+            clear_status(output, &mut spinner);
+            if new_manifest.is_some() {
+                Manifest::invalidate(project_dir, config);
+            }
+            return Err(e);
+        }
+        let stats = Stats {
+            files: Some(files.len() as u64),
+            ..Stats::default()
+        };
+        (None, Some(stats))
+    } else {
+        // Use --files-from to sync only the listed files
+        // We need to write the list to a temp file
+        let temp_file = match file_list {
+            Some(files) => {
+                let temp_dir = dirs::cache_dir().unwrap_or_else(env::temp_dir);
+                let temp_file = temp_dir.join(format!("remotebuild_{}", std::process::id()));
 
-    // Run rsync
-    let status = rsync_cmd
-        .status()
-        .context("Failed to run rsync. Make sure rsync is installed.")?;
+                write_files_from(&temp_file, &files)?;
+
+                let mut files_from = OsString::from("--files-from=");
+                files_from.push(&temp_file);
+                rsync_cmd.arg(files_from).arg("--from0");
+
+                Some(temp_file)
+            }
+            None => None,
+        };
+
+        // Add source and destination
+        rsync_cmd.arg(dir_contents(project_dir));
+        rsync_cmd.arg(remote_dir.rsync(&config.host, ""));
+
+        // A dry run lists what rsync would change, leaving the remembered
+        // state as it is
+        if config.dry_run {
+            let result = rsync_cmd
+                .output()
+                .context("Failed to run rsync. Make sure rsync is installed.");
+            if let Some(temp_file) = &temp_file {
+                let _ = fs::remove_file(temp_file);
+            }
+            clear_status(output, &mut spinner);
+            return dry_run::report_sync(config, &result?, created, &remote_removals);
+        }
+
+        // Run rsync, again after an interruption
+        let mut attempt = 1;
+        let (status, stderr, progress, stats) = loop {
+            let run = if show_progress {
+                run_rsync_with_progress(&mut rsync_cmd, output, &mut spinner)
+            } else {
+                run_rsync(&mut rsync_cmd, output)
+                    .map(|(status, stderr)| (status, stderr, None, None))
+            }
+            .context("Failed to run rsync. Make sure rsync is installed.")?;
+            if !resumable::should_retry(config, run.0, attempt) {
+                break run;
+            }
+            clear_status(output, &mut spinner);
+            resumable::wait(config, "Sync", run.0, &run.1, attempt);
+            spinner = print_status(output, &estimate::sync_message(config));
+            attempt += 1;
+        };
 
-    // Clean up temp file if we created one
-    if let Some(temp_file) = temp_file {
-        let _ = fs::remove_file(&temp_file);
+        // Clean up temp file if we created one
+        if let Some(temp_file) = temp_file {
+            let _ = fs::remove_file(&temp_file);
+        }
+
+        if !status.success() {
+            clear_status(output, &mut spinner);
+            if new_manifest.is_some() {
+                Manifest::invalidate(project_dir, config);
+            }
+            return Err(resilient::rsync_error(
+                status,
+                format!(
+                    "rsync failed with {}{}{}",
+                    status,
+                    resumable::attempts(attempt),
+                    indented_tail(&stderr)
+                ),
+            ));
+        }
+        (progress, stats)
+    };
+    let elapsed = start.elapsed();
+
+    clear_status(output, &mut spinner);
+    if created && !matches!(output, OutputLevel::Quiet) {
+        println!("   ✓ Created remote directory {}", config.remote_path);
     }
+    record_sync_mode(project_dir, config, mode, output);
 
-    if !status.success() {
-        clear_status(output, &mut spinner);
-        return Err(anyhow!("rsync failed with exit code: {:?}", status));
+    if !remote_removals.is_empty() {
+        sync_delete::remove_remote(config, &remote_removals)?;
+    }
+    if config.fix_permissions {
+        // Only files the sync sent or could have sent are on the remote
+        let excludes = sync_excludes(config, true);
+        let listed: Option<HashSet<&str>> = git_list
+            .as_ref()
+            .map(|files| files.iter().map(String::as_str).collect());
+        let synced = |path: &str| match &listed {
+            Some(listed) => listed.contains(path),
+            None => !excludes.excludes_file(path),
+        };
+        let (executable, plain) =
+            permissions::git_mode_mismatches(project_dir, config.sync_submodules, synced)?;
+        if !executable.is_empty() || !plain.is_empty() {
+            if matches!(output, OutputLevel::Verbose) {
+                println!(
+                    "   Restoring the mode git records for {} file(s)",
+                    executable.len() + plain.len()
+                );
+            }
+            permissions::fix_remote_modes(config, &executable, &plain)?;
+        }
+    }
+    if let Some(files) = git_list {
+        if let Err(e) = sync_delete::save_list(project_dir, config, &files) {
+            eprintln!("   ⚠ Warning: Could not save git file list: {}", e);
+        }
     }
 
-    clear_status(output, &mut spinner);
+    // Only remember the manifest once the remote is known to match it
+    if let Some(manifest) = new_manifest {
+        if let Err(e) = manifest.save(project_dir, config) {
+            eprintln!("   ⚠ Warning: Could not save sync manifest: {}", e);
+        }
+    }
 
+    match (output, stats, progress) {
+        (OutputLevel::Minimal | OutputLevel::Normal, Some(stats), _) => {
+            println!("   ✓ Synced {}", stats.sent_summary(elapsed))
+        }
+        (OutputLevel::Normal, None, Some(progress)) => {
+            println!("   ✓ Sync complete ({})", progress.summary())
+        }
+        (OutputLevel::Normal, None, None) => println!("   ✓ Sync complete"),
+        _ => {}
+    }
     if matches!(output, OutputLevel::Normal) {
-        println!("   ✓ Sync complete");
         println!();
     }
 
     Ok(())
 }
 
-/// Get the list of files tracked by git in the project directory
-fn get_git_files(project_dir: &Path) -> Result<Vec<String>> {
-    let output = Command::new("git")
-        .args(["ls-files"])
+/// List what a clean sync would delete remotely and ask before deleting it
+///
+/// Asks only when stdin is a terminal; `--clean-sync` itself is taken as
+/// consent otherwise.
+fn confirm_clean_sync(project_dir: &Path, config: &Config, filter_args: &[String]) -> Result<()> {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    let output = rsync_command(config)
+        .args(["-a", "--dry-run", "--itemize-changes", "--delete"])
+        .arg("-e")
+        .arg(ssh_control_path_arg(config))
+        .args(filter_args)
+        .arg(dir_contents(project_dir))
+        .arg(config.remote_dir().rsync(&config.host, ""))
+        .output()
+        .context("Failed to run rsync. Make sure rsync is installed.")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "rsync failed with {}{}",
+            output.status,
+            indented_tail(&String::from_utf8_lossy(&output.stderr))
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let deletions: Vec<&str> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("*deleting"))
+        .map(str::trim)
+        .collect();
+    if deletions.is_empty() {
+        return Ok(());
+    }
+
+    println!(
+        "🧹 Clean sync will delete {} remote paths:",
+        deletions.len()
+    );
+    for path in &deletions {
+        println!("   - {}", path);
+    }
+
+    if std::io::stdin().is_terminal() {
+        print!("Continue? [y/N] ");
+        std::io::stdout().flush().ok();
+        let mut answer = String::new();
+        std::io::stdin().lock().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Err(anyhow!("Clean sync cancelled"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Remember the sync mode for this destination, suggesting `--clean-sync`
+/// when it differs from the previous sync
+fn record_sync_mode(project_dir: &Path, config: &Config, mode: &str, output: OutputLevel) {
+    let destination = format!("{}:{}", config.host, config.remote_path);
+    let mut state = state::State::load(project_dir);
+    let previous = state.sync_modes.insert(destination, mode.to_string());
+
+    if let Some(previous) = previous.filter(|p| p != mode) {
+        if !matches!(output, OutputLevel::Quiet) {
+            println!(
+                "   ℹ Sync mode changed from {} to {}; run with --clean-sync if stale files \
+                 remain on the remote",
+                previous, mode
+            );
+        }
+    }
+
+    if let Err(e) = state.save(project_dir) {
+        eprintln!("   ⚠ Warning: Could not save sync state: {}", e);
+    }
+}
+
+/// Get every exclude pattern applied to the sync, defaults, detected and
+/// (with `gitignore`) `.gitignore` ones first
+fn sync_exclude_patterns(config: &Config, gitignore: bool) -> Vec<String> {
+    let ignored = if gitignore {
+        config.gitignore_excludes.as_slice()
+    } else {
+        &[]
+    };
+    config
+        .builtin_excludes()
+        .into_iter()
+        .map(str::to_string)
+        .chain(sync_back::excludes(config))
+        .chain(config.detected_excludes.iter().map(|d| d.pattern.clone()))
+        .chain(ignored.iter().cloned())
+        .chain(config.exclude_patterns.iter().cloned())
+        .collect()
+}
+
+/// The exclude set of the sync, from [`sync_exclude_patterns`] and the
+/// config's include patterns
+fn sync_excludes(config: &Config, gitignore: bool) -> ExcludeSet {
+    ExcludeSet::new(
+        sync_exclude_patterns(config, gitignore)
+            .iter()
+            .map(String::as_str),
+    )
+    .with_includes(config.include_patterns.iter().map(String::as_str))
+}
+
+/// Add the files below `dir` (relative path `relative`) that an include
+/// pattern forces into the sync, walking the directories that aren't
+/// excluded
+fn collect_included_files(
+    dir: &Path,
+    relative: &str,
+    excludes: &ExcludeSet,
+    files: &mut Vec<String>,
+) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if relative.is_empty() {
+            name
+        } else {
+            format!("{}/{}", relative, name)
+        };
+        let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+        if excludes.excludes_entry(&path, is_dir) {
+            continue;
+        }
+        if is_dir {
+            collect_included_files(&entry.path(), &path, excludes, files);
+        } else if excludes.forces(&path, false) {
+            files.push(path);
+        }
+    }
+}
+
+/// Get the list of files tracked by git in the project directory, with
+/// the files of its submodules when `submodules` is set
+fn get_git_files(project_dir: &Path, submodules: bool) -> Result<Vec<String>> {
+    let mut ls_files = Command::new("git");
+    ls_files.args(["ls-files", "-z"]);
+    if submodules {
+        ls_files.arg("--recurse-submodules");
+    }
+    let output = ls_files
         .current_dir(project_dir)
         .output()
         .context("Failed to run git ls-files. Is this a git repository?")?;
@@ -447,124 +2915,1342 @@ fn get_git_files(project_dir: &Path) -> Result<Vec<String>> {
         return Ok(vec![]);
     }
 
-    let tracked = String::from_utf8_lossy(&output.stdout);
-    let mut files: Vec<String> = tracked.lines().map(|s| s.to_string()).collect();
+    // Also get untracked files that aren't ignored. `--others` doesn't
+    // recurse into submodules, so each of them is asked separately
+    let mut files = git_paths(&output.stdout, "");
+    extend_paths(&mut files, get_untracked_files(project_dir, "")?);
+    if submodules {
+        for path in get_submodule_paths(project_dir) {
+            let prefix = format!("{}/", path);
+            extend_paths(
+                &mut files,
+                get_untracked_files(&project_dir.join(&path), &prefix)?,
+            );
+        }
+    }
 
-    // Also get untracked files that aren't ignored
-    let output_untracked = Command::new("git")
-        .args(["ls-files", "--others", "--exclude-standard"])
-        .current_dir(project_dir)
+    // The exclude patterns and the remembered list work on text, so a name
+    // that isn't UTF-8 sends the whole tree instead of a mangled list
+    Ok(files.unwrap_or_else(|| {
+        eprintln!("   ⚠ Warning: git lists a file name that isn't UTF-8; syncing the whole tree");
+        Vec::new()
+    }))
+}
+
+/// Get the untracked files in `dir` that git doesn't ignore, each prefixed
+/// with `prefix`
+fn get_untracked_files(dir: &Path, prefix: &str) -> Result<Option<Vec<String>>> {
+    let output = Command::new("git")
+        .args(["ls-files", "-z", "--others", "--exclude-standard"])
+        .current_dir(dir)
         .output()?;
 
-    if output_untracked.status.success() {
-        let untracked = String::from_utf8_lossy(&output_untracked.stdout);
-        for file in untracked.lines() {
-            if !file.is_empty() {
-                files.push(file.to_string());
-            }
-        }
+    if !output.status.success() {
+        return Ok(Some(vec![]));
     }
+    Ok(git_paths(&output.stdout, prefix))
+}
 
-    Ok(files)
+/// The paths in the NUL-separated output of `git ls-files -z`, each
+/// prefixed with `prefix`, or `None` if one isn't UTF-8
+///
+/// Without `-z`, git quotes names with non-ASCII characters, quotes or
+/// newlines the way C strings are written, which names no file on disk.
+fn git_paths(stdout: &[u8], prefix: &str) -> Option<Vec<String>> {
+    stdout
+        .split(|&byte| byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            std::str::from_utf8(path)
+                .ok()
+                .map(|path| format!("{}{}", prefix, path))
+        })
+        .collect()
+}
+
+/// Append `more` to `files`, keeping `None` once either is
+fn extend_paths(files: &mut Option<Vec<String>>, more: Option<Vec<String>>) {
+    match (files.as_mut(), more) {
+        (Some(files), Some(more)) => files.extend(more),
+        _ => *files = None,
+    }
+}
+
+/// Write `files` as the list for rsync's `--files-from` with `--from0`,
+/// so no name is split or unquoted on the way
+fn write_files_from(path: &Path, files: &[String]) -> std::io::Result<()> {
+    let mut list = Vec::new();
+    for file in files {
+        list.extend_from_slice(file.as_bytes());
+        list.push(0);
+    }
+    fs::write(path, list)
+}
+
+/// Get the paths of the checked-out submodules below the project
+/// directory, nested ones included
+fn get_submodule_paths(project_dir: &Path) -> Vec<String> {
+    let Ok(output) = Command::new("git")
+        .args([
+            "submodule",
+            "foreach",
+            "--recursive",
+            "--quiet",
+            "echo \"$displaypath\"",
+        ])
+        .current_dir(project_dir)
+        .output()
+    else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        // Run from a subdirectory, git also names the submodules outside it
+        .filter(|path| !path.is_empty() && !path.starts_with("../"))
+        .map(str::to_string)
+        .collect()
 }
 
 /// Execute the build command on the remote server via SSH
 fn run_remote_build_command(config: &Config) -> Result<()> {
+    if config.dry_run {
+        return dry_run::report_build(config);
+    }
     let output = config.output_level();
 
-    let mut spinner = print_status(output, "🔨 Building ");
+    let mut spinner = print_status(output, &estimate::build_message(config));
+    let start = Instant::now();
 
-    // Don't escape the cd path, just the build command if needed
-    let cmd = format!("cd {} && {}", config.remote_path, config.build_command);
+    let cmd = remote_build_command(config)?;
 
     // Clear spinner before build output
     clear_status(output, &mut spinner);
 
+    let log = config.local_log_dir.as_deref().and_then(|dir| {
+        match local_log::create(dir, config.keep_local_logs) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                eprintln!("   ⚠ Warning: Not keeping a local build log: {:#}", e);
+                None
+            }
+        }
+    });
+
     // Run SSH command with output streaming
-    let status = if matches!(output, OutputLevel::Verbose) {
-        ssh_command(config).arg(&cmd).status()?
+    let status = if let Some(prefix) = &config.output_prefix {
+        let mut child = remote_command(config, &cmd)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .context("Failed to run build over SSH")?;
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        std::thread::scope(|s| {
+            s.spawn(|| forward_lines(stdout, prefix, true, false));
+            s.spawn(|| forward_lines(stderr, prefix, true, true));
+            child.wait()
+        })?
+    } else if let Some(log) = &log {
+        let mut child = remote_command(config, &cmd)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .context("Failed to run build over SSH")?;
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        std::thread::scope(|s| {
+            s.spawn(|| local_log::tee(stdout, std::io::stdout(), log));
+            s.spawn(|| local_log::tee(stderr, std::io::stderr(), log));
+            child.wait()
+        })?
+    } else if matches!(output, OutputLevel::Verbose) {
+        remote_command(config, &cmd).status()?
     } else {
-        ssh_command(config)
-            .arg(&cmd)
+        remote_command(config, &cmd)
             .stdout(std::process::Stdio::inherit())
             .stderr(std::process::Stdio::inherit())
             .status()?
     };
 
     if !status.success() {
-        return Err(anyhow!(
-            "Remote build command failed with exit code: {:?}",
-            status
-        ));
+        return Err(BuildFailed {
+            status,
+            command: config.build_command.clone(),
+        }
+        .into());
     }
 
     if matches!(output, OutputLevel::Normal) {
         println!();
-        println!("   ✓ Build complete");
+        println!("{}", estimate::build_complete(config, start.elapsed()));
         println!();
     }
 
     Ok(())
 }
 
-/// Copy build artifacts from the remote server back to the local machine
-fn sync_artifacts(config: &Config) -> Result<()> {
+/// The full remote command running the build in the project directory, with
+/// the env exports and any container or Nix shell applied
+fn remote_build_command(config: &Config) -> Result<String> {
+    let headers = matches!(
+        config.output_level(),
+        OutputLevel::Normal | OutputLevel::Verbose
+    );
+    let mut command = format!(
+        "{}{}",
+        env_exports(&config.env)?,
+        config.build_command.script(headers)
+    );
+    if let Some(docker) = &config.docker {
+        command = container::wrap_command(docker, &command, &config.artifacts)?;
+    }
+    if let Some(nix) = &config.nix {
+        command = nix::wrap_command(nix, &command)?;
+    }
+    Ok(format!(
+        "cd {} && {}{}{}{}",
+        config.remote_dir().shell(),
+        shared::build_prelude(config),
+        supersede::record_prefix(),
+        permissions::build_prefix(config)?,
+        remote_log::wrap_command(config, &command)
+    ))
+}
+
+/// Render environment variables as an `export ... && ` prefix for a remote
+/// command, or an empty string when there are none
+fn env_exports(env: &BTreeMap<String, String>) -> Result<String> {
+    if env.is_empty() {
+        return Ok(String::new());
+    }
+
+    let mut exports = String::from("export");
+    for (key, value) in env {
+        let valid = !key.is_empty()
+            && !key.starts_with(|c: char| c.is_ascii_digit())
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(anyhow!("Invalid environment variable name: {}", key));
+        }
+        exports.push_str(&format!(
+            " {}={}",
+            key,
+            escape(Cow::Borrowed(value.as_str()))
+        ));
+    }
+    exports.push_str(" && ");
+    Ok(exports)
+}
+
+/// Copy build artifacts from the remote server into `local_dir`, creating it
+/// if missing
+///
+/// With `rewrite_paths`, paths in downloaded files are rewritten to point
+/// into `project_dir`.
+fn sync_artifacts(config: &Config, project_dir: &Path, local_dir: &Path) -> Result<()> {
     let output = config.output_level();
+    if config.no_artifacts {
+        if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) || config.dry_run {
+            println!("   ℹ Skipping artifact download (--no-artifacts)");
+            println!();
+        }
+        return Ok(());
+    }
+    if config.dry_run {
+        dry_run::report_artifacts(config, local_dir);
+        return Ok(());
+    }
+    if !config.artifacts.is_empty() {
+        fs::create_dir_all(local_dir)
+            .with_context(|| format!("Failed to create artifact dir: {}", local_dir.display()))?;
+    }
 
     let mut spinner = print_status(output, "📥 Copying artifacts ");
+    let artifacts: Vec<&Artifact> = config.artifacts.iter().collect();
+    let start = Instant::now();
+    let result = copy_artifacts(config, &artifacts, project_dir, local_dir, &mut spinner);
+    let elapsed = start.elapsed();
+    clear_status(output, &mut spinner);
 
-    for artifact in &config.artifacts {
-        let mut rsync_cmd = Command::new("rsync");
-        rsync_cmd.arg("-avz");
+    // A failed download may be resumed by the next run
+    let (complete, stats) = result?;
+    if complete {
+        remove_partial_dir(local_dir);
+    }
 
-        match output {
-            OutputLevel::Verbose => rsync_cmd.arg("-v"),
-            _ => rsync_cmd.arg("--quiet"),
-        };
+    match (output, stats) {
+        (OutputLevel::Normal, Some(stats)) => println!(
+            "   ✓ Artifacts downloaded to {} ({})",
+            local_dir.display(),
+            stats.received_summary(elapsed)
+        ),
+        (OutputLevel::Normal, None) => {
+            println!("   ✓ Artifacts downloaded to {}", local_dir.display())
+        }
+        (OutputLevel::Minimal, Some(stats)) => {
+            println!("   ✓ Downloaded {}", stats.received_summary(elapsed))
+        }
+        _ => {}
+    }
+    if matches!(output, OutputLevel::Normal) {
+        println!();
+    }
+
+    Ok(())
+}
+
+/// Copy `artifacts` with one rsync each, up to [`ARTIFACT_JOBS`] at once,
+/// returning whether all of them were copied and the downloads' combined
+/// `--stats`
+///
+/// Missing artifacts are only warned about; the error is for an interrupted
+/// download in resilient mode. The spinner counts the finished downloads,
+/// and their output and warnings are printed in the order of `artifacts`
+/// once all are done.
+fn copy_artifacts(
+    config: &Config,
+    artifacts: &[&Artifact],
+    project_dir: &Path,
+    local_dir: &Path,
+    spinner: &mut Option<Spinner>,
+) -> Result<(bool, Option<Stats>)> {
+    let output = config.output_level();
+    let rewriter = if config.rewrite_paths && !artifacts.is_empty() {
+        rewrite::Rewriter::new(config, project_dir)
+            .map_err(|e| {
+                eprintln!(
+                    "   ⚠ Warning: Paths in artifacts won't be rewritten: {:#}",
+                    e
+                )
+            })
+            .ok()
+    } else {
+        None
+    };
+
+    let tar = transport::uses_tar(config);
+    let mut snapshots = Vec::new();
+    let mut commands = Vec::new();
+    let mut lists = Vec::new();
+    let mut staged = BTreeMap::new();
+    for (i, &artifact) in artifacts.iter().enumerate() {
+        let destination = artifact.destination(local_dir);
+        if let Some(local) = &artifact.local {
+            let root = artifact.destination(local_dir);
+            let dir = match root.parent() {
+                Some(parent) if !local.ends_with('/') => parent.to_path_buf(),
+                _ => root,
+            };
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        snapshots.push(
+            rewriter
+                .as_ref()
+                .map(|_| rewrite::Snapshot::take(local_dir, artifact)),
+        );
+        if tar {
+            let (cd, members) = if artifact_glob::needs_expansion(&artifact.remote) {
+                let expansion = artifact_glob::expand(config, &artifact.remote)
+                    .with_context(|| format!("Failed to expand artifact {}", artifact))?;
+                if expansion.paths.is_empty() {
+                    commands.push(None);
+                    continue;
+                }
+                transport::expansion_members(config, &expansion)
+            } else {
+                transport::artifact_members(config, &artifact.remote)
+            };
+            // tar can't rename, so a `local` name is extracted aside first
+            let into = match &artifact.local {
+                Some(local) if !local.ends_with('/') => {
+                    let staging = transport::staging_dir(&destination);
+                    staged.insert(i, staging.clone());
+                    staging
+                }
+                _ => destination,
+            };
+            fs::create_dir_all(&into)
+                .with_context(|| format!("Failed to create {}", into.display()))?;
+            commands.push(Some(transport::download(config, &cd, &members, &into)));
+            continue;
+        }
+        let mut rsync_cmd = rsync_command(config);
+        // The itemized changes tell updated artifacts from unchanged ones
+        rsync_cmd
+            .arg("-av")
+            .arg("--itemize-changes")
+            .arg("--stats")
+            .args(config.artifact_compare_arg())
+            .args(compression::current(config).rsync_args())
+            .args(config.resumes().then(|| partial_dir_arg(local_dir)));
+        if matches!(output, OutputLevel::Verbose) {
+            rsync_cmd.arg("-v");
+        }
 
         // Use SSH control path for connection reuse
         rsync_cmd.arg("-e").arg(ssh_control_path_arg(config));
 
-        // Copy from remote to current directory
-        rsync_cmd.arg(format!(
-            "{}:{}/{}",
-            config.host, config.remote_path, artifact
-        ));
-        rsync_cmd.arg("."); // Copy to current directory
+        // Never copy remotebuild's own metadata back as part of an artifact
+        rsync_cmd.arg(format!("--exclude={}/", REMOTE_META_DIR));
 
-        let status = rsync_cmd
-            .status()
-            .context("Failed to run rsync for artifacts")?;
+        if artifact_glob::needs_expansion(&artifact.remote) {
+            let expansion = artifact_glob::expand(config, &artifact.remote)
+                .with_context(|| format!("Failed to expand artifact {}", artifact))?;
+            if expansion.paths.is_empty() {
+                commands.push(None);
+                continue;
+            }
+            let temp_dir = dirs::cache_dir().unwrap_or_else(env::temp_dir);
+            let list = temp_dir.join(format!("remotebuild_artifact_{}_{}", std::process::id(), i));
+            fs::write(&list, expansion.paths.join("\n"))?;
+            let mut files_from = OsString::from("--files-from=");
+            files_from.push(&list);
+            // --files-from keeps -a from recursing into listed directories
+            rsync_cmd.arg(files_from).arg("-r");
+            rsync_cmd.arg(expansion.rsync_source(config));
+            lists.push(list);
+        } else {
+            rsync_cmd.arg(artifact_source(config, &artifact.remote));
+        }
+        rsync_cmd.arg(&destination);
+        commands.push(Some(rsync_cmd));
+    }
 
-        if !status.success() {
+    let results = run_concurrently(&mut commands, spinner);
+    clear_status(output, spinner);
+    for list in lists {
+        let _ = fs::remove_file(list);
+    }
+
+    let mut complete = true;
+    let mut stats: Option<Stats> = None;
+    for (i, ((&artifact, snapshot), result)) in
+        artifacts.iter().zip(&snapshots).zip(results).enumerate()
+    {
+        // Expanded patterns matching nothing have no download
+        let Some(result) = result else {
+            complete = false;
+            eprintln!(
+                "   ⚠ Warning: Could not copy artifact: {} (no such file)",
+                artifact
+            );
+            continue;
+        };
+        let context = || {
+            let program = if tar { "tar" } else { "rsync" };
+            format!("Failed to run {} for artifacts", program)
+        };
+        let mut rsync = result.with_context(context)?;
+        let mut attempt = 1;
+        while let Some(command) = commands[i]
+            .as_mut()
+            .filter(|_| resumable::should_retry(config, rsync.status, attempt))
+        {
+            let what = format!("Download of {}", artifact);
+            let stderr = String::from_utf8_lossy(&rsync.stderr);
+            resumable::wait(config, &what, rsync.status, &stderr, attempt);
+            rsync = command.output().with_context(context)?;
+            attempt += 1;
+        }
+        let placed = match staged.get(&i) {
+            Some(staging) if rsync.status.success() => {
+                transport::place(staging, &artifact.destination(local_dir))
+            }
+            Some(staging) => {
+                let _ = fs::remove_dir_all(staging);
+                Ok(())
+            }
+            None => Ok(()),
+        };
+        if let Err(e) = placed {
+            complete = false;
+            eprintln!(
+                "   ⚠ Warning: Could not copy artifact: {}: {:#}",
+                artifact, e
+            );
+            continue;
+        }
+        let stdout = String::from_utf8_lossy(&rsync.stdout);
+        let stderr = String::from_utf8_lossy(&rsync.stderr);
+        if matches!(output, OutputLevel::Verbose) {
+            print!("{}", stdout);
+            eprint!("{}", stderr);
+        }
+        if let Some(download) = Stats::parse(&stdout) {
+            stats.get_or_insert_with(Stats::default).add(&download);
+        }
+
+        if !rsync.status.success() {
+            // A dropped connection is retried after reconnecting, resuming
+            // from the partial file
+            let retried = config.resilient || config.connection_retries > 0;
+            if retried && resilient::transient_rsync_failure(rsync.status) {
+                return Err(resilient::ConnectionLost(format!(
+                    "Download of {} was interrupted ({}){}{}",
+                    artifact,
+                    rsync.status,
+                    resumable::attempts(attempt),
+                    indented_tail(&stderr)
+                ))
+                .into());
+            }
             // Non-fatal: just warn about missing artifacts
-            eprintln!("   ⚠ Warning: Could not copy artifact: {}", artifact);
-        } else if matches!(output, OutputLevel::Verbose) {
-            println!("   ✓ Copied: {}", artifact);
+            complete = false;
+            eprintln!(
+                "   ⚠ Warning: Could not copy artifact: {}{}{}",
+                artifact,
+                resumable::attempts(attempt),
+                indented_tail(&stderr)
+            );
+        } else {
+            // tar copies everything, so it can't tell if anything changed
+            let state = if tar {
+                "copied"
+            } else if itemize::Changes::parse(&stdout).transferred.is_empty() {
+                "unchanged"
+            } else {
+                "updated"
+            };
+            match (output, artifact.flag) {
+                (OutputLevel::Verbose, Some(flag)) => {
+                    println!("   ✓ Copied: {} ({}, from {})", artifact, state, flag)
+                }
+                (OutputLevel::Verbose, None) => println!("   ✓ Copied: {} ({})", artifact, state),
+                (OutputLevel::Minimal | OutputLevel::Normal, _) => {
+                    println!("   ✓ {} ({})", artifact, state)
+                }
+                (OutputLevel::Quiet, _) => {}
+            }
+            if let (Some(rewriter), Some(snapshot)) = (&rewriter, snapshot) {
+                let verbose = matches!(output, OutputLevel::Verbose);
+                rewriter.rewrite_changed(snapshot, &artifact.remote, verbose);
+            }
         }
     }
+    Ok((complete, stats))
+}
 
-    clear_status(output, &mut spinner);
+/// Run `commands` up to [`ARTIFACT_JOBS`] at once, capturing their output,
+/// and count the finished ones in the spinner
+///
+/// The results are in the order of `commands`, `None` where there is no
+/// command.
+fn run_concurrently(
+    commands: &mut [Option<Command>],
+    spinner: &mut Option<Spinner>,
+) -> Vec<Option<std::io::Result<Output>>> {
+    let total = commands.len();
+    let skipped: Vec<bool> = commands.iter().map(Option::is_none).collect();
+    let queue = Mutex::new(
+        commands
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, command)| Some((i, command.as_mut()?))),
+    );
+    let (sender, receiver) = mpsc::channel();
+    let mut results: Vec<Option<std::io::Result<Output>>> = (0..total).map(|_| None).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..ARTIFACT_JOBS.min(total) {
+            let sender = sender.clone();
+            let queue = &queue;
+            scope.spawn(move || {
+                while let Some((i, command)) = queue.lock().ok().and_then(|mut q| q.next()) {
+                    if sender.send((i, command.output())).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
 
-    if matches!(output, OutputLevel::Normal) {
-        println!("   ✓ Artifacts downloaded");
-        println!();
+        for (done, (i, result)) in receiver.iter().enumerate() {
+            results[i] = Some(result);
+            if let Some(spinner) = spinner.as_mut().filter(|_| total > 1) {
+                spinner.set_detail(format!("({}/{})", done + 1, total));
+                spinner.tick();
+            }
+        }
+    });
+    results
+        .into_iter()
+        .zip(skipped)
+        .map(|(result, skipped)| {
+            (!skipped).then(|| {
+                result.unwrap_or_else(|| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        "rsync was never started",
+                    ))
+                })
+            })
+        })
+        .collect()
+}
+
+/// rsync argument keeping partial downloads into `local_dir` for resuming
+///
+/// The directory is absolute, so there is one per destination rather than
+/// one in every directory receiving files.
+fn partial_dir_arg(local_dir: &Path) -> OsString {
+    let mut arg = OsString::from("--partial-dir=");
+    arg.push(local_dir.join(PARTIAL_DIR));
+    arg
+}
+
+/// Remove what is left of partial downloads into `local_dir` once nothing
+/// needs resuming
+fn remove_partial_dir(local_dir: &Path) {
+    let dir = local_dir.join(PARTIAL_DIR);
+    if dir.exists() {
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            eprintln!("   ⚠ Warning: Could not remove {}: {}", dir.display(), e);
+        }
     }
+}
 
-    Ok(())
+/// The rsync source of an artifact: below the remote project directory, or
+/// the remote path itself when the artifact is absolute
+///
+/// Either way the pattern stays unquoted for the remote shell to expand.
+fn artifact_source(config: &Config, artifact: &str) -> String {
+    if artifact.starts_with('/') {
+        format!("{}:{}", config.host, artifact)
+    } else {
+        config.remote_dir().rsync(&config.host, artifact)
+    }
 }
 
-/// Run a command on the remote server via SSH and return the output
+/// Warn about absolute artifacts that look like they were meant to be
+/// relative to the project, such as `/build/out.bin` in a project with a
+/// `build` directory
+fn lint_artifacts(project_dir: &Path, config: &Config) {
+    /// Build output directory names rarely found at the root of a host
+    const OUTPUT_DIRS: &[&str] = &["build", "out", "dist", "target", "bin", "artifacts"];
+
+    for artifact in config
+        .artifacts
+        .iter()
+        .map(|a| a.remote.as_str())
+        .filter(|a| a.starts_with('/'))
+    {
+        let first = artifact
+            .trim_start_matches('/')
+            .split('/')
+            .next()
+            .unwrap_or("");
+        if first.is_empty() {
+            continue;
+        }
+        if OUTPUT_DIRS.contains(&first) || project_dir.join(first).exists() {
+            eprintln!(
+                "   ⚠ Warning: Artifact {} is an absolute remote path; \
+                 use {} to copy it from the project",
+                artifact,
+                artifact.trim_start_matches('/')
+            );
+        }
+    }
+}
+
+/// A local directory as an rsync source for its contents (with a trailing
+/// `/`), keeping file names that aren't valid UTF-8
+fn dir_contents(dir: &Path) -> OsString {
+    let mut source = dir.as_os_str().to_owned();
+    source.push("/");
+    source
+}
+
+/// Run rsync with stdout streamed and stderr captured
+///
+/// In verbose mode stderr is also echoed as it arrives; otherwise it would be
+/// overwritten by the status line anyway and is only shown on failure.
+fn run_rsync(cmd: &mut Command, output: OutputLevel) -> Result<(ExitStatus, String)> {
+    let mut child = cmd.stderr(std::process::Stdio::piped()).spawn()?;
+
+    let stderr = match child.stderr.take() {
+        Some(pipe) => read_rsync_stderr(pipe, output)?,
+        None => String::new(),
+    };
+
+    Ok((child.wait()?, stderr))
+}
+
+/// Read rsync's stderr to the end, echoing it in verbose mode
+fn read_rsync_stderr(pipe: impl std::io::Read, output: OutputLevel) -> Result<String> {
+    use std::io::BufRead;
+
+    let mut stderr = String::new();
+    let mut reader = std::io::BufReader::new(pipe);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        // File names in rsync errors aren't necessarily UTF-8
+        let text = String::from_utf8_lossy(&line);
+        if matches!(output, OutputLevel::Verbose) {
+            eprint!("{}", text);
+        }
+        stderr.push_str(&text);
+        line.clear();
+    }
+    Ok(stderr)
+}
+
+/// Run rsync like [`run_rsync`], reading `--info=progress2` updates from
+/// its stdout into the status line, and return the last update and the
+/// `--stats` totals as well
+fn run_rsync_with_progress(
+    cmd: &mut Command,
+    output: OutputLevel,
+    spinner: &mut Option<Spinner>,
+) -> Result<(ExitStatus, String, Option<Progress>, Option<Stats>)> {
+    use std::io::Read;
+
+    let mut child = cmd
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+
+    // stderr is drained alongside, so neither pipe fills up and blocks rsync
+    let stderr = child
+        .stderr
+        .take()
+        .map(|pipe| std::thread::spawn(move || read_rsync_stderr(pipe, output)));
+
+    let mut last = None;
+    let mut stdout = String::new();
+    if let Some(mut pipe) = child.stdout.take() {
+        let mut buffer = [0; 4096];
+        let mut line = Vec::new();
+        loop {
+            let read = pipe.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            // Progress lines are rewritten with `\r`
+            for &byte in &buffer[..read] {
+                if byte != b'\r' && byte != b'\n' {
+                    line.push(byte);
+                    continue;
+                }
+                if let Some(progress) = Progress::parse(&String::from_utf8_lossy(&line)) {
+                    if let Some(spinner) = spinner.as_mut() {
+                        spinner.set_detail(progress.status());
+                        spinner.tick();
+                    }
+                    last = Some(progress);
+                } else {
+                    stdout.push_str(&String::from_utf8_lossy(&line));
+                    stdout.push('\n');
+                }
+                line.clear();
+            }
+        }
+    }
+
+    let status = child.wait()?;
+    let stderr = match stderr {
+        Some(thread) => thread
+            .join()
+            .map_err(|_| anyhow!("Failed to read rsync's stderr"))??,
+        None => String::new(),
+    };
+    Ok((status, stderr, last, Stats::parse(&stdout)))
+}
+
+/// Format the last lines of a command's stderr for appending to a message
+fn indented_tail(stderr: &str) -> String {
+    /// Number of stderr lines kept in error messages
+    const TAIL_LINES: usize = 10;
+
+    let lines: Vec<&str> = stderr.lines().filter(|l| !l.trim().is_empty()).collect();
+    lines[lines.len().saturating_sub(TAIL_LINES)..]
+        .iter()
+        .map(|line| format!("\n      {}", line))
+        .collect()
+}
+
+/// Create an rsync command that leaves remote path quoting to the remote shell
+fn rsync_command(config: &Config) -> Command {
+    let mut cmd = Command::new(&config.rsync_binary);
+    cmd.env(RSYNC_OLD_ARGS, "1");
+    cmd.args(bwlimit::rsync_arg(config));
+    cmd
+}
+
+/// Run a command on the remote server via SSH, failing on a non-zero exit
 fn run_ssh_command(config: &Config, cmd: &str) -> Result<()> {
-    let output = ssh_command(config)
-        .arg(cmd)
+    run_ssh_command_output(config, cmd).map(|_| ())
+}
+
+/// Output of a remote command run with [`run_ssh_command_streaming`]
+struct StreamedOutput {
+    /// Exit status of the ssh process
+    status: ExitStatus,
+    /// Last lines of stdout
+    stdout_tail: Vec<String>,
+    /// Last lines of stderr
+    stderr_tail: Vec<String>,
+}
+
+impl StreamedOutput {
+    /// The last captured lines, formatted for appending to an error message
+    ///
+    /// Prefers stderr, falling back to stdout for commands that report
+    /// errors there.
+    fn tail(&self) -> String {
+        let lines = if self.stderr_tail.is_empty() {
+            &self.stdout_tail
+        } else {
+            &self.stderr_tail
+        };
+        indented_tail(&lines.join("\n"))
+    }
+}
+
+/// Run a possibly long-running command on the remote server via SSH,
+/// forwarding its output line by line as it arrives
+///
+/// In normal and verbose mode each line is printed with `prefix`; in the
+/// quieter modes output is only captured. Only the last lines of each stream
+/// are kept, so chatty commands don't grow memory without bound. Use
+/// [`run_ssh_command_output`] for short internal probes instead.
+fn run_ssh_command_streaming(config: &Config, cmd: &str, prefix: &str) -> Result<StreamedOutput> {
+    let show = matches!(
+        config.output_level(),
+        OutputLevel::Normal | OutputLevel::Verbose
+    );
+    stream_command(remote_command(config, cmd), prefix, show)
+}
+
+/// Run `command`, printing its output with `prefix` if `show` and keeping
+/// the last lines of each stream
+fn stream_command(mut command: Command, prefix: &str, show: bool) -> Result<StreamedOutput> {
+    let mut child = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("Failed to run SSH command")?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    let (stdout_tail, stderr_tail) = std::thread::scope(|scope| {
+        let stdout_thread = scope.spawn(|| forward_lines(stdout, prefix, show, false));
+        let stderr_tail = forward_lines(stderr, prefix, show, true);
+        (stdout_thread.join().unwrap_or_default(), stderr_tail)
+    });
+
+    Ok(StreamedOutput {
+        status: child.wait().context("Failed to wait for SSH command")?,
+        stdout_tail,
+        stderr_tail,
+    })
+}
+
+/// Print each line read from `pipe` with `prefix`, returning the last lines
+fn forward_lines(
+    pipe: Option<impl std::io::Read>,
+    prefix: &str,
+    show: bool,
+    to_stderr: bool,
+) -> Vec<String> {
+    /// Number of lines kept from each stream
+    const TAIL_LINES: usize = 20;
+
+    use std::io::BufRead;
+    let Some(pipe) = pipe else {
+        return Vec::new();
+    };
+
+    let mut tail = std::collections::VecDeque::with_capacity(TAIL_LINES);
+    let mut reader = std::io::BufReader::new(pipe);
+    let mut line = Vec::new();
+    while let Ok(read) = reader.read_until(b'\n', &mut line) {
+        if read == 0 {
+            break;
+        }
+        let text = String::from_utf8_lossy(&line).trim_end().to_string();
+        if show {
+            if to_stderr {
+                eprintln!("   [{}] {}", prefix, text);
+            } else {
+                println!("   [{}] {}", prefix, text);
+            }
+        }
+        if tail.len() == TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(text);
+        line.clear();
+    }
+
+    tail.into()
+}
+
+/// Run a command on the remote server via SSH and return its stdout
+fn run_ssh_command_output(config: &Config, cmd: &str) -> Result<String> {
+    let output = remote_command(config, cmd)
         .output()
         .context("Failed to run SSH command")?;
 
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("SSH command failed: {}", stderr));
+        return Err(resilient::ssh_error(
+            output.status,
+            format!("SSH command failed: {}", stderr),
+        ));
     }
 
-    Ok(())
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// The absolute remote project directory, with `~` and relative paths resolved
+fn resolve_remote_dir(config: &Config) -> Result<String> {
+    let pwd = format!("cd {} && pwd", config.remote_dir().shell());
+    let remote_dir = run_ssh_command_output(config, &pwd)?.trim().to_string();
+    if remote_dir.is_empty() {
+        return Err(anyhow!(
+            "Could not resolve remote path: {}",
+            config.remote_path
+        ));
+    }
+    Ok(remote_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A short host in a short directory keeps its readable name
+    #[test]
+    fn control_path_fits_unchanged() {
+        let dir = Path::new("/home/me/.cache/remotebuild");
+        assert_eq!(
+            control_socket_path(dir, "builder@example.com", None),
+            "/home/me/.cache/remotebuild/control_builder_example.com"
+        );
+        assert_eq!(
+            control_socket_path(dir, "example.com", Some(2222)),
+            "/home/me/.cache/remotebuild/control_example.com_2222"
+        );
+    }
+
+    /// A 200-character host falls back to a prefix plus a hash
+    #[test]
+    fn control_path_hashes_long_hosts() {
+        let dir = Path::new("/home/me/.cache/remotebuild");
+        let host = format!("{}.example.com", "a".repeat(188));
+        assert_eq!(host.len(), 200);
+
+        let path = control_socket_path(dir, &host, None);
+        let name = path
+            .strip_prefix("/home/me/.cache/remotebuild/control_aaaaaaaa_")
+            .expect("shortened name");
+        assert_eq!(name.len(), 8);
+        assert!(name.chars().all(|c| c.is_ascii_hexdigit()));
+
+        // Stable across runs, and distinct per host and port
+        assert_eq!(control_socket_path(dir, &host, None), path);
+        let other = format!("{}.example.org", "a".repeat(188));
+        assert_ne!(control_socket_path(dir, &other, None), path);
+        assert_ne!(control_socket_path(dir, &host, Some(22)), path);
+    }
+
+    /// Every path, with ssh's temporary suffix and the NUL, fits the 104
+    /// bytes of macOS's `sun_path`, for cache directories of up to 60 bytes
+    #[test]
+    fn control_path_stays_under_socket_limit() {
+        let deep = format!("/var/{}/remotebuild", "d".repeat(43));
+        assert_eq!(deep.len(), 60);
+        for dir in ["/tmp/remotebuild", deep.as_str()] {
+            for len in [1, 40, 60, 80, 200] {
+                let host = "h".repeat(len);
+                let path = control_socket_path(Path::new(dir), &host, Some(2222));
+                assert!(path.len() + 17 < 104, "{} is {} bytes", path, path.len());
+            }
+        }
+
+        // The longest path used as is sits right at the limit
+        let dir = Path::new("/tmp/remotebuild");
+        let fits = MAX_CONTROL_PATH - "/tmp/remotebuild/control_".len();
+        let path = control_socket_path(dir, &"h".repeat(fits), None);
+        assert_eq!(path.len(), MAX_CONTROL_PATH);
+        assert!(path.ends_with(&"h".repeat(fits)));
+        let path = control_socket_path(dir, &"h".repeat(fits + 1), None);
+        assert!(path.len() < MAX_CONTROL_PATH);
+    }
+
+    /// Values that need quoting for the remote shell
+    const TRICKY_VALUES: [(&str, &str); 6] = [
+        ("QUOTES", r#"it's "quoted""#),
+        ("DOLLAR", "$HOME and ${PATH} and `id` and $(id)"),
+        ("NEWLINE", "first\nsecond\n"),
+        ("EMPTY", ""),
+        ("SPACES", "  a  b  "),
+        ("GLOB", "*.o ~ !x; rm -rf /"),
+    ];
+
+    /// Exports are escaped so a shell sees each value byte for byte
+    #[test]
+    fn env_exports_round_trip() {
+        for (key, value) in TRICKY_VALUES {
+            let env = BTreeMap::from([(key.to_string(), value.to_string())]);
+            let exports = env_exports(&env).unwrap();
+            let script = format!("{}printf '%s' \"${}\"", exports, key);
+            let output = Command::new("sh").arg("-c").arg(&script).output().unwrap();
+            assert!(output.status.success(), "{}", script);
+            assert_eq!(String::from_utf8_lossy(&output.stdout), value, "{}", script);
+        }
+    }
+
+    /// The rendered exports, with empty values quoted
+    #[test]
+    fn env_exports_text() {
+        assert_eq!(env_exports(&BTreeMap::new()).unwrap(), "");
+        let env = BTreeMap::from([
+            ("A".to_string(), "plain".to_string()),
+            ("B".to_string(), String::new()),
+            ("C".to_string(), "it's".to_string()),
+        ]);
+        assert_eq!(
+            env_exports(&env).unwrap(),
+            r#"export A=plain B='' C='it'\''s' && "#
+        );
+    }
+
+    /// Names a shell can't assign are refused
+    #[test]
+    fn env_exports_rejects_bad_names() {
+        for name in ["", "1ST", "A-B", "A B", "A=B", "$A"] {
+            let env = BTreeMap::from([(name.to_string(), "x".to_string())]);
+            assert!(env_exports(&env).is_err(), "{:?}", name);
+        }
+    }
+
+    /// Every frame redraws the line and erases what a longer one left
+    #[test]
+    fn spinner_frames() {
+        let mut spinner = Spinner::with_terminal("📤 Syncing ", true);
+        assert_eq!(spinner.next_frame(), "\r\x1b[1m📤 Syncing ⣾\x1b[0m\x1b[K");
+        spinner.set_detail("(3/8)".to_string());
+        assert_eq!(
+            spinner.next_frame(),
+            "\r\x1b[1m📤 Syncing ⣽ (3/8)\x1b[0m\x1b[K"
+        );
+        for _ in 0..6 {
+            spinner.next_frame();
+        }
+        assert!(spinner.next_frame().contains("Syncing ⣾ (3/8)"));
+    }
+
+    /// Stopping erases the whole line once, and nothing is drawn after
+    #[test]
+    fn spinner_clears_line() {
+        let mut spinner = Spinner::with_terminal("📤 Syncing ", true);
+        spinner.next_frame();
+        assert_eq!(spinner.finish(), "\r\x1b[2K");
+        assert_eq!(spinner.finish(), "");
+        assert_eq!(spinner.next_frame(), "");
+    }
+
+    /// Without a terminal the message is one plain line, without escapes
+    #[test]
+    fn spinner_without_terminal() {
+        let mut spinner = Spinner::with_terminal("📤 Syncing ", false);
+        assert_eq!(spinner.next_frame(), "📤 Syncing\n");
+        spinner.set_detail("(3/8)".to_string());
+        assert_eq!(spinner.next_frame(), "");
+        assert_eq!(spinner.finish(), "");
+    }
+
+    /// A command printing 100k lines to each stream keeps only the tails
+    #[test]
+    fn streaming_keeps_bounded_tails() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(
+            "i=0; while [ $i -lt 100000 ]; do echo out $i; echo err $i >&2; i=$((i+1)); done; exit 3",
+        );
+        let streamed = stream_command(command, "chatty", false).unwrap();
+
+        assert_eq!(streamed.status.code(), Some(3));
+        let expected = |stream: &str| -> Vec<String> {
+            (99_980..100_000)
+                .map(|i| format!("{} {}", stream, i))
+                .collect()
+        };
+        assert_eq!(streamed.stdout_tail, expected("out"));
+        assert_eq!(streamed.stderr_tail, expected("err"));
+        assert!(streamed.tail().ends_with("err 99999"));
+    }
+
+    /// Errors are taken from stdout when a command wrote nothing to stderr
+    #[test]
+    fn streaming_tail_falls_back_to_stdout() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("printf 'one\\ntwo\\n'; exit 1");
+        let streamed = stream_command(command, "quiet", false).unwrap();
+
+        assert!(!streamed.status.success());
+        assert_eq!(streamed.stdout_tail, ["one", "two"]);
+        assert!(streamed.stderr_tail.is_empty());
+        assert!(streamed.tail().ends_with("two"));
+    }
+
+    /// Names git would quote reach the remote in a git-aware sync: the list
+    /// keeps them as they are on disk, and rsync reads it back unchanged
+    #[test]
+    fn git_list_keeps_quoted_names() {
+        let dir =
+            std::env::temp_dir().join(format!("remotebuild-test-git-names-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let project = dir.join("project");
+        fs::create_dir_all(&project).unwrap();
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .args(args)
+                .current_dir(&project)
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+        if !git(&["init", "-q", "."]) {
+            eprintln!("git not found, skipping");
+            return;
+        }
+        for name in ["plain.c", "город.c", "with\"quote.c", "gone.c"] {
+            fs::write(project.join(name), name).unwrap();
+        }
+        assert!(git(&["add", "plain.c", "город.c", "gone.c"]));
+        fs::remove_file(project.join("gone.c")).unwrap();
+
+        // The unicode name is tracked, the quoted one untracked
+        let mut files = get_git_files(&project, false).unwrap();
+        files.sort_unstable();
+        assert_eq!(files, ["gone.c", "plain.c", "with\"quote.c", "город.c"]);
+        assert_eq!(sync_delete::take_gone(&project, &mut files), ["gone.c"]);
+
+        let list = dir.join("files-from");
+        write_files_from(&list, &files).unwrap();
+        assert_eq!(
+            fs::read(&list).unwrap(),
+            "plain.c\0with\"quote.c\0город.c\0".as_bytes()
+        );
+        let remote = dir.join("remote");
+        let mut files_from = OsString::from("--files-from=");
+        files_from.push(&list);
+        let Ok(output) = Command::new("rsync")
+            .arg("-a")
+            .arg(files_from)
+            .arg("--from0")
+            .arg(dir_contents(&project))
+            .arg(&remote)
+            .output()
+        else {
+            eprintln!("rsync not found, skipping the transfer");
+            let _ = fs::remove_dir_all(&dir);
+            return;
+        };
+        assert!(output.status.success(), "{:?}", output);
+        for name in ["plain.c", "город.c", "with\"quote.c"] {
+            assert_eq!(fs::read_to_string(remote.join(name)).unwrap(), name);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// A git-aware sync from a project path with spaces and unicode, through
+    /// rsync's ssh command, delivers non-ASCII and quoted file names
+    #[test]
+    fn sync_from_unicode_path_keeps_names() {
+        let dir = std::env::temp_dir().join(format!(
+            "remotebuild-test-unicode-path-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let project = dir.join("NDS Homebrew/город/it's");
+        let bin = dir.join("bin");
+        fs::create_dir_all(&project).unwrap();
+        fs::create_dir_all(&bin).unwrap();
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .args(args)
+                .current_dir(&project)
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+        if !git(&["init", "-q", "."]) || Command::new("rsync").arg("--version").output().is_err() {
+            eprintln!("git or rsync not found, skipping");
+            let _ = fs::remove_dir_all(&dir);
+            return;
+        }
+        for name in ["город.c", "with\"quote.c"] {
+            fs::write(project.join(name), name).unwrap();
+        }
+        assert!(git(&["add", "город.c"]));
+
+        // The "remote" runs locally; the stub ssh records its options
+        let ssh = bin.join("ssh");
+        fs::write(
+            &ssh,
+            "#!/bin/sh\nwhile [ \"$1\" = -o ]; do printf '%s\\n' \"$2\" >> \"$0.options\"; \
+             shift 2; done\nshift\nexec sh -c \"$*\"\n",
+        )
+        .unwrap();
+        Command::new("chmod").arg("+x").arg(&ssh).status().unwrap();
+        let remote = dir.join("remote");
+        let config: Config = serde_yaml::from_str(&format!(
+            "host: build-box\nremote_path: {}",
+            remote.display()
+        ))
+        .unwrap();
+
+        let files = get_git_files(&project, false).unwrap();
+        let list = dir.join("files-from");
+        write_files_from(&list, &files).unwrap();
+        let mut files_from = OsString::from("--files-from=");
+        files_from.push(&list);
+        let path = format!("{}:{}", bin.display(), env::var("PATH").unwrap_or_default());
+        let output = Command::new("rsync")
+            .env("PATH", path)
+            .arg("-a")
+            .arg("-e")
+            .arg(ssh_control_path_arg(&config))
+            .arg(files_from)
+            .arg("--from0")
+            .arg(dir_contents(&project))
+            .arg(config.remote_dir().rsync(&config.host, ""))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+
+        for name in ["город.c", "with\"quote.c"] {
+            assert_eq!(fs::read_to_string(remote.join(name)).unwrap(), name);
+        }
+        let options = fs::read_to_string(bin.join("ssh.options")).unwrap();
+        assert!(options
+            .lines()
+            .any(|option| option == ssh_control_option(&config)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// A config given by absolute path inside the project is excluded from
+    /// the sync by an anchored pattern; other configs are left alone
+    #[test]
+    fn explicit_config_is_excluded() {
+        let dir = std::env::temp_dir().join(format!(
+            "remotebuild-test-explicit-config-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("ci")).unwrap();
+        let project = dir.canonicalize().unwrap();
+        let inside = project.join("ci/remote.yaml");
+        let outside = std::env::temp_dir().join("remote.yaml");
+        let excludes_for = |arg: &str, path: &Path| {
+            let mut config: Config = serde_yaml::from_str("host: build-box").unwrap();
+            exclude_explicit_config(&project, arg, path, &mut config);
+            config.exclude_patterns
+        };
+
+        let arg = inside.to_string_lossy().to_string();
+        let patterns = excludes_for(&arg, &inside);
+        assert_eq!(patterns, ["/ci/remote.yaml"]);
+        let set = ExcludeSet::new(patterns.iter().map(String::as_str));
+        assert!(set.excludes_file("ci/remote.yaml"));
+        assert!(!set.excludes_file("other/ci/remote.yaml"));
+
+        assert!(excludes_for("ci/remote.yaml", &inside).is_empty());
+        let arg = outside.to_string_lossy().to_string();
+        assert!(excludes_for(&arg, &outside).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A config read from stdin parses like a file and names stdin in errors
+    #[test]
+    fn stdin_config_names_its_source() {
+        global_config::disable();
+        let config = parse_config(
+            "host: build-box\nremote_path: ~/builds/app",
+            "from stdin",
+            ConfigFormat::Yaml,
+            None,
+        )
+        .unwrap();
+        assert_eq!(config.host, "build-box");
+
+        let error = parse_config("host: [", "from stdin", ConfigFormat::Yaml, None).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Failed to parse config file: from stdin - "),
+            "{}",
+            error
+        );
+    }
+
+    /// With `sync_submodules`, the git file list has the tracked and
+    /// untracked files of checked-out submodules, nested ones included
+    #[test]
+    fn git_list_includes_submodules() {
+        let dir = std::env::temp_dir().join(format!(
+            "remotebuild-test-submodules-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let git = |cwd: &Path, args: &[&str]| {
+            Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(["-c", "protocol.file.allow=always"])
+                .args(args)
+                .current_dir(cwd)
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+        let repo = |name: &str, file: &str| {
+            let path = dir.join(name);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join(file), "").unwrap();
+            git(&path, &["init", "-q", "."])
+                && git(&path, &["add", "."])
+                && git(&path, &["commit", "-q", "-m", "init"])
+        };
+        if !repo("inner", "inner.c") {
+            eprintln!("git not found, skipping");
+            let _ = fs::remove_dir_all(&dir);
+            return;
+        }
+        let inner = dir.join("inner").to_string_lossy().to_string();
+        assert!(repo("lib", "lib.c"));
+        assert!(git(
+            &dir.join("lib"),
+            &["submodule", "add", "-q", &inner, "deps/inner"]
+        ));
+        assert!(git(&dir.join("lib"), &["commit", "-q", "-m", "inner"]));
+        let lib = dir.join("lib").to_string_lossy().to_string();
+        assert!(repo("project", "main.c"));
+        let project = dir.join("project");
+        assert!(git(&project, &["submodule", "add", "-q", &lib, "lib"]));
+        assert!(git(
+            &project,
+            &["submodule", "update", "-q", "--init", "--recursive"]
+        ));
+        fs::write(project.join("lib/new.c"), "").unwrap();
+        fs::write(project.join("lib/deps/inner/new.h"), "").unwrap();
+
+        let mut files = get_git_files(&project, true).unwrap();
+        files.sort_unstable();
+        assert_eq!(
+            files,
+            [
+                ".gitmodules",
+                "lib/.gitmodules",
+                "lib/deps/inner/inner.c",
+                "lib/deps/inner/new.h",
+                "lib/lib.c",
+                "lib/new.c",
+                "main.c",
+            ]
+        );
+        let mut files = get_git_files(&project, false).unwrap();
+        files.sort_unstable();
+        assert_eq!(files, [".gitmodules", "lib", "main.c"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,275 @@
+//! Content-hash manifest sync for projects without git
+//!
+//! The manifest records the blake3 hash and size of every file that would be
+//! synced. Diffing it against the manifest from the previous successful sync
+//! gives the exact set of changed and deleted paths, which is handed to rsync
+//! through `--files-from` instead of letting it scan the whole tree. Renames
+//! show up as a delete plus an add.
+//!
+//! A manifest is kept for each host and remote path, as each describes what
+//! one destination received. Files whose size and mtime match the previous
+//! manifest keep their hash without being read again, like git's index, so
+//! an unchanged tree costs one `stat` per file. As in git, an mtime that
+//! isn't older than the previous manifest could hide a later write within
+//! the same timestamp, so those files are always hashed. A failed sync
+//! removes the manifest, leaving the next sync to transfer the whole tree.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::patterns::ExcludeSet;
+use crate::state::project_state_path;
+use crate::{stable_hash, Config};
+
+/// Extension of the manifest file in the state directory
+const MANIFEST_EXTENSION: &str = "manifest.json";
+
+/// Snapshot of the synced files of a project
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// Hash of the sync options the manifest was built with
+    options: String,
+    /// When the manifest was built, in nanoseconds since the Unix epoch
+    #[serde(default)]
+    built: u64,
+    /// Hashed files, keyed by path relative to the project root
+    files: BTreeMap<String, FileEntry>,
+}
+
+/// Hash record for a single file
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct FileEntry {
+    /// File size in bytes
+    size: u64,
+    /// Hex-encoded blake3 hash of the contents (or symlink target)
+    hash: String,
+    /// Modification time in nanoseconds since the Unix epoch, 0 if unknown
+    #[serde(default)]
+    mtime: u64,
+}
+
+impl FileEntry {
+    /// Whether both entries record the same contents
+    fn same_contents(&self, other: &Self) -> bool {
+        self.size == other.size && self.hash == other.hash
+    }
+}
+
+/// Paths that differ between two manifests
+#[derive(Debug, Default)]
+pub(crate) struct ManifestDiff {
+    /// Paths that are new or whose contents changed
+    pub(crate) changed: Vec<String>,
+    /// Paths that no longer exist locally
+    pub(crate) deleted: Vec<String>,
+}
+
+impl Manifest {
+    /// Hash every non-excluded file under `project_dir`
+    ///
+    /// `options` identifies the sync settings; a previous manifest built with
+    /// different settings is never diffed against. Hashes of `previous` are
+    /// reused for files it shows unchanged since.
+    pub(crate) fn build(
+        project_dir: &Path,
+        excludes: &ExcludeSet,
+        options: String,
+        previous: Option<&Manifest>,
+    ) -> Result<Self> {
+        let built = nanos(SystemTime::now());
+        let mut paths = Vec::new();
+        collect_files(project_dir, "", excludes, &mut paths)?;
+
+        let workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(paths.len().max(1));
+        let chunk_size = ((paths.len() + workers - 1) / workers).max(1);
+
+        let files = thread::scope(|scope| {
+            let handles: Vec<_> = paths
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .filter_map(|(rel, abs)| {
+                                let known = previous.and_then(|m| m.unchanged(rel, abs));
+                                known.or_else(|| hash_entry(abs)).map(|e| (rel.clone(), e))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap_or_default())
+                .collect()
+        });
+
+        Ok(Self {
+            options,
+            built,
+            files,
+        })
+    }
+
+    /// The entry of `rel` (at `abs`) if its size and mtime show it unchanged
+    /// since this manifest was built
+    fn unchanged(&self, rel: &str, abs: &Path) -> Option<FileEntry> {
+        let entry = self.files.get(rel)?;
+        let meta = fs::symlink_metadata(abs).ok()?;
+        let mtime = meta.modified().map(nanos).ok()?;
+        let trusted = mtime != 0 && mtime / 1_000_000_000 < self.built / 1_000_000_000;
+        (trusted && entry.mtime == mtime && entry.size == meta.len()).then(|| FileEntry {
+            size: entry.size,
+            hash: entry.hash.clone(),
+            mtime,
+        })
+    }
+
+    /// Load the manifest from the last successful sync to the config's
+    /// destination, if any
+    pub(crate) fn load(project_dir: &Path, config: &Config) -> Option<Self> {
+        let content = fs::read_to_string(manifest_path(project_dir, config)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Forget the manifest of the config's destination after a failed sync,
+    /// which may have left the remote in any state
+    pub(crate) fn invalidate(project_dir: &Path, config: &Config) {
+        let _ = fs::remove_file(manifest_path(project_dir, config));
+    }
+
+    /// Store the manifest after a successful sync
+    pub(crate) fn save(&self, project_dir: &Path, config: &Config) -> Result<()> {
+        let path = manifest_path(project_dir, config);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create state dir: {}", parent.display()))?;
+        }
+
+        fs::write(&path, serde_json::to_string(self)?)
+            .with_context(|| format!("Failed to write manifest: {}", path.display()))
+    }
+
+    /// Whether this manifest was built with the given sync options
+    pub(crate) fn has_options(&self, options: &str) -> bool {
+        self.options == options
+    }
+
+    /// Number of files in the manifest
+    pub(crate) fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Compute the paths that changed since `previous`
+    pub(crate) fn diff(&self, previous: &Manifest) -> ManifestDiff {
+        let changed = self
+            .files
+            .iter()
+            .filter(|(path, entry)| {
+                !previous
+                    .files
+                    .get(*path)
+                    .is_some_and(|old| old.same_contents(entry))
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        let deleted = previous
+            .files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .cloned()
+            .collect();
+
+        ManifestDiff { changed, deleted }
+    }
+}
+
+/// Location of the manifest of the config's destination
+fn manifest_path(project_dir: &Path, config: &Config) -> PathBuf {
+    let destination = format!("{}:{}", config.host, config.remote_path);
+    project_state_path(
+        project_dir,
+        &format!(
+            "{:016x}.{}",
+            stable_hash(destination.as_bytes()),
+            MANIFEST_EXTENSION
+        ),
+    )
+}
+
+/// Nanoseconds since the Unix epoch, 0 for earlier times
+fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+/// Recursively collect non-excluded files as (relative, absolute) path pairs
+fn collect_files(
+    dir: &Path,
+    prefix: &str,
+    excludes: &ExcludeSet,
+    paths: &mut Vec<(String, PathBuf)>,
+) -> Result<()> {
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let rel = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+
+        if file_type.is_dir() {
+            if !excludes.excludes_entry(&rel, true) {
+                collect_files(&entry.path(), &rel, excludes, paths)?;
+            }
+        } else if !excludes.excludes_entry(&rel, false) {
+            paths.push((rel, entry.path()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Hash a single file or symlink, skipping entries that vanished
+fn hash_entry(path: &Path) -> Option<FileEntry> {
+    let meta = fs::symlink_metadata(path).ok()?;
+    let mut hasher = blake3::Hasher::new();
+
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(path).ok()?;
+        hasher.update(b"symlink:");
+        hasher.update(target.to_string_lossy().as_bytes());
+    } else {
+        let mut file = fs::File::open(path).ok()?;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buf).ok()?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+    }
+
+    Some(FileEntry {
+        size: meta.len(),
+        hash: hasher.finalize().to_hex().to_string(),
+        mtime: meta.modified().map_or(0, nanos),
+    })
+}
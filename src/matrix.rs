@@ -0,0 +1,395 @@
+//! Build matrix across several hosts
+//!
+//! A config can define a `matrix` of platforms, each with its own host and
+//! optionally its own remote path, build command, environment and artifacts.
+//! `--matrix` syncs and builds every platform concurrently, prefixing build
+//! output with the platform name, and copies each host's artifacts into its
+//! own local directory (by default `dist/<platform>`).
+//!
+//! By default the first failure cancels the other platforms;
+//! `--matrix-continue` lets them finish. The placeholder `{platform}` is
+//! expanded in remote paths, build commands, environment values, artifact
+//! patterns and the local artifact directory.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::artifact::Artifact;
+use crate::{abort, container, estimate, history, nix, resilient, setup, shared};
+use crate::{
+    forward_lines, remote_build_command, remote_command, sync_artifacts, sync_to_remote,
+    BuildCommand, BuildFailed, Config, OutputLevel, SyncScope,
+};
+
+/// Local artifact directory used when a platform doesn't set one
+const DEFAULT_ARTIFACT_DIR: &str = "dist/{platform}";
+
+/// How often a running build checks whether it has been cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+/// Configuration of one platform in the matrix
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PlatformConfig {
+    /// SSH host building this platform
+    host: String,
+
+    /// Remote path replacing the top-level `remote_path`
+    #[serde(default)]
+    remote_path: Option<String>,
+
+    /// Build command replacing the top-level `build_command`
+    #[serde(default)]
+    build_command: Option<BuildCommand>,
+
+    /// Environment variables added to the top-level `env`
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+
+    /// Artifact patterns replacing the top-level `artifacts`
+    #[serde(default)]
+    artifacts: Option<Vec<Artifact>>,
+
+    /// Local directory, relative to the project, that artifacts are copied
+    /// into (default: `dist/{platform}`)
+    #[serde(default)]
+    artifact_dir: Option<String>,
+}
+
+/// A platform ready to build
+struct Platform<'a> {
+    /// Platform name from the config
+    name: &'a str,
+    /// Config with the platform's settings applied
+    config: Config,
+    /// Local directory receiving the artifacts
+    artifact_dir: PathBuf,
+}
+
+/// Result of building a single platform
+enum Outcome {
+    /// Built and fetched successfully in the given time
+    Built(Duration),
+    /// A phase failed after the given time
+    Failed(Duration),
+    /// Stopped because another platform failed
+    Cancelled(Duration),
+}
+
+/// Error for a platform stopped because another one failed
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cancelled after another platform failed")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Build every platform of the matrix concurrently
+pub(crate) fn run_matrix(
+    project_dir: &Path,
+    config: &Config,
+    scope: SyncScope,
+    keep_going: bool,
+) -> Result<()> {
+    if config.matrix.is_empty() {
+        return Err(anyhow!("No matrix configured: add platforms under matrix"));
+    }
+
+    let platforms: Vec<Platform> = config
+        .matrix
+        .iter()
+        .map(|(name, platform)| {
+            let dir = platform
+                .artifact_dir
+                .as_deref()
+                .unwrap_or(DEFAULT_ARTIFACT_DIR);
+            Ok(Platform {
+                name,
+                config: platform_config(config, name, platform)?,
+                artifact_dir: project_dir.join(expand(dir, name)),
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    estimate::load(project_dir);
+    let show = !matches!(config.output_level(), OutputLevel::Quiet);
+    if show {
+        let names: Vec<&str> = platforms.iter().map(|p| p.name).collect();
+        println!(
+            "🧩 Building {} platforms: {}",
+            names.len(),
+            names.join(", ")
+        );
+    }
+
+    let cancel = AtomicBool::new(false);
+    let outcomes: Vec<Outcome> = thread::scope(|s| {
+        let handles: Vec<_> = platforms
+            .iter()
+            .map(|platform| {
+                let cancel = &cancel;
+                s.spawn(move || {
+                    let name = platform.name;
+                    let start = Instant::now();
+                    let result = build_platform(project_dir, platform, scope, show, cancel);
+                    let elapsed = start.elapsed();
+                    match result {
+                        Ok(()) => Outcome::Built(elapsed),
+                        Err(e) if e.downcast_ref::<Cancelled>().is_some() => {
+                            Outcome::Cancelled(elapsed)
+                        }
+                        Err(e) => {
+                            eprintln!("   ✗ {}: {:#}", name, e);
+                            if !keep_going {
+                                cancel.store(true, Ordering::SeqCst);
+                            }
+                            Outcome::Failed(elapsed)
+                        }
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| h.join().unwrap_or(Outcome::Failed(Duration::ZERO)))
+            .collect()
+    });
+
+    println!();
+    print_summary(&platforms, &outcomes);
+
+    let failed: Vec<&str> = platforms
+        .iter()
+        .zip(&outcomes)
+        .filter(|(_, outcome)| !matches!(outcome, Outcome::Built(_)))
+        .map(|(platform, _)| platform.name)
+        .collect();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} of {} platforms did not build: {}",
+            failed.len(),
+            platforms.len(),
+            failed.join(", ")
+        ))
+    }
+}
+
+/// Sync, build and fetch the artifacts of one platform
+fn build_platform(
+    project_dir: &Path,
+    platform: &Platform,
+    scope: SyncScope,
+    show: bool,
+    cancel: &AtomicBool,
+) -> Result<()> {
+    let (config, name) = (&platform.config, platform.name);
+    let check_cancel = || {
+        if cancel.load(Ordering::SeqCst) {
+            Err(anyhow::Error::new(Cancelled))
+        } else {
+            Ok(())
+        }
+    };
+
+    let guard = abort::RunGuard::new(Some(name.to_string()));
+    guard.enter("container check");
+    container::prepare(config)?;
+    nix::check_installed(config)?;
+    check_cancel()?;
+    guard.enter("sync");
+    let sync_start = Instant::now();
+    let mut reconnects = resilient::Reconnects::default();
+    resilient::retry(config, &mut reconnects, "Sync", || {
+        sync_to_remote(project_dir, config, scope)
+    })?;
+    let tags = history::RunTags::new(scope).with_sync(sync_start.elapsed());
+    check_cancel()?;
+    guard.enter("setup");
+    setup::run_setup(config)?;
+    guard.enter("nix shell");
+    nix::enter_shell(config)?;
+    check_cancel()?;
+
+    guard.enter("build");
+    let start = Instant::now();
+    let build = resilient::retry_lost(config, &mut reconnects, "Build", || {
+        run_build(config, name, show, cancel)
+    });
+    let exit_code = match &build {
+        Ok(()) => Some(0),
+        Err(e) => e
+            .downcast_ref::<BuildFailed>()
+            .and_then(|b| b.status.code()),
+    };
+    if build
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<Cancelled>())
+        .is_none()
+    {
+        history::record_build(project_dir, config, exit_code, start.elapsed(), tags);
+    }
+    build?;
+    check_cancel()?;
+
+    guard.enter("artifact download");
+    let local_dir = &platform.artifact_dir;
+    fs::create_dir_all(local_dir)
+        .with_context(|| format!("Failed to create artifact dir: {}", local_dir.display()))?;
+    resilient::retry(config, &mut reconnects, "Artifact download", || {
+        sync_artifacts(config, project_dir, local_dir)
+    })?;
+
+    if show {
+        println!("   ✓ {}: done", name);
+    }
+    Ok(())
+}
+
+/// Run the build command, streaming its output with the platform as prefix
+/// and killing it when the run is cancelled
+fn run_build(config: &Config, name: &str, show: bool, cancel: &AtomicBool) -> Result<()> {
+    let cmd = remote_build_command(config)?;
+    let mut child = remote_command(config, &cmd)
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("Failed to run build over SSH")?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    let status = thread::scope(|s| {
+        s.spawn(|| forward_lines(stdout, name, show, false));
+        s.spawn(|| forward_lines(stderr, name, show, true));
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            if cancel.load(Ordering::SeqCst) {
+                // Closing the connection ends the remote command once it
+                // next writes output
+                let _ = child.kill();
+                let _ = child.wait();
+                return Ok::<_, anyhow::Error>(None);
+            }
+            thread::sleep(CANCEL_POLL);
+        }
+    })?;
+
+    match status {
+        None => Err(anyhow::Error::new(Cancelled)),
+        Some(status) if !status.success() => Err(BuildFailed {
+            status,
+            command: config.build_command.clone(),
+        }
+        .into()),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Derive the config used to build `name`, with the platform's host,
+/// command, environment and artifacts applied
+fn platform_config(config: &Config, name: &str, platform: &PlatformConfig) -> Result<Config> {
+    if platform.host.is_empty() {
+        return Err(anyhow!("Platform {} has no host", name));
+    }
+
+    let build_command = platform
+        .build_command
+        .as_ref()
+        .unwrap_or(&config.build_command);
+    if build_command.is_empty() {
+        return Err(anyhow!(
+            "Platform {} has no build_command and none is configured at the top level",
+            name
+        ));
+    }
+
+    let mut env = config.env.clone();
+    env.extend(
+        platform
+            .env
+            .iter()
+            .map(|(key, value)| (key.clone(), expand(value, name))),
+    );
+    env.insert("REMOTEBUILD_PLATFORM".to_string(), name.to_string());
+
+    let artifacts = platform
+        .artifacts
+        .as_ref()
+        .unwrap_or(&config.artifacts)
+        .iter()
+        .map(|artifact| artifact.map(|pattern| expand(pattern, name)))
+        .collect();
+
+    Ok(Config {
+        host: platform.host.clone(),
+        remote_path: expand(
+            &platform
+                .remote_path
+                .as_deref()
+                .map_or_else(|| config.remote_path.clone(), shared::expand_user),
+            name,
+        ),
+        build_command: build_command.map(|command| expand(command, name)),
+        env,
+        artifacts,
+        // Concurrent spinners would garble each other; build output is
+        // streamed with a prefix instead
+        output: "quiet".to_string(),
+        // Platforms syncing to the same host and remote path at once would
+        // race on one manifest
+        manifest_sync: false,
+        ..config.clone()
+    })
+}
+
+/// Expand the `{platform}` placeholder
+fn expand(template: &str, name: &str) -> String {
+    template.replace("{platform}", name)
+}
+
+/// Print the platform × host × status table
+fn print_summary(platforms: &[Platform], outcomes: &[Outcome]) {
+    let name_width = platforms
+        .iter()
+        .map(|p| p.name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let host_width = platforms
+        .iter()
+        .map(|p| p.config.host.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    println!("📋 Matrix summary");
+    for (platform, outcome) in platforms.iter().zip(outcomes) {
+        let (mark, status, duration) = match outcome {
+            Outcome::Built(d) => ("✓", "built", d),
+            Outcome::Failed(d) => ("✗", "failed", d),
+            Outcome::Cancelled(d) => ("-", "cancelled", d),
+        };
+        println!(
+            "   {} {:<nw$}  {:<hw$}  {:<9}  {:.1}s",
+            mark,
+            platform.name,
+            platform.config.host,
+            status,
+            duration.as_secs_f64(),
+            nw = name_width,
+            hw = host_width
+        );
+    }
+}
@@ -0,0 +1,155 @@
+//! Running the build command inside a Nix development shell
+//!
+//! With a `nix` section, the build command runs through `nix develop <ref> -c`
+//! for a flake devshell, or `nix-shell <file> --run` for a legacy shell file.
+//! The `env` map, target variables and the command itself are passed as one
+//! quoted script, so they reach the shell unchanged.
+//!
+//! Whether nix is installed is checked before the sync. Evaluating and
+//! building a devshell for the first time can take minutes, so after the sync
+//! the shell is entered once with a no-op command as its own phase, with nix's
+//! progress streamed, and the build phase only measures the build.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+
+use crate::{
+    clear_status, ensure_ssh_connection, print_status, run_ssh_command_output,
+    run_ssh_command_streaming, Config, OutputLevel,
+};
+
+/// Nix shell settings for the build command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct NixConfig {
+    /// Flake devshell for `nix develop`, e.g. `.#embedded`
+    #[serde(default)]
+    devshell: Option<String>,
+
+    /// Shell file for `nix-shell`, e.g. `shell.nix`
+    #[serde(default)]
+    shell_file: Option<String>,
+
+    /// Extra arguments for `nix develop` or `nix-shell`
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+/// The kind of shell a [`NixConfig`] selects
+enum Shell<'a> {
+    /// `nix develop` with a flake reference
+    Develop(&'a str),
+    /// `nix-shell` with a shell file
+    File(&'a str),
+}
+
+impl NixConfig {
+    /// The configured shell, which must be exactly one of the two kinds
+    fn shell(&self) -> Result<Shell<'_>> {
+        match (&self.devshell, &self.shell_file) {
+            (Some(devshell), None) => Ok(Shell::Develop(devshell)),
+            (None, Some(file)) => Ok(Shell::File(file)),
+            (Some(_), Some(_)) => Err(anyhow!("Set only one of nix.devshell and nix.shell_file")),
+            (None, None) => Err(anyhow!("Set nix.devshell or nix.shell_file")),
+        }
+    }
+
+    /// Name of the shell for messages
+    fn describe(&self) -> String {
+        match self.shell() {
+            Ok(Shell::Develop(devshell)) => devshell.to_string(),
+            Ok(Shell::File(file)) => file.to_string(),
+            Err(_) => "nix shell".to_string(),
+        }
+    }
+}
+
+/// Wrap a command so that it runs in the configured Nix shell
+pub(crate) fn wrap_command(nix: &NixConfig, command: &str) -> Result<String> {
+    let mut extra = String::new();
+    for arg in &nix.args {
+        extra.push(' ');
+        extra.push_str(&escape(Cow::Borrowed(arg.as_str())));
+    }
+    let script = escape(Cow::Borrowed(command));
+
+    Ok(match nix.shell()? {
+        Shell::Develop(devshell) => format!(
+            "nix develop {}{} -c sh -c {}",
+            escape(Cow::Borrowed(devshell)),
+            extra,
+            script
+        ),
+        Shell::File(file) => format!(
+            "nix-shell {}{} --run {}",
+            escape(Cow::Borrowed(file)),
+            extra,
+            script
+        ),
+    })
+}
+
+/// Check that nix is installed on the remote host
+pub(crate) fn check_installed(config: &Config) -> Result<()> {
+    let Some(nix) = &config.nix else {
+        return Ok(());
+    };
+    if config.docker.is_some() {
+        return Err(anyhow!(
+            "nix and docker can't be combined; run nix from the build command inside the image"
+        ));
+    }
+    ensure_ssh_connection(config)?;
+    let binary = match nix.shell()? {
+        Shell::Develop(_) => "nix",
+        Shell::File(_) => "nix-shell",
+    };
+
+    let found = run_ssh_command_output(config, &format!("command -v {} || echo missing", binary))?;
+    if found.trim() == "missing" {
+        return Err(anyhow!(
+            "{} is not installed on {} (if it is, make sure it is on PATH for \
+             non-interactive SSH sessions)",
+            binary,
+            config.host
+        ));
+    }
+    Ok(())
+}
+
+/// Enter the Nix shell once in the remote project directory, so that its
+/// first evaluation runs (and can fail) before the build
+///
+/// The project must already be synced, since the flake or shell file comes
+/// from it.
+pub(crate) fn enter_shell(config: &Config) -> Result<()> {
+    let Some(nix) = &config.nix else {
+        return Ok(());
+    };
+
+    let output = config.output_level();
+    let mut spinner = print_status(output, &format!("❄️  Preparing {} ", nix.describe()));
+    let cmd = format!(
+        "cd {} && {}",
+        config.remote_dir().shell(),
+        wrap_command(nix, "true")?
+    );
+    let result = run_ssh_command_streaming(config, &cmd, "nix");
+    clear_status(output, &mut spinner);
+
+    let entered = result?;
+    if !entered.status.success() {
+        return Err(anyhow!(
+            "Failed to enter nix shell {} ({}){}",
+            nix.describe(),
+            entered.status,
+            entered.tail()
+        ));
+    }
+
+    if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) {
+        println!("   ✓ Nix shell {} ready", nix.describe());
+    }
+    Ok(())
+}
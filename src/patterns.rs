@@ -0,0 +1,925 @@
+//! Exclude patterns with gitignore semantics
+//!
+//! `exclude_patterns` (after the default and detected excludes) follow the
+//! rules of `.gitignore`:
+//!
+//! - A trailing `/` only matches directories.
+//! - A pattern with a `/` at the start or in the middle is anchored at the
+//!   project root; anything else matches at any depth.
+//! - `*` and `?` don't cross `/`. `**` as a whole path component matches any
+//!   number of directories (`a/**/b`, `**/b`, `a/**`). Other runs of `*`
+//!   count as a single `*`.
+//! - `!pattern` re-includes what an earlier pattern excluded, and the last
+//!   matching pattern decides. A file can't be re-included while one of its
+//!   parent directories is excluded, since neither git nor rsync looks inside
+//!   excluded directories.
+//!
+//! rsync uses the first matching rule and anchors patterns differently, so
+//! the patterns are translated into `--include`/`--exclude` rules in reverse
+//! order. File lists we build ourselves (manifests, `--files-from` lists)
+//! are filtered with the same rules before they are handed to rsync, which
+//! doesn't filter listed paths.
+//!
+//! `include_patterns` use the same syntax and win over every exclude, so
+//! `build/config.h` is synced even though `build/` is excluded. What an
+//! included directory contains is included with it. To reach into excluded
+//! directories, the leading directories of an anchored include are entered,
+//! up to its first component with a wildcard, without syncing anything else
+//! they contain. They come first in the rsync rules, followed by rules
+//! excluding the rest of the excluded leading directories, then the excludes.
+//! An include without a leading directory, such as `*.h`, has none to enter,
+//! so it matches at any depth but only outside excluded directories.
+
+/// A compiled exclude pattern
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    /// The pattern as configured
+    source: String,
+    /// Glob to match, without leading or trailing slashes
+    glob: String,
+    /// Whether the pattern is matched against the full relative path
+    anchored: bool,
+    /// Whether the pattern only matches directories
+    dir_only: bool,
+    /// Whether the pattern re-includes what it matches (`!pattern`)
+    negated: bool,
+}
+
+impl Pattern {
+    /// Parse a single pattern
+    pub(crate) fn new(pattern: &str) -> Self {
+        let (negated, body) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let dir_only = body.ends_with('/');
+        let trimmed = body.trim_end_matches('/');
+        let anchored = trimmed.contains('/');
+        Self {
+            source: pattern.to_string(),
+            glob: collapse_stars(trimmed.trim_start_matches('/')),
+            anchored,
+            dir_only,
+            negated,
+        }
+    }
+
+    /// The leading directories of the pattern as rsync would walk into
+    /// them, up to its first component with a wildcard
+    fn literal_parents(&self) -> Vec<&str> {
+        if !self.anchored {
+            return Vec::new();
+        }
+        let literal = self
+            .glob
+            .split('/')
+            .take_while(|c| !c.contains(['*', '?', '[', '\\']))
+            .map(|c| c.len() + 1)
+            .collect::<Vec<_>>();
+        let mut end = 0;
+        let mut parents = Vec::new();
+        for len in literal {
+            end += len;
+            if end > self.glob.len() {
+                // The last component is the path itself
+                break;
+            }
+            parents.push(&self.glob[..end - 1]);
+        }
+        parents
+    }
+
+    /// Check whether the pattern matches one path (file or directory)
+    fn matches_path(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(&self.glob, path)
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            glob_match(&self.glob, name)
+        }
+    }
+
+    /// The rsync patterns matching the same paths
+    ///
+    /// rsync matches a pattern containing a `/` at any depth unless it starts
+    /// with `/`, and its `**/` needs at least one directory, so `a/**/b`
+    /// becomes both `/a/b` and `/a/**/b`.
+    fn rsync_patterns(&self) -> Vec<String> {
+        let suffix = if self.dir_only { "/" } else { "" };
+        let (prefix, glob) = if !self.anchored {
+            ("", self.glob.as_str())
+        } else if let Some(rest) = self.glob.strip_prefix("**/") {
+            // Matching at any depth is what rsync does without the `/`
+            ("", rest)
+        } else {
+            ("/", self.glob.as_str())
+        };
+
+        let mut variants = vec![String::new()];
+        let mut rest = glob;
+        while let Some(index) = rest.find("/**/") {
+            let head = &rest[..index];
+            variants = variants
+                .into_iter()
+                .flat_map(|v| [format!("{}{}/", v, head), format!("{}{}/**/", v, head)])
+                .collect();
+            rest = &rest[index + 4..];
+        }
+        variants
+            .into_iter()
+            .map(|v| {
+                let pattern = format!("{}{}{}{}", prefix, v, rest, suffix);
+                // rsync only honours `\` escapes in patterns with wildcards
+                if pattern.contains(['*', '?', '[']) {
+                    pattern
+                } else {
+                    pattern.replace('\\', "")
+                }
+            })
+            .collect()
+    }
+}
+
+/// Replace runs of `*` that aren't a whole path component with a single `*`,
+/// as git does
+fn collapse_stars(glob: &str) -> String {
+    glob.split('/')
+        .map(|component| {
+            if component == "**" {
+                return component.to_string();
+            }
+            let mut collapsed = String::with_capacity(component.len());
+            for c in component.chars() {
+                if !(c == '*' && collapsed.ends_with('*')) {
+                    collapsed.push(c);
+                }
+            }
+            collapsed
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// A set of exclude patterns, with the include patterns overriding them
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExcludeSet {
+    /// The compiled patterns, in order
+    patterns: Vec<Pattern>,
+    /// The compiled include patterns
+    includes: Vec<Pattern>,
+}
+
+impl ExcludeSet {
+    /// Compile a list of exclude patterns
+    pub(crate) fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            patterns: patterns.into_iter().map(Pattern::new).collect(),
+            includes: Vec::new(),
+        }
+    }
+
+    /// Add include patterns, which win over the excludes
+    pub(crate) fn with_includes<'a>(mut self, patterns: impl IntoIterator<Item = &'a str>) -> Self {
+        self.includes.extend(patterns.into_iter().map(Pattern::new));
+        self
+    }
+
+    /// Check whether an include pattern matches `path` or one of its parent
+    /// directories
+    pub(crate) fn forces(&self, path: &str, is_dir: bool) -> bool {
+        self.includes.iter().any(|include| {
+            include.matches_path(path, is_dir)
+                || path
+                    .match_indices('/')
+                    .any(|(end, _)| include.matches_path(&path[..end], true))
+        })
+    }
+
+    /// Check whether `path` is forced, or a directory leading to an include
+    fn included(&self, path: &str, is_dir: bool) -> bool {
+        self.forces(path, is_dir)
+            || (is_dir
+                && self
+                    .includes
+                    .iter()
+                    .any(|include| include.literal_parents().contains(&path)))
+    }
+
+    /// The last pattern matching a single path, ignoring its parents
+    fn last_match(&self, path: &str, is_dir: bool) -> Option<&Pattern> {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| p.matches_path(path, is_dir))
+    }
+
+    /// Check whether a single path is excluded by the exclude patterns,
+    /// ignoring its parents and the includes
+    fn excluded_itself(&self, path: &str, is_dir: bool) -> bool {
+        self.last_match(path, is_dir).is_some_and(|p| !p.negated)
+    }
+
+    /// Check whether a path met while walking the tree is excluded
+    ///
+    /// Walks don't enter excluded directories, so the parents only need
+    /// checking when includes lead into excluded directories.
+    pub(crate) fn excludes_entry(&self, path: &str, is_dir: bool) -> bool {
+        if self.includes.is_empty() {
+            return self.excluded_itself(path, is_dir);
+        }
+        !self.included(path, is_dir)
+            && (self.excluded_itself(path, is_dir) || self.excluded_parent(path).is_some())
+    }
+
+    /// The first parent directory of `path` that is excluded, with the
+    /// pattern excluding it
+    fn excluded_parent<'a>(&self, path: &'a str) -> Option<(&'a str, &Pattern)> {
+        let mut end = 0;
+        while let Some(offset) = path[end..].find('/') {
+            end += offset;
+            if let Some(pattern) = self.last_match(&path[..end], true).filter(|p| !p.negated) {
+                return Some((&path[..end], pattern));
+            }
+            end += 1;
+        }
+        None
+    }
+
+    /// Check whether a file is excluded, either itself or through one of
+    /// its parent directories, as rsync would while walking the tree
+    ///
+    /// An include only reaches the file if the walk enters every directory
+    /// leading to it, as rsync doesn't look inside excluded ones either.
+    pub(crate) fn excludes_file(&self, path: &str) -> bool {
+        path.match_indices('/')
+            .any(|(end, _)| self.excludes_entry(&path[..end], true))
+            || self.excludes_entry(path, false)
+    }
+
+    /// rsync arguments applying the patterns: the includes and their leading
+    /// directories first, then the excludes in reverse, since rsync uses the
+    /// first matching rule
+    pub(crate) fn rsync_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = self
+            .includes
+            .iter()
+            .flat_map(|include| {
+                include.rsync_patterns().into_iter().flat_map(|p| {
+                    let contents = format!("{}/**", p.trim_end_matches('/'));
+                    [
+                        format!("--include={}", p),
+                        format!("--include={}", contents),
+                    ]
+                })
+            })
+            .collect();
+
+        let mut parents: Vec<&str> = Vec::new();
+        for include in &self.includes {
+            for parent in include.literal_parents() {
+                if !parents.contains(&parent) {
+                    parents.push(parent);
+                }
+            }
+        }
+        args.extend(parents.iter().flat_map(|parent| {
+            Pattern::new(&format!("/{}/", parent))
+                .rsync_patterns()
+                .into_iter()
+                .map(|p| format!("--include={}", p))
+        }));
+        // The rest of what an excluded leading directory contains stays out
+        args.extend(
+            parents
+                .iter()
+                .filter(|parent| self.excluded_parent(&format!("{}/_", parent)).is_some())
+                .map(|parent| format!("--exclude=/{}/*", parent)),
+        );
+
+        args.extend(self.patterns.iter().rev().flat_map(|pattern| {
+            let kind = if pattern.negated {
+                "include"
+            } else {
+                "exclude"
+            };
+            pattern
+                .rsync_patterns()
+                .into_iter()
+                .map(move |p| format!("--{}={}", kind, p))
+        }));
+        args
+    }
+
+    /// Warn about patterns that can't work as written, naming them
+    pub(crate) fn lint(&self) {
+        for (i, pattern) in self.patterns.iter().enumerate() {
+            let body = pattern.source.trim_start_matches('!');
+            let problem = if body.trim_matches('/').is_empty() {
+                Some("it matches nothing".to_string())
+            } else if body.starts_with("+ ") || body.starts_with("- ") {
+                Some(
+                    "rsync filter rules aren't supported; patterns are gitignore-style".to_string(),
+                )
+            } else if body.contains("***") {
+                Some("`***` is rsync syntax; use `dir/` or `dir/**` instead".to_string())
+            } else if pattern.negated {
+                // A literal directory prefix can be checked against the
+                // patterns before this one
+                let components: Vec<&str> = pattern.glob.split('/').collect();
+                let literal = components
+                    .iter()
+                    .take_while(|c| !c.contains(['*', '?', '[', '\\']))
+                    .count();
+                // Without wildcards, the last component is the path itself
+                let dirs = components[..literal.min(components.len() - 1)].join("/");
+                let earlier = ExcludeSet {
+                    patterns: self.patterns[..i].to_vec(),
+                    includes: Vec::new(),
+                };
+                let probe = format!("{}/_", dirs);
+                Some(&earlier)
+                    .filter(|_| pattern.anchored && !dirs.is_empty())
+                    .and_then(|earlier| earlier.excluded_parent(&probe))
+                    .map(|(dir, by)| {
+                        format!(
+                            "files in {}/ can't be re-included while `{}` excludes the \
+                             directory; add `!{}/` before it",
+                            dir, by.source, dir
+                        )
+                    })
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                eprintln!(
+                    "   ⚠ Warning: exclude pattern `{}`: {}",
+                    pattern.source, problem
+                );
+            }
+        }
+        for include in self.includes.iter().filter(|p| p.negated) {
+            eprintln!(
+                "   ⚠ Warning: include pattern `{}`: includes can't be negated; \
+                 add the pattern to exclude_patterns instead",
+                include.source
+            );
+        }
+    }
+}
+
+/// Match `text` against a glob supporting `*`, `**`, `?` and `[...]` classes
+///
+/// A single `*` never crosses a `/`, while `**` matches across directories.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_from(&pattern, &text)
+}
+
+/// Recursive helper for [`glob_match`] operating on char slices
+fn glob_match_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let mut rest = &pattern[2..];
+            // "**/" may also match zero directories
+            if rest.first() == Some(&'/') && glob_match_from(&rest[1..], text) {
+                return true;
+            }
+            while rest.first() == Some(&'*') {
+                rest = &rest[1..];
+            }
+            (0..=text.len()).any(|i| glob_match_from(rest, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match_from(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => match text.first() {
+            Some(c) if *c != '/' => glob_match_from(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some('[') => {
+            let Some(c) = text.first() else {
+                return false;
+            };
+            match match_class(&pattern[1..], *c) {
+                Some((matched, consumed)) => {
+                    matched && glob_match_from(&pattern[1 + consumed..], &text[1..])
+                }
+                // Unterminated class: treat '[' literally
+                None => *c == '[' && glob_match_from(&pattern[1..], &text[1..]),
+            }
+        }
+        Some('\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match_from(&pattern[2..], &text[1..])
+        }
+        Some(p) => text.first() == Some(p) && glob_match_from(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Match a character against a `[...]` class body
+///
+/// Returns whether the character matched and how many pattern characters the
+/// class consumed (including the closing `]`), or `None` if unterminated.
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(class.first(), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        let start = class[i];
+        if start == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        if class.get(i + 1) == Some(&'-') && class.get(i + 2).is_some_and(|e| *e != ']') {
+            let end = class[i + 2];
+            matched |= start <= c && c <= end;
+            i += 3;
+        } else {
+            matched |= start == c;
+            i += 1;
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process::Command;
+
+    /// Files of the fixture tree the tables are checked against
+    const TREE: &[&str] = &[
+        "README.md",
+        "a/b/c/d.txt",
+        "a/d.txt",
+        "assets/config/app.json",
+        "assets/config/nested/deep.json",
+        "assets/images/logo.png",
+        "build/config.h",
+        "build/gen/version.h",
+        "build/obj/main.o",
+        "debug.log",
+        "docs/build/index.html",
+        "logs/app.log",
+        "logs/keep.log",
+        "main.c",
+        "src/debug.log",
+        "src/gen/out.rs",
+        "src/main.rs",
+        "src/with\"quote.c",
+        "src/город.c",
+        "third_party/big-vendor/lib.c",
+        "third_party/small/lib.c",
+        "x/a/d.txt",
+    ];
+
+    /// Exclude patterns and the files of [`TREE`] that `git check-ignore`
+    /// reports as ignored with them as `.gitignore`
+    const GITIGNORE_TABLE: &[(&[&str], &[&str])] = &[
+        (
+            &["*.log"],
+            &[
+                "debug.log",
+                "logs/app.log",
+                "logs/keep.log",
+                "src/debug.log",
+            ],
+        ),
+        (&["/main.c"], &["main.c"]),
+        (&["город.*"], &["src/город.c"]),
+        (&["*\"*"], &["src/with\"quote.c"]),
+        (
+            &["build/"],
+            &[
+                "build/config.h",
+                "build/gen/version.h",
+                "build/obj/main.o",
+                "docs/build/index.html",
+            ],
+        ),
+        (
+            &["/build/"],
+            &["build/config.h", "build/gen/version.h", "build/obj/main.o"],
+        ),
+        // The parent directory stays excluded, so the negation can't work
+        (
+            &["assets/**", "!assets/config/*.json"],
+            &[
+                "assets/config/app.json",
+                "assets/config/nested/deep.json",
+                "assets/images/logo.png",
+            ],
+        ),
+        (
+            &["assets/**", "!assets/config/", "!assets/config/*.json"],
+            &["assets/config/nested/deep.json", "assets/images/logo.png"],
+        ),
+        (&["a/**/d.txt"], &["a/b/c/d.txt", "a/d.txt"]),
+        (&["**/gen/"], &["build/gen/version.h", "src/gen/out.rs"]),
+        (&["d.txt"], &["a/b/c/d.txt", "a/d.txt", "x/a/d.txt"]),
+        (
+            &["*.log", "!keep.log"],
+            &["debug.log", "logs/app.log", "src/debug.log"],
+        ),
+        (
+            &["logs/", "!logs/keep.log"],
+            &["logs/app.log", "logs/keep.log"],
+        ),
+        (&["src/*.rs"], &["src/main.rs"]),
+        (
+            &["third_party/big-vendor/"],
+            &["third_party/big-vendor/lib.c"],
+        ),
+        (
+            &["*", "!*/", "!*.rs"],
+            &[
+                "README.md",
+                "a/b/c/d.txt",
+                "a/d.txt",
+                "assets/config/app.json",
+                "assets/config/nested/deep.json",
+                "assets/images/logo.png",
+                "build/config.h",
+                "build/gen/version.h",
+                "build/obj/main.o",
+                "debug.log",
+                "docs/build/index.html",
+                "logs/app.log",
+                "logs/keep.log",
+                "main.c",
+                "src/debug.log",
+                "src/with\"quote.c",
+                "src/город.c",
+                "third_party/big-vendor/lib.c",
+                "third_party/small/lib.c",
+                "x/a/d.txt",
+            ],
+        ),
+        (&["doc?/"], &["docs/build/index.html"]),
+        (&["a/d.txt"], &["a/d.txt"]),
+        (&["/a/**"], &["a/b/c/d.txt", "a/d.txt"]),
+    ];
+
+    /// The files of [`TREE`] the set excludes, as filtered from a file list
+    fn excluded(set: &ExcludeSet) -> Vec<&'static str> {
+        TREE.iter()
+            .copied()
+            .filter(|file| set.excludes_file(file))
+            .collect()
+    }
+
+    /// A fresh empty directory for a test
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("remotebuild-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The patterns exclude what the table says
+    #[test]
+    fn gitignore_table() {
+        for (patterns, ignored) in GITIGNORE_TABLE {
+            let set = ExcludeSet::new(patterns.iter().copied());
+            assert_eq!(&excluded(&set), ignored, "patterns {:?}", patterns);
+        }
+    }
+
+    /// The table agrees with `git check-ignore` on the fixture tree
+    #[test]
+    fn gitignore_table_matches_git() {
+        let dir = temp_dir("gitignore");
+        let git = |args: &[&str], stdin: Option<&str>| {
+            use std::io::Write;
+            let mut child = Command::new("git")
+                .arg("-C")
+                .arg(&dir)
+                .args(["-c", "core.excludesFile=/dev/null"])
+                .args(args)
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .ok()?;
+            let mut input = child.stdin.take()?;
+            input.write_all(stdin.unwrap_or("").as_bytes()).ok()?;
+            drop(input);
+            child.wait_with_output().ok()
+        };
+        if git(&["init", "-q", "."], None).is_none() {
+            eprintln!("git not found, skipping");
+            return;
+        }
+        for file in TREE {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        // NUL-separated, so git doesn't quote the unusual names
+        let files = TREE.join("\0");
+        for (patterns, ignored) in GITIGNORE_TABLE {
+            fs::write(dir.join(".gitignore"), patterns.join("\n")).unwrap();
+            let output = git(&["check-ignore", "-z", "--stdin"], Some(&files)).unwrap();
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let mut reported: Vec<&str> = stdout.split_terminator('\0').collect();
+            reported.sort_unstable();
+            assert_eq!(&reported, ignored, "patterns {:?}", patterns);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Match `text` against an rsync wildcard pattern: `*` and `?` stop at
+    /// `/`, `**` doesn't, and nothing else is special in the fixtures
+    fn rsync_wild(pattern: &[u8], text: &[u8]) -> bool {
+        match pattern.first() {
+            None => text.is_empty(),
+            Some(b'*') if pattern.get(1) == Some(&b'*') => {
+                (0..=text.len()).any(|i| rsync_wild(&pattern[2..], &text[i..]))
+            }
+            Some(b'*') => {
+                for i in 0..=text.len() {
+                    if rsync_wild(&pattern[1..], &text[i..]) {
+                        return true;
+                    }
+                    if text.get(i) == Some(&b'/') {
+                        break;
+                    }
+                }
+                false
+            }
+            Some(b'?') => {
+                text.first().is_some_and(|c| *c != b'/') && rsync_wild(&pattern[1..], &text[1..])
+            }
+            Some(c) => text.first() == Some(c) && rsync_wild(&pattern[1..], &text[1..]),
+        }
+    }
+
+    /// Whether an rsync filter pattern matches `path`, as rsync's manual
+    /// describes: a leading `/` anchors at the transfer root, a pattern
+    /// with a `/` or `**` matches the end of the path at a `/`, and any
+    /// other only the name
+    fn rsync_rule_matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+        let dir_only = pattern.ends_with('/');
+        if dir_only && !is_dir {
+            return false;
+        }
+        let pattern = pattern.trim_end_matches('/');
+        if let Some(anchored) = pattern.strip_prefix('/') {
+            return rsync_wild(anchored.as_bytes(), path.as_bytes());
+        }
+        let pattern = pattern.as_bytes();
+        if pattern.contains(&b'/') || pattern.windows(2).any(|w| w == b"**") {
+            rsync_wild(pattern, path.as_bytes())
+                || path
+                    .match_indices('/')
+                    .any(|(i, _)| rsync_wild(pattern, &path.as_bytes()[i + 1..]))
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            rsync_wild(pattern, name.as_bytes())
+        }
+    }
+
+    /// The files of `tree` a full rsync sync with `args` leaves out: the
+    /// first matching rule decides, and excluded directories aren't entered
+    fn rsync_excluded<'a>(args: &[String], tree: &[&'a str]) -> Vec<&'a str> {
+        let excludes = |path: &str, is_dir: bool| {
+            args.iter()
+                .find_map(|arg| {
+                    let (kind, pattern) = arg.strip_prefix("--")?.split_once('=')?;
+                    rsync_rule_matches(pattern, path, is_dir).then_some(kind == "exclude")
+                })
+                .unwrap_or(false)
+        };
+        tree.iter()
+            .copied()
+            .filter(|file| {
+                file.match_indices('/')
+                    .any(|(end, _)| excludes(&file[..end], true))
+                    || excludes(file, false)
+            })
+            .collect()
+    }
+
+    /// A git file list filtered with the patterns keeps the same files as a
+    /// full sync applying them as rsync rules
+    #[test]
+    fn file_list_filter_matches_rsync() {
+        for (patterns, ignored) in GITIGNORE_TABLE {
+            let set = ExcludeSet::new(patterns.iter().copied());
+            assert_eq!(
+                &rsync_excluded(&set.rsync_args(), TREE),
+                ignored,
+                "patterns {:?}, rules {:?}",
+                patterns,
+                set.rsync_args()
+            );
+        }
+    }
+
+    /// The built-in and default excludes leave out the same files of a file
+    /// list as of a full sync
+    #[test]
+    fn default_excludes_match_rsync() {
+        let tree = [
+            ".git",
+            ".gitmodules",
+            ".ninja_log",
+            ".remotebuild-project",
+            ".remotebuild/lock",
+            "build/out.o",
+            "compile_commands.json",
+            "game.elf",
+            "game.nds",
+            "src/.remotebuild-project",
+            "src/main.c",
+            "src/with\"quote.c",
+            "src/город.c",
+            "third_party/big-vendor/lib.c",
+            "third_party/small/lib.c",
+            "with\"quote.nds",
+            "игра.elf",
+        ];
+        let set = ExcludeSet::new(
+            crate::ALWAYS_EXCLUDED
+                .iter()
+                .chain(crate::DEFAULT_EXCLUDES)
+                .chain(&["third_party/big-vendor/"])
+                .copied(),
+        );
+        let listed: Vec<&str> = tree
+            .iter()
+            .copied()
+            .filter(|file| set.excludes_file(file))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ".git",
+                ".ninja_log",
+                ".remotebuild-project",
+                ".remotebuild/lock",
+                "build/out.o",
+                "compile_commands.json",
+                "game.elf",
+                "game.nds",
+                "third_party/big-vendor/lib.c",
+                "with\"quote.nds",
+                "игра.elf",
+            ]
+        );
+        assert_eq!(rsync_excluded(&set.rsync_args(), &tree), listed);
+    }
+
+    /// rsync rules come in reverse, so the last matching pattern wins as in
+    /// gitignore, and `**/` gets a variant for zero directories
+    #[test]
+    fn rsync_rules_reverse_the_patterns() {
+        let set = ExcludeSet::new(["*.log", "!keep.log", "a/**/d.txt"]);
+        assert_eq!(
+            set.rsync_args(),
+            [
+                "--exclude=/a/d.txt",
+                "--exclude=/a/**/d.txt",
+                "--include=keep.log",
+                "--exclude=*.log",
+            ]
+        );
+    }
+
+    /// Exclude and include patterns with the files of [`TREE`] they leave
+    /// out
+    const INCLUDE_TABLE: &[(&[&str], &[&str], &[&str])] = &[
+        (
+            &["build/"],
+            &["build/config.h"],
+            &[
+                "build/gen/version.h",
+                "build/obj/main.o",
+                "docs/build/index.html",
+            ],
+        ),
+        (
+            &["build/"],
+            &["build/gen/"],
+            &[
+                "build/config.h",
+                "build/obj/main.o",
+                "docs/build/index.html",
+            ],
+        ),
+        (
+            &["*.log"],
+            &["logs/keep.log"],
+            &["debug.log", "logs/app.log", "src/debug.log"],
+        ),
+        (
+            &["assets/**"],
+            &["assets/config/app.json"],
+            &["assets/config/nested/deep.json", "assets/images/logo.png"],
+        ),
+        // An include wins over a later negation's exclude too
+        (
+            &["*", "!*/", "!*.rs"],
+            &["README.md"],
+            &[
+                "a/b/c/d.txt",
+                "a/d.txt",
+                "assets/config/app.json",
+                "assets/config/nested/deep.json",
+                "assets/images/logo.png",
+                "build/config.h",
+                "build/gen/version.h",
+                "build/obj/main.o",
+                "debug.log",
+                "docs/build/index.html",
+                "logs/app.log",
+                "logs/keep.log",
+                "main.c",
+                "src/debug.log",
+                "src/with\"quote.c",
+                "src/город.c",
+                "third_party/big-vendor/lib.c",
+                "third_party/small/lib.c",
+                "x/a/d.txt",
+            ],
+        ),
+        // Nothing leads `*.h` into the excluded directory
+        (
+            &["build/"],
+            &["*.h"],
+            &[
+                "build/config.h",
+                "build/gen/version.h",
+                "build/obj/main.o",
+                "docs/build/index.html",
+            ],
+        ),
+        (
+            &["third_party/"],
+            &["third_party/small/"],
+            &["third_party/big-vendor/lib.c"],
+        ),
+    ];
+
+    /// Includes win over the excludes the same way in a filtered file list
+    /// and in a full sync
+    #[test]
+    fn includes_win_over_excludes() {
+        for (patterns, includes, left_out) in INCLUDE_TABLE {
+            let set =
+                ExcludeSet::new(patterns.iter().copied()).with_includes(includes.iter().copied());
+            let case = format!("excludes {:?}, includes {:?}", patterns, includes);
+            assert_eq!(&excluded(&set), left_out, "file list, {}", case);
+            assert_eq!(
+                &rsync_excluded(&set.rsync_args(), TREE),
+                left_out,
+                "rsync, {}, rules {:?}",
+                case,
+                set.rsync_args()
+            );
+        }
+    }
+
+    /// Includes and the directories leading to them come before the rules
+    /// excluding the rest of those directories, which come before the
+    /// excludes
+    #[test]
+    fn include_rules_come_first() {
+        let set = ExcludeSet::new(["*.o", "build/"]).with_includes(["build/gen/config.h"]);
+        assert_eq!(
+            set.rsync_args(),
+            [
+                "--include=/build/gen/config.h",
+                "--include=/build/gen/config.h/**",
+                "--include=/build/",
+                "--include=/build/gen/",
+                "--exclude=/build/*",
+                "--exclude=/build/gen/*",
+                "--exclude=build/",
+                "--exclude=*.o",
+            ]
+        );
+    }
+}
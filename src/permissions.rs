@@ -0,0 +1,412 @@
+//! Permissions of the remote tree
+//!
+//! With `remote_umask`, files and directories on the remote are readable the
+//! same way however they got there: the upload gets an rsync `--chmod`
+//! policy equivalent to the umask (executable bits are kept where the local
+//! file has them), and the build runs under that umask. `remote_dir_mode` is
+//! the mode of `remote_path` itself, set when the directory is created and
+//! again before every build, since the upload copies the mode of the local
+//! project directory onto it. A fresh tree and an updated one thus end up
+//! with the same permissions.
+//!
+//! `chmod` is passed to rsync as one more `--chmod` rule, after the umask's.
+//! Checkouts on filesystems without Unix modes, such as exFAT, have every
+//! file executable or none, which rsync copies as is. With
+//! `fix_permissions`, the tracked files whose mode on disk disagrees with the
+//! one git records (`git ls-files -s`) get git's execute bits back on the
+//! remote after each sync, with batched `chmod` commands.
+
+use anyhow::{anyhow, Context, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::remote_path::RemotePath;
+use crate::{run_ssh_command, Config};
+
+/// Paths passed to one remote `chmod`
+const CHMOD_BATCH: usize = 200;
+
+/// The configured umask, validated
+fn umask(config: &Config) -> Result<Option<u32>> {
+    config
+        .remote_umask
+        .as_deref()
+        .map(|value| parse_octal("remote_umask", value, 0o777))
+        .transpose()
+}
+
+/// The configured mode of the remote directory, validated
+fn dir_mode(config: &Config) -> Result<Option<u32>> {
+    config
+        .remote_dir_mode
+        .as_deref()
+        .map(|value| parse_octal("remote_dir_mode", value, 0o7777))
+        .transpose()
+}
+
+/// Check both options, so a typo fails when the config is loaded
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    umask(config)?;
+    dir_mode(config)?;
+    Ok(())
+}
+
+/// Parse an octal mode such as `027` or `2775`
+fn parse_octal(option: &str, value: &str, max: u32) -> Result<u32> {
+    u32::from_str_radix(value.trim(), 8)
+        .ok()
+        .filter(|mode| *mode <= max)
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid {}: {} (expected an octal mode up to {:o})",
+                option,
+                value,
+                max
+            )
+        })
+}
+
+/// The command creating the remote directory with the configured mode
+pub(crate) fn mkdir_command(config: &Config, dir: &RemotePath) -> Result<String> {
+    let mut command = format!("mkdir -p {}", dir.shell());
+    if let Some(mode) = dir_mode(config)? {
+        command.push_str(&format!(" && chmod {:o} {}", mode, dir.shell()));
+    }
+    Ok(command)
+}
+
+/// rsync arguments giving uploaded files the permissions the umask allows,
+/// then applying `chmod`
+///
+/// `ugo=rwX` starts from full access, with execute only for directories and
+/// files executable locally, and the umask then removes its bits per class.
+pub(crate) fn rsync_args(config: &Config) -> Result<Vec<String>> {
+    let mut args = umask_args(config)?;
+    args.extend(
+        config
+            .chmod
+            .iter()
+            .map(|rules| format!("--chmod={}", rules)),
+    );
+    Ok(args)
+}
+
+/// The `--chmod` argument equivalent to the umask, if one is configured
+fn umask_args(config: &Config) -> Result<Vec<String>> {
+    let Some(umask) = umask(config)? else {
+        return Ok(Vec::new());
+    };
+    let mut policy = vec!["ugo=rwX".to_string()];
+    for (who, shift) in [('u', 6), ('g', 3), ('o', 0)] {
+        let bits = (umask >> shift) & 0o7;
+        let removed: String = [(0o4, 'r'), (0o2, 'w'), (0o1, 'x')]
+            .iter()
+            .filter(|(bit, _)| bits & bit != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        if !removed.is_empty() {
+            policy.push(format!("{}-{}", who, removed));
+        }
+    }
+    Ok(vec![format!("--chmod={}", policy.join(","))])
+}
+
+/// Prefix for a command running in the remote directory, applying the umask
+/// and restoring the directory's mode; empty or ends with `&& `
+pub(crate) fn build_prefix(config: &Config) -> Result<String> {
+    let mut prefix = String::new();
+    if let Some(umask) = umask(config)? {
+        prefix.push_str(&format!("umask {:03o} && ", umask));
+    }
+    if let Some(mode) = dir_mode(config)? {
+        prefix.push_str(&format!("chmod {:o} . && ", mode));
+    }
+    Ok(prefix)
+}
+
+/// Tracked files whose execute bits on disk differ from the mode git
+/// records, as the files git has executable and those it has not
+///
+/// Only files `synced` accepts are considered. Outside Unix, files count as
+/// not executable locally.
+pub(crate) fn git_mode_mismatches(
+    project_dir: &Path,
+    submodules: bool,
+    synced: impl Fn(&str) -> bool,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut ls_files = Command::new("git");
+    ls_files.args(["ls-files", "-s", "-z"]);
+    if submodules {
+        ls_files.arg("--recurse-submodules");
+    }
+    let output = ls_files
+        .current_dir(project_dir)
+        .output()
+        .context("Failed to run git ls-files")?;
+    let (mut executable, mut plain) = (Vec::new(), Vec::new());
+    if !output.status.success() {
+        return Ok((executable, plain));
+    }
+
+    // Entries are `<mode> <object> <stage>\t<path>`
+    for entry in String::from_utf8_lossy(&output.stdout).split('\0') {
+        let Some((info, path)) = entry.split_once('\t') else {
+            continue;
+        };
+        let fields: Vec<&str> = info.split(' ').collect();
+        let wanted = match fields[..] {
+            ["100755", _, "0"] => true,
+            ["100644", _, "0"] => false,
+            // Symlinks and submodules have no execute bits of their own, and
+            // files with merge conflicts no single mode
+            _ => continue,
+        };
+        if !synced(path) {
+            continue;
+        }
+        let Ok(meta) = fs::symlink_metadata(project_dir.join(path)) else {
+            continue;
+        };
+        if meta.is_file() && is_executable(&meta) != wanted {
+            if wanted {
+                executable.push(path.to_string());
+            } else {
+                plain.push(path.to_string());
+            }
+        }
+    }
+    Ok((executable, plain))
+}
+
+/// Whether any execute bit of the file is set
+#[cfg(unix)]
+fn is_executable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+/// Whether any execute bit of the file is set, never outside Unix
+#[cfg(not(unix))]
+fn is_executable(_meta: &fs::Metadata) -> bool {
+    false
+}
+
+/// Give the remote copies of `executable` their execute bits, as far as the
+/// umask allows, and remove those of `plain`
+pub(crate) fn fix_remote_modes(
+    config: &Config,
+    executable: &[String],
+    plain: &[String],
+) -> Result<()> {
+    let mut prefix = format!("cd {} && ", config.remote_dir().shell());
+    if let Some(umask) = umask(config)? {
+        prefix.push_str(&format!("umask {:03o} && ", umask));
+    }
+    for (change, paths) in [("+x", executable), ("a-x", plain)] {
+        for batch in paths.chunks(CHMOD_BATCH) {
+            let quoted: Vec<Cow<str>> = batch
+                .iter()
+                .map(|path| escape(Cow::Borrowed(path.as_str())))
+                .collect();
+            run_ssh_command(
+                config,
+                &format!("{}chmod {} -- {}", prefix, change, quoted.join(" ")),
+            )
+            .context("Failed to restore file modes on the remote")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A config for `build-box` with `extra` YAML appended
+    fn config(extra: &str) -> Config {
+        serde_yaml::from_str(&format!("host: build-box\n{}", extra)).unwrap()
+    }
+
+    /// The permission bits of `path`
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    /// Modes are octal and bounded, and a bad value fails validation
+    #[test]
+    fn modes_are_validated() {
+        assert_eq!(parse_octal("remote_umask", " 027 ", 0o777).unwrap(), 0o27);
+        assert_eq!(
+            parse_octal("remote_dir_mode", "2775", 0o7777).unwrap(),
+            0o2775
+        );
+        for value in ["", "8", "0x1f", "1000"] {
+            let error = parse_octal("remote_umask", value, 0o777).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "Invalid remote_umask: {} (expected an octal mode up to 777)",
+                    value
+                )
+            );
+        }
+        assert!(validate(&config("remote_umask: '022'\nremote_dir_mode: '755'")).is_ok());
+        assert!(validate(&config("remote_dir_mode: '79'")).is_err());
+    }
+
+    /// The umask becomes one `--chmod` policy taking its bits away per class
+    #[test]
+    fn umask_becomes_chmod_policy() {
+        assert!(rsync_args(&config("")).unwrap().is_empty());
+        assert_eq!(
+            rsync_args(&config("remote_umask: '027'")).unwrap(),
+            ["--chmod=ugo=rwX,g-w,o-rwx"]
+        );
+        assert_eq!(
+            rsync_args(&config("remote_umask: '000'")).unwrap(),
+            ["--chmod=ugo=rwX"]
+        );
+        assert_eq!(
+            rsync_args(&config("remote_umask: '722'")).unwrap(),
+            ["--chmod=ugo=rwX,u-rwx,g-w,o-w"]
+        );
+    }
+
+    /// `chmod` rules follow the umask's, so they can add back what it removes
+    #[test]
+    fn chmod_rules_follow_umask() {
+        assert_eq!(
+            rsync_args(&config("chmod: Fu+x")).unwrap(),
+            ["--chmod=Fu+x"]
+        );
+        assert_eq!(
+            rsync_args(&config("remote_umask: '077'\nchmod: D2770,g+rX")).unwrap(),
+            ["--chmod=ugo=rwX,g-rwx,o-rwx", "--chmod=D2770,g+rX"]
+        );
+    }
+
+    /// Tracked files count as mismatched when their execute bit on disk
+    /// differs from git's mode, among the files `synced` accepts
+    #[test]
+    fn git_mode_mismatches_compare_with_the_index() {
+        let dir =
+            std::env::temp_dir().join(format!("remotebuild-test-git-modes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("scripts")).unwrap();
+        let git = |args: &[&str]| {
+            Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+        if !git(&["init", "-q", "."]) {
+            eprintln!("git not found, skipping");
+            let _ = fs::remove_dir_all(&dir);
+            return;
+        }
+        let files = [
+            ("build.sh", 0o755, 0o644),
+            ("scripts/run.sh", 0o755, 0o644),
+            ("main.c", 0o644, 0o755),
+            ("ok.sh", 0o755, 0o755),
+            ("ok.c", 0o644, 0o644),
+        ];
+        for (path, _, _) in files {
+            fs::write(dir.join(path), "").unwrap();
+        }
+        assert!(git(&["add", "."]));
+        for (path, tracked, _) in files {
+            let change = if tracked == 0o755 {
+                "--chmod=+x"
+            } else {
+                "--chmod=-x"
+            };
+            assert!(git(&["update-index", change, path]));
+        }
+        for (path, _, on_disk) in files {
+            fs::set_permissions(dir.join(path), fs::Permissions::from_mode(on_disk)).unwrap();
+        }
+
+        let (executable, plain) = git_mode_mismatches(&dir, false, |_| true).unwrap();
+        assert_eq!(executable, ["build.sh", "scripts/run.sh"]);
+        assert_eq!(plain, ["main.c"]);
+        let (executable, plain) =
+            git_mode_mismatches(&dir, false, |path| !path.starts_with("scripts/")).unwrap();
+        assert_eq!(executable, ["build.sh"]);
+        assert_eq!(plain, ["main.c"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// The remote copies get the modes git records, under the umask
+    #[test]
+    fn remote_modes_are_fixed() {
+        crate::resilient::tests::install_fake_ssh();
+        let remote = std::env::temp_dir().join(format!(
+            "remotebuild-test-remote-modes-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&remote);
+        fs::create_dir_all(remote.join("my dir")).unwrap();
+        for (path, mode) in [("build.sh", 0o644), ("my dir/it's.c", 0o755)] {
+            fs::write(remote.join(path), "").unwrap();
+            fs::set_permissions(remote.join(path), fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let config = config(&format!(
+            "remote_path: {}\nremote_umask: '027'\nremote_shell: login",
+            remote.display()
+        ));
+        fix_remote_modes(
+            &config,
+            &["build.sh".to_string()],
+            &["my dir/it's.c".to_string()],
+        )
+        .unwrap();
+        assert_eq!(mode(&remote.join("build.sh")), 0o754);
+        assert_eq!(mode(&remote.join("my dir/it's.c")), 0o644);
+        fs::remove_dir_all(&remote).unwrap();
+    }
+
+    /// The directory is created with its mode, and a build under the prefix
+    /// creates files the umask allows in a directory with that mode
+    #[test]
+    fn build_prefix_applies_umask_and_dir_mode() {
+        let plain = config("");
+        assert_eq!(build_prefix(&plain).unwrap(), "");
+        let dir = RemotePath::new("~/builds/my app");
+        assert_eq!(
+            mkdir_command(&plain, &dir).unwrap(),
+            format!("mkdir -p {}", dir.shell())
+        );
+
+        let config = config("remote_umask: '027'\nremote_dir_mode: '2750'");
+        assert_eq!(
+            mkdir_command(&config, &dir).unwrap(),
+            format!("mkdir -p {0} && chmod 2750 {0}", dir.shell())
+        );
+        let prefix = build_prefix(&config).unwrap();
+        assert_eq!(prefix, "umask 027 && chmod 2750 . && ");
+
+        let tree =
+            std::env::temp_dir().join(format!("remotebuild-test-umask-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&tree);
+        fs::create_dir_all(&tree).unwrap();
+        fs::set_permissions(&tree, fs::Permissions::from_mode(0o777)).unwrap();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{}touch out.o && mkdir obj", prefix))
+            .current_dir(&tree)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(mode(&tree), 0o2750);
+        assert_eq!(mode(&tree.join("out.o")), 0o640);
+        assert_eq!(mode(&tree.join("obj")) & 0o777, 0o750);
+        fs::remove_dir_all(&tree).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! Named host profiles
+//!
+//! A config can define `profiles`, each overriding the host, remote path or
+//! build command, for building the same project on different machines (a
+//! server on the LAN at home, a cloud VM on the road). The profile selected
+//! with `--profile`, or else `default_profile`, is merged over the top-level
+//! values when the config is loaded; without either, the top-level values
+//! are used as they are, so single-host configs need no profile.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{interpolate, shared, BuildCommand, Config};
+
+/// Settings of one profile, each replacing the top-level value when set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct ProfileConfig {
+    /// SSH host replacing the top-level `host`
+    #[serde(default)]
+    host: Option<String>,
+
+    /// Remote path replacing the top-level `remote_path`
+    #[serde(default)]
+    remote_path: Option<String>,
+
+    /// Build command replacing the top-level `build_command`
+    #[serde(default)]
+    build_command: Option<BuildCommand>,
+}
+
+/// Merge the selected profile (`selected`, else `default_profile`) over the
+/// top-level values of `config`
+pub(crate) fn apply(config: &mut Config, selected: Option<&str>) -> Result<()> {
+    let Some(name) = selected.or(config.default_profile.as_deref()) else {
+        return Ok(());
+    };
+    let Some(profile) = config.profiles.get(name).cloned() else {
+        let known: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+        return Err(anyhow!(
+            "Unknown profile: {} (available: {})",
+            name,
+            if known.is_empty() {
+                "none configured".to_string()
+            } else {
+                known.join(", ")
+            }
+        ));
+    };
+
+    let context = || format!("Invalid profile {}", name);
+    if let Some(host) = profile.host {
+        config.host = interpolate::expand(&host).with_context(context)?;
+    }
+    if let Some(remote_path) = profile.remote_path {
+        config.remote_path =
+            shared::expand_user(&interpolate::expand(&remote_path).with_context(context)?);
+    }
+    if let Some(build_command) = profile.build_command {
+        config.build_command = build_command;
+    }
+    Ok(())
+}
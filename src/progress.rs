@@ -0,0 +1,114 @@
+//! Transfer progress reported by rsync
+//!
+//! Syncs in minimal and normal mode run rsync with `--info=progress2`, which
+//! rewrites one line with the bytes transferred so far, the percentage of
+//! the whole transfer and the files transferred:
+//!
+//! ```text
+//!      13,238,112  42%    1.20MB/s    0:00:10 (xfr#5, to-chk=12/30)
+//! ```
+//!
+//! Minimal mode shows it in the status line, normal mode prints a summary
+//! once the sync is done. Versions and locales differ in the details
+//! (thousands separators, `-h` units, `xfer#` before 3.1), so the line is
+//! read loosely: the number before the first percentage is the byte count,
+//! and lines that don't fit are skipped.
+
+use crate::gc::format_size;
+
+/// A progress update parsed from rsync's output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Progress {
+    /// Bytes transferred so far
+    pub(crate) transferred: u64,
+    /// Percentage of the transfer done
+    pub(crate) percent: u64,
+    /// Files transferred so far, when reported
+    pub(crate) files: Option<u64>,
+}
+
+impl Progress {
+    /// Parse one progress line, `None` for other output
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let at = tokens.iter().position(|token| token.ends_with('%'))?;
+        let percent = tokens[at]
+            .trim_end_matches('%')
+            .parse::<u64>()
+            .ok()
+            .filter(|percent| *percent <= 100)?;
+        let transferred = parse_bytes(tokens[..at].last()?)?;
+        let files = tokens[at..].iter().find_map(|token| {
+            let token = token.trim_start_matches('(');
+            let count = token
+                .strip_prefix("xfr#")
+                .or_else(|| token.strip_prefix("xfer#"))?;
+            count.trim_end_matches([',', ')']).parse().ok()
+        });
+        Some(Self {
+            transferred,
+            percent,
+            files,
+        })
+    }
+
+    /// The size of the whole transfer, estimated from the percentage
+    fn total(&self) -> Option<u64> {
+        (self.percent > 0).then(|| self.transferred.saturating_mul(100) / self.percent)
+    }
+
+    /// Detail for the status line, e.g. `42% (13.2 MB / 31.5 MB)`
+    pub(crate) fn status(&self) -> String {
+        match self.total() {
+            Some(total) => format!(
+                "{}% ({} / {})",
+                self.percent,
+                format_size(self.transferred / 1024),
+                format_size(total / 1024)
+            ),
+            None => format!(
+                "{}% ({})",
+                self.percent,
+                format_size(self.transferred / 1024)
+            ),
+        }
+    }
+
+    /// Summary for the end of the sync, e.g. `5 files, 31.5 MB`
+    pub(crate) fn summary(&self) -> String {
+        let size = format_size(self.transferred / 1024);
+        match self.files {
+            Some(1) => format!("1 file, {}", size),
+            Some(files) => format!("{} files, {}", files, size),
+            None => size,
+        }
+    }
+}
+
+/// Parse a byte count as rsync prints it: digits with locale-dependent
+/// thousands separators, or a decimal with a unit suffix under `-h`
+pub(crate) fn parse_bytes(token: &str) -> Option<u64> {
+    let split = token
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(token.len());
+    let (number, unit) = token.split_at(split);
+    if !number.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    if unit.is_empty() {
+        let digits: String = number.chars().filter(char::is_ascii_digit).collect();
+        return digits.parse().ok();
+    }
+
+    let power = match unit.chars().next()?.to_ascii_uppercase() {
+        'B' => 0,
+        'K' => 1,
+        'M' => 2,
+        'G' => 3,
+        'T' => 4,
+        _ => return None,
+    };
+    let base: f64 = if unit.contains('i') { 1024.0 } else { 1000.0 };
+    let value: f64 = number.replace(',', ".").parse().ok()?;
+    Some((value * base.powi(power)) as u64)
+}
@@ -0,0 +1,351 @@
+//! Build logs kept on the remote host
+//!
+//! Every build's output is also written to
+//! `<remote_path>/.remotebuild/logs/<timestamp>.log` on the remote, so the log
+//! of yesterday's build can still be fetched after the terminal is gone. The
+//! tee wraps the complete build command, container and Nix shell included,
+//! and keeps stdout and stderr apart on the way to the terminal. Only the
+//! newest `keep_remote_logs` files are kept; `0` turns the logs off.
+//!
+//! Being below `.remotebuild/`, the logs are never synced, deleted by
+//! `--clean-sync` or downloaded as part of an artifact.
+//!
+//! `remotebuild logs` lists them together with the local copies of
+//! [`crate::local_log`], with a column naming where each one is kept;
+//! `--remote` lists only the remote ones. With a name (or `latest`) it
+//! prints a local log, or a remote one with `--remote`, following it with
+//! `--follow`.
+
+use anyhow::{anyhow, Context, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::gc::format_size;
+use crate::history::format_age;
+use crate::{
+    ensure_ssh_connection, local_log, remote_command, run_ssh_command_output, Config,
+    REMOTE_META_DIR,
+};
+
+/// Directory of the logs, relative to the remote project directory
+const LOG_DIR: &str = "logs";
+
+/// Name standing for the newest log
+const LATEST: &str = "latest";
+
+/// Default number of logs kept per remote project directory
+pub(crate) fn default_keep() -> usize {
+    10
+}
+
+/// What `logs` shows
+pub(crate) struct LogsOptions<'a> {
+    /// Show only the logs kept on the remote host
+    pub(crate) remote: bool,
+    /// Log to print, or `latest`; lists the logs when absent
+    pub(crate) name: Option<&'a str>,
+    /// Keep printing what is appended to the log
+    pub(crate) follow: bool,
+}
+
+/// Wrap a command that runs in the remote project directory so that its
+/// output is also written to a new log file, rotating out the oldest
+///
+/// The exit code of `command` is kept, and `command` runs in a subshell so
+/// an `exit` in it doesn't skip the bookkeeping.
+pub(crate) fn wrap_command(config: &Config, command: &str) -> String {
+    if config.keep_remote_logs == 0 {
+        return command.to_string();
+    }
+    format!(
+        "RB_LOGS=\"$PWD\"/{meta}/{logs} && mkdir -p \"$RB_LOGS\" && \
+         {{ ls -1 \"$RB_LOGS\" | grep '\\.log$' | sort -r | tail -n +{keep} \
+         | while read -r old; do rm -f \"$RB_LOGS/$old\"; done; }} && \
+         RB_LOG=\"$RB_LOGS/$(date -u +%Y%m%d-%H%M%S)-$$.log\" && : > \"$RB_LOG\" && \
+         {{ {{ ( {command}\n); echo $? > \"$RB_LOG.status\"; }} 2>&1 1>&3 \
+         | tee -a \"$RB_LOG\" >&2; }} 3>&1 | tee -a \"$RB_LOG\"; \
+         rb_status=$(cat \"$RB_LOG.status\" 2>/dev/null || echo 1); \
+         rm -f \"$RB_LOG.status\"; exit \"$rb_status\"",
+        meta = REMOTE_META_DIR,
+        logs = LOG_DIR,
+        // Leaves room for the new log
+        keep = config.keep_remote_logs,
+        command = command
+    )
+}
+
+/// A log in the listing
+struct Listed {
+    /// Where the log is kept, `local` or `remote`
+    source: &'static str,
+    /// Name without the `.log` extension
+    name: String,
+    /// Size in bytes
+    size: u64,
+    /// Seconds since it was last written, if known
+    age: Option<u64>,
+}
+
+/// The `logs` subcommand
+pub(crate) fn run_logs(project_dir: &Path, config: &Config, options: &LogsOptions) -> Result<()> {
+    let name = match options.name {
+        Some(name) => name,
+        None if options.follow => LATEST,
+        None => return list(project_dir, config, options.remote),
+    };
+    if name != LATEST && name.contains('/') {
+        return Err(anyhow!("Invalid log name: {}", name));
+    }
+    if !options.remote {
+        return show_local(project_dir, name, options.follow);
+    }
+    let dir = remote_dir(config);
+
+    let file = if name == LATEST {
+        "\"$(ls -1 | grep '\\.log$' | sort | tail -n 1)\"".to_string()
+    } else {
+        escape(Cow::Owned(file_name(name))).to_string()
+    };
+    let show = if options.follow {
+        "tail -n +1 -f"
+    } else {
+        "cat"
+    };
+    let command = format!(
+        "cd {dir} 2>/dev/null && f={file} && [ -n \"$f\" ] && [ -f \"$f\" ] \
+         && exec {show} -- \"$f\"; echo 'No such log on the remote' >&2; exit 1",
+        dir = dir,
+        file = file,
+        show = show
+    );
+    let status = remote_command(config, &command).status()?;
+    if !status.success() {
+        return Err(anyhow!("Could not show log {} ({})", name, status));
+    }
+    Ok(())
+}
+
+/// Directory of the logs on the remote, quoted for the remote shell
+fn remote_dir(config: &Config) -> String {
+    format!(
+        "{}/{}/{}",
+        config.remote_dir().shell(),
+        REMOTE_META_DIR,
+        LOG_DIR
+    )
+}
+
+/// File name of the log `name`, which may be given with its extension
+fn file_name(name: &str) -> String {
+    if name.ends_with(".log") {
+        name.to_string()
+    } else {
+        format!("{}.log", name)
+    }
+}
+
+/// Print, or follow, a log kept locally
+fn show_local(project_dir: &Path, name: &str, follow: bool) -> Result<()> {
+    let dir = local_log::dir(project_dir);
+    let file = if name == LATEST {
+        let newest = local_log::list(&dir).into_iter().next();
+        match newest {
+            Some(log) => file_name(&log.name),
+            None => return Err(anyhow!("No build logs kept in {} yet", dir.display())),
+        }
+    } else {
+        file_name(name)
+    };
+    let path = dir.join(file);
+    if !path.is_file() {
+        return Err(anyhow!(
+            "No such local log: {} (`remotebuild logs --remote {}` looks on the remote)",
+            name,
+            name
+        ));
+    }
+
+    if follow {
+        let status = Command::new("tail")
+            .args(["-n", "+1", "-f", "--"])
+            .arg(&path)
+            .status()
+            .context("Failed to run tail")?;
+        if !status.success() {
+            return Err(anyhow!("Could not follow log {} ({})", name, status));
+        }
+        return Ok(());
+    }
+    let mut file =
+        fs::File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+    std::io::copy(&mut file, &mut std::io::stdout().lock())
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(())
+}
+
+/// The logs on the remote, newest first
+fn remote_logs(config: &Config) -> Result<Vec<Listed>> {
+    let command = format!(
+        "cd {} 2>/dev/null || exit 0; now=$(date +%s); \
+         for f in $(ls -1 | grep '\\.log$' | sort -r); do \
+         printf '%s\\t%s\\t%s\\n' \"$f\" \"$(wc -c < \"$f\")\" \
+         \"$(( now - $(stat -c %Y \"$f\" 2>/dev/null || stat -f %m \"$f\") ))\"; done",
+        remote_dir(config)
+    );
+    let output = run_ssh_command_output(config, &command)?;
+    Ok(output
+        .lines()
+        .map(|line| line.split('\t').collect())
+        .filter(|fields: &Vec<&str>| fields.len() == 3)
+        .map(|fields| Listed {
+            source: "remote",
+            name: fields[0].trim_end_matches(".log").to_string(),
+            size: fields[1].trim().parse().unwrap_or(0),
+            age: fields[2].trim().parse().ok(),
+        })
+        .collect())
+}
+
+/// Print the logs, newest first: the remote ones, and unless `remote_only`
+/// the local ones too
+///
+/// Without `remote_only`, a host that can't be reached leaves the remote
+/// logs out with a warning instead of failing the listing.
+fn list(project_dir: &Path, config: &Config, remote_only: bool) -> Result<()> {
+    let destination = format!("{}:{}", config.host, config.remote_path);
+    let mut logs = if remote_only {
+        remote_logs(config)?
+    } else {
+        match ensure_ssh_connection(config).and_then(|()| remote_logs(config)) {
+            Ok(logs) => logs,
+            Err(e) => {
+                eprintln!(
+                    "   ⚠ Warning: Could not list the logs in {}: {:#}",
+                    destination, e
+                );
+                Vec::new()
+            }
+        }
+    };
+    if !remote_only {
+        let local = local_log::list(&local_log::dir(project_dir));
+        logs.extend(local.into_iter().map(|log| Listed {
+            source: "local",
+            name: log.name,
+            size: log.size,
+            age: log.age,
+        }));
+    }
+    // Unknown ages go last; names break ties, newest first
+    logs.sort_by(|a, b| {
+        (a.age.is_none(), a.age, &b.name, a.source).cmp(&(
+            b.age.is_none(),
+            b.age,
+            &a.name,
+            b.source,
+        ))
+    });
+
+    let location = if remote_only {
+        destination
+    } else {
+        format!(
+            "{} and {}",
+            local_log::dir(project_dir).display(),
+            destination
+        )
+    };
+    if logs.is_empty() {
+        println!("No build logs kept in {} yet", location);
+        return Ok(());
+    }
+
+    println!("📜 Build logs in {}", location);
+    for log in &logs {
+        let age = log
+            .age
+            .map(format_age)
+            .unwrap_or_else(|| "unknown age".to_string());
+        println!(
+            "   {:<6}  {:<28} {:>10}  {}",
+            log.source,
+            log.name,
+            format_size((log.size + 1023) / 1024),
+            age
+        );
+    }
+    if remote_only {
+        println!("   (`remotebuild logs --remote NAME` prints one, `latest` the newest)");
+    } else {
+        println!(
+            "   (`remotebuild logs NAME` prints a local one, `--remote NAME` a remote one, \
+             `latest` the newest)"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artifact_glob;
+
+    /// Artifact globs never match the remote logs, and they are excluded
+    /// from the sync, so `--delete` leaves them
+    #[test]
+    fn logs_survive_sync_and_artifact_globs() {
+        crate::resilient::tests::install_fake_ssh();
+        let dir = std::env::temp_dir().join(format!(
+            "remotebuild-test-remote-logs-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let (project, remote) = (dir.join("project"), dir.join("remote"));
+        let logs = remote.join(REMOTE_META_DIR).join(LOG_DIR);
+        fs::create_dir_all(&logs).unwrap();
+        fs::create_dir_all(remote.join("out")).unwrap();
+        fs::create_dir_all(&project).unwrap();
+        fs::write(logs.join("20250301-142210-4733.log"), "build output").unwrap();
+        fs::write(remote.join("out/game.nds"), "").unwrap();
+        fs::write(remote.join("stale.c"), "").unwrap();
+        fs::write(project.join("main.c"), "").unwrap();
+        let config: Config = serde_yaml::from_str(&format!(
+            "host: build-box\nremote_path: {}\nremote_shell: login",
+            remote.display()
+        ))
+        .unwrap();
+
+        let mut all = artifact_glob::expand(&config, "**").unwrap();
+        all.paths.sort_unstable();
+        assert_eq!(all.paths, ["out", "out/game.nds", "stale.c"]);
+        assert!(artifact_glob::expand(&config, "**/*.log")
+            .unwrap()
+            .paths
+            .is_empty());
+
+        let excludes = crate::sync_excludes(&config, true);
+        assert!(excludes.excludes_entry(REMOTE_META_DIR, true));
+        let synced = Command::new("rsync")
+            .arg("-a")
+            .arg("--delete")
+            .args(excludes.rsync_args())
+            .arg(format!("{}/", project.display()))
+            .arg(format!("{}/", remote.display()))
+            .status();
+        if synced.is_ok() {
+            assert!(!remote.join("stale.c").exists());
+            assert!(remote.join("main.c").exists());
+        } else {
+            eprintln!("rsync not found, skipping the transfer");
+        }
+        assert_eq!(
+            fs::read_to_string(logs.join("20250301-142210-4733.log")).unwrap(),
+            "build output"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+//! Quoting of the remote path for every place it reaches a remote shell
+//!
+//! The same `remote_path` value ends up in `mkdir`, in the `cd` before build
+//! and check commands, and in rsync sources and destinations. [`RemotePath`]
+//! resolves it once and renders it for each of those, so values with spaces,
+//! quotes or `$` behave the same in every phase.
+//!
+//! A leading `~/` is resolved by dropping it: ssh runs commands in the login
+//! directory, and rsync resolves relative remote paths against it too, so the
+//! remaining relative path needs no unquoted tilde. `~user/` prefixes are the
+//! only part ever left unquoted, since the shell has to expand them; a tilde
+//! followed by anything but a portable user name is taken literally.
+
+use shell_escape::escape;
+use std::borrow::Cow;
+
+/// Environment variable making rsync pass remote paths to the remote shell as
+/// given, which older rsync versions always do
+pub(crate) const RSYNC_OLD_ARGS: &str = "RSYNC_OLD_ARGS";
+
+/// A remote directory, resolved relative to the login directory when possible
+#[derive(Debug, Clone)]
+pub(crate) struct RemotePath {
+    /// `~user` prefix that must stay unquoted for the shell to expand it
+    home: Option<String>,
+    /// Path after the home prefix (relative when `home` is set or the
+    /// configured path started with `~/`)
+    path: String,
+}
+
+impl RemotePath {
+    /// Resolve a configured remote path
+    pub(crate) fn new(raw: &str) -> Self {
+        let trimmed = if raw.len() > 1 {
+            raw.trim_end_matches('/')
+        } else {
+            raw
+        };
+
+        if trimmed == "~" || trimmed.is_empty() {
+            return Self::relative(".");
+        }
+        if let Some(rest) = trimmed.strip_prefix("~/") {
+            return Self::relative(rest.trim_start_matches('/'));
+        }
+        if let Some(user_path) = trimmed.strip_prefix('~') {
+            let (user, rest) = user_path.split_once('/').unwrap_or((user_path, ""));
+            if is_user_name(user) {
+                return Self {
+                    home: Some(format!("~{}", user)),
+                    path: rest.trim_start_matches('/').to_string(),
+                };
+            }
+        }
+
+        Self::relative(trimmed)
+    }
+
+    /// A path without a home prefix
+    fn relative(path: &str) -> Self {
+        Self {
+            home: None,
+            path: if path.is_empty() { "." } else { path }.to_string(),
+        }
+    }
+
+    /// The path quoted for embedding in a command run by the remote shell
+    pub(crate) fn shell(&self) -> String {
+        match &self.home {
+            Some(home) if self.path.is_empty() => home.clone(),
+            Some(home) => format!("{}/{}", home, escape(Cow::Borrowed(self.path.as_str()))),
+            None => escape(Cow::Borrowed(self.path.as_str())).to_string(),
+        }
+    }
+
+    /// The path quoted as an absolute path, for commands that run after a
+    /// `cd` or export it to other programs
+    pub(crate) fn absolute_shell(&self) -> String {
+        if self.home.is_some() || self.path.starts_with('/') {
+            self.shell()
+        } else if self.path == "." {
+            "\"$HOME\"".to_string()
+        } else {
+            format!("\"$HOME\"/{}", escape(Cow::Borrowed(self.path.as_str())))
+        }
+    }
+
+    /// The `host:path` form rsync uses for a file below this directory
+    ///
+    /// `rel` is appended unquoted, so artifact patterns are still expanded by
+    /// the remote shell. Rsync must run with [`RSYNC_OLD_ARGS`] set, so
+    /// that rsync 3.2.4 and later leave the quoting to the remote shell like
+    /// older versions do.
+    pub(crate) fn rsync(&self, host: &str, rel: &str) -> String {
+        let dir = self.shell();
+        let separator = if dir.ends_with('/') { "" } else { "/" };
+        format!("{}:{}{}{}", host, dir, separator, rel)
+    }
+}
+
+/// Whether `~name` is a user's home the shell expands, with a name from the
+/// POSIX portable character set
+fn is_user_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    /// Configured paths and their shell, absolute shell and rsync forms
+    const PATHS: &[(&str, &str, &str, &str)] = &[
+        ("~", ".", "\"$HOME\"", "host:./out/*.bin"),
+        ("~/", ".", "\"$HOME\"", "host:./out/*.bin"),
+        ("", ".", "\"$HOME\"", "host:./out/*.bin"),
+        ("/", "/", "/", "host:/out/*.bin"),
+        (
+            "~/builds/app",
+            "builds/app",
+            "\"$HOME\"/builds/app",
+            "host:builds/app/out/*.bin",
+        ),
+        (
+            "~/builds/app/",
+            "builds/app",
+            "\"$HOME\"/builds/app",
+            "host:builds/app/out/*.bin",
+        ),
+        (
+            "~//builds",
+            "builds",
+            "\"$HOME\"/builds",
+            "host:builds/out/*.bin",
+        ),
+        (
+            "builds/app",
+            "builds/app",
+            "\"$HOME\"/builds/app",
+            "host:builds/app/out/*.bin",
+        ),
+        (
+            "/srv/build//",
+            "/srv/build",
+            "/srv/build",
+            "host:/srv/build/out/*.bin",
+        ),
+        (
+            "~/my builds/app",
+            "'my builds/app'",
+            "\"$HOME\"/'my builds/app'",
+            "host:'my builds/app'/out/*.bin",
+        ),
+        (
+            "/srv/it's here",
+            r"'/srv/it'\''s here'",
+            r"'/srv/it'\''s here'",
+            r"host:'/srv/it'\''s here'/out/*.bin",
+        ),
+        (
+            "~/say \"hi\"",
+            "'say \"hi\"'",
+            "\"$HOME\"/'say \"hi\"'",
+            "host:'say \"hi\"'/out/*.bin",
+        ),
+        (
+            "~/$HOME/`id`",
+            "'$HOME/`id`'",
+            "\"$HOME\"/'$HOME/`id`'",
+            "host:'$HOME/`id`'/out/*.bin",
+        ),
+        (
+            "~/données/ビルド",
+            "'données/ビルド'",
+            "\"$HOME\"/'données/ビルド'",
+            "host:'données/ビルド'/out/*.bin",
+        ),
+        ("~bob", "~bob", "~bob", "host:~bob/out/*.bin"),
+        ("~bob/", "~bob", "~bob", "host:~bob/out/*.bin"),
+        (
+            "~bob/my app/",
+            "~bob/'my app'",
+            "~bob/'my app'",
+            "host:~bob/'my app'/out/*.bin",
+        ),
+        (
+            "~b$(id)/app",
+            "'~b$(id)/app'",
+            "\"$HOME\"/'~b$(id)/app'",
+            "host:'~b$(id)/app'/out/*.bin",
+        ),
+        (
+            r"back\slash",
+            r"'back\slash'",
+            r#""$HOME"/'back\slash'"#,
+            r"host:'back\slash'/out/*.bin",
+        ),
+    ];
+
+    /// Every path renders as expected in each form
+    #[test]
+    fn path_matrix() {
+        for &(raw, shell, absolute, rsync) in PATHS {
+            let path = RemotePath::new(raw);
+            assert_eq!(path.shell(), shell, "{:?}", raw);
+            assert_eq!(path.absolute_shell(), absolute, "{:?}", raw);
+            assert_eq!(path.rsync("host", "out/*.bin"), rsync, "{:?}", raw);
+        }
+    }
+
+    /// A shell reaches the same directory through every form, and still
+    /// expands the artifact pattern appended to the rsync form
+    #[test]
+    fn shell_resolves_paths() {
+        let home = std::env::temp_dir().join(format!(
+            "remotebuild-test-remote-path-{}",
+            std::process::id()
+        ));
+        for &(raw, ..) in PATHS {
+            // Other users' homes and absolute paths are outside the test
+            if raw.starts_with('/') || raw.starts_with("~bob") {
+                continue;
+            }
+            let rel = raw
+                .strip_prefix('~')
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+                .unwrap_or(raw)
+                .trim_start_matches('/');
+            let path = RemotePath::new(raw);
+            let spec = path.rsync("host", "out/*.bin");
+            let script = format!(
+                "mkdir -p {dir}/out && touch {dir}/out/a.bin && cd / && cd {abs} && pwd && \
+                 cd \"$HOME\" && ls -d {spec}",
+                dir = path.shell(),
+                abs = path.absolute_shell(),
+                spec = &spec["host:".len()..],
+            );
+            fs::create_dir_all(&home).unwrap();
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(&script)
+                .current_dir(&home)
+                .env("HOME", &home)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{:?}: {}", raw, script);
+            let expected = home.join(rel.trim_end_matches('/'));
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let mut lines = stdout.lines();
+            assert_eq!(
+                lines.next().map(Path::new),
+                Some(expected.as_path()),
+                "{:?}",
+                raw
+            );
+            assert!(
+                lines.next().is_some_and(|l| l.ends_with("out/a.bin")),
+                "{:?}",
+                raw
+            );
+            fs::remove_dir_all(&home).unwrap();
+        }
+    }
+}
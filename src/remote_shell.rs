@@ -0,0 +1,299 @@
+//! Remote commands under non-POSIX login shells
+//!
+//! ssh hands every command to the remote user's login shell, and the commands
+//! remotebuild generates (`cd ... && export ... && make`) are POSIX shell
+//! code that fish and the csh family don't parse. With `remote_shell: auto`,
+//! the default, the login shell is detected once per host (cached for a week
+//! next to the project state) and, unless it is a POSIX shell, every command
+//! runs as `sh -c '...'`, quoted for the login shell it passes through first.
+//!
+//! `remote_shell: bash` (or any other POSIX shell) always runs commands in
+//! that shell, and `remote_shell: login` hands them to the login shell as
+//! they are.
+
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::state::shared_state_path;
+use crate::{ssh_command, Config};
+
+/// `remote_shell` value detecting the login shell
+const AUTO: &str = "auto";
+
+/// `remote_shell` value trusting the login shell
+const LOGIN: &str = "login";
+
+/// Seconds a detected login shell is trusted before detecting it again
+const CACHE_SECS: u64 = 7 * 24 * 3600;
+
+/// Name of the file caching the login shells of all hosts
+const CACHE_FILE: &str = "login-shells.json";
+
+/// Login shells detected in this run, keyed by host
+static DETECTED: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Default of `remote_shell`
+pub(crate) fn default_remote_shell() -> String {
+    AUTO.to_string()
+}
+
+/// A login shell detected earlier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedShell {
+    /// `$SHELL` of the remote user
+    shell: String,
+    /// Unix timestamp (seconds) of the detection
+    detected_at: u64,
+}
+
+/// How a shell parses quoted strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    /// sh, bash, dash, zsh, ksh and the like
+    Posix,
+    /// fish, where `\\` and `\'` are escapes inside single quotes
+    Fish,
+    /// csh and tcsh, where newlines and `!` need a backslash even when quoted
+    Csh,
+    /// Anything else, quoted like a POSIX shell as the best guess
+    Other,
+}
+
+impl Family {
+    /// The family of a shell, from its path or name
+    fn of(shell: &str) -> Self {
+        let name = shell.trim().rsplit('/').next().unwrap_or("");
+        match name {
+            "sh" | "bash" | "dash" | "zsh" | "ksh" | "mksh" | "ash" | "yash" | "posh"
+            | "busybox" => Family::Posix,
+            "fish" => Family::Fish,
+            "csh" | "tcsh" => Family::Csh,
+            _ => Family::Other,
+        }
+    }
+
+    /// Quote `text` as one word for a shell of this family
+    fn quote(self, text: &str) -> String {
+        match self {
+            Family::Posix | Family::Other => escape(Cow::Borrowed(text)).to_string(),
+            Family::Fish => format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'")),
+            Family::Csh => format!(
+                "'{}'",
+                text.replace('\'', "'\\''")
+                    .replace('!', "\\!")
+                    .replace('\n', "\\\n")
+            ),
+        }
+    }
+}
+
+/// The command to hand to ssh for running POSIX shell code `command`
+pub(crate) fn wrap(config: &Config, command: &str) -> String {
+    match config.remote_shell.as_str() {
+        LOGIN => command.to_string(),
+        AUTO => {
+            let login = Family::of(&login_shell(config));
+            if login == Family::Posix {
+                command.to_string()
+            } else {
+                format!("sh -c {}", login.quote(command))
+            }
+        }
+        // An explicit shell runs everything, whatever the login shell is
+        shell => {
+            let login = Family::of(&login_shell(config));
+            format!("{} -c {}", login.quote(shell), login.quote(command))
+        }
+    }
+}
+
+/// The remote user's login shell, detected once per host
+///
+/// When detection fails the shell is assumed to be POSIX and left undecided,
+/// so the next command tries again.
+fn login_shell(config: &Config) -> String {
+    if let Some(shell) = DETECTED
+        .lock()
+        .ok()
+        .and_then(|detected| detected.get(&config.host).cloned())
+    {
+        return shell;
+    }
+
+    let mut cache = load_cache();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let shell = match cache.get(&config.host) {
+        Some(cached) if now.saturating_sub(cached.detected_at) < CACHE_SECS => cached.shell.clone(),
+        _ => {
+            // `echo $SHELL` means the same in every shell in question
+            let output = ssh_command(config)
+                .arg("echo $SHELL")
+                .stdin(std::process::Stdio::null())
+                .output();
+            let shell = match output {
+                Ok(output) if output.status.success() => {
+                    String::from_utf8_lossy(&output.stdout).trim().to_string()
+                }
+                _ => return "sh".to_string(),
+            };
+            if Family::of(&shell) == Family::Other && !shell.is_empty() {
+                eprintln!(
+                    "   ⚠ Warning: Unknown login shell {} on {}; running commands with sh -c \
+                     (set remote_shell to choose the shell)",
+                    shell, config.host
+                );
+            }
+            cache.insert(
+                config.host.clone(),
+                CachedShell {
+                    shell: shell.clone(),
+                    detected_at: now,
+                },
+            );
+            save_cache(&cache);
+            shell
+        }
+    };
+
+    if let Ok(mut detected) = DETECTED.lock() {
+        detected.insert(config.host.clone(), shell.clone());
+    }
+    shell
+}
+
+/// The cached login shells per host, empty when unreadable
+fn load_cache() -> BTreeMap<String, CachedShell> {
+    fs::read_to_string(shared_state_path(CACHE_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Write the cached login shells back, ignoring failures
+fn save_cache(cache: &BTreeMap<String, CachedShell>) {
+    let path = shared_state_path(CACHE_FILE);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).ok();
+    }
+    if let Ok(content) = serde_json::to_string_pretty(cache) {
+        fs::write(path, content).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    /// Text that needs quoting in every shell family
+    const TRICKY: &[&str] = &[
+        "plain",
+        "it's",
+        "back\\slash",
+        "two words",
+        "line\nbreak",
+        "bang!",
+        "$HOME `cmd` \"quoted\"",
+    ];
+
+    /// A config for `host` with the given `remote_shell`
+    fn config(host: &str, remote_shell: &str) -> Config {
+        serde_yaml::from_str(&format!("host: {}\nremote_shell: {}", host, remote_shell)).unwrap()
+    }
+
+    /// Pretend `shell` was detected as the login shell of `host`
+    fn detected(host: &str, shell: &str) {
+        DETECTED
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), shell.to_string());
+    }
+
+    /// Shells are sorted into families by their name
+    #[test]
+    fn families_by_name() {
+        for (shell, family) in [
+            ("/bin/bash", Family::Posix),
+            ("/usr/bin/zsh\n", Family::Posix),
+            ("dash", Family::Posix),
+            ("/usr/local/bin/fish", Family::Fish),
+            ("/bin/tcsh", Family::Csh),
+            ("csh", Family::Csh),
+            ("/usr/bin/nu", Family::Other),
+            ("", Family::Other),
+        ] {
+            assert_eq!(Family::of(shell), family, "{:?}", shell);
+        }
+    }
+
+    /// Each family's quoting is one word that the shell turns back into the
+    /// text; shells that aren't installed are checked against the expected
+    /// quoting
+    #[test]
+    fn quoting_round_trips() {
+        for (family, shell) in [
+            (Family::Posix, "sh"),
+            (Family::Fish, "fish"),
+            (Family::Csh, "csh"),
+        ] {
+            for text in TRICKY {
+                let quoted = family.quote(text);
+                let Ok(output) = Command::new(shell)
+                    .arg("-c")
+                    .arg(format!("printf '%s' {}", quoted))
+                    .output()
+                else {
+                    continue;
+                };
+                assert_eq!(
+                    String::from_utf8_lossy(&output.stdout),
+                    *text,
+                    "{} quoting {}",
+                    shell,
+                    quoted
+                );
+            }
+        }
+        assert_eq!(Family::Fish.quote("it's a\\b"), "'it\\'s a\\\\b'");
+        assert_eq!(Family::Csh.quote("it's!\nx"), "'it'\\''s\\!\\\nx'");
+        assert_eq!(Family::Other.quote("it's"), Family::Posix.quote("it's"));
+    }
+
+    /// Commands go through `sh -c` only under a non-POSIX login shell, or
+    /// through the configured shell, quoted for the login shell
+    #[test]
+    fn wrap_follows_the_login_shell() {
+        let command = "cd 'my dir' && make";
+        detected("posix-box", "/bin/bash");
+        detected("fish-box", "/usr/bin/fish");
+        detected("csh-box", "/bin/tcsh");
+
+        assert_eq!(wrap(&config("posix-box", "auto"), command), command);
+        assert_eq!(
+            wrap(&config("fish-box", "auto"), command),
+            "sh -c 'cd \\'my dir\\' && make'"
+        );
+        assert_eq!(
+            wrap(&config("csh-box", "auto"), command),
+            "sh -c 'cd '\\''my dir'\\'' && make'"
+        );
+        assert_eq!(
+            wrap(&config("posix-box", "bash"), command),
+            "bash -c 'cd '\\''my dir'\\'' && make'"
+        );
+        assert_eq!(
+            wrap(&config("fish-box", "/bin/zsh"), command),
+            "'/bin/zsh' -c 'cd \\'my dir\\' && make'"
+        );
+        // The login shell isn't even asked
+        assert_eq!(wrap(&config("unknown-box", "login"), command), command);
+    }
+}
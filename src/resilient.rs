@@ -0,0 +1,724 @@
+//! Builds that survive dropped connections
+//!
+//! With `--resilient` (or `resilient: true`) a run is driven so that losing
+//! the connection costs time, not the build:
+//!
+//! - every ssh connection uses keepalives, so a dead link is noticed within
+//!   a minute instead of hanging
+//! - syncs and artifact downloads keep partial files (`rsync --partial`) and
+//!   are retried after reconnecting when they lost the connection
+//! - the build command runs detached on the remote, in a tmux session when
+//!   tmux is installed and under `nohup` otherwise, with its output written
+//!   to `<remote_path>/.remotebuild/run/log`
+//! - the log is followed from the offset received so far, and after a
+//!   disconnect the connection is re-established and following resumes where
+//!   it stopped, until the exit code appears in `.remotebuild/run/status`
+//!
+//! Without it a step that loses the connection (ssh exiting with 255, rsync
+//! with one of its connection errors) is still run again after reconnecting,
+//! up to `connection_retries` times (default 2), waiting `retry_backoff`
+//! seconds before the first retry and twice as long before each further
+//! one. The old control master is closed first, as it may hang on the dead
+//! link. A build command exiting with any other code failed on its own and
+//! is never retried.
+//!
+//! The number of reconnects is recorded in the run report.
+
+use anyhow::{anyhow, Context, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{BufRead, Read, Write};
+use std::process::{ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{
+    clear_status, ensure_ssh_connection, estimate, print_status, remote_build_command,
+    remote_command, resumable, shared, ssh_master, stable_hash, BuildFailed, Config, OutputLevel,
+};
+
+/// Longest keepalive interval in resilient mode, in seconds
+pub(crate) const KEEPALIVE_INTERVAL_SECS: u64 = 10;
+
+/// Unanswered keepalives before ssh gives up in resilient mode
+pub(crate) const KEEPALIVE_COUNT_MAX: u32 = 6;
+
+/// Connect timeout of the control master in resilient mode
+pub(crate) const CONNECT_TIMEOUT: &str = "ConnectTimeout=20";
+
+/// Reconnect attempts after one disconnect before giving up
+const MAX_RECONNECT_ATTEMPTS: u32 = 30;
+
+/// Longest wait between two reconnect attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Attempts of a sync or download before its error is reported
+const MAX_TRANSFER_ATTEMPTS: u32 = 5;
+
+/// Seconds the detached build may take to record its pid
+const START_TIMEOUT_SECS: u32 = 30;
+
+/// Exit code of ssh when it lost or couldn't open the connection
+const SSH_CONNECTION_LOST: i32 = 255;
+
+/// Prefix of the line carrying the build's exit code on the follow stream's
+/// stderr
+const STATUS_MARKER: &str = "__remotebuild_status__:";
+
+/// Connection statistics of a resilient run
+#[derive(Debug, Default)]
+pub(crate) struct Reconnects {
+    /// Number of times the connection was re-established
+    pub(crate) count: u32,
+}
+
+/// Steps of a detached build
+enum Step {
+    /// Start the build command on the remote
+    Launch,
+    /// Stream the log from the given byte offset
+    Follow(u64),
+    /// Re-establish the connection, then continue following from the offset
+    Reconnect(u64),
+    /// The build finished with the given status
+    Finished(ExitStatus),
+}
+
+/// What ended one attempt to follow the log
+enum FollowEnd {
+    /// The build finished; the stream exited with the build's exit code
+    Finished(ExitStatus),
+    /// The build's shell is gone and no exit code was recorded
+    Lost,
+    /// The connection dropped
+    Disconnected,
+}
+
+/// A step that failed because the connection dropped, not because of the
+/// step itself
+#[derive(Debug)]
+pub(crate) struct ConnectionLost(pub(crate) String);
+
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectionLost {}
+
+/// Default `connection_retries`
+pub(crate) fn default_connection_retries() -> u32 {
+    2
+}
+
+/// An error for an ssh command that ended with `status`, marked as a lost
+/// connection when ssh exited with 255
+pub(crate) fn ssh_error(status: ExitStatus, message: String) -> anyhow::Error {
+    if status.code() == Some(SSH_CONNECTION_LOST) {
+        ConnectionLost(message).into()
+    } else {
+        anyhow!(message)
+    }
+}
+
+/// An error for an rsync that ended with `status`, marked as a lost
+/// connection when it was one
+pub(crate) fn rsync_error(status: ExitStatus, message: String) -> anyhow::Error {
+    if transient_rsync_failure(status) {
+        ConnectionLost(message).into()
+    } else {
+        anyhow!(message)
+    }
+}
+
+/// Whether `error` came from the connection dropping, including a build
+/// whose ssh exited with 255
+///
+/// Any other build exit code is the build's own and never retried.
+fn connection_lost(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<ConnectionLost>()
+            || cause
+                .downcast_ref::<BuildFailed>()
+                .is_some_and(|failed| failed.status.code() == Some(SSH_CONNECTION_LOST))
+    })
+}
+
+/// Run a sync or download, reconnecting and retrying after it lost the
+/// connection
+///
+/// Resilient mode reconnects for as long as [`reconnect`] allows and retries
+/// up to [`MAX_TRANSFER_ATTEMPTS`] times, otherwise `connection_retries`
+/// applies. Any other failure is reported right away, as running the step
+/// again would only fail the same way.
+pub(crate) fn retry<T>(
+    config: &Config,
+    reconnects: &mut Reconnects,
+    what: &str,
+    mut operation: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    if !config.resilient {
+        return retry_lost(config, reconnects, what, operation);
+    }
+
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if !connection_lost(&e) => return Err(e),
+            Err(e) if attempt >= MAX_TRANSFER_ATTEMPTS => {
+                return Err(e.context(format!("{} failed{}", what, resumable::attempts(attempt))))
+            }
+            Err(e) => {
+                eprintln!(
+                    "   ⚠ Warning: {} failed (attempt {} of {}): {:#}",
+                    what, attempt, MAX_TRANSFER_ATTEMPTS, e
+                );
+                reconnect(config, reconnects)?;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Run a step over the connection, reconnecting and retrying it up to
+/// `connection_retries` times when it lost the connection
+///
+/// Used directly for builds, whose own failures must not be retried.
+pub(crate) fn retry_lost<T>(
+    config: &Config,
+    reconnects: &mut Reconnects,
+    what: &str,
+    mut operation: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut retry = 0;
+    loop {
+        let error = match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        if !connection_lost(&error) || retry >= config.connection_retries {
+            if retry == 0 {
+                return Err(error);
+            }
+            return Err(error.context(format!(
+                "{} lost the connection to {} again after {} retries",
+                what, config.host, retry
+            )));
+        }
+        retry = reconnect_for_retry(config, reconnects, what, retry + 1, &error)?;
+    }
+}
+
+/// Close the dropped connection and open a new one before retry number
+/// `retry`, returning the number of the last retry used up
+///
+/// A failed reconnect uses up a retry of its own.
+fn reconnect_for_retry(
+    config: &Config,
+    reconnects: &mut Reconnects,
+    what: &str,
+    mut retry: u32,
+    error: &anyhow::Error,
+) -> Result<u32> {
+    loop {
+        let delay = Duration::from_secs(
+            config
+                .retry_backoff
+                .saturating_mul(1 << (retry - 1).min(16)),
+        )
+        .min(MAX_RECONNECT_DELAY);
+        eprintln!(
+            "   ⚠ {} lost the connection to {}, reconnecting in {}s (retry {} of {})",
+            what,
+            config.host,
+            delay.as_secs(),
+            retry,
+            config.connection_retries
+        );
+        if matches!(config.output_level(), OutputLevel::Verbose) {
+            eprintln!("     {:#}", error);
+        }
+        thread::sleep(delay);
+
+        ssh_master::invalidate(config);
+        match ensure_ssh_connection(config) {
+            Ok(()) => {
+                reconnects.count += 1;
+                return Ok(retry);
+            }
+            Err(e) if retry >= config.connection_retries => {
+                return Err(e.context(format!(
+                    "Gave up reconnecting to {} after {} retries",
+                    config.host, retry
+                )));
+            }
+            Err(_) => retry += 1,
+        }
+    }
+}
+
+/// Whether rsync failed because the connection broke rather than because of
+/// the transfer itself (a missing file, a full disk)
+///
+/// 10, 12, 30 and 35 are rsync's socket, protocol stream and timeout errors,
+/// 255 is ssh losing the connection, and no code means rsync was killed.
+pub(crate) fn transient_rsync_failure(status: ExitStatus) -> bool {
+    matches!(status.code(), Some(10 | 12 | 30 | 35 | 255) | None)
+}
+
+/// Run the build command detached on the remote and follow its output until
+/// it finishes, reconnecting as often as needed
+pub(crate) fn run_build(config: &Config, reconnects: &mut Reconnects) -> Result<()> {
+    let output = config.output_level();
+    let mut spinner = print_status(output, &estimate::build_message(config));
+    let start = Instant::now();
+    let mut step = Step::Launch;
+
+    let status = loop {
+        step = match step {
+            Step::Launch => {
+                launch(config)?;
+                clear_status(output, &mut spinner);
+                Step::Follow(0)
+            }
+            Step::Follow(offset) => {
+                let (received, end) = follow(config, offset)?;
+                match end {
+                    FollowEnd::Finished(status) => Step::Finished(status),
+                    FollowEnd::Lost => {
+                        return Err(anyhow!(
+                            "The remote build didn't start or ended without recording an \
+                             exit code (was the host restarted?); its output is in {}",
+                            run_dir(config)
+                        ))
+                    }
+                    FollowEnd::Disconnected => Step::Reconnect(offset + received),
+                }
+            }
+            Step::Reconnect(offset) => {
+                reconnect(config, reconnects)?;
+                Step::Follow(offset)
+            }
+            Step::Finished(status) => break status,
+        };
+    };
+
+    if !status.success() {
+        return Err(BuildFailed {
+            status,
+            command: config.build_command.clone(),
+        }
+        .into());
+    }
+    if matches!(output, OutputLevel::Normal) {
+        println!();
+        println!("{}", estimate::build_complete(config, start.elapsed()));
+        println!();
+    }
+    Ok(())
+}
+
+/// Directory holding the detached build's log, pid and exit code
+///
+/// Relative paths stay relative to the login directory, which the detached
+/// job starts in too, so they don't depend on the environment tmux passes on.
+fn run_dir(config: &Config) -> String {
+    format!("{}/.remotebuild/run", config.remote_dir().shell())
+}
+
+/// Name of the tmux session running the project's build
+fn session_name(config: &Config) -> String {
+    format!(
+        "remotebuild-{:08x}",
+        stable_hash(config.remote_path.as_bytes()) as u32
+    )
+}
+
+/// Start the build command detached from the connection
+fn launch(config: &Config) -> Result<()> {
+    let run = run_dir(config);
+    let session = session_name(config);
+
+    // The exit code is moved into place only once the log is complete
+    let job = format!(
+        "echo $$ > {run}/pid; sh -c {cmd} > {run}/log 2>&1 < /dev/null; \
+         echo $? > {run}/status.tmp && mv {run}/status.tmp {run}/status",
+        run = run,
+        cmd = escape(Cow::Owned(remote_build_command(config)?))
+    );
+    let job = escape(Cow::Owned(job));
+    let script = format!(
+        "mkdir -p {run} && rm -f {run}/status {run}/pid && : > {run}/log && \
+         if command -v tmux >/dev/null 2>&1; then \
+         tmux new-session -d -s {session} -c \"$PWD\" {job} && echo tmux; \
+         else nohup sh -c {job} > /dev/null 2>&1 < /dev/null & echo nohup; fi",
+        run = run,
+        session = session,
+        job = job
+    );
+
+    let launched = remote_command(config, &script)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to start the build over SSH")?;
+    let stdout = String::from_utf8_lossy(&launched.stdout);
+    if !launched.status.success() {
+        let stderr = String::from_utf8_lossy(&launched.stderr);
+        if stderr.contains("duplicate session") {
+            return Err(anyhow!(
+                "A detached build is still running in tmux session {} on {}; \
+                 attach with `ssh -t {} tmux attach -t {}`",
+                session,
+                config.host,
+                config.host,
+                session
+            ));
+        }
+        return Err(anyhow!(
+            "Failed to start the build ({}): {}",
+            launched.status,
+            stderr.trim()
+        ));
+    }
+
+    if matches!(config.output_level(), OutputLevel::Verbose) {
+        match stdout.trim() {
+            "tmux" => println!("   Build running in tmux session {}", session),
+            _ => println!("   Build running detached with nohup"),
+        }
+    }
+    Ok(())
+}
+
+/// Stream the log from `offset` until the build finishes or the connection
+/// drops, returning the number of bytes received
+fn follow(config: &Config, offset: u64) -> Result<(u64, FollowEnd)> {
+    let run = run_dir(config);
+    // The exit code is checked before the log size, so once it exists the
+    // last chunk read is the end of the log
+    let script = format!(
+        "off={offset}; \
+         while :; do \
+         finished=0; [ -f {run}/status ] && finished=1; \
+         size=$(wc -c < {run}/log 2>/dev/null | tr -d ' '); size=${{size:-0}}; \
+         if [ \"$size\" -gt \"$off\" ]; then \
+         tail -c +$((off + 1)) {run}/log | head -c $((size - off)); off=$size; fi; \
+         if [ $finished = 1 ]; then code=$(cat {run}/status); \
+         echo \"{marker}$code\" >&2; exit \"$code\"; fi; \
+         if [ -f {run}/pid ]; then read -r job_pid < {run}/pid; \
+         if ! {alive} && [ ! -f {run}/status ]; then echo \"{marker}lost\" >&2; exit 1; fi; \
+         else waited=$((${{waited:-0}} + 1)); \
+         if [ $waited -gt {start_timeout} ]; then echo \"{marker}lost\" >&2; exit 1; fi; fi; \
+         sleep 1; done",
+        offset = offset,
+        run = run,
+        marker = STATUS_MARKER,
+        start_timeout = START_TIMEOUT_SECS,
+        alive = shared::alive("job_pid")
+    );
+
+    let mut child = remote_command(config, &script)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to follow the build over SSH")?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+
+    let (received, marker) = thread::scope(|s| {
+        let received = s.spawn(|| copy_output(stdout));
+        let marker = read_marker(stderr);
+        (received.join().unwrap_or(0), marker)
+    });
+    let status = child.wait().context("Failed to wait for SSH command")?;
+
+    // A connection dropping right after the marker leaves ssh's own status
+    let end = match marker.as_deref() {
+        Some("lost") => FollowEnd::Lost,
+        Some(code) if code.parse().ok() == status.code() => FollowEnd::Finished(status),
+        _ => FollowEnd::Disconnected,
+    };
+    Ok((received, end))
+}
+
+/// Copy the build output to stdout, returning the number of bytes copied
+fn copy_output(pipe: Option<impl Read>) -> u64 {
+    let Some(mut pipe) = pipe else {
+        return 0;
+    };
+    let mut buffer = [0u8; 8192];
+    let mut total = 0;
+    let mut stdout = std::io::stdout();
+    while let Ok(read) = pipe.read(&mut buffer) {
+        if read == 0 {
+            break;
+        }
+        let _ = stdout.write_all(&buffer[..read]);
+        let _ = stdout.flush();
+        total += read as u64;
+    }
+    total
+}
+
+/// Forward stderr of the follow stream, returning the status marker's value
+fn read_marker(pipe: Option<impl Read>) -> Option<String> {
+    let pipe = pipe?;
+    let mut marker = None;
+    let mut reader = std::io::BufReader::new(pipe);
+    let mut line = Vec::new();
+    while let Ok(read) = reader.read_until(b'\n', &mut line) {
+        if read == 0 {
+            break;
+        }
+        let text = String::from_utf8_lossy(&line);
+        match text.trim_end().strip_prefix(STATUS_MARKER) {
+            Some(value) => marker = Some(value.to_string()),
+            None => eprint!("{}", text),
+        }
+        line.clear();
+    }
+    marker
+}
+
+/// Re-establish the connection, waiting longer after every failed attempt
+fn reconnect(config: &Config, reconnects: &mut Reconnects) -> Result<()> {
+    let mut attempt = 1;
+    loop {
+        let delay = Duration::from_secs(2 * u64::from(attempt)).min(MAX_RECONNECT_DELAY);
+        eprintln!(
+            "   ⚠ Connection to {} lost, reconnecting in {}s (attempt {} of {})",
+            config.host,
+            delay.as_secs(),
+            attempt,
+            MAX_RECONNECT_ATTEMPTS
+        );
+        thread::sleep(delay);
+
+        match ensure_ssh_connection(config) {
+            Ok(()) => {
+                reconnects.count += 1;
+                return Ok(());
+            }
+            Err(e) if attempt >= MAX_RECONNECT_ATTEMPTS => {
+                return Err(e.context(format!(
+                    "Gave up reconnecting to {} after {} attempts",
+                    config.host, MAX_RECONNECT_ATTEMPTS
+                )));
+            }
+            Err(_) => attempt += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::supersede::CancelToken;
+    use crate::{run_cancellable_build, SyncScope};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::Once;
+
+    /// Directory holding the fake `ssh` put first on `PATH`
+    fn fake_ssh_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("remotebuild-test-fake-ssh-{}", std::process::id()))
+    }
+
+    /// Put an `ssh` first on `PATH` that runs remote commands locally
+    ///
+    /// A control master or check (no command) succeeds. While
+    /// `<host>.drops` counts down, following the log gets five bytes through
+    /// and then drops the connection, and the offset each follow starts at
+    /// is appended to `<host>.offsets`.
+    pub(crate) fn install_fake_ssh() -> PathBuf {
+        static INSTALL: Once = Once::new();
+        let dir = fake_ssh_dir();
+        INSTALL.call_once(|| {
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("bin")).unwrap();
+            let script = format!(
+                "#!/bin/sh\n\
+                 while [ $# -gt 0 ]; do case \"$1\" in \
+                 -o|-p|-i|-J|-O|-S|-l|-F|-E) shift 2;; -*) shift;; *) break;; esac; done\n\
+                 state={dir}/$1; shift\n\
+                 [ $# -eq 0 ] && exit 0\n\
+                 case \"$*\" in *{marker}*) \
+                 echo \"$*\" | sed -n 's/^off=\\([0-9]*\\);.*/\\1/p' >> \"$state.offsets\"; \
+                 drops=$(cat \"$state.drops\" 2>/dev/null || echo 0); \
+                 if [ \"$drops\" -gt 0 ]; then echo $((drops - 1)) > \"$state.drops\"; \
+                 sh -c \"$*\" 2>/dev/null | head -c 5; exit 255; fi;; esac\n\
+                 exec sh -c \"$*\"\n",
+                dir = dir.display(),
+                marker = STATUS_MARKER
+            );
+            let ssh = dir.join("bin/ssh");
+            fs::write(&ssh, script).unwrap();
+            Command::new("chmod").arg("+x").arg(&ssh).status().unwrap();
+            let path = std::env::var("PATH").unwrap_or_default();
+            std::env::set_var("PATH", format!("{}:{}", dir.join("bin").display(), path));
+        });
+        dir
+    }
+
+    /// A project built on `host`, whose connection drops `drops` times while
+    /// the build runs, with a hook saving the run report
+    fn project(name: &str, host: &str, drops: u32, build: &str) -> (PathBuf, Config) {
+        let state = install_fake_ssh();
+        fs::write(state.join(format!("{}.drops", host)), drops.to_string()).unwrap();
+        let dir =
+            std::env::temp_dir().join(format!("remotebuild-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let project = dir.join("project");
+        fs::create_dir_all(project.join(".remotebuild/hooks")).unwrap();
+        fs::write(project.join("main.c"), "int main;").unwrap();
+        for hook in ["post-artifacts", "on-failure"] {
+            let path = project.join(".remotebuild/hooks").join(hook);
+            fs::write(&path, format!("#!/bin/sh\ncat > ../{}.json\n", hook)).unwrap();
+            Command::new("chmod").arg("+x").arg(&path).status().unwrap();
+        }
+        let config: Config = serde_yaml::from_str(&format!(
+            "host: {}\nremote_path: {}\nbuild_command: {:?}\nartifacts: [out.bin]\n\
+             transport: tar\nremote_shell: login\nresilient: true\noutput: quiet",
+            host,
+            dir.join("remote").display(),
+            build
+        ))
+        .unwrap();
+        (project, config)
+    }
+
+    /// The offsets the follows of the build on `host` started at
+    fn offsets(host: &str) -> Vec<u64> {
+        fs::read_to_string(fake_ssh_dir().join(format!("{}.offsets", host)))
+            .unwrap_or_default()
+            .lines()
+            .map(|line| line.parse().unwrap())
+            .collect()
+    }
+
+    /// The run report a hook of the project saved
+    fn saved_report(project: &Path, hook: &str) -> serde_json::Value {
+        let path = project.with_file_name(format!("{}.json", hook));
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    /// A build whose connection drops mid-stream resumes its output where it
+    /// stopped, and the artifacts arrive after the reconnects
+    #[test]
+    fn dropped_connections_resume_the_output() {
+        let host = "resilient-drops";
+        let build = "for i in 1 2 3 4 5 6; do echo line $i; done; echo built > out.bin";
+        let (project, config) = project("resilient-drops", host, 3, build);
+
+        run_cancellable_build(&project, &config, SyncScope::Full, &CancelToken::default()).unwrap();
+
+        assert_eq!(offsets(host), [0, 5, 10, 15]);
+        assert_eq!(
+            fs::read_to_string(config.artifact_dir(&project).join("out.bin")).unwrap(),
+            "built\n"
+        );
+        let report = saved_report(&project, "post-artifacts");
+        assert_eq!(report["reconnects"], 3);
+        assert_eq!(report["exit_code"], 0);
+        let _ = fs::remove_dir_all(project.parent().unwrap());
+    }
+
+    /// A build failing after the connection dropped fails with its own exit
+    /// code, which the report has too
+    #[test]
+    fn dropped_connection_keeps_the_exit_code() {
+        let host = "resilient-fails";
+        let build = "echo compiling; echo error: broken >&2; exit 3";
+        let (project, config) = project("resilient-fails", host, 1, build);
+
+        let error =
+            run_cancellable_build(&project, &config, SyncScope::Full, &CancelToken::default())
+                .unwrap_err();
+
+        let failed = error.downcast_ref::<BuildFailed>().unwrap();
+        assert_eq!(failed.status.code(), Some(3));
+        assert_eq!(offsets(host), [0, 5]);
+        let report = saved_report(&project, "on-failure");
+        assert_eq!(report["reconnects"], 1);
+        assert_eq!(report["exit_code"], 3);
+        let _ = fs::remove_dir_all(project.parent().unwrap());
+    }
+
+    /// An artifact download interrupted by a dropped connection is retried
+    /// after reconnecting, resumes from its partial file and then removes
+    /// the partial directory
+    #[test]
+    fn interrupted_download_resumes() {
+        install_fake_ssh();
+        let dir =
+            std::env::temp_dir().join(format!("remotebuild-test-resume-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let project = dir.join("project");
+        fs::create_dir_all(&project).unwrap();
+        // The first transfer stops halfway with rsync's stream error, which
+        // only reconnecting retries
+        let rsync = dir.join("rsync");
+        fs::write(
+            &rsync,
+            "#!/bin/sh\n\
+             [ \"$1\" = --version ] && { echo 'rsync  version 3.2.7  protocol version 31'; exit 0; }\n\
+             for arg; do case $arg in --partial-dir=*) partial=${arg#--partial-dir=};; esac; \
+             dest=$arg; done\n\
+             [ -d \"$dest\" ] && dest=$dest/out.bin\n\
+             if [ ! -f \"$partial/out.bin\" ]; then mkdir -p \"$partial\"; \
+             printf 'first half ' > \"$partial/out.bin\"; exit 12; fi\n\
+             { cat \"$partial/out.bin\"; echo 'second half'; } > \"$dest\"; rm \"$partial/out.bin\"\n",
+        )
+        .unwrap();
+        Command::new("chmod")
+            .arg("+x")
+            .arg(&rsync)
+            .status()
+            .unwrap();
+        let config: Config = serde_yaml::from_str(&format!(
+            "host: resilient-resume\nremote_path: {}\nartifacts: [out.bin]\ntransport: rsync\n\
+             rsync_binary: {}\nremote_shell: login\nresilient: true\noutput: quiet",
+            dir.join("remote").display(),
+            rsync.display()
+        ))
+        .unwrap();
+
+        let local_dir = config.artifact_dir(&project);
+        let mut reconnects = Reconnects::default();
+        retry(&config, &mut reconnects, "Artifact download", || {
+            crate::sync_artifacts(&config, &project, &local_dir)
+        })
+        .unwrap();
+
+        assert_eq!(reconnects.count, 1);
+        assert_eq!(
+            fs::read_to_string(local_dir.join("out.bin")).unwrap(),
+            "first half second half\n"
+        );
+        assert!(!local_dir.join(crate::PARTIAL_DIR).exists());
+        // The partial directory is never uploaded
+        assert!(crate::sync_excludes(&config, true)
+            .excludes_file(&format!("{}/out.bin", crate::PARTIAL_DIR)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// rsync's connection errors, ssh's 255 and a killed rsync are
+    /// transient; errors of the transfer itself are not
+    #[test]
+    fn transient_rsync_codes() {
+        use std::os::unix::process::ExitStatusExt;
+        let exited = |code: i32| ExitStatus::from_raw(code << 8);
+        for code in [10, 12, 30, 35, 255] {
+            assert!(transient_rsync_failure(exited(code)), "{}", code);
+        }
+        for code in [0, 1, 11, 23, 24] {
+            assert!(!transient_rsync_failure(exited(code)), "{}", code);
+        }
+        assert!(transient_rsync_failure(ExitStatus::from_raw(9)));
+    }
+}
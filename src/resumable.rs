@@ -0,0 +1,152 @@
+//! Retrying transfers cut off by the connection
+//!
+//! With `resumable: true` (the default) the sync and the artifact downloads
+//! keep partially transferred files in `.remotebuild-partial` directories
+//! (`rsync --partial-dir`), so a transfer that is run again continues them
+//! instead of starting over. An rsync that exits with 30 (I/O timeout) or
+//! 255 (ssh losing the connection) is run again after `retry_backoff`
+//! seconds, doubling after each attempt, up to `transfer_retries` times.
+//!
+//! 23 (partial transfer) is retried once at most: it can come from files
+//! changing while they are sent, but usually from a file rsync can't read or
+//! write, or an artifact the build didn't produce, which fail the same way
+//! every time.
+//!
+//! With `--resilient`, 30 and 255 are left to its reconnecting retries, so
+//! the attempts of both don't multiply.
+
+use std::process::ExitStatus;
+use std::thread;
+use std::time::Duration;
+
+use crate::{indented_tail, Config, OutputLevel};
+
+/// rsync exit codes of a transfer cut off by the connection
+const INTERRUPTED_CODES: &[i32] = &[30, 255];
+
+/// rsync exit code of a partial transfer
+const PARTIAL_TRANSFER: i32 = 23;
+
+/// Most retries after a partial transfer
+const PARTIAL_TRANSFER_RETRIES: u32 = 1;
+
+/// Longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Default `transfer_retries`
+pub(crate) fn default_retries() -> u32 {
+    3
+}
+
+/// Default `retry_backoff`
+pub(crate) fn default_backoff() -> u64 {
+    2
+}
+
+/// Number of times an rsync that ended with `status` is run again
+fn retries(config: &Config, status: ExitStatus) -> u32 {
+    match status.code() {
+        _ if !config.resumable => 0,
+        Some(PARTIAL_TRANSFER) => config.transfer_retries.min(PARTIAL_TRANSFER_RETRIES),
+        // Resilient mode reconnects before running these again
+        Some(code) if INTERRUPTED_CODES.contains(&code) && !config.resilient => {
+            config.transfer_retries
+        }
+        _ => 0,
+    }
+}
+
+/// Whether an rsync that ended with `status` gets another attempt after
+/// attempt number `attempt`
+pub(crate) fn should_retry(config: &Config, status: ExitStatus, attempt: u32) -> bool {
+    attempt <= retries(config, status)
+}
+
+/// Report that `what` is tried again and wait before the next attempt
+pub(crate) fn wait(config: &Config, what: &str, status: ExitStatus, stderr: &str, attempt: u32) {
+    let delay = Duration::from_secs(
+        config
+            .retry_backoff
+            .saturating_mul(1 << (attempt - 1).min(16)),
+    )
+    .min(MAX_BACKOFF);
+    match config.output_level() {
+        OutputLevel::Normal => eprintln!(
+            "   ⚠ {} interrupted ({}), retrying in {}s (attempt {} of {})",
+            what,
+            status,
+            delay.as_secs(),
+            attempt + 1,
+            retries(config, status) + 1
+        ),
+        OutputLevel::Verbose => eprintln!(
+            "   ⚠ {} interrupted ({}), retrying in {}s (attempt {} of {}){}",
+            what,
+            status,
+            delay.as_secs(),
+            attempt + 1,
+            retries(config, status) + 1,
+            indented_tail(stderr)
+        ),
+        OutputLevel::Minimal | OutputLevel::Quiet => {}
+    }
+    thread::sleep(delay);
+}
+
+/// ` after N attempts` for a failure report, empty after a single one
+pub(crate) fn attempts(attempt: u32) -> String {
+    if attempt > 1 {
+        format!(" after {} attempts", attempt)
+    } else {
+        String::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    /// An rsync that exited with `code`
+    fn exited(code: i32) -> ExitStatus {
+        ExitStatus::from_raw(code << 8)
+    }
+
+    /// The attempts each exit code gets with `extra` config, which keeps the
+    /// default 3 retries unless it sets them
+    fn attempts_by_code(extra: &str) -> Vec<(i32, u32)> {
+        let config: Config = serde_yaml::from_str(&format!("host: build-box\n{}", extra)).unwrap();
+        [0, 12, 23, 24, 30, 255]
+            .into_iter()
+            .map(|code| {
+                let attempts = (1..10)
+                    .take_while(|&attempt| should_retry(&config, exited(code), attempt))
+                    .count() as u32;
+                (code, attempts + 1)
+            })
+            .collect()
+    }
+
+    /// Interrupted transfers get every retry, partial transfers one, and
+    /// resilient mode leaves the interrupted ones to its reconnects
+    #[test]
+    fn retries_by_exit_code() {
+        assert_eq!(
+            attempts_by_code(""),
+            [(0, 1), (12, 1), (23, 2), (24, 1), (30, 4), (255, 4)]
+        );
+        assert_eq!(
+            attempts_by_code("resilient: true"),
+            [(0, 1), (12, 1), (23, 2), (24, 1), (30, 1), (255, 1)]
+        );
+        assert_eq!(
+            attempts_by_code("transfer_retries: 0"),
+            [(0, 1), (12, 1), (23, 1), (24, 1), (30, 1), (255, 1)]
+        );
+        assert!(attempts_by_code("resumable: false")
+            .iter()
+            .all(|(_, attempts)| *attempts == 1));
+        assert_eq!(attempts(1), "");
+        assert_eq!(attempts(4), " after 4 attempts");
+    }
+}
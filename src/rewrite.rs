@@ -0,0 +1,352 @@
+//! Rewriting remote paths in downloaded reports
+//!
+//! Coverage data (lcov, JSON formats) and JUnit XML produced remotely name
+//! files by their remote path. With `rewrite_paths: true`, every artifact file
+//! changed by a download has the resolved remote project directory replaced
+//! with the local project directory. Files are rewritten in a streaming pass
+//! into a temporary file next to them, so large reports aren't read into
+//! memory. Binary files are left alone with a warning.
+//!
+//! Only files the download created or changed are rewritten, so a file is
+//! never rewritten twice even when the local path contains the remote one.
+//!
+//! A `compile_commands.json` copied back by `sync_back` is rewritten as a
+//! compilation database instead: only the `directory`, `file`, `command` and
+//! `arguments` fields of its entries change.
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::artifact::Artifact;
+use crate::patterns::glob_match;
+use crate::{resolve_remote_dir, Config};
+
+/// Bytes inspected to tell text from binary files
+const SNIFF_BYTES: usize = 8192;
+
+/// Size of the chunks a file is rewritten in
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Replaces the remote project directory with the local one in downloads
+pub(crate) struct Rewriter {
+    /// Resolved remote project directory
+    from: Vec<u8>,
+    /// Local project directory
+    to: Vec<u8>,
+}
+
+/// Sizes and modification times of the local files an artifact lands in,
+/// taken before its download
+pub(crate) struct Snapshot {
+    /// Where the artifact lands; its last component may be a glob
+    root: PathBuf,
+    /// Metadata of every file matching the root
+    files: BTreeMap<PathBuf, (u64, Option<SystemTime>)>,
+}
+
+/// Result of rewriting one file
+enum Rewrite {
+    /// The file looks binary and was left unchanged
+    Binary,
+    /// The number of paths replaced
+    Replaced(usize),
+}
+
+impl Rewriter {
+    /// Resolve the remote project directory of the config
+    pub(crate) fn new(config: &Config, project_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            from: resolve_remote_dir(config)?.into_bytes(),
+            to: project_dir.to_string_lossy().into_owned().into_bytes(),
+        })
+    }
+
+    /// Rewrite the files an artifact's download changed since the snapshot
+    pub(crate) fn rewrite_changed(&self, snapshot: &Snapshot, artifact: &str, verbose: bool) {
+        self.rewrite_files(&snapshot.changed(), artifact, verbose);
+    }
+
+    /// Rewrite the given downloaded files of an artifact
+    ///
+    /// Problems are reported as warnings, since the download itself worked.
+    pub(crate) fn rewrite_files(&self, paths: &[PathBuf], artifact: &str, verbose: bool) {
+        if self.from == self.to {
+            return;
+        }
+        let mut binary = Vec::new();
+        for path in paths {
+            match self.rewrite_file(path) {
+                Ok(Rewrite::Binary) => binary.push(path),
+                Ok(Rewrite::Replaced(count)) => {
+                    if verbose && count > 0 {
+                        println!("   ✓ Rewrote {} paths in {}", count, path.display());
+                    }
+                }
+                Err(e) => eprintln!(
+                    "   ⚠ Warning: Could not rewrite paths in {}: {:#}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        match binary.as_slice() {
+            [] => {}
+            [path] => eprintln!(
+                "   ⚠ Warning: rewrite_paths skipped binary file {}",
+                path.display()
+            ),
+            paths => eprintln!(
+                "   ⚠ Warning: rewrite_paths skipped {} binary files of artifact {}",
+                paths.len(),
+                artifact
+            ),
+        }
+    }
+
+    /// Replace the remote directory in the path fields of a compilation
+    /// database, returning the number of paths replaced
+    pub(crate) fn rewrite_compile_commands(&self, path: &Path) -> Result<usize> {
+        if self.from == self.to {
+            return Ok(0);
+        }
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut database: Value = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let entries = database
+            .as_array_mut()
+            .ok_or_else(|| anyhow!("{} is not a compilation database", path.display()))?;
+
+        let mut count = 0;
+        for entry in entries.iter_mut().filter_map(Value::as_object_mut) {
+            for field in ["directory", "file", "command"] {
+                if let Some(Value::String(value)) = entry.get_mut(field) {
+                    count += self.replace_string(value)?;
+                }
+            }
+            if let Some(Value::Array(arguments)) = entry.get_mut("arguments") {
+                for argument in arguments {
+                    if let Value::String(value) = argument {
+                        count += self.replace_string(value)?;
+                    }
+                }
+            }
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".remotebuild-tmp");
+        let tmp = path.with_file_name(tmp_name);
+        let mut rewritten = serde_json::to_string_pretty(&database)?;
+        rewritten.push('\n');
+        fs::write(&tmp, rewritten).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+        Ok(count)
+    }
+
+    /// Replace the remote directory in one string, returning the number of
+    /// paths replaced
+    fn replace_string(&self, value: &mut String) -> Result<usize> {
+        let mut output = Vec::new();
+        let count = self.replace_stream(value.as_bytes(), &mut std::io::empty(), &mut output)?;
+        if count > 0 {
+            *value = String::from_utf8_lossy(&output).into_owned();
+        }
+        Ok(count)
+    }
+
+    /// Replace the remote directory in one file, through a temporary file
+    fn rewrite_file(&self, path: &Path) -> Result<Rewrite> {
+        let mut input =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut head = vec![0u8; SNIFF_BYTES];
+        let read = read_full(&mut input, &mut head)?;
+        head.truncate(read);
+        if head.contains(&0) {
+            return Ok(Rewrite::Binary);
+        }
+
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".remotebuild-tmp");
+        let tmp = path.with_file_name(tmp_name);
+        let result = (|| {
+            let mut output = BufWriter::new(
+                File::create(&tmp)
+                    .with_context(|| format!("Failed to create {}", tmp.display()))?,
+            );
+            let count = self.replace_stream(&head, &mut input, &mut output)?;
+            output.flush()?;
+            Ok(count)
+        })();
+
+        match result {
+            Ok(0) => {
+                fs::remove_file(&tmp).ok();
+                Ok(Rewrite::Replaced(0))
+            }
+            Ok(count) => {
+                let permissions = fs::metadata(path)?.permissions();
+                fs::set_permissions(&tmp, permissions)?;
+                fs::rename(&tmp, path)
+                    .with_context(|| format!("Failed to replace {}", path.display()))?;
+                Ok(Rewrite::Replaced(count))
+            }
+            Err(e) => {
+                fs::remove_file(&tmp).ok();
+                Err(e)
+            }
+        }
+    }
+
+    /// Copy `head` and the rest of `input` to `output` with whole-path
+    /// occurrences of the remote directory replaced, returning their number
+    ///
+    /// Like the remapping of `check`, an occurrence followed by another file
+    /// name character (`/src/app` in `/src/app2`) is not a match. Only the
+    /// pattern's length is kept back between chunks.
+    fn replace_stream(
+        &self,
+        head: &[u8],
+        input: &mut impl Read,
+        output: &mut impl Write,
+    ) -> Result<usize> {
+        let from = self.from.as_slice();
+        let mut pending = head.to_vec();
+        let mut chunk = vec![0u8; CHUNK_BYTES];
+        let mut count = 0;
+        let mut eof = false;
+
+        while !eof {
+            let read = input.read(&mut chunk)?;
+            if read == 0 {
+                eof = true;
+            }
+            pending.extend_from_slice(&chunk[..read]);
+
+            let mut start = 0;
+            while let Some(pos) = find(&pending[start..], from) {
+                let found = start + pos;
+                let end = found + from.len();
+                // Without the following byte it's unknown whether this is a match
+                if end == pending.len() && !eof {
+                    break;
+                }
+                output.write_all(&pending[start..found])?;
+                if pending.get(end).is_some_and(|&b| continues_name(b)) {
+                    output.write_all(from)?;
+                } else {
+                    output.write_all(&self.to)?;
+                    count += 1;
+                }
+                start = end;
+            }
+
+            // Keep back what could still be the start of an occurrence
+            let keep = if eof {
+                0
+            } else {
+                from.len().min(pending.len() - start)
+            };
+            let flush = pending.len() - keep;
+            output.write_all(&pending[start..flush])?;
+            pending.drain(..flush);
+        }
+        Ok(count)
+    }
+}
+
+impl Snapshot {
+    /// Record the local files an artifact's download could change
+    ///
+    /// rsync puts the artifact's last path component (or, with a trailing
+    /// `/`, its contents) into its destination directory, or renames it to a
+    /// local path not ending in `/`.
+    pub(crate) fn take(local_dir: &Path, artifact: &Artifact) -> Self {
+        let dir = artifact.destination(local_dir);
+        let root = match (&artifact.local, artifact.remote.rsplit('/').next()) {
+            (Some(local), _) if !local.ends_with('/') => dir,
+            (_, Some(name)) if !name.is_empty() => dir.join(name),
+            _ => dir,
+        };
+        let files = scan_matching(&root);
+        Self { root, files }
+    }
+
+    /// Files created or changed since the snapshot was taken
+    fn changed(&self) -> Vec<PathBuf> {
+        scan_matching(&self.root)
+            .into_iter()
+            .filter(|(path, stamp)| self.files.get(path) != Some(stamp))
+            .map(|(path, _)| path)
+            .collect()
+    }
+}
+
+/// Metadata of the files below a path whose last component may be a glob
+fn scan_matching(root: &Path) -> BTreeMap<PathBuf, (u64, Option<SystemTime>)> {
+    let mut files = BTreeMap::new();
+    let name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !name.contains(['*', '?', '[']) {
+        scan(root, &mut files);
+    } else if let Ok(entries) = fs::read_dir(root.parent().unwrap_or(Path::new("."))) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            if glob_match(&name, &entry.file_name().to_string_lossy()) {
+                scan(&entry.path(), &mut files);
+            }
+        }
+    }
+    files
+}
+
+/// Record every regular file at or below `path`
+fn scan(path: &Path, files: &mut BTreeMap<PathBuf, (u64, Option<SystemTime>)>) {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return;
+    };
+    if meta.is_file() {
+        files.insert(path.to_path_buf(), (meta.len(), meta.modified().ok()));
+    } else if meta.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                scan(&entry.path(), files);
+            }
+        }
+    }
+}
+
+/// Whether a byte following a path continues its last file name
+fn continues_name(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-' | b'.') || byte >= 0x80
+}
+
+/// Position of the first occurrence of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return None;
+    }
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Read until `buffer` is full or the input ends, returning the bytes read
+fn read_full(input: &mut impl Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
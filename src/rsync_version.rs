@@ -0,0 +1,257 @@
+//! rsync versions on both ends of the transfer
+//!
+//! The local rsync is `rsync_binary` (default: `rsync` from PATH), which
+//! matters on macOS, where the system rsync is 2.6.9 or openrsync and a
+//! current one is usually installed with Homebrew. The local and remote
+//! versions are detected the first time a feature depends on them and kept
+//! for the run. A feature that the installed versions don't support is left
+//! out, with one warning naming the older side and what happens instead.
+//! An undetectable version counts as current, so a vendor's unusual
+//! `--version` output never turns features off.
+//!
+//! `remotebuild self-test` prints both versions and the known problems of
+//! the combination.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::{run_ssh_command_output, Config};
+
+/// Versions detected in this run: local ones keyed by binary, remote ones
+/// keyed by host
+static DETECTED: Mutex<BTreeMap<(Side, String), Option<Version>>> = Mutex::new(BTreeMap::new());
+
+/// Features a warning was already printed for in this run
+static WARNED: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
+/// Default of `rsync_binary`
+pub(crate) fn default_rsync_binary() -> String {
+    "rsync".to_string()
+}
+
+/// An end of the transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Side {
+    /// This machine
+    Local,
+    /// The build host
+    Remote,
+}
+
+/// A detected rsync version
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Version {
+    /// Release as (major, minor, patch), for openrsync the release it is
+    /// compatible with
+    release: (u32, u32, u32),
+    /// Whether this is openrsync, which implements a subset of the options
+    openrsync: bool,
+}
+
+/// rsync options used only when both ends support them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Feature {
+    /// `--delete-missing-args`, which lets manifest and git-aware syncs
+    /// delete files
+    DeleteMissingArgs,
+    /// `--info=progress2`, the progress of the whole transfer, which only
+    /// the local rsync reads
+    InfoProgress2,
+}
+
+impl Feature {
+    /// The option this feature stands for
+    fn flag(self) -> &'static str {
+        match self {
+            Feature::DeleteMissingArgs => "--delete-missing-args",
+            Feature::InfoProgress2 => "--info=progress2",
+        }
+    }
+
+    /// The first rsync release supporting the option
+    fn minimum(self) -> (u32, u32, u32) {
+        match self {
+            Feature::DeleteMissingArgs => (3, 1, 0),
+            Feature::InfoProgress2 => (3, 1, 0),
+        }
+    }
+
+    /// Whether a version supports the option; only known openrsync options
+    /// count for openrsync
+    fn supported_by(self, version: &Version) -> bool {
+        !version.openrsync && version.release >= self.minimum()
+    }
+}
+
+impl Version {
+    /// Parse the output of `rsync --version`
+    ///
+    /// rsync prints `rsync  version 3.2.7  protocol version 31`; openrsync
+    /// prints `openrsync: protocol version 29` and then
+    /// `rsync version 2.6.9 compatible`.
+    fn parse(output: &str) -> Option<Self> {
+        let openrsync = output.contains("openrsync");
+        let release = output.lines().find_map(|line| {
+            let rest = line.trim_start().strip_prefix("rsync")?;
+            let rest = rest.trim_start().strip_prefix("version")?;
+            let number = rest.split_whitespace().next()?;
+            let mut parts = number
+                .split(|c: char| !c.is_ascii_digit())
+                .map(|part| part.parse::<u32>().ok());
+            let major = parts.next()??;
+            let minor = parts.next().flatten().unwrap_or(0);
+            let patch = parts.next().flatten().unwrap_or(0);
+            Some((major, minor, patch))
+        })?;
+        Some(Self { release, openrsync })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (major, minor, patch) = self.release;
+        if self.openrsync {
+            write!(f, "openrsync ({}.{}.{} compatible)", major, minor, patch)
+        } else {
+            write!(f, "{}.{}.{}", major, minor, patch)
+        }
+    }
+}
+
+/// The local rsync version, detected once per run
+pub(crate) fn local(config: &Config) -> Option<Version> {
+    detect(Side::Local, &config.rsync_binary, || {
+        Command::new(&config.rsync_binary)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+    })
+}
+
+/// The remote rsync version, detected once per run and host
+pub(crate) fn remote(config: &Config) -> Option<Version> {
+    detect(Side::Remote, &config.host, || {
+        run_ssh_command_output(config, "rsync --version").ok()
+    })
+}
+
+/// Look up a version, detecting it with `run` the first time
+fn detect(side: Side, key: &str, run: impl FnOnce() -> Option<String>) -> Option<Version> {
+    let key = (side, key.to_string());
+    if let Some(version) = DETECTED
+        .lock()
+        .ok()
+        .and_then(|detected| detected.get(&key).copied())
+    {
+        return version;
+    }
+    let version = run().as_deref().and_then(Version::parse);
+    if let Ok(mut detected) = DETECTED.lock() {
+        detected.insert(key, version);
+    }
+    version
+}
+
+/// Whether both rsyncs support `feature`, warning once per run with
+/// `fallback` (what happens instead) when one doesn't
+///
+/// Needs the SSH connection for the remote version.
+pub(crate) fn supports(config: &Config, feature: Feature, fallback: &str) -> bool {
+    let sides = [
+        ("this machine", local(config)),
+        (config.host.as_str(), remote(config)),
+    ];
+    let Some((name, version)) = sides.iter().find_map(|(name, version)| {
+        version
+            .filter(|version| !feature.supported_by(version))
+            .map(|version| (name, version))
+    }) else {
+        return true;
+    };
+
+    let first = WARNED
+        .lock()
+        .map(|mut warned| warned.insert(feature.flag()))
+        .unwrap_or(true);
+    if first {
+        let (major, minor, patch) = feature.minimum();
+        eprintln!(
+            "   ⚠ Warning: rsync {} on {} doesn't support {} (needs {}.{}.{}); {}",
+            version,
+            name,
+            feature.flag(),
+            major,
+            minor,
+            patch,
+            fallback
+        );
+    }
+    false
+}
+
+/// Whether the local rsync supports `feature`, without a warning, for
+/// options that only change what is displayed
+pub(crate) fn supported_locally(config: &Config, feature: Feature) -> bool {
+    local(config).map_or(true, |version| feature.supported_by(&version))
+}
+
+/// Describe both versions and the known problems of the combination, one
+/// line each
+pub(crate) fn report(config: &Config) -> Vec<String> {
+    let local = local(config);
+    let remote = remote(config);
+    let show = |version: Option<Version>| {
+        version
+            .map(|version| version.to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    };
+    let mut lines = vec![
+        format!("local rsync ({}): {}", config.rsync_binary, show(local)),
+        format!("remote rsync ({}): {}", config.host, show(remote)),
+    ];
+
+    for (side, version) in [("local", local), ("remote", remote)] {
+        let Some(version) = version else {
+            lines.push(format!(
+                "⚠ the {} rsync version could not be detected",
+                side
+            ));
+            continue;
+        };
+        if version.openrsync {
+            lines.push(format!(
+                "⚠ the {} rsync is openrsync, which lacks some rsync options; \
+                 install rsync{}",
+                side,
+                if side == "local" {
+                    " (e.g. with Homebrew) and set rsync_binary"
+                } else {
+                    ""
+                }
+            ));
+        } else if version.release < (3, 0, 0) {
+            lines.push(format!(
+                "⚠ the {} rsync {} predates 3.0 and builds the whole file list before \
+                 transferring, which is slow for large trees",
+                side, version
+            ));
+        }
+    }
+    let feature = Feature::DeleteMissingArgs;
+    if [local, remote]
+        .iter()
+        .flatten()
+        .any(|version| !feature.supported_by(version))
+    {
+        lines.push(format!(
+            "⚠ {} is unavailable, so manifest_sync transfers the whole tree every time",
+            feature.flag()
+        ));
+    }
+    lines
+}
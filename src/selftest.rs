@@ -0,0 +1,165 @@
+//! End-to-end round-trip verification against the configured host
+//!
+//! `remotebuild self-test` builds a throwaway project in a local temp
+//! directory, syncs it into a fresh `mktemp -d` directory on the remote, runs a
+//! trivial build that copies a file containing a random token, fetches the
+//! result back and checks the token survived. The configured `remote_path` is
+//! never touched.
+
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::artifact::Artifact;
+use crate::remote_path::RemotePath;
+use crate::{
+    ensure_ssh_connection, run_remote_build_command, run_ssh_command, run_ssh_command_output,
+    stable_hash, sync_artifacts, sync_to_remote, Config, SyncScope,
+};
+use crate::{nix, rsync_version, state};
+
+/// File holding the random token in the temp project
+const TOKEN_FILE: &str = "token.txt";
+
+/// Artifact the fake build produces from the token file
+const ARTIFACT_FILE: &str = "artifact.txt";
+
+/// Run the self-test, printing each step with its timing
+pub(crate) fn run_self_test(config: &Config) -> Result<()> {
+    let token = format!(
+        "{:016x}",
+        stable_hash(
+            format!(
+                "{}-{:?}",
+                std::process::id(),
+                SystemTime::now().duration_since(UNIX_EPOCH)
+            )
+            .as_bytes()
+        )
+    );
+
+    println!("🧪 Self-test against {}", config.host);
+
+    let local_dir = env::temp_dir().join(format!("remotebuild-self-test-{}", token));
+    let result = run_steps(config, &local_dir, &token);
+    let _ = fs::remove_dir_all(&local_dir);
+    let _ = fs::remove_file(state::project_state_path(
+        &local_dir.join("project"),
+        "json",
+    ));
+
+    match result {
+        Ok(()) => {
+            println!();
+            println!("✅ Self-test passed");
+            Ok(())
+        }
+        Err(e) => Err(e.context("Self-test failed")),
+    }
+}
+
+/// Run every step, cleaning up the remote temp directory once it exists
+fn run_steps(config: &Config, local_dir: &Path, token: &str) -> Result<()> {
+    step("connect", || ensure_ssh_connection(config))?;
+    if config.nix.is_some() {
+        step("check nix", || nix::check_installed(config))?;
+    }
+    let rsync = step("detect rsync versions", || {
+        Ok(rsync_version::report(config))
+    })?;
+    for line in rsync {
+        println!("     {}", line);
+    }
+
+    let remote_dir = step("create remote temp dir", || {
+        let output = run_ssh_command_output(
+            config,
+            "mktemp -d \"${TMPDIR:-/tmp}/remotebuild-self-test.XXXXXX\"",
+        )?;
+        let dir = output.trim().to_string();
+        if dir.is_empty() || dir == config.remote_path {
+            return Err(anyhow!("mktemp returned an unusable path: {:?}", dir));
+        }
+        Ok(dir)
+    })?;
+
+    let result = run_round_trip(config, local_dir, token, &remote_dir);
+
+    let cleanup = step("clean up remote temp dir", || {
+        run_ssh_command(
+            config,
+            &format!("rm -rf {}", RemotePath::new(&remote_dir).shell()),
+        )
+    });
+
+    result.and(cleanup)
+}
+
+/// Sync, build, fetch and verify using the remote temp directory
+fn run_round_trip(config: &Config, local_dir: &Path, token: &str, remote_dir: &str) -> Result<()> {
+    let project_dir = local_dir.join("project");
+    let fetch_dir = local_dir.join("fetched");
+    fs::create_dir_all(&project_dir)
+        .with_context(|| format!("Failed to create temp dir: {}", project_dir.display()))?;
+    fs::create_dir_all(&fetch_dir)
+        .with_context(|| format!("Failed to create temp dir: {}", fetch_dir.display()))?;
+    fs::write(project_dir.join(TOKEN_FILE), token)?;
+
+    let test_config = Config {
+        remote_path: remote_dir.to_string(),
+        build_command: format!("cp {} {}", TOKEN_FILE, ARTIFACT_FILE).into(),
+        artifacts: vec![Artifact::from(ARTIFACT_FILE)],
+        exclude_patterns: Vec::new(),
+        git_aware: false,
+        manifest_sync: false,
+        output: "minimal".to_string(),
+        // The round trip checks the transport, not the build toolchain
+        docker: None,
+        nix: None,
+        retention: None,
+        local_log_dir: None,
+        ..config.clone()
+    };
+
+    step("sync", || {
+        sync_to_remote(&project_dir, &test_config, SyncScope::Full)
+    })?;
+    step("build", || run_remote_build_command(&test_config))?;
+    step("fetch artifact", || {
+        sync_artifacts(&test_config, &project_dir, &fetch_dir)
+    })?;
+    step("verify token", || {
+        verify_token(&fetch_dir.join(ARTIFACT_FILE), token)
+    })
+}
+
+/// Check that the fetched artifact contains the expected token
+fn verify_token(path: &Path, token: &str) -> Result<()> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Artifact was not fetched: {}", path.display()))?;
+    if content.trim() == token {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Artifact content mismatch: expected {:?}, got {:?}",
+            token,
+            content.trim()
+        ))
+    }
+}
+
+/// Run one step, printing its outcome and duration
+fn step<T>(name: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let start = Instant::now();
+    let result = f();
+    let mark = if result.is_ok() { "✓" } else { "✗" };
+    println!(
+        "   {} {:<26} {:.2}s",
+        mark,
+        name,
+        start.elapsed().as_secs_f64()
+    );
+    result.with_context(|| format!("Step '{}' failed", name))
+}
@@ -0,0 +1,64 @@
+//! One-time remote setup
+//!
+//! `setup_command` bootstraps a fresh build host, e.g. installing a
+//! toolchain. It runs in the remote project directory after the sync, and once
+//! it succeeds a hash of its text is written to `.remotebuild/setup-done`, so
+//! later runs skip it until the command changes. `--rerun-setup` runs it
+//! regardless. A failing setup stops the run before the build, instead of
+//! leaving the build to fail on a missing compiler.
+//!
+//! The check happens on the remote in the same command as the setup itself,
+//! so it costs one round trip over the control connection, and a tree that
+//! was removed (e.g. by `gc`) is set up again.
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    clear_status, env_exports, permissions, print_status, run_ssh_command_streaming, stable_hash,
+    Config, OutputLevel, REMOTE_META_DIR,
+};
+
+/// File in the metadata directory recording the last completed setup
+const MARKER_FILE: &str = "setup-done";
+
+/// Run the setup command unless the remote tree was already set up with it
+pub(crate) fn run_setup(config: &Config) -> Result<()> {
+    let Some(setup) = &config.setup_command else {
+        return Ok(());
+    };
+
+    let hash = format!("{:016x}", stable_hash(setup.as_bytes()));
+    let marker = format!("{}/{}", REMOTE_META_DIR, MARKER_FILE);
+    // The newline ends a trailing comment in the command
+    let cmd = format!(
+        "cd {dir} && if [ {force} = 0 ] && [ \"$(cat {marker} 2>/dev/null)\" = {hash} ]; then :; \
+         else {prefix}{exports}( {setup}\n) && mkdir -p {meta} && echo {hash} > {marker}; fi",
+        dir = config.remote_dir().shell(),
+        force = u8::from(config.rerun_setup),
+        marker = marker,
+        hash = hash,
+        prefix = permissions::build_prefix(config)?,
+        exports = env_exports(&config.env)?,
+        setup = setup,
+        meta = REMOTE_META_DIR
+    );
+
+    let output = config.output_level();
+    let mut spinner = print_status(output, "🧰 Checking remote setup ");
+    let result = run_ssh_command_streaming(config, &cmd, "setup");
+    clear_status(output, &mut spinner);
+
+    let ran = result?;
+    if !ran.status.success() {
+        return Err(anyhow!(
+            "Remote setup_command failed ({}); it runs again on the next build{}",
+            ran.status,
+            ran.tail()
+        ));
+    }
+
+    if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) {
+        println!("   ✓ Remote setup done");
+    }
+    Ok(())
+}
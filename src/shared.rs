@@ -0,0 +1,174 @@
+//! Sharing one build server between several users
+//!
+//! `{user}` in `remote_path` expands to the local user name, so every user
+//! gets their own build tree. A `cache_path` shared by everyone is exported
+//! to builds as `CCACHE_DIR` and `SCCACHE_DIR` (below `REMOTEBUILD_CACHE_DIR`),
+//! created group-writable and setgid, with builds run under `umask 002` so
+//! everyone in the group can reuse each other's cache entries.
+//!
+//! Every build takes a lock file in `<remote_path>/.remotebuild/lock` holding
+//! the local `user@hostname`, the remote shell's pid and the start time. A
+//! second build in the same tree fails with the name of the user holding it,
+//! and a lock whose process is gone is taken over. With a `cache_path`, the
+//! same record is also kept in the cache's `.remotebuild/active` directory, so
+//! `status` can list who is building on the host.
+
+use anyhow::Result;
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::history::{format_age, local_user};
+use crate::remote_path::RemotePath;
+use crate::{run_ssh_command_output, Config};
+
+/// Exit code of a build that found the tree locked by someone else
+const LOCKED_EXIT_CODE: i32 = 75;
+
+/// Exit code of a build stopped by `remotebuild cancel`, also used as
+/// remotebuild's own exit code then
+pub(crate) const CANCELLED_EXIT_CODE: i32 = 76;
+
+/// Shell condition true while the process whose pid is in `var` is alive
+///
+/// `kill -0` fails for other users' processes, hence the fallbacks.
+pub(crate) fn alive(var: &str) -> String {
+    format!(
+        "{{ kill -0 \"${v}\" 2>/dev/null || [ -d \"/proc/${v}\" ] || ps -p \"${v}\" >/dev/null 2>&1; }}",
+        v = var
+    )
+}
+
+/// A build currently running on the remote host
+pub(crate) struct ActiveBuild {
+    /// Local `user@hostname` that started it
+    user: String,
+    /// Unix timestamp (seconds) when it started
+    started: u64,
+    /// Remote directory it builds in
+    dir: String,
+}
+
+/// The local user name, restricted to characters safe in a path
+pub(crate) fn local_username() -> String {
+    let user = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    user.replace(
+        |c: char| !c.is_alphanumeric() && !matches!(c, '-' | '_' | '.'),
+        "_",
+    )
+}
+
+/// Expand the `{user}` placeholder
+pub(crate) fn expand_user(template: &str) -> String {
+    template.replace("{user}", &local_username())
+}
+
+/// Shell code run in the remote project directory before the build command:
+/// take the lock, register the build and set up the shared cache
+///
+/// Ends with `&& `, so the build command only runs once everything is in
+/// place.
+pub(crate) fn build_prelude(config: &Config) -> String {
+    let record = escape(Cow::Owned(local_user()));
+    let mut prelude = format!(
+        "mkdir -p .remotebuild && LOCK=\"$PWD/.remotebuild/lock\" && \
+         CANCEL=\"$PWD/.remotebuild/cancel\" && \
+         if [ -f \"$LOCK\" ] && IFS='\t' read -r lock_user lock_pid lock_since < \"$LOCK\" \
+         && [ \"$lock_pid\" != $$ ] && {alive}; then \
+         echo \"   ✗ $lock_user is already building in $PWD (pid $lock_pid)\" >&2; exit {code}; fi && \
+         RECORD=\"$(printf '%s\\t%s\\t%s\\t%s' {record} $$ \"$(date +%s)\" \"$PWD\")\" && \
+         echo \"$RECORD\" > \"$LOCK\" && CLEANUP='rm -f \"$LOCK\"' && ",
+        alive = alive("lock_pid"),
+        code = LOCKED_EXIT_CODE,
+        record = record
+    );
+
+    if let Some(cache) = &config.cache_path {
+        let cache = RemotePath::new(cache).absolute_shell();
+        prelude.push_str(&format!(
+            "mkdir -p {cache}/ccache {cache}/sccache {cache}/.remotebuild/active && \
+             {{ chmod g+rwxs {cache} {cache}/ccache {cache}/sccache {cache}/.remotebuild \
+             {cache}/.remotebuild/active 2>/dev/null; true; }} && \
+             ACTIVE={cache}/.remotebuild/active/{user}-$$ && echo \"$RECORD\" > \"$ACTIVE\" && \
+             CLEANUP=\"$CLEANUP \\\"$ACTIVE\\\"\" && umask 002 && \
+             export REMOTEBUILD_CACHE_DIR={cache} CCACHE_DIR={cache}/ccache \
+             SCCACHE_DIR={cache}/sccache && ",
+            cache = cache,
+            user = local_username()
+        ));
+    }
+
+    // Run the cleanup on normal exit and when the connection drops; a TERM
+    // sent by `cancel` (which names this shell in the cancel file) ends the
+    // build with its own exit code
+    prelude.push_str(&format!(
+        "trap 'eval \"$CLEANUP\"' EXIT && trap 'exit 129' HUP INT && \
+         trap 'if [ \"$(cat \"$CANCEL\" 2>/dev/null)\" = $$ ]; then rm -f \"$CANCEL\"; \
+         exit {}; fi; exit 129' TERM && ",
+        CANCELLED_EXIT_CODE
+    ));
+    prelude
+}
+
+/// List the builds running in the project's tree and, with a `cache_path`,
+/// anywhere on the host sharing that cache
+pub(crate) fn active_builds(config: &Config) -> Result<Vec<ActiveBuild>> {
+    let mut sources = vec![format!("{}/.remotebuild/lock", config.remote_dir().shell())];
+    if let Some(cache) = &config.cache_path {
+        sources.push(format!(
+            "{}/.remotebuild/active/*",
+            RemotePath::new(cache).absolute_shell()
+        ));
+    }
+
+    // Only records whose shell is still alive count
+    let cmd = format!(
+        "for f in {}; do [ -f \"$f\" ] || continue; \
+         IFS='\t' read -r u p s d < \"$f\"; \
+         {} && printf '%s\\t%s\\t%s\\t%s\\n' \"$u\" \"$p\" \"$s\" \"$d\"; \
+         done; true",
+        sources.join(" "),
+        alive("p")
+    );
+    let output = run_ssh_command_output(config, &cmd)?;
+
+    let mut builds: Vec<(String, ActiveBuild)> = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [user, pid, started, dir] = fields[..] else {
+            continue;
+        };
+        // The tree's lock and the cache record describe the same build
+        if builds.iter().any(|(p, _)| p == pid) {
+            continue;
+        }
+        builds.push((
+            pid.to_string(),
+            ActiveBuild {
+                user: user.to_string(),
+                started: started.parse().unwrap_or(0),
+                dir: dir.to_string(),
+            },
+        ));
+    }
+    Ok(builds.into_iter().map(|(_, build)| build).collect())
+}
+
+/// Print the running builds, one per line
+pub(crate) fn print_active(builds: &[ActiveBuild]) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    for build in builds {
+        println!(
+            "   🔨 {} is building in {} (started {})",
+            build.user,
+            build.dir,
+            format_age(now.saturating_sub(build.started))
+        );
+    }
+}
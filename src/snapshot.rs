@@ -0,0 +1,259 @@
+//! Snapshots of the remote build environment
+//!
+//! With `snapshot_commands` or `snapshot_env` configured, every build is
+//! followed by one batched remote command recording the distribution
+//! release, the kernel, the configured variables and the output of the
+//! configured commands (e.g. `cc --version`), run the way the build runs
+//! (env, container, nix shell). Snapshots of successful builds are kept in
+//! the state file per host and remote path. Whenever a new snapshot differs
+//! from the last successful one, a notice names the entries that changed, so
+//! a build failing today can be traced to the compiler update that happened
+//! since. `remotebuild env-diff` shows the full difference on demand.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::history::format_age;
+use crate::state::State;
+use crate::{container, env_exports, nix, run_ssh_command_output, Config, OutputLevel};
+
+/// Prefix of the lines separating the entries in the snapshot output
+const MARKER: &str = "__remotebuild_snapshot__ ";
+
+/// Lines of output kept per command
+const MAX_COMMAND_LINES: usize = 20;
+
+/// The remote environment at one point in time
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct EnvSnapshot {
+    /// Unix timestamp (seconds) the snapshot was taken
+    pub(crate) taken_at: u64,
+    /// Output per entry: `os`, `kernel`, `env NAME` and `$ command`
+    pub(crate) entries: BTreeMap<String, String>,
+}
+
+/// Whether snapshots are configured
+pub(crate) fn enabled(config: &Config) -> bool {
+    !config.snapshot_commands.is_empty() || !config.snapshot_env.is_empty()
+}
+
+/// Take a snapshot after a build and report changes since the last
+/// successful one, which the snapshot replaces if this build succeeded
+///
+/// Failures are only warnings, since the build itself is done.
+pub(crate) fn after_build(project_dir: &Path, config: &Config, succeeded: bool) {
+    if !enabled(config) {
+        return;
+    }
+    let current = match take(config) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("   ⚠ Warning: Could not snapshot the environment: {:#}", e);
+            return;
+        }
+    };
+
+    let mut state = State::load(project_dir);
+    let key = state_key(config);
+    if let Some(previous) = state.snapshots.get(&key) {
+        // Entries added to or removed from the config aren't news
+        let changes: Vec<_> = changes(previous, &current)
+            .into_iter()
+            .filter(|(_, old, new)| old.is_some() && new.is_some())
+            .collect();
+        if !changes.is_empty() && !matches!(config.output_level(), OutputLevel::Quiet) {
+            eprintln!(
+                "   ⚠ Environment changed since the last successful build ({}):",
+                format_age(current.taken_at.saturating_sub(previous.taken_at))
+            );
+            for (name, old, new) in &changes {
+                eprintln!(
+                    "     {}: {} → {}",
+                    name,
+                    first_line(old.as_deref()),
+                    first_line(new.as_deref())
+                );
+            }
+            eprintln!("     (`remotebuild env-diff` shows the full difference)");
+        }
+    }
+
+    if succeeded {
+        state.snapshots.insert(key, current);
+        if let Err(e) = state.save(project_dir) {
+            eprintln!("   ⚠ Warning: Could not save environment snapshot: {}", e);
+        }
+    }
+}
+
+/// Compare the environment now with the last successful build's
+pub(crate) fn run_env_diff(project_dir: &Path, config: &Config) -> Result<()> {
+    if !enabled(config) {
+        return Err(anyhow!(
+            "No environment snapshot configured: set snapshot_commands or snapshot_env"
+        ));
+    }
+    let current = take(config)?;
+    let state = State::load(project_dir);
+    let Some(previous) = state.snapshots.get(&state_key(config)) else {
+        println!(
+            "No snapshot of a successful build in {}:{} yet; the environment now:",
+            config.host, config.remote_path
+        );
+        for (name, value) in &current.entries {
+            print_entry(name, "  ", value);
+        }
+        return Ok(());
+    };
+
+    let changes = changes(previous, &current);
+    println!(
+        "🔍 Environment of {}:{} compared with the last successful build ({})",
+        config.host,
+        config.remote_path,
+        format_age(current.taken_at.saturating_sub(previous.taken_at))
+    );
+    if changes.is_empty() {
+        println!("   No changes in {} entries", current.entries.len());
+        return Ok(());
+    }
+    for (name, old, new) in &changes {
+        println!();
+        println!("   {}", name);
+        match old {
+            Some(old) => print_entry("", "- ", old),
+            None => println!("   - (not recorded)"),
+        }
+        match new {
+            Some(new) => print_entry("", "+ ", new),
+            None => println!("   + (not recorded)"),
+        }
+    }
+    println!();
+    println!(
+        "   {} changed, {} unchanged",
+        changes.len(),
+        current.entries.len().saturating_sub(changes.len())
+    );
+    Ok(())
+}
+
+/// Capture the remote environment with one remote command
+fn take(config: &Config) -> Result<EnvSnapshot> {
+    let mut script = format!(
+        "printf '%s\\n' '{m}os'; (. /etc/os-release 2>/dev/null && echo \"$PRETTY_NAME\") \
+         || uname -s; printf '%s\\n' '{m}kernel'; uname -srm; ",
+        m = MARKER
+    );
+    for name in &config.snapshot_env {
+        let valid = !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(anyhow!("Invalid variable name in snapshot_env: {}", name));
+        }
+        script.push_str(&format!(
+            "printf '%s\\n' '{m}env {name}'; printf '%s\\n' \"${{{name}-}}\"; ",
+            m = MARKER,
+            name = name
+        ));
+    }
+    for command in &config.snapshot_commands {
+        let marker = format!("{}$ {}", MARKER, command);
+        script.push_str(&format!(
+            "printf '%s\\n' {}; ({}) 2>&1 < /dev/null | head -n {}; ",
+            escape(Cow::Owned(marker)),
+            command,
+            MAX_COMMAND_LINES
+        ));
+    }
+    script.push_str("true");
+
+    // Run where and how the build runs, minus the lock
+    let mut command = format!("{}{}", env_exports(&config.env)?, script);
+    if let Some(docker) = &config.docker {
+        command = container::wrap_command(docker, &command, &[])?;
+    }
+    if let Some(nix) = &config.nix {
+        command = nix::wrap_command(nix, &command)?;
+    }
+    let remote = format!("cd {} && {}", config.remote_dir().shell(), command);
+    let output = run_ssh_command_output(config, &remote).context("Snapshot command failed")?;
+
+    let mut entries = BTreeMap::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    for line in output.lines() {
+        if let Some(name) = line.strip_prefix(MARKER) {
+            if let Some((name, lines)) = current.take() {
+                entries.insert(name, lines.join("\n").trim().to_string());
+            }
+            current = Some((name.to_string(), Vec::new()));
+        } else if let Some((_, lines)) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+    if let Some((name, lines)) = current {
+        entries.insert(name, lines.join("\n").trim().to_string());
+    }
+
+    Ok(EnvSnapshot {
+        taken_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        entries,
+    })
+}
+
+/// Entries that differ, with their old and new values
+fn changes(
+    previous: &EnvSnapshot,
+    current: &EnvSnapshot,
+) -> Vec<(String, Option<String>, Option<String>)> {
+    let mut names: Vec<&String> = previous
+        .entries
+        .keys()
+        .chain(current.entries.keys())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = previous.entries.get(name);
+            let new = current.entries.get(name);
+            (old != new).then(|| (name.clone(), old.cloned(), new.cloned()))
+        })
+        .collect()
+}
+
+/// Key of a snapshot in the state file
+fn state_key(config: &Config) -> String {
+    format!("{}:{}", config.host, config.remote_path)
+}
+
+/// The first line of a value, for one-line notices
+fn first_line(value: Option<&str>) -> &str {
+    match value {
+        Some(value) => value.lines().next().unwrap_or("(empty)"),
+        None => "(not recorded)",
+    }
+}
+
+/// Print a multi-line value, every line indented and prefixed
+fn print_entry(name: &str, prefix: &str, value: &str) {
+    if !name.is_empty() {
+        println!("   {}", name);
+    }
+    if value.is_empty() {
+        println!("   {}(empty)", prefix);
+    }
+    for line in value.lines() {
+        println!("   {}{}", prefix, line);
+    }
+}
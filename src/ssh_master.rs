@@ -0,0 +1,223 @@
+//! Waiting for the SSH control master
+//!
+//! `ssh -f` returns once the master authenticated, but the first command's
+//! connection can still beat the control socket, and a master that gave up
+//! on multiplexing (another one bound the socket first) leaves nothing to
+//! connect to. After starting the master remotebuild polls `ssh -O check`
+//! until it answers, for up to `ssh_ready_timeout` seconds, and otherwise
+//! fails with what the master logged.
+//!
+//! Processes starting a master for the same host and port at once (two
+//! terminals, matrix platforms sharing a host) take turns through a lock
+//! file next to the socket: the first starts the master and the others wait
+//! for it to answer instead of replacing its socket. A lock older than the
+//! timeout is left by a process that died and is taken over.
+//!
+//! `connection_persist` is the master's `ControlPersist`, how long it stays
+//! after the last command (default `10m`), in ssh's time format such as
+//! `30s`, `2h` or `1h30m`. With `0` or `no` remotebuild closes the masters it
+//! used with `ssh -O exit` when it exits; they still get a minute's grace,
+//! so one left by a killed run goes away on its own.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::{ssh_control_option, ssh_control_path, Config};
+
+/// Interval between two readiness checks
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `ControlPersist` of a master closed when remotebuild exits, in case it
+/// is killed before
+const CLOSING_PERSIST: &str = "1m";
+
+/// Control options and hosts of the masters to close at exit
+static CLOSE_AT_EXIT: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// Default `connection_persist`
+pub(crate) fn default_persist() -> String {
+    "10m".to_string()
+}
+
+/// Check `connection_persist` when the config is loaded
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    let value = config.connection_persist.as_str();
+    if matches!(value, "yes" | "no") || ssh_duration(value) {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Invalid connection_persist: {} (expected an ssh duration like 0, 30s, 2h or 1h30m, or \
+         yes or no)",
+        value
+    ))
+}
+
+/// Whether `value` is in ssh's time format: numbers each with an optional
+/// unit of s, m, h, d or w
+fn ssh_duration(value: &str) -> bool {
+    let mut digits = false;
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            digits = true;
+        } else if digits && "sSmMhHdDwW".contains(c) {
+            digits = false;
+        } else {
+            return false;
+        }
+    }
+    !value.is_empty()
+}
+
+/// Whether the master outlives the run
+fn persists(config: &Config) -> bool {
+    !matches!(config.connection_persist.as_str(), "0" | "no")
+}
+
+/// The `ControlPersist` option of a new master
+pub(crate) fn persist_option(config: &Config) -> String {
+    if persists(config) {
+        format!("ControlPersist={}", config.connection_persist)
+    } else {
+        format!("ControlPersist={}", CLOSING_PERSIST)
+    }
+}
+
+/// Remember to close the config's master at exit unless it should persist
+pub(crate) fn close_at_exit(config: &Config) {
+    if persists(config) {
+        return;
+    }
+    let entry = (ssh_control_option(config), config.host.clone());
+    if let Ok(mut masters) = CLOSE_AT_EXIT.lock() {
+        if !masters.contains(&entry) {
+            masters.push(entry);
+        }
+    }
+}
+
+/// Close the masters that shouldn't persist, before remotebuild exits
+pub(crate) fn close_unpersisted() {
+    let masters = match CLOSE_AT_EXIT.lock() {
+        Ok(mut masters) => std::mem::take(&mut *masters),
+        Err(_) => return,
+    };
+    for (control, host) in masters {
+        exit(&control, &host);
+    }
+}
+
+/// Ask the master behind the `ControlPath=...` option `control` to exit,
+/// returning whether one was running
+pub(crate) fn exit(control: &str, host: &str) -> bool {
+    Command::new("ssh")
+        .arg("-o")
+        .arg(control)
+        .arg("-O")
+        .arg("exit")
+        .arg(host)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Close the master and remove its socket after the connection dropped, so
+/// the next connection doesn't go through a master stuck on the dead link
+pub(crate) fn invalidate(config: &Config) {
+    exit(&ssh_control_option(config), &config.host);
+    let _ = fs::remove_file(ssh_control_path(config));
+}
+
+/// Default `ssh_ready_timeout`
+pub(crate) fn default_ready_timeout() -> u64 {
+    10
+}
+
+/// The time a master may take to answer
+fn ready_timeout(config: &Config) -> Duration {
+    Duration::from_secs(config.ssh_ready_timeout)
+}
+
+/// Whether the master behind the control socket answers
+pub(crate) fn alive(config: &Config) -> bool {
+    Command::new("ssh")
+        .arg("-o")
+        .arg(ssh_control_option(config))
+        .arg("-O")
+        .arg("check")
+        .arg(&config.host)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Poll until the master answers, `false` after the timeout
+pub(crate) fn wait_ready(config: &Config) -> bool {
+    let deadline = Instant::now() + ready_timeout(config);
+    loop {
+        if alive(config) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Permission to start the master, released when dropped
+pub(crate) struct StartLock {
+    /// The lock file
+    path: PathBuf,
+}
+
+impl Drop for StartLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Take the start lock of the control socket at `control_path`
+///
+/// Returns `None` when another process held it and its master came up in
+/// the meantime, so there is nothing left to start.
+pub(crate) fn lock(config: &Config, control_path: &str) -> Result<Option<StartLock>> {
+    let path = PathBuf::from(format!("{}.lock", control_path));
+    let deadline = Instant::now() + ready_timeout(config);
+    loop {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(_) => return Ok(Some(StartLock { path })),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create {}", path.display()))
+            }
+        }
+        if alive(config) {
+            return Ok(None);
+        }
+        if Instant::now() >= deadline || stale(&path, ready_timeout(config)) {
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Whether the lock file at `path` is older than `timeout`
+fn stale(path: &Path, timeout: Duration) -> bool {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > timeout)
+}
@@ -0,0 +1,184 @@
+//! SSH settings from the config
+//!
+//! `ssh_port`, `ssh_identity` and `ssh_options` (`-o` options such as
+//! `StrictHostKeyChecking=accept-new`) replace a `~/.ssh/config` stanza for
+//! the build host. They are passed to every ssh remotebuild runs: the control
+//! master, the commands sharing its connection and the `-e` command of each
+//! rsync, so a transfer falling back to a connection of its own still
+//! reaches the same server with the same key. The control socket name
+//! includes the port, keeping two servers on one host name apart.
+//!
+//! `jump_host` reaches the host through a bastion with `ProxyJump`, passed
+//! through verbatim so `bastion1,bastion2` hops through both. The liveness
+//! check of an existing connection then waits longer, and a failed
+//! connection says whether a jump host or the host behind it was out of
+//! reach when ssh's messages tell.
+//!
+//! Every ssh also sends a keepalive after `keepalive_interval` idle seconds
+//! (default 30, `0` for none) and gives up after three unanswered ones, so
+//! a NAT mapping doesn't expire during a long silent build step and a dead
+//! link is noticed. Resilient mode checks at least every 10 seconds. An
+//! option set in `ssh_options` replaces remotebuild's own.
+
+use anyhow::{anyhow, Result};
+
+use crate::{resilient, Config};
+
+/// Seconds the liveness check of an existing connection waits
+const CHECK_TIMEOUT_SECS: u32 = 2;
+
+/// Seconds the liveness check waits through a jump host, which adds a
+/// connection of its own
+const JUMP_CHECK_TIMEOUT_SECS: u32 = 10;
+
+/// Unanswered keepalives before ssh gives up on the connection
+const KEEPALIVE_COUNT_MAX: u32 = 3;
+
+/// Default `keepalive_interval`
+pub(crate) fn default_keepalive_interval() -> u64 {
+    30
+}
+
+/// Check `ssh_options` and `jump_host` when the config is loaded
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    if let Some(jump) = &config.jump_host {
+        if jump.is_empty() || jump.contains(char::is_whitespace) {
+            return Err(anyhow!(
+                "Invalid jump_host: {} (expected e.g. bastion or user@bastion1,bastion2)",
+                jump
+            ));
+        }
+    }
+    for option in &config.ssh_options {
+        let key = option.split(['=', ' ']).next().unwrap_or_default();
+        if key.is_empty() || key.starts_with('-') || !option.contains(['=', ' ']) {
+            return Err(anyhow!(
+                "Invalid ssh_options entry: {} (expected an ssh -o option like \
+                 ServerAliveInterval=15)",
+                option
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The ssh arguments for the configured port, identity and options
+pub(crate) fn args(config: &Config) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(port) = config.ssh_port {
+        args.push("-p".to_string());
+        args.push(port.to_string());
+    }
+    if let Some(identity) = &config.ssh_identity {
+        // ssh expands a leading `~` itself
+        args.push("-i".to_string());
+        args.push(identity.clone());
+        args.push("-o".to_string());
+        args.push("IdentitiesOnly=yes".to_string());
+    }
+    if let Some(jump) = &config.jump_host {
+        args.push("-o".to_string());
+        args.push(format!("ProxyJump={}", jump));
+    }
+    for option in &config.ssh_options {
+        args.push("-o".to_string());
+        args.push(option.clone());
+    }
+    args.extend(unless_set(config, &keepalive(config)));
+    args
+}
+
+/// The keepalive options: `keepalive_interval`, or resilient mode's tighter
+/// ones
+fn keepalive(config: &Config) -> Vec<String> {
+    let (interval, count) = if config.resilient {
+        let interval = match config.keepalive_interval {
+            0 => resilient::KEEPALIVE_INTERVAL_SECS,
+            interval => interval.min(resilient::KEEPALIVE_INTERVAL_SECS),
+        };
+        (interval, resilient::KEEPALIVE_COUNT_MAX)
+    } else if config.keepalive_interval == 0 {
+        return Vec::new();
+    } else {
+        (config.keepalive_interval, KEEPALIVE_COUNT_MAX)
+    };
+    vec![
+        format!("ServerAliveInterval={}", interval),
+        format!("ServerAliveCountMax={}", count),
+    ]
+}
+
+/// `-o` arguments for the `options` whose keyword `ssh_options` doesn't set
+///
+/// ssh keeps the first value it gets for each keyword, so these come after
+/// the user's options and are left out where they would only be ignored.
+pub(crate) fn unless_set(config: &Config, options: &[String]) -> Vec<String> {
+    let set: Vec<String> = config
+        .ssh_options
+        .iter()
+        .map(|option| keyword(option))
+        .collect();
+    options
+        .iter()
+        .filter(|option| !set.contains(&keyword(option)))
+        .flat_map(|option| ["-o".to_string(), option.clone()])
+        .collect()
+}
+
+/// The keyword of an `-o` option, which ssh matches case-insensitively
+fn keyword(option: &str) -> String {
+    option
+        .split(['=', ' '])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// The `ConnectTimeout` option of the liveness check
+pub(crate) fn check_timeout(config: &Config) -> String {
+    let secs = match config.jump_host {
+        Some(_) => JUMP_CHECK_TIMEOUT_SECS,
+        None => CHECK_TIMEOUT_SECS,
+    };
+    format!("ConnectTimeout={}", secs)
+}
+
+/// Which end of a jump host connection failed, if ssh's `stderr` tells
+pub(crate) fn jump_failure(config: &Config, stderr: &str) -> Option<String> {
+    let jump = config.jump_host.as_ref()?;
+    // The bastion answered but couldn't open the forwarded connection
+    if stderr.contains("stdio forwarding failed") || stderr.contains("open failed") {
+        return Some(format!("jump host {} couldn't reach {}", jump, config.host));
+    }
+    jump.split(',').map(hop_name).find_map(|hop| {
+        let unreachable = [
+            format!("connect to host {} ", hop),
+            format!("resolve hostname {}:", hop),
+        ];
+        unreachable
+            .iter()
+            .any(|message| stderr.contains(message.as_str()))
+            .then(|| format!("can't reach jump host {}", hop))
+    })
+}
+
+/// The host name of a `[user@]host[:port]` hop
+fn hop_name(hop: &str) -> &str {
+    let host = hop.rsplit_once('@').map_or(hop, |(_, host)| host);
+    host.split(':').next().unwrap_or(host)
+}
+
+/// Quote `arg` for rsync's `-e` command, which is split on whitespace
+/// except inside quotes
+///
+/// Single quotes keep everything literal, so any in `arg` are put between
+/// double quotes.
+pub(crate) fn rsync_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && !arg.contains(|c: char| c.is_whitespace() || matches!(c, '\'' | '"' | '\\'))
+    {
+        return arg.to_string();
+    }
+    let quoted: Vec<String> = arg.split('\'').map(|part| format!("'{}'", part)).collect();
+    quoted.join("\"'\"")
+}
@@ -0,0 +1,122 @@
+//! Persistent local state kept between runs
+//!
+//! The state file lives in the remotebuild cache directory and is keyed by the
+//! absolute project path, so every project gets its own file. Loading is
+//! best-effort: a missing or unreadable state file simply behaves like a fresh
+//! project.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::compression::LinkRecord;
+use crate::estimate::Estimate;
+use crate::snapshot::EnvSnapshot;
+use crate::stable_hash;
+
+/// State recorded for a single project
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct State {
+    /// Per-component records for workspace builds, keyed by component path
+    #[serde(default)]
+    pub(crate) components: BTreeMap<String, ComponentState>,
+
+    /// Mode of the last sync (git, manifest or full), keyed by
+    /// `host:remote_path`
+    #[serde(default)]
+    pub(crate) sync_modes: BTreeMap<String, String>,
+
+    /// Unix timestamp (seconds) of the last automatic retention run, keyed
+    /// by host
+    #[serde(default)]
+    pub(crate) gc_runs: BTreeMap<String, u64>,
+
+    /// Last link measurement and compression decision, keyed by host
+    #[serde(default)]
+    pub(crate) links: BTreeMap<String, LinkRecord>,
+
+    /// Environment snapshot of the last successful build, keyed by
+    /// `host:remote_path`
+    #[serde(default)]
+    pub(crate) snapshots: BTreeMap<String, EnvSnapshot>,
+
+    /// Unix timestamp (seconds) of the last successful build, keyed by
+    /// `host:remote_path`
+    #[serde(default)]
+    pub(crate) last_builds: BTreeMap<String, u64>,
+
+    /// Typical sync and build durations from the build history, keyed by
+    /// `host:remote_path`
+    #[serde(default)]
+    pub(crate) estimates: BTreeMap<String, Estimate>,
+
+    /// Random ID written to the `.remotebuild-project` marker of the remote
+    /// paths this project syncs to
+    #[serde(default)]
+    pub(crate) project_id: Option<String>,
+}
+
+/// State recorded for one workspace component
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct ComponentState {
+    /// Fingerprint of the component's inputs at the last successful build
+    pub(crate) fingerprint: String,
+
+    /// Unix timestamp (seconds) of the last successful build
+    pub(crate) last_success: u64,
+}
+
+impl State {
+    /// Load the state for a project, falling back to an empty state
+    pub(crate) fn load(project_dir: &Path) -> Self {
+        fs::read_to_string(project_state_path(project_dir, "json"))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    /// Write the state for a project back to the cache directory
+    pub(crate) fn save(&self, project_dir: &Path) -> Result<()> {
+        let path = project_state_path(project_dir, "json");
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create state dir: {}", parent.display()))?;
+        }
+
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(&path, content)
+            .with_context(|| format!("Failed to write state file: {}", path.display()))
+    }
+}
+
+/// Get the location of a per-project file in the state directory
+pub(crate) fn project_state_path(project_dir: &Path, extension: &str) -> PathBuf {
+    shared_state_path(&format!("{}.{}", project_key(project_dir), extension))
+}
+
+/// Get the location of a file in the state directory shared by all projects
+pub(crate) fn shared_state_path(name: &str) -> PathBuf {
+    let cache_dir = dirs::cache_dir().unwrap_or_else(env::temp_dir);
+    cache_dir.join("remotebuild").join("state").join(name)
+}
+
+/// Get a file-name-safe key identifying a project directory
+///
+/// The key combines the directory name (for humans) with a hash of its
+/// absolute path (for uniqueness).
+pub(crate) fn project_key(project_dir: &Path) -> String {
+    let name = project_dir
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let safe_name = name.replace(|c: char| !c.is_alphanumeric() && c != '-' && c != '.', "_");
+
+    format!(
+        "{}-{:016x}",
+        safe_name,
+        stable_hash(project_dir.to_string_lossy().as_bytes())
+    )
+}
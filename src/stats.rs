@@ -0,0 +1,97 @@
+//! Transfer statistics reported by rsync's `--stats`
+//!
+//! The sync and the artifact downloads read rsync's statistics block to
+//! print one line such as `214 files, 18.4 MB sent (3.1 MB/s), 2.3 s` in
+//! minimal and normal output; verbose output shows the block itself. The
+//! labels differ between versions (`Number of files transferred` before 3.1,
+//! `Number of regular files transferred` since) and numbers may carry
+//! thousands separators or `-h` units, so lines are matched by the words in
+//! their label, and ones that don't fit are skipped. The elapsed time is
+//! measured by remotebuild, as rsync doesn't report it.
+
+use std::time::Duration;
+
+use crate::gc::format_size;
+use crate::progress::parse_bytes;
+
+/// Totals from one or more `--stats` blocks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stats {
+    /// Regular files transferred
+    pub(crate) files: Option<u64>,
+    /// Bytes sent over the connection by the local rsync
+    pub(crate) sent: Option<u64>,
+    /// Bytes received over the connection by the local rsync
+    pub(crate) received: Option<u64>,
+}
+
+impl Stats {
+    /// Parse the statistics from rsync's stdout, `None` if it has none
+    pub(crate) fn parse(stdout: &str) -> Option<Self> {
+        let mut stats = Self::default();
+        for line in stdout.lines() {
+            let Some((label, value)) = line.split_once(':') else {
+                continue;
+            };
+            let label = label.trim().to_ascii_lowercase();
+            let Some(value) = value.split_whitespace().next().and_then(parse_bytes) else {
+                continue;
+            };
+            if label.starts_with("number of") && label.ends_with("files transferred") {
+                stats.files = Some(value);
+            } else if label == "total bytes sent" {
+                stats.sent = Some(value);
+            } else if label == "total bytes received" {
+                stats.received = Some(value);
+            }
+        }
+        (stats != Self::default()).then_some(stats)
+    }
+
+    /// Add up the statistics of several transfers
+    pub(crate) fn add(&mut self, other: &Self) {
+        /// Sum of the counts reported by either
+        fn sum(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            }
+        }
+        self.files = sum(self.files, other.files);
+        self.sent = sum(self.sent, other.sent);
+        self.received = sum(self.received, other.received);
+    }
+
+    /// Summary of an upload taking `elapsed`, e.g. `214 files, 18.4 MB sent
+    /// (3.1 MB/s), 2.3 s`
+    pub(crate) fn sent_summary(&self, elapsed: Duration) -> String {
+        self.summary(self.sent, "sent", elapsed)
+    }
+
+    /// Summary of a download taking `elapsed`, like [`Self::sent_summary`]
+    pub(crate) fn received_summary(&self, elapsed: Duration) -> String {
+        self.summary(self.received, "received", elapsed)
+    }
+
+    /// Summary with `bytes` moved in the direction named by `verb`
+    fn summary(&self, bytes: Option<u64>, verb: &str, elapsed: Duration) -> String {
+        let mut parts = Vec::new();
+        match self.files {
+            Some(1) => parts.push("1 file".to_string()),
+            Some(files) => parts.push(format!("{} files", files)),
+            None => {}
+        }
+        let secs = elapsed.as_secs_f64();
+        if let Some(bytes) = bytes {
+            let size = format_size(bytes / 1024);
+            if secs > 0.0 {
+                let rate = format_size((bytes as f64 / secs) as u64 / 1024);
+                parts.push(format!("{} {} ({}/s)", size, verb, rate));
+            } else {
+                parts.push(format!("{} {}", size, verb));
+            }
+        }
+        parts.push(format!("{:.1} s", secs));
+        parts.join(", ")
+    }
+}
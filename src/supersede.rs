@@ -0,0 +1,420 @@
+//! Cancelling a build that a newer one supersedes
+//!
+//! Each build claims its remote tree (host and remote path) in a small file
+//! in the state directory, naming the invocation that runs it. A newer build
+//! of the same tree takes the claim over and waits for the previous
+//! invocation to exit before syncing, so the two never write the tree at
+//! once. The previous invocation notices that it lost the claim at its next
+//! check, between the sync, build and artifact phases, and stops with
+//! [`Superseded`]. Watch mode cancels its own running cycle through the same
+//! [`CancelToken`] when files change.
+//!
+//! A running build is given [`DEBOUNCE`] to finish by itself; one that does
+//! completes normally. Otherwise its remote process group, recorded in
+//! `.remotebuild/build.pgid` when the build starts, receives TERM, which
+//! ends the ssh session and with it the phase, so the usual cleanup runs.
+
+use anyhow::Result;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::state::project_state_path;
+use crate::{run_ssh_command, stable_hash, Config, OutputLevel};
+
+/// Time a superseded build gets to finish before it is cancelled
+pub(crate) const DEBOUNCE: Duration = Duration::from_secs(3);
+
+/// Longest wait for a superseded invocation to exit before building anyway
+const EXIT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Interval between checks of the claim and of a superseded invocation
+const POLL: Duration = Duration::from_millis(200);
+
+/// File in the remote tree recording the build's process group
+const PGID_FILE: &str = ".remotebuild/build.pgid";
+
+/// Claims taken by this process, telling its watch cycles apart
+static CLAIMS: AtomicU64 = AtomicU64::new(0);
+
+/// Error returned by a run that was cancelled for a newer one
+#[derive(Debug)]
+pub(crate) struct Superseded;
+
+impl fmt::Display for Superseded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Build cancelled: superseded by a newer build")
+    }
+}
+
+impl std::error::Error for Superseded {}
+
+/// Flag that cancels the run it is handed to, shared between threads
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CancelToken {
+    /// Whether the run was cancelled
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Cancel the run at its next check
+    pub(crate) fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the run was cancelled
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Cancellation state of one run: its token and its claim on the tree
+pub(crate) struct Cancellation<'a> {
+    /// Token cancelling the run from within the process
+    token: &'a CancelToken,
+    /// Claim on the tree, lost to a newer invocation
+    claim: Claim,
+    /// Set when a superseded build finished within the debounce, letting
+    /// the rest of the run complete
+    spared: AtomicBool,
+}
+
+impl<'a> Cancellation<'a> {
+    /// Claim the tree of `config` for a run cancelled by `token`, first
+    /// waiting for a previous invocation building it to stop
+    pub(crate) fn start(project_dir: &Path, config: &Config, token: &'a CancelToken) -> Self {
+        let claim = Claim::take(claim_path(project_dir, config));
+        if let Some(pid) = claim.previous {
+            if !matches!(config.output_level(), OutputLevel::Quiet) {
+                println!("🛑 Superseding the running build (pid {})", pid);
+            }
+            if !wait_for_exit(pid, EXIT_TIMEOUT) {
+                eprintln!(
+                    "   ⚠ Warning: The superseded build (pid {}) is still running",
+                    pid
+                );
+            }
+        }
+        Self {
+            token,
+            claim,
+            spared: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the run was cancelled and has to stop
+    fn cancelled(&self) -> bool {
+        !self.spared.load(Ordering::SeqCst) && (self.token.is_cancelled() || self.claim.lost())
+    }
+
+    /// Fail with [`Superseded`] once the run was cancelled
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.cancelled() {
+            return Err(Superseded.into());
+        }
+        Ok(())
+    }
+
+    /// Run the remote build, stopping its process group when the run is
+    /// cancelled and the build doesn't finish within [`DEBOUNCE`]
+    pub(crate) fn during_build(
+        &self,
+        config: &Config,
+        build: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        self.watch_build(DEBOUNCE, build, || {
+            if let Err(e) = run_ssh_command(config, &stop_script(config)) {
+                eprintln!("   ⚠ Warning: Could not stop the superseded build: {}", e);
+            }
+        })
+    }
+
+    /// Run `build`, calling `stop` once the run was cancelled for longer than
+    /// `debounce` while it is still running
+    fn watch_build(
+        &self,
+        debounce: Duration,
+        build: impl FnOnce() -> Result<()>,
+        stop: impl FnOnce() + Send,
+    ) -> Result<()> {
+        let done = AtomicBool::new(false);
+        let stopped = AtomicBool::new(false);
+        let result = thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut since = None;
+                while !done.load(Ordering::SeqCst) {
+                    thread::sleep(POLL / 4);
+                    if !self.cancelled() {
+                        continue;
+                    }
+                    let since = *since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= debounce && !done.load(Ordering::SeqCst) {
+                        stopped.store(true, Ordering::SeqCst);
+                        stop();
+                        return;
+                    }
+                }
+            });
+            let result = build();
+            done.store(true, Ordering::SeqCst);
+            result
+        });
+
+        if stopped.load(Ordering::SeqCst) {
+            return Err(Superseded.into());
+        }
+        if self.cancelled() {
+            // Close enough to done that its artifacts are still wanted
+            self.spared.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+/// Claim of one invocation on a tree, released when dropped
+struct Claim {
+    /// Claim file in the state directory
+    path: PathBuf,
+    /// `<pid>-<n>`, unique between the claims of all invocations
+    id: String,
+    /// Pid of a previous invocation still holding the claim
+    previous: Option<u32>,
+}
+
+impl Claim {
+    /// Take over the claim at `path`
+    fn take(path: PathBuf) -> Self {
+        let pid = std::process::id();
+        let id = format!("{}-{}", pid, CLAIMS.fetch_add(1, Ordering::SeqCst));
+        let previous = fs::read_to_string(&path)
+            .ok()
+            .and_then(|holder| holder.split('-').next()?.trim().parse().ok())
+            .filter(|&holder| holder != pid && alive(holder));
+
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        // Without the file the run can't be superseded, but still builds
+        if let Err(e) = fs::write(&path, &id) {
+            eprintln!("   ⚠ Warning: Could not claim the build tree: {}", e);
+        }
+        Self { path, id, previous }
+    }
+
+    /// Whether a newer invocation took the claim over
+    fn lost(&self) -> bool {
+        fs::read_to_string(&self.path).is_ok_and(|holder| holder != self.id)
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if !self.lost() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Remote command prefix recording the build's process group along with
+/// this process, for a superseded run to stop it
+pub(crate) fn record_prefix() -> String {
+    format!(
+        "mkdir -p .remotebuild && echo {} $(ps -o pgid= -p $$) > {} && ",
+        std::process::id(),
+        PGID_FILE
+    )
+}
+
+/// Remote script sending TERM to the build's process group, if this process
+/// recorded it
+fn stop_script(config: &Config) -> String {
+    format!(
+        "cd {} && read -r pid pgid < {} && [ \"$pid\" = {} ] && rm -f {} && kill -TERM -$pgid",
+        config.remote_dir().shell(),
+        PGID_FILE,
+        std::process::id(),
+        PGID_FILE
+    )
+}
+
+/// Location of the claim file of the tree `config` builds in
+fn claim_path(project_dir: &Path, config: &Config) -> PathBuf {
+    let tree = format!("{}\n{}", config.host, config.remote_path);
+    project_state_path(
+        project_dir,
+        &format!("{:016x}.claim", stable_hash(tree.as_bytes())),
+    )
+}
+
+/// Wait up to `timeout` for process `pid` to exit, returning whether it did
+fn wait_for_exit(pid: u32, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while alive(pid) {
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(POLL);
+    }
+    true
+}
+
+/// Whether a local process with the given pid is running
+fn alive(pid: u32) -> bool {
+    Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A claim file in the temp directory, removed before the test
+    fn temp_claim(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "remotebuild-test-{}-{}.claim",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// A run that lost its claim fails its next check, and only the newest
+    /// claim is removed on release
+    #[test]
+    fn newer_claim_supersedes() {
+        let path = temp_claim("supersede");
+        let token = CancelToken::default();
+        let old = Cancellation {
+            token: &token,
+            claim: Claim::take(path.clone()),
+            spared: AtomicBool::new(false),
+        };
+        assert!(old.check().is_ok());
+        // This process is the holder, so nothing is waited for
+        let new = Claim::take(path.clone());
+        assert_eq!(new.previous, None);
+        let error = old.check().unwrap_err();
+        assert!(error.downcast_ref::<Superseded>().is_some());
+
+        drop(old);
+        assert_eq!(fs::read_to_string(&path).unwrap(), new.id);
+        drop(new);
+        assert!(!path.exists());
+    }
+
+    /// The token cancels a run that still holds its claim
+    #[test]
+    fn token_cancels() {
+        let token = CancelToken::default();
+        let run = Cancellation {
+            token: &token,
+            claim: Claim::take(temp_claim("token")),
+            spared: AtomicBool::new(false),
+        };
+        assert!(run.check().is_ok());
+        token.cancel();
+        assert!(run.check().is_err());
+    }
+
+    /// A build outlasting the debounce is stopped and the run superseded
+    #[test]
+    fn slow_build_is_stopped() {
+        let token = CancelToken::default();
+        let run = Cancellation {
+            token: &token,
+            claim: Claim::take(temp_claim("slow-build")),
+            spared: AtomicBool::new(false),
+        };
+        let stopped = AtomicBool::new(false);
+        let result = run.watch_build(
+            Duration::from_millis(100),
+            || {
+                token.cancel();
+                while !stopped.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(anyhow::anyhow!("killed"))
+            },
+            || stopped.store(true, Ordering::SeqCst),
+        );
+        assert!(result.unwrap_err().downcast_ref::<Superseded>().is_some());
+        assert!(run.check().is_err());
+    }
+
+    /// A build finishing within the debounce completes the run
+    #[test]
+    fn build_finishing_in_debounce_is_spared() {
+        let token = CancelToken::default();
+        let run = Cancellation {
+            token: &token,
+            claim: Claim::take(temp_claim("spared-build")),
+            spared: AtomicBool::new(false),
+        };
+        let result = run.watch_build(
+            Duration::from_secs(5),
+            || {
+                token.cancel();
+                thread::sleep(Duration::from_millis(200));
+                Ok(())
+            },
+            || panic!("stopped a build within the debounce"),
+        );
+        assert!(result.is_ok());
+        assert!(run.check().is_ok());
+    }
+
+    /// The prefix and stop script stop the recorded process group
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn stop_script_terminates_the_group() {
+        let dir =
+            std::env::temp_dir().join(format!("remotebuild-test-pgid-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config: Config =
+            serde_yaml::from_str(&format!("host: build-box\nremote_path: {}", dir.display()))
+                .unwrap();
+
+        // setsid gives the build its own group, as sshd does
+        let mut build = Command::new("setsid")
+            .arg("sh")
+            .arg("-c")
+            .arg(format!("{}sleep 30 & wait", record_prefix()))
+            .current_dir(&dir)
+            .spawn()
+            .unwrap();
+        while !fs::read_to_string(dir.join(PGID_FILE)).is_ok_and(|r| r.ends_with('\n')) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(stop_script(&config))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!build.wait().unwrap().success());
+        assert!(!dir.join(PGID_FILE).exists());
+
+        // A group recorded by another process is left alone
+        fs::create_dir_all(dir.join(".remotebuild")).unwrap();
+        fs::write(dir.join(PGID_FILE), "1 1\n").unwrap();
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(stop_script(&config))
+            .status()
+            .unwrap();
+        assert!(!status.success());
+        assert!(dir.join(PGID_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! Generated files copied back after the build
+//!
+//! `sync_back` lists files the build generates that aren't artifacts but
+//! that local tools need, such as `compile_commands.json` for clangd. They
+//! are paths relative to the project, copied back to the same place in the
+//! local project after the build (also with `--no-artifacts`,
+//! `--artifacts-only` and `remotebuild fetch`) with one rsync. They are left
+//! out of the sync, so a local copy never overwrites the one the build
+//! wrote, and a clean sync doesn't delete them remotely. Files the build
+//! didn't write are skipped with a warning.
+//!
+//! Over the tar transport every file found is copied, and counts as changed.
+//!
+//! With `rewrite_paths: true` the copies that changed get the remote project
+//! directory replaced with the local one, a `compile_commands.json` field by
+//! field and other files like artifacts.
+
+use anyhow::{anyhow, Context, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use crate::rewrite::Rewriter;
+use crate::{
+    compression, dir_contents, indented_tail, itemize, rsync_command, ssh_control_path_arg,
+    transport, Config, OutputLevel,
+};
+
+/// File name rewritten as a compilation database
+const COMPILE_COMMANDS: &str = "compile_commands.json";
+
+/// Check the `sync_back` paths when the config is loaded
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    for path in &config.sync_back {
+        let escapes = path.split('/').any(|component| component == "..");
+        if path.is_empty() || path.starts_with(['/', '~']) || escapes {
+            return Err(anyhow!(
+                "Invalid sync_back entry: {} (expected a path inside the project)",
+                path
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Anchored exclude patterns keeping the `sync_back` files out of the sync
+pub(crate) fn excludes(config: &Config) -> impl Iterator<Item = String> + '_ {
+    config
+        .sync_back
+        .iter()
+        .map(|path| format!("/{}", path.trim_end_matches('/')))
+}
+
+/// Copy the `sync_back` files into the project, rewriting paths in the
+/// changed ones with `rewrite_paths`
+///
+/// Problems are warnings, since the build itself worked.
+pub(crate) fn fetch(config: &Config, project_dir: &Path) -> Result<()> {
+    if config.sync_back.is_empty() {
+        return Ok(());
+    }
+    let output = config.output_level();
+    if config.dry_run {
+        for path in &config.sync_back {
+            println!("   Would copy back {}", path);
+        }
+        return Ok(());
+    }
+
+    let changed = if transport::uses_tar(config) {
+        copy_with_tar(config, project_dir)?
+    } else {
+        copy_with_rsync(config, project_dir)?
+    };
+    if matches!(output, OutputLevel::Verbose) {
+        for path in &changed {
+            println!("   ✓ Copied back: {}", path);
+        }
+    }
+
+    if !config.rewrite_paths || changed.is_empty() {
+        return Ok(());
+    }
+    let rewriter = match Rewriter::new(config, project_dir) {
+        Ok(rewriter) => rewriter,
+        Err(e) => {
+            eprintln!(
+                "   ⚠ Warning: Paths in sync_back files won't be rewritten: {:#}",
+                e
+            );
+            return Ok(());
+        }
+    };
+    let verbose = matches!(output, OutputLevel::Verbose);
+    for path in &changed {
+        let local = project_dir.join(path);
+        if local
+            .file_name()
+            .map_or(true, |name| name != COMPILE_COMMANDS)
+        {
+            rewriter.rewrite_files(&[local], path, verbose);
+            continue;
+        }
+        match rewriter.rewrite_compile_commands(&local) {
+            Ok(count) if verbose && count > 0 => {
+                println!("   ✓ Rewrote {} paths in {}", count, local.display())
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!(
+                "   ⚠ Warning: Could not rewrite paths in {}: {:#}",
+                local.display(),
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Copy the files back with one rsync, returning the files that changed
+fn copy_with_rsync(config: &Config, project_dir: &Path) -> Result<Vec<String>> {
+    let temp_dir = dirs::cache_dir().unwrap_or_else(env::temp_dir);
+    let list = temp_dir.join(format!("remotebuild_sync_back_{}", std::process::id()));
+    fs::write(&list, config.sync_back.join("\n"))
+        .with_context(|| format!("Failed to write {}", list.display()))?;
+    let mut files_from = OsString::from("--files-from=");
+    files_from.push(&list);
+
+    let mut rsync_cmd = rsync_command(config);
+    rsync_cmd
+        .arg("-a")
+        .arg("--itemize-changes")
+        .args(config.artifact_compare_arg())
+        .args(compression::current(config).rsync_args())
+        .arg("-e")
+        .arg(ssh_control_path_arg(config))
+        .arg(files_from)
+        // --files-from keeps -a from recursing into listed directories
+        .arg("-r")
+        .arg(config.remote_dir().rsync(&config.host, ""))
+        .arg(dir_contents(project_dir));
+    let result = rsync_cmd.output();
+    let _ = fs::remove_file(&list);
+    let rsync = result.context("Failed to run rsync for sync_back")?;
+
+    let stderr = String::from_utf8_lossy(&rsync.stderr);
+    if !rsync.status.success() {
+        eprintln!(
+            "   ⚠ Warning: Could not copy back every sync_back file{}",
+            indented_tail(&stderr)
+        );
+    }
+    Ok(
+        itemize::Changes::parse(&String::from_utf8_lossy(&rsync.stdout))
+            .transferred
+            .into_iter()
+            .filter(|path| !path.ends_with('/'))
+            .collect(),
+    )
+}
+
+/// Copy the files back with one tar stream, returning the files copied
+///
+/// tar copies every file it finds, so all of them count as changed.
+fn copy_with_tar(config: &Config, project_dir: &Path) -> Result<Vec<String>> {
+    let members: Vec<_> = config
+        .sync_back
+        .iter()
+        .map(|path| escape(Cow::Borrowed(path.trim_end_matches('/'))))
+        .collect();
+    let cd = format!("cd {}", config.remote_dir().shell());
+    let tar = transport::download(config, &cd, &members.join(" "), project_dir)
+        .output()
+        .context("Failed to run tar for sync_back")?;
+    // Remote tar exits 0 after skipping missing files on some systems, so
+    // any complaint counts
+    let stderr = String::from_utf8_lossy(&tar.stderr);
+    if !tar.status.success() || !stderr.trim().is_empty() {
+        eprintln!(
+            "   ⚠ Warning: Could not copy back every sync_back file{}",
+            indented_tail(&stderr)
+        );
+    }
+    let mut copied = Vec::new();
+    for path in &config.sync_back {
+        let path = path.trim_end_matches('/');
+        if project_dir.join(path).is_dir() {
+            collect_files(&project_dir.join(path), path, &mut copied);
+        } else if project_dir.join(path).exists() {
+            copied.push(path.to_string());
+        }
+    }
+    Ok(copied)
+}
+
+/// Recursively collect the files below a copied directory
+fn collect_files(dir: &Path, prefix: &str, files: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let rel = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            collect_files(&entry.path(), &rel, files);
+        } else {
+            files.push(rel);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Whether a sync deletes remote files
+//!
+//! `sync_delete` picks what rsync may delete on the remote:
+//!
+//! - `always` (the default) deletes remote files that no longer exist
+//!   locally, so the remote tree matches the project.
+//! - `never` only adds and updates files, keeping remote-only ones such as a
+//!   `node_modules` installed on the remote. `--no-delete` selects it for one
+//!   run.
+//! - `excluded-too` also deletes remote files matched by `exclude_patterns`,
+//!   like `--clean-sync` without the listing and prompt. The built-in,
+//!   detected and `.gitignore` excludes stay protected, as they cover build
+//!   output.
+//!
+//! rsync only deletes inside the directories it transfers, and a git file
+//! list passed with `--files-from` names files only. Git-aware syncs
+//! therefore remember the list they sent to each host and remote path, and
+//! list the paths that have disappeared locally since then with
+//! `--delete-missing-args`, as manifest syncs do. Where rsync is too old for
+//! that, the paths are left out of the list and removed with `rm` over ssh
+//! after the transfer. Excluded files that still exist locally are only
+//! deleted by syncs of the whole tree, such as `--force-full-sync`.
+//!
+//! Files deleted without `git rm` are still listed by git, but never sent as
+//! is: they are deleted remotely like the others, or under `never` skipped.
+
+use anyhow::{anyhow, Context, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::state::project_state_path;
+use crate::{run_ssh_command, stable_hash, Config};
+
+/// Extension of the last git file lists in the state directory, after a
+/// hash of the destination
+const LIST_EXTENSION: &str = "files.json";
+
+/// Paths removed by one `rm` command
+const RM_BATCH: usize = 200;
+
+/// What a sync deletes on the remote
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DeleteMode {
+    /// Remote files that no longer exist locally
+    Always,
+    /// Nothing
+    Never,
+    /// Remote files that no longer exist locally or are excluded
+    ExcludedToo,
+}
+
+/// Default `sync_delete`
+pub(crate) fn default_mode() -> String {
+    "always".to_string()
+}
+
+impl DeleteMode {
+    /// Parse a configured `sync_delete`
+    pub(crate) fn parse(name: &str) -> Result<Self> {
+        match name {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            "excluded-too" => Ok(Self::ExcludedToo),
+            other => Err(anyhow!(
+                "Invalid sync_delete: {} (expected always, never or excluded-too)",
+                other
+            )),
+        }
+    }
+
+    /// Whether the sync deletes anything
+    pub(crate) fn deletes(self) -> bool {
+        self != Self::Never
+    }
+
+    /// Description for verbose output
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            Self::Always => "always (files missing locally, --delete)",
+            Self::Never => "never (remote-only files are kept)",
+            Self::ExcludedToo => {
+                "excluded-too (files missing locally or excluded, --delete-excluded)"
+            }
+        }
+    }
+}
+
+/// The paths of the last git file list sent to the config's destination
+/// that are gone locally and missing from `files`, the list about to be sent
+pub(crate) fn missing(project_dir: &Path, config: &Config, files: &[String]) -> Vec<String> {
+    let gone = |path: &String| fs::symlink_metadata(project_dir.join(path)).is_err();
+    let previous: Vec<String> = fs::read_to_string(list_path(project_dir, config))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+    let listed: HashSet<&str> = files.iter().map(String::as_str).collect();
+    previous
+        .into_iter()
+        .filter(|path| !listed.contains(path.as_str()) && gone(path))
+        .collect()
+}
+
+/// Remove the paths gone locally from `files`, returning them
+pub(crate) fn take_gone(project_dir: &Path, files: &mut Vec<String>) -> Vec<String> {
+    let (gone, present) = files
+        .drain(..)
+        .partition(|path| fs::symlink_metadata(project_dir.join(path)).is_err());
+    *files = present;
+    gone
+}
+
+/// Delete `paths`, relative to the remote path, with `rm` over ssh
+pub(crate) fn remove_remote(config: &Config, paths: &[String]) -> Result<()> {
+    let dir = config.remote_dir();
+    for batch in paths.chunks(RM_BATCH) {
+        let quoted: Vec<Cow<str>> = batch
+            .iter()
+            .map(|path| escape(Cow::Borrowed(path.as_str())))
+            .collect();
+        run_ssh_command(
+            config,
+            &format!("cd {} && rm -f -- {}", dir.shell(), quoted.join(" ")),
+        )
+        .context("Failed to delete files missing locally from the remote")?;
+    }
+    Ok(())
+}
+
+/// Location of the last git file list sent to the config's destination
+fn list_path(project_dir: &Path, config: &Config) -> PathBuf {
+    let destination = format!("{}:{}", config.host, config.remote_path);
+    project_state_path(
+        project_dir,
+        &format!(
+            "{:016x}.{}",
+            stable_hash(destination.as_bytes()),
+            LIST_EXTENSION
+        ),
+    )
+}
+
+/// Remember the git file list sent by a successful sync
+pub(crate) fn save_list(project_dir: &Path, config: &Config, files: &[String]) -> Result<()> {
+    let path = list_path(project_dir, config);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create state dir: {}", parent.display()))?;
+    }
+    fs::write(&path, serde_json::to_string(files)?)
+        .with_context(|| format!("Failed to write file list: {}", path.display()))
+}
@@ -0,0 +1,293 @@
+//! Named build targets for cross-compilation
+//!
+//! A config can define several `targets`, each with its own environment,
+//! build command, artifacts and remote build directory. `remotebuild NAME` or
+//! `--target NAME` selects one or more of them, and `default_target` one for
+//! runs naming none; the project is synced once and the targets are built one
+//! after another. Every target builds into its own directory (by default
+//! `build/<name>`), so incremental state is never shared between toolchains.
+//! A target with a `remote_path_suffix` gets a synced tree of its own instead.
+//!
+//! The placeholders `{target}` and `{build_dir}` are expanded in target
+//! commands, arguments, environment values, artifact patterns and the local
+//! artifact directory.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::artifact::Artifact;
+use crate::{container, estimate, history, nix, setup};
+use crate::{
+    env_exports, run_remote_build_command, sync_artifacts, sync_to_remote, BuildCommand,
+    BuildFailed, Config, SyncScope,
+};
+
+/// Build directory used when a target doesn't set one
+const DEFAULT_BUILD_DIR: &str = "build/{target}";
+
+/// Configuration of one named target
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct TargetConfig {
+    /// Environment variables exported before the build command
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+
+    /// Build command replacing the top-level `build_command`
+    #[serde(default)]
+    build_command: Option<BuildCommand>,
+
+    /// Arguments appended (shell-escaped) to the build command
+    #[serde(default)]
+    extra_args: Vec<String>,
+
+    /// Artifact patterns replacing the top-level `artifacts`
+    #[serde(default)]
+    artifacts: Option<Vec<Artifact>>,
+
+    /// Remote build directory relative to `remote_path`
+    /// (default: `build/{target}`)
+    #[serde(default)]
+    build_dir: Option<String>,
+
+    /// Local directory, relative to the project, that artifacts are copied
+    /// into (default: the project directory)
+    #[serde(default)]
+    artifact_dir: Option<String>,
+
+    /// Appended to `remote_path` for a remote tree of the target's own
+    #[serde(default)]
+    remote_path_suffix: Option<String>,
+}
+
+/// Result of building a single target
+enum Outcome {
+    /// Built successfully in the given time
+    Built(Duration),
+    /// The build or artifact download failed
+    Failed(Duration),
+}
+
+/// Sync the project once per remote tree, then build each selected target in
+/// order
+pub(crate) fn run_target_builds(
+    project_dir: &Path,
+    config: &Config,
+    names: &[String],
+    scope: SyncScope,
+) -> Result<()> {
+    for name in names {
+        if !config.targets.contains_key(name) {
+            let known: Vec<&str> = config.targets.keys().map(String::as_str).collect();
+            return Err(anyhow!(
+                "Unknown target: {} (configured targets: {})",
+                name,
+                if known.is_empty() {
+                    "none".to_string()
+                } else {
+                    known.join(", ")
+                }
+            ));
+        }
+    }
+
+    // Keep every target's build directory out of the sync, not just the
+    // selected ones, so `--delete` never wipes another target's state
+    let mut sync_config = config.clone();
+    for (name, target) in &config.targets {
+        sync_config
+            .exclude_patterns
+            .push(format!("/{}/", build_dir(name, target)));
+    }
+    estimate::load(project_dir);
+    container::prepare(config)?;
+    nix::check_installed(config)?;
+
+    let mut synced: Vec<String> = Vec::new();
+    let mut outcomes = Vec::with_capacity(names.len());
+    for name in names {
+        // Targets sharing a remote tree share its sync
+        let remote_path = remote_path(config, name);
+        if !synced.contains(&remote_path) {
+            let tree_config = Config {
+                remote_path: remote_path.clone(),
+                ..sync_config.clone()
+            };
+            sync_to_remote(project_dir, &tree_config, scope)?;
+            setup::run_setup(&tree_config)?;
+            nix::enter_shell(&tree_config)?;
+            synced.push(remote_path);
+        }
+
+        println!("\x1b[1m── target {} ──\x1b[0m", name);
+        let start = Instant::now();
+        let tags = history::RunTags::new(scope);
+        let result = build_target(project_dir, config, name, tags, start);
+        match result {
+            Ok(()) => outcomes.push(Outcome::Built(start.elapsed())),
+            Err(e) => {
+                eprintln!("   ✗ {}: {}", name, e);
+                outcomes.push(Outcome::Failed(start.elapsed()));
+            }
+        }
+        println!();
+    }
+
+    print_summary(names, &outcomes);
+
+    let failed: Vec<&str> = names
+        .iter()
+        .zip(&outcomes)
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Failed(_)))
+        .map(|(name, _)| name.as_str())
+        .collect();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} of {} targets failed: {}",
+            failed.len(),
+            names.len(),
+            failed.join(", ")
+        ))
+    }
+}
+
+/// Build one target and download its artifacts
+fn build_target(
+    project_dir: &Path,
+    config: &Config,
+    name: &str,
+    tags: history::RunTags,
+    start: Instant,
+) -> Result<()> {
+    let target = config.targets.get(name).cloned().unwrap_or_default();
+    let target_config = target_config(config, name, &target)?;
+
+    let build = run_remote_build_command(&target_config);
+    let exit_code = match &build {
+        Ok(()) => Some(0),
+        Err(e) => e
+            .downcast_ref::<BuildFailed>()
+            .and_then(|b| b.status.code()),
+    };
+    history::record_build(
+        project_dir,
+        &target_config,
+        exit_code,
+        start.elapsed(),
+        tags,
+    );
+    build?;
+
+    let dir = build_dir(name, &target);
+    let local_dir = match &target.artifact_dir {
+        Some(artifact_dir) => project_dir.join(expand(artifact_dir, name, &dir)),
+        None => target_config.artifact_dir(project_dir),
+    };
+    fs::create_dir_all(&local_dir)
+        .with_context(|| format!("Failed to create artifact dir: {}", local_dir.display()))?;
+
+    sync_artifacts(&target_config, project_dir, &local_dir)
+}
+
+/// Derive the config used to build `name`, with the target's command,
+/// environment and artifacts applied
+fn target_config(config: &Config, name: &str, target: &TargetConfig) -> Result<Config> {
+    let dir = build_dir(name, target);
+
+    let base_command = target
+        .build_command
+        .as_ref()
+        .unwrap_or(&config.build_command);
+    if base_command.is_empty() {
+        return Err(anyhow!(
+            "Target {} has no build_command and none is configured at the top level",
+            name
+        ));
+    }
+
+    // Exported after the top-level `env`, so target variables override it
+    let mut env: BTreeMap<String, String> = target
+        .env
+        .iter()
+        .map(|(key, value)| (key.clone(), expand(value, name, &dir)))
+        .collect();
+    env.insert("REMOTEBUILD_TARGET".to_string(), name.to_string());
+    env.insert("REMOTEBUILD_BUILD_DIR".to_string(), dir.clone());
+    let exports = env_exports(&env).map_err(|e| anyhow!("Target {}: {}", name, e))?;
+
+    let mut command = base_command
+        .map(|step| expand(step, name, &dir))
+        .prefixed(&format!(
+            "mkdir -p {} && {}",
+            escape(Cow::Borrowed(dir.as_str())),
+            exports
+        ));
+    for arg in &target.extra_args {
+        command.append(&format!(" {}", escape(Cow::Owned(expand(arg, name, &dir)))));
+    }
+
+    let artifacts = target
+        .artifacts
+        .as_ref()
+        .unwrap_or(&config.artifacts)
+        .iter()
+        .map(|artifact| artifact.map(|pattern| expand(pattern, name, &dir)))
+        .collect();
+
+    Ok(Config {
+        build_command: command,
+        artifacts,
+        remote_path: remote_path(config, name),
+        ..config.clone()
+    })
+}
+
+/// Remote tree a target is built in: `remote_path`, with the target's
+/// `remote_path_suffix` if it has one
+fn remote_path(config: &Config, name: &str) -> String {
+    let suffix = config
+        .targets
+        .get(name)
+        .and_then(|target| target.remote_path_suffix.as_deref());
+    match suffix {
+        Some(suffix) => format!("{}{}", config.remote_path, suffix.replace("{target}", name)),
+        None => config.remote_path.clone(),
+    }
+}
+
+/// Remote build directory of a target, relative to `remote_path`
+fn build_dir(name: &str, target: &TargetConfig) -> String {
+    let dir = target.build_dir.as_deref().unwrap_or(DEFAULT_BUILD_DIR);
+    dir.replace("{target}", name).trim_matches('/').to_string()
+}
+
+/// Expand the `{target}` and `{build_dir}` placeholders
+fn expand(template: &str, name: &str, build_dir: &str) -> String {
+    template
+        .replace("{target}", name)
+        .replace("{build_dir}", build_dir)
+}
+
+/// Print the per-target summary table
+fn print_summary(names: &[String], outcomes: &[Outcome]) {
+    let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0);
+
+    println!("📋 Target summary");
+    for (name, outcome) in names.iter().zip(outcomes) {
+        let (mark, detail) = match outcome {
+            Outcome::Built(duration) => ("✓", format!("built in {:.1}s", duration.as_secs_f64())),
+            Outcome::Failed(duration) => {
+                ("✗", format!("failed after {:.1}s", duration.as_secs_f64()))
+            }
+        };
+        println!("   {} {:<width$}  {}", mark, name, detail, width = width);
+    }
+}
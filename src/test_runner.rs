@@ -0,0 +1,976 @@
+//! Remote test runs with a summary of the results
+//!
+//! `remotebuild test` syncs the project and runs `test.command` like a build,
+//! streaming its output. Every line is also read by parsers for the output of
+//! `cargo test`, `ctest`, `pytest` and GoogleTest, or by the patterns of a
+//! `custom` format, which count passed, failed and skipped tests and collect
+//! the names of failures. The run ends with a summary listing the failed
+//! tests, the `test.reports` files (e.g. JUnit XML) are downloaded, and the
+//! exit code is non-zero when the command failed or any test did.
+//!
+//! With `format: auto` every built-in parser reads the output and the one
+//! recognizing the most tests is used.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::io::{BufRead, BufReader, IsTerminal, Read};
+use std::path::Path;
+use std::process::Stdio;
+use std::thread;
+
+use crate::artifact::Artifact;
+use crate::{
+    clear_status, print_status, remote_build_command, remote_command, setup, sync_artifacts,
+    sync_to_remote, Config, SyncScope,
+};
+
+/// Built-in formats tried by `auto`, in order of preference on ties
+const BUILT_IN: &[Format] = &[Format::Cargo, Format::Ctest, Format::Pytest, Format::Gtest];
+
+/// Test settings of a config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct TestConfig {
+    /// Command running the tests in the remote project directory
+    command: String,
+
+    /// Output format: `auto`, `cargo`, `ctest`, `pytest`, `gtest` or `custom`
+    #[serde(default = "default_format")]
+    format: String,
+
+    /// Report files downloaded after the run (artifact patterns)
+    #[serde(default)]
+    reports: Vec<String>,
+
+    /// Line patterns of the `custom` format
+    #[serde(default)]
+    patterns: TestPatterns,
+}
+
+/// Line patterns recognizing test results
+///
+/// A pattern matches a whole line. `*` matches any text and `{name}` matches
+/// the test name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct TestPatterns {
+    /// Lines reporting a passed test
+    #[serde(default)]
+    passed: Vec<String>,
+    /// Lines reporting a failed test
+    #[serde(default)]
+    failed: Vec<String>,
+    /// Lines reporting a skipped test
+    #[serde(default)]
+    skipped: Vec<String>,
+}
+
+/// Default output format
+fn default_format() -> String {
+    "auto".to_string()
+}
+
+/// Options for a remote test run
+pub(crate) struct TestOptions<'a> {
+    /// Extra arguments appended to the test command
+    pub(crate) args: &'a [String],
+    /// Which files the sync considers
+    pub(crate) scope: SyncScope,
+}
+
+/// A known test output format
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// `cargo test`
+    Cargo,
+    /// CTest
+    Ctest,
+    /// pytest
+    Pytest,
+    /// GoogleTest binaries
+    Gtest,
+    /// The configured line patterns
+    Custom,
+}
+
+impl Format {
+    /// Parse a configured format; `None` stands for `auto`
+    fn parse(name: &str) -> Result<Option<Self>> {
+        Ok(Some(match name {
+            "auto" => return Ok(None),
+            "cargo" => Self::Cargo,
+            "ctest" => Self::Ctest,
+            "pytest" => Self::Pytest,
+            "gtest" => Self::Gtest,
+            "custom" => Self::Custom,
+            other => {
+                return Err(anyhow!(
+                "Unknown test format: {} (expected auto, cargo, ctest, pytest, gtest or custom)",
+                other
+            ))
+            }
+        }))
+    }
+
+    /// Name for the summary
+    fn name(self) -> &'static str {
+        match self {
+            Self::Cargo => "cargo test",
+            Self::Ctest => "ctest",
+            Self::Pytest => "pytest",
+            Self::Gtest => "gtest",
+            Self::Custom => "custom",
+        }
+    }
+}
+
+/// Test results read from the output by one parser
+#[derive(Debug, Clone)]
+struct Tally {
+    /// Format being parsed
+    format: Format,
+    /// Counts from lines reporting single tests
+    counted: Counts,
+    /// Counts from summary lines, which take precedence when present
+    summary: Option<Counts>,
+    /// Names of failed tests, in the order they were reported
+    failures: Vec<String>,
+}
+
+/// Numbers of passed, failed and skipped tests
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Counts {
+    /// Passed tests
+    passed: u64,
+    /// Failed tests, including errors
+    failed: u64,
+    /// Skipped or ignored tests
+    skipped: u64,
+}
+
+impl Counts {
+    /// Number of tests with any result
+    fn total(&self) -> u64 {
+        self.passed + self.failed + self.skipped
+    }
+
+    /// Add another set of counts
+    fn add(&mut self, other: Counts) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+    }
+}
+
+/// Outcome of a single test reported on a line
+enum Outcome {
+    /// The test passed
+    Passed,
+    /// The test failed
+    Failed,
+    /// The test was skipped or ignored
+    Skipped,
+}
+
+impl Tally {
+    /// An empty tally for a format
+    fn new(format: Format) -> Self {
+        Self {
+            format,
+            counted: Counts::default(),
+            summary: None,
+            failures: Vec::new(),
+        }
+    }
+
+    /// The final counts
+    fn counts(&self) -> Counts {
+        self.summary.unwrap_or(self.counted)
+    }
+
+    /// Record a single test's result
+    fn test(&mut self, outcome: Outcome, name: &str) {
+        match outcome {
+            Outcome::Passed => self.counted.passed += 1,
+            Outcome::Skipped => self.counted.skipped += 1,
+            Outcome::Failed => {
+                self.counted.failed += 1;
+                self.failure(name);
+            }
+        }
+    }
+
+    /// Record the name of a failed test, once
+    fn failure(&mut self, name: &str) {
+        let name = name.trim();
+        if !name.is_empty() && !self.failures.iter().any(|f| f == name) {
+            self.failures.push(name.to_string());
+        }
+    }
+
+    /// Add counts from a summary line
+    fn summarize(&mut self, counts: Counts) {
+        self.summary.get_or_insert_with(Counts::default).add(counts);
+    }
+
+    /// Merge the results another reader of the same output collected
+    fn merge(&mut self, other: Tally) {
+        self.counted.add(other.counted);
+        if let Some(summary) = other.summary {
+            self.summarize(summary);
+        }
+        for name in &other.failures {
+            self.failure(name);
+        }
+    }
+
+    /// Read one line of output
+    fn line(&mut self, line: &str, patterns: &TestPatterns) {
+        match self.format {
+            Format::Cargo => self.cargo_line(line),
+            Format::Ctest => self.ctest_line(line),
+            Format::Pytest => self.pytest_line(line),
+            Format::Gtest => self.gtest_line(line),
+            Format::Custom => self.custom_line(line, patterns),
+        }
+    }
+
+    /// `test module::name ... ok` and `test result: ok. 3 passed; ...`
+    fn cargo_line(&mut self, line: &str) {
+        if let Some(rest) = line.strip_prefix("test result: ") {
+            self.summarize(Counts {
+                passed: count(rest, "passed").unwrap_or(0),
+                failed: count(rest, "failed").unwrap_or(0),
+                skipped: count(rest, "ignored").unwrap_or(0),
+            });
+            return;
+        }
+        let Some((name, result)) = line
+            .strip_prefix("test ")
+            .and_then(|rest| rest.split_once(" ... "))
+        else {
+            return;
+        };
+        match result.split([',', ' ']).next().unwrap_or_default() {
+            "ok" => self.test(Outcome::Passed, name),
+            "FAILED" => self.test(Outcome::Failed, name),
+            "ignored" => self.test(Outcome::Skipped, name),
+            _ => {}
+        }
+    }
+
+    /// `1/3 Test #1: name .....   Passed    0.01 sec` and
+    /// `67% tests passed, 1 tests failed out of 3`
+    fn ctest_line(&mut self, line: &str) {
+        if line.contains("% tests passed") {
+            if let (Some(failed), Some(total)) = (count(line, "tests"), last_number(line)) {
+                self.summarize(Counts {
+                    passed: total.saturating_sub(failed),
+                    failed,
+                    skipped: 0,
+                });
+            }
+            return;
+        }
+        // Numbers are right-aligned once there are ten tests: `Test  #1:`
+        let Some(rest) = line
+            .split_once(" Test ")
+            .and_then(|(_, rest)| rest.trim_start().strip_prefix('#'))
+        else {
+            return;
+        };
+        let Some((_, rest)) = rest.split_once(": ") else {
+            return;
+        };
+        let name = rest.split_whitespace().next().unwrap_or_default();
+        if rest.contains(" Passed") {
+            self.test(Outcome::Passed, name);
+        } else if rest.contains("Not Run") || rest.contains("Skipped") {
+            self.test(Outcome::Skipped, name);
+        } else if rest.contains("***") {
+            self.test(Outcome::Failed, name);
+        }
+    }
+
+    /// `path::name PASSED [ 50%]`, `FAILED path::name - reason` and
+    /// `==== 1 failed, 2 passed in 0.12s ====`
+    fn pytest_line(&mut self, line: &str) {
+        let trimmed = line.trim();
+        if trimmed.starts_with('=') && trimmed.ends_with('=') && trimmed.contains(" in ") {
+            let failed =
+                count(trimmed, "failed").unwrap_or(0) + count(trimmed, "error").unwrap_or(0);
+            let passed = count(trimmed, "passed").unwrap_or(0);
+            let skipped = count(trimmed, "skipped").unwrap_or(0);
+            if failed + passed + skipped > 0 {
+                self.summarize(Counts {
+                    passed,
+                    failed,
+                    skipped,
+                });
+            }
+            return;
+        }
+        // Short test summary lines name failures counted elsewhere
+        for prefix in ["FAILED ", "ERROR "] {
+            if let Some(rest) = trimmed.strip_prefix(prefix) {
+                if rest.contains("::") {
+                    self.failure(rest.split(" - ").next().unwrap_or(rest));
+                }
+                return;
+            }
+        }
+        let mut words = trimmed.split_whitespace();
+        let (Some(name), Some(result)) = (words.next(), words.next()) else {
+            return;
+        };
+        if !name.contains("::") {
+            return;
+        }
+        match result {
+            "PASSED" | "XPASS" => self.test(Outcome::Passed, name),
+            "FAILED" | "ERROR" => self.test(Outcome::Failed, name),
+            "SKIPPED" | "XFAIL" => self.test(Outcome::Skipped, name),
+            _ => {}
+        }
+    }
+
+    /// `[       OK ] Suite.Name (0 ms)` and `[  FAILED  ] Suite.Name (0 ms)`
+    fn gtest_line(&mut self, line: &str) {
+        let Some(rest) = line.trim_start().strip_prefix('[') else {
+            return;
+        };
+        let Some((tag, rest)) = rest.split_once(']') else {
+            return;
+        };
+        // Names repeated in the final list have no duration
+        let Some((name, _)) = rest.trim().split_once(" (") else {
+            if tag.trim() == "FAILED" && !rest.contains(" listed below") {
+                self.failure(rest.split(", where ").next().unwrap_or(rest));
+            }
+            return;
+        };
+        let name = name.split(", where ").next().unwrap_or(name);
+        match tag.trim() {
+            "OK" => self.test(Outcome::Passed, name),
+            "FAILED" => self.test(Outcome::Failed, name),
+            "SKIPPED" => self.test(Outcome::Skipped, name),
+            _ => {}
+        }
+    }
+
+    /// Lines matching the configured patterns
+    fn custom_line(&mut self, line: &str, patterns: &TestPatterns) {
+        let sets = [
+            (&patterns.failed, Outcome::Failed),
+            (&patterns.passed, Outcome::Passed),
+            (&patterns.skipped, Outcome::Skipped),
+        ];
+        for (set, outcome) in sets {
+            for pattern in set {
+                if let Some(name) = match_line(pattern, line) {
+                    let name = name.unwrap_or_else(|| line.trim().to_string());
+                    self.test(outcome, &name);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The number right before a word starting with `word`, e.g. `3` in
+/// `3 passed;`
+fn count(text: &str, word: &str) -> Option<u64> {
+    let words: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | ';' | '='))
+        .filter(|w| !w.is_empty())
+        .collect();
+    words
+        .windows(2)
+        .find(|pair| pair[1].starts_with(word) && pair[0].parse::<u64>().is_ok())
+        .and_then(|pair| pair[0].parse().ok())
+}
+
+/// The last number on a line
+fn last_number(text: &str) -> Option<u64> {
+    text.split_whitespace()
+        .rev()
+        .find_map(|word| word.parse().ok())
+}
+
+/// Match a whole line against a pattern, returning the `{name}` capture
+///
+/// `None` means no match; `Some(None)` a match of a pattern without `{name}`.
+fn match_line(pattern: &str, line: &str) -> Option<Option<String>> {
+    /// A piece of a compiled pattern
+    enum Piece {
+        /// A literal character
+        Char(char),
+        /// `*`: any text
+        Any,
+        /// `{name}`: the test name, not empty
+        Name,
+    }
+
+    /// Backtracking matcher over the pieces, recording the name's span
+    fn matches(
+        pieces: &[Piece],
+        text: &[char],
+        pos: usize,
+        name: &mut Option<(usize, usize)>,
+    ) -> bool {
+        match pieces.first() {
+            None => pos == text.len(),
+            Some(Piece::Char(c)) => {
+                text.get(pos) == Some(c) && matches(&pieces[1..], text, pos + 1, name)
+            }
+            Some(Piece::Any) => (pos..=text.len()).any(|i| matches(&pieces[1..], text, i, name)),
+            Some(Piece::Name) => (pos + 1..=text.len()).any(|end| {
+                let found = matches(&pieces[1..], text, end, name);
+                if found && name.is_none() {
+                    *name = Some((pos, end));
+                }
+                found
+            }),
+        }
+    }
+
+    let mut pieces = Vec::new();
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("{name}") {
+            pieces.push(Piece::Name);
+            rest = after;
+        } else {
+            pieces.push(if c == '*' { Piece::Any } else { Piece::Char(c) });
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    let text: Vec<char> = line.trim_end().chars().collect();
+    let mut name = None;
+    if !matches(&pieces, &text, 0, &mut name) {
+        return None;
+    }
+    Some(name.map(|(start, end)| text[start..end].iter().collect()))
+}
+
+/// Sync the project, run the tests remotely and print a summary, returning
+/// the exit code
+pub(crate) fn run_tests(project_dir: &Path, config: &Config, options: &TestOptions) -> Result<i32> {
+    let test = config
+        .test
+        .as_ref()
+        .ok_or_else(|| anyhow!("No test command configured: add a test section with a command"))?;
+    let format = Format::parse(&test.format)?;
+
+    sync_to_remote(project_dir, config, options.scope)?;
+    setup::run_setup(config)?;
+
+    let mut command = test.command.clone();
+    for arg in options.args {
+        command.push(' ');
+        command.push_str(&escape(Cow::Borrowed(arg.as_str())));
+    }
+    let test_config = Config {
+        build_command: command.into(),
+        artifacts: test.reports.iter().cloned().map(Artifact::from).collect(),
+        ..config.clone()
+    };
+
+    let output = config.output_level();
+    let mut spinner = print_status(output, "🧪 Running tests ");
+    let cmd = remote_build_command(&test_config)?;
+    let mut child = remote_command(config, &cmd)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run tests over SSH")?;
+    clear_status(output, &mut spinner);
+
+    let formats: Vec<Format> = match format {
+        Some(format) => vec![format],
+        None => BUILT_IN.to_vec(),
+    };
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let (mut tallies, stderr_tallies) = thread::scope(|s| {
+        let stderr_reader = s.spawn(|| read_output(stderr, &formats, &test.patterns, true));
+        let tallies = read_output(stdout, &formats, &test.patterns, false);
+        (tallies, stderr_reader.join().unwrap_or_default())
+    });
+    for (tally, other) in tallies.iter_mut().zip(stderr_tallies) {
+        tally.merge(other);
+    }
+    let status = child.wait().context("Failed to wait for the tests")?;
+
+    let best = best_tally(tallies);
+
+    println!();
+    let failed = match &best {
+        Some(tally) if tally.counts().total() > 0 => {
+            print_summary(tally);
+            tally.counts().failed > 0
+        }
+        _ => {
+            println!("🧪 No test results recognized in the output");
+            false
+        }
+    };
+
+    if !test.reports.is_empty() {
+        if let Err(e) = sync_artifacts(&test_config, project_dir, project_dir) {
+            eprintln!("   ⚠ Warning: Failed to download test reports: {:#}", e);
+        }
+    }
+
+    Ok(match status.code() {
+        Some(0) if failed => 1,
+        Some(code) => code,
+        None => 1,
+    })
+}
+
+/// The tally of the parser recognizing the most tests, the first of them on
+/// ties
+fn best_tally(tallies: Vec<Tally>) -> Option<Tally> {
+    tallies
+        .into_iter()
+        .rev()
+        .max_by_key(|tally| tally.counts().total())
+}
+
+/// Print each line of a stream while parsing it with every format
+fn read_output(
+    pipe: Option<impl Read>,
+    formats: &[Format],
+    patterns: &TestPatterns,
+    to_stderr: bool,
+) -> Vec<Tally> {
+    let mut tallies: Vec<Tally> = formats.iter().map(|f| Tally::new(*f)).collect();
+    let Some(pipe) = pipe else {
+        return tallies;
+    };
+
+    let mut reader = BufReader::new(pipe);
+    let mut line = Vec::new();
+    while let Ok(read) = reader.read_until(b'\n', &mut line) {
+        if read == 0 {
+            break;
+        }
+        let text = String::from_utf8_lossy(&line);
+        if to_stderr {
+            eprint!("{}", text);
+        } else {
+            print!("{}", text);
+        }
+        let text = strip_ansi(text.trim_end());
+        for tally in &mut tallies {
+            tally.line(&text, patterns);
+        }
+        line.clear();
+    }
+    tallies
+}
+
+/// Remove ANSI escape sequences, which colored test output is full of
+fn strip_ansi(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip to the final byte of a CSI sequence
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Print the counts and the failed tests
+fn print_summary(tally: &Tally) {
+    let color = std::io::stdout().is_terminal();
+    let paint = |code: &str, text: String| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text
+        }
+    };
+
+    let counts = tally.counts();
+    let mut parts = vec![paint("32", format!("{} passed", counts.passed))];
+    if counts.failed > 0 {
+        parts.push(paint("31", format!("{} failed", counts.failed)));
+    }
+    if counts.skipped > 0 {
+        parts.push(format!("{} skipped", counts.skipped));
+    }
+    let mark = if counts.failed > 0 { "❌" } else { "✅" };
+    println!(
+        "{} Tests: {} ({})",
+        mark,
+        parts.join(", "),
+        tally.format.name()
+    );
+    for name in &tally.failures {
+        println!("   {} {}", paint("31", "✗".to_string()), name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `cargo test` of a crate with an integration test and a failing unit test
+    const CARGO: &str = "\
+    Finished `test` profile [unoptimized + debuginfo] target(s) in 0.41s
+     Running tests/cli.rs (target/debug/deps/cli-3f1c2a9b8e4d7c6a)
+
+running 2 tests
+test runs_help ... ok
+test rejects_unknown_flag ... ok
+
+test result: ok. 2 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.02s
+
+     Running unittests src/lib.rs (target/debug/deps/fixcrate-bc7c4138e8a36545)
+
+running 4 tests
+test tests::adds ... ok
+test tests::parses ... ok
+test tests::rounds ... FAILED
+test tests::slow ... ignored, needs a network
+
+failures:
+
+---- tests::rounds stdout ----
+
+thread 'tests::rounds' panicked at src/lib.rs:5:27:
+assertion `left == right` failed
+  left: 1
+ right: 2
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+
+failures:
+    tests::rounds
+
+test result: FAILED. 2 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.01s
+
+error: test failed, to rerun pass `--lib`
+";
+
+    /// `ctest` with ten tests, so the numbers are right-aligned
+    const CTEST: &str = "\
+Test project /home/dev/project/build
+      Start  1: math_adds
+ 1/10 Test  #1: math_adds ........................   Passed    0.01 sec
+      Start  2: math_rounds
+ 2/10 Test  #2: math_rounds ......................   Passed    0.01 sec
+      Start  3: io_opens
+ 3/10 Test  #3: io_opens .........................   Passed    0.02 sec
+      Start  4: io_reads
+ 4/10 Test  #4: io_reads .........................***Failed    0.02 sec
+      Start  5: io_writes
+ 5/10 Test  #5: io_writes ........................   Passed    0.02 sec
+      Start  6: parse_json
+ 6/10 Test  #6: parse_json .......................   Passed    0.03 sec
+      Start  7: parse_yaml
+ 7/10 Test  #7: parse_yaml .......................   Passed    0.03 sec
+      Start  8: net_resolve
+ 8/10 Test  #8: net_resolve ......................   Passed    0.05 sec
+      Start  9: net_timeout
+ 9/10 Test  #9: net_timeout ......................***Timeout   1.50 sec
+      Start 10: net_slow
+10/10 Test #10: net_slow .........................***Not Run (Disabled)   0.00 sec
+
+78% tests passed, 2 tests failed out of 9
+
+Total Test time (real) =   1.70 sec
+
+The following tests did not run:
+\t 10 - net_slow (Disabled)
+
+The following tests FAILED:
+\t  4 - io_reads (Failed)
+\t  9 - net_timeout (Timeout)
+Errors while running CTest
+";
+
+    /// `pytest -v` with a failure, an error and a skip
+    const PYTEST: &str = "\
+============================= test session starts ==============================
+platform linux -- Python 3.11.4, pytest-7.4.0, pluggy-1.2.0 -- /usr/bin/python3
+cachedir: .pytest_cache
+rootdir: /home/dev/project
+collecting ... collected 5 items
+
+tests/test_math.py::test_adds PASSED                                     [ 20%]
+tests/test_math.py::test_rounds FAILED                                   [ 40%]
+tests/test_math.py::test_slow SKIPPED (needs network)                    [ 60%]
+tests/test_io.py::test_reads[utf-8] PASSED                               [ 80%]
+tests/test_io.py::test_writes ERROR                                      [100%]
+
+==================================== ERRORS ====================================
+________________________ ERROR at setup of test_writes _________________________
+
+    @pytest.fixture
+    def target():
+>       return open(\"/missing/out.txt\", \"w\")
+E       FileNotFoundError: [Errno 2] No such file or directory: '/missing/out.txt'
+
+tests/test_io.py:7: FileNotFoundError
+=================================== FAILURES ===================================
+_________________________________ test_rounds __________________________________
+
+    def test_rounds():
+>       assert round(2.5) == 3
+E       assert 2 == 3
+
+tests/test_math.py:9: AssertionError
+=========================== short test summary info ============================
+FAILED tests/test_math.py::test_rounds - assert 2 == 3
+ERROR tests/test_io.py::test_writes - FileNotFoundError: [Errno 2] No such fi...
+============== 1 failed, 2 passed, 1 skipped, 1 error in 0.12s ===============
+";
+
+    /// GoogleTest with a failing parameterized test and a skip
+    const GTEST: &str = "\
+[==========] Running 4 tests from 2 test suites.
+[----------] Global test environment set-up.
+[----------] 3 tests from MathTest
+[ RUN      ] MathTest.Adds
+[       OK ] MathTest.Adds (0 ms)
+[ RUN      ] MathTest.Rounds
+math_test.cc:12: Failure
+Expected equality of these values:
+  round(2.5)
+    Which is: 2
+  3
+[  FAILED  ] MathTest.Rounds (0 ms)
+[ RUN      ] MathTest.Slow
+math_test.cc:20: Skipped
+needs a network
+
+[  SKIPPED ] MathTest.Slow (0 ms)
+[----------] 3 tests from MathTest (0 ms total)
+
+[----------] 1 test from Sizes/ParseTest
+[ RUN      ] Sizes/ParseTest.Reads/0
+parse_test.cc:31: Failure
+Value of: Parse(GetParam())
+  Actual: false
+Expected: true
+[  FAILED  ] Sizes/ParseTest.Reads/0, where GetParam() = \"1k\" (1 ms)
+[----------] 1 test from Sizes/ParseTest (1 ms total)
+
+[----------] Global test environment tear-down
+[==========] 4 tests from 2 test suites ran. (1 ms total)
+[  PASSED  ] 1 test.
+[  SKIPPED ] 1 test, listed below:
+[  SKIPPED ] MathTest.Slow
+[  FAILED  ] 2 tests, listed below:
+[  FAILED  ] MathTest.Rounds
+[  FAILED  ] Sizes/ParseTest.Reads/0, where GetParam() = \"1k\"
+
+ 2 FAILED TESTS
+";
+
+    /// Automake-style output read with custom patterns
+    const CUSTOM: &str = "\
+PASS: test_adds
+FAIL: test_rounds (exit status: 1)
+SKIP: test_slow
+PASS: test_reads
+============================================================================
+# TOTAL: 4
+# PASS:  2
+# SKIP:  1
+# FAIL:  1
+============================================================================
+";
+
+    /// Counts of passed, failed and skipped tests
+    fn counts(passed: u64, failed: u64, skipped: u64) -> Counts {
+        Counts {
+            passed,
+            failed,
+            skipped,
+        }
+    }
+
+    /// Patterns reading the automake fixture
+    fn automake() -> TestPatterns {
+        TestPatterns {
+            passed: vec!["PASS: {name}".to_string()],
+            failed: vec!["FAIL: {name} (*)".to_string()],
+            skipped: vec!["SKIP: {name}".to_string()],
+        }
+    }
+
+    /// Parse output the way a run reads it, with every format given
+    fn parse(output: &str, formats: &[Format], patterns: &TestPatterns) -> Vec<Tally> {
+        read_output(Some(output.as_bytes()), formats, patterns, false)
+    }
+
+    /// Parse output with a single format
+    fn parse_as(format: Format, output: &str) -> Tally {
+        let mut tallies = parse(output, &[format], &automake());
+        tallies.remove(0)
+    }
+
+    /// The cargo summaries of every test binary add up
+    #[test]
+    fn cargo_output() {
+        let tally = parse_as(Format::Cargo, CARGO);
+        assert_eq!(tally.counted, counts(4, 1, 1));
+        assert_eq!(tally.counts(), counts(4, 1, 1));
+        assert_eq!(tally.failures, ["tests::rounds"]);
+    }
+
+    /// Cargo lines count without a summary, e.g. when the run was cut short
+    #[test]
+    fn cargo_output_without_summary() {
+        let output: String = CARGO
+            .lines()
+            .filter(|line| !line.starts_with("test result:"))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let tally = parse_as(Format::Cargo, &output);
+        assert_eq!(tally.summary, None);
+        assert_eq!(tally.counts(), counts(4, 1, 1));
+    }
+
+    /// The ctest summary counts disabled tests as not run at all
+    #[test]
+    fn ctest_output() {
+        let tally = parse_as(Format::Ctest, CTEST);
+        assert_eq!(tally.counted, counts(7, 2, 1));
+        assert_eq!(tally.counts(), counts(7, 2, 0));
+        assert_eq!(tally.failures, ["io_reads", "net_timeout"]);
+    }
+
+    /// Errors count as failures and the short summary names nothing twice
+    #[test]
+    fn pytest_output() {
+        let tally = parse_as(Format::Pytest, PYTEST);
+        assert_eq!(tally.counted, counts(2, 2, 1));
+        assert_eq!(tally.counts(), counts(2, 2, 1));
+        assert_eq!(
+            tally.failures,
+            [
+                "tests/test_math.py::test_rounds",
+                "tests/test_io.py::test_writes"
+            ]
+        );
+    }
+
+    /// Pytest failures only named in the short summary are collected
+    #[test]
+    fn pytest_output_without_verbose() {
+        let output = "\
+tests/test_math.py .F.                                                   [100%]
+=========================== short test summary info ============================
+FAILED tests/test_math.py::test_rounds - assert 2 == 3
+========================= 1 failed, 2 passed in 0.05s ==========================
+";
+        let tally = parse_as(Format::Pytest, output);
+        assert_eq!(tally.counts(), counts(2, 1, 0));
+        assert_eq!(tally.failures, ["tests/test_math.py::test_rounds"]);
+    }
+
+    /// The final GoogleTest list repeats failures without counting them again
+    #[test]
+    fn gtest_output() {
+        let tally = parse_as(Format::Gtest, GTEST);
+        assert_eq!(tally.counts(), counts(1, 2, 1));
+        assert_eq!(
+            tally.failures,
+            ["MathTest.Rounds", "Sizes/ParseTest.Reads/0"]
+        );
+    }
+
+    /// Custom patterns capture the name and ignore the totals
+    #[test]
+    fn custom_output() {
+        let tally = parse_as(Format::Custom, CUSTOM);
+        assert_eq!(tally.counts(), counts(2, 1, 1));
+        assert_eq!(tally.failures, ["test_rounds"]);
+    }
+
+    /// A pattern without `{name}` names the failure by the whole line
+    #[test]
+    fn custom_pattern_without_name() {
+        let patterns = TestPatterns {
+            failed: vec!["FAIL: *".to_string()],
+            ..TestPatterns::default()
+        };
+        let tally = parse(CUSTOM, &[Format::Custom], &patterns).remove(0);
+        assert_eq!(tally.counts(), counts(0, 1, 0));
+        assert_eq!(tally.failures, ["FAIL: test_rounds (exit status: 1)"]);
+    }
+
+    /// Colored output parses like plain output
+    #[test]
+    fn colored_output() {
+        let output = "\
+test tests::adds ... \x1b[32mok\x1b[0m
+test tests::rounds ... \x1b[31mFAILED\x1b[0m
+\x1b[1mtests/test_math.py::test_adds\x1b[0m \x1b[32mPASSED\x1b[0m [ 50%]
+\x1b[0;32m[       OK ] \x1b[mMathTest.Adds (0 ms)
+";
+        let tallies = parse(output, BUILT_IN, &TestPatterns::default());
+        let found: Vec<Counts> = tallies.iter().map(Tally::counts).collect();
+        assert_eq!(
+            found,
+            [
+                counts(1, 1, 0),
+                counts(0, 0, 0),
+                counts(1, 0, 0),
+                counts(1, 0, 0)
+            ]
+        );
+        assert_eq!(tallies[0].failures, ["tests::rounds"]);
+    }
+
+    /// `auto` picks the parser of the tool that wrote the output
+    #[test]
+    fn auto_picks_the_format() {
+        let fixtures = [
+            (CARGO, Format::Cargo),
+            (CTEST, Format::Ctest),
+            (PYTEST, Format::Pytest),
+            (GTEST, Format::Gtest),
+        ];
+        for (output, format) in fixtures {
+            let tallies = parse(output, BUILT_IN, &TestPatterns::default());
+            let best = best_tally(tallies).map(|tally| tally.format);
+            assert_eq!(best, Some(format));
+        }
+    }
+
+    /// Ties go to the first built-in format
+    #[test]
+    fn auto_prefers_the_first_on_ties() {
+        let output = "test tests::adds ... ok\n[       OK ] MathTest.Adds (0 ms)\n";
+        let tallies = parse(output, BUILT_IN, &TestPatterns::default());
+        let best = best_tally(tallies).map(|tally| tally.format);
+        assert_eq!(best, Some(Format::Cargo));
+    }
+
+    /// Stdout and stderr of the same run merge without naming failures twice
+    #[test]
+    fn merge_streams() {
+        let mut stdout = parse_as(Format::Cargo, "test a ... FAILED\ntest b ... ok\n");
+        let stderr = parse_as(Format::Cargo, "test a ... FAILED\n");
+        stdout.merge(stderr);
+        assert_eq!(stdout.counts(), counts(1, 2, 0));
+        assert_eq!(stdout.failures, ["a"]);
+    }
+}
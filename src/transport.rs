@@ -0,0 +1,306 @@
+//! Moving files with tar where the remote has no rsync
+//!
+//! `transport` picks how files travel:
+//!
+//! - `auto` (the default) uses rsync unless the remote has none, which is
+//!   probed with `command -v rsync` once per run and host.
+//! - `rsync` always uses rsync.
+//! - `tar` streams a gzipped tar over the SSH connection instead:
+//!   `tar -czf - <files> | ssh host 'tar -xzf -'` for the sync, and the
+//!   reverse for each artifact and the `sync_back` files.
+//!
+//! tar can't compare with the remote, so every sync sends all files: the
+//! git file list, or else every file outside the excludes. Nothing is
+//! deleted remotely except files gone from the git file list, which are
+//! removed with `rm`. `--clean-sync` needs rsync, a dry run only counts the
+//! files, and artifacts aren't downloaded during the build.
+
+use anyhow::{anyhow, Context, Result};
+use shell_escape::escape;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::artifact_glob::Expansion;
+use crate::patterns::ExcludeSet;
+use crate::{
+    indented_tail, permissions, remote_command, run_ssh_command_output, sync_excludes, Config,
+    OutputLevel, REMOTE_META_DIR,
+};
+
+/// Hosts probed in this run, and whether they lack rsync
+static MISSING_RSYNC: Mutex<BTreeMap<String, bool>> = Mutex::new(BTreeMap::new());
+
+/// Default `transport`
+pub(crate) fn default_transport() -> String {
+    "auto".to_string()
+}
+
+/// Check `transport` when the config is loaded
+pub(crate) fn validate(config: &Config) -> Result<()> {
+    match config.transport.as_str() {
+        "auto" | "rsync" | "tar" => Ok(()),
+        other => Err(anyhow!(
+            "Invalid transport: {} (expected auto, rsync or tar)",
+            other
+        )),
+    }
+}
+
+/// Whether files travel with tar, probing the remote under `auto`
+///
+/// Needs the SSH connection. A failed probe counts as rsync being there, so
+/// rsync reports the actual problem.
+pub(crate) fn uses_tar(config: &Config) -> bool {
+    match config.transport.as_str() {
+        "tar" => true,
+        "auto" => missing_rsync(config),
+        _ => false,
+    }
+}
+
+/// Whether the remote has no rsync, probed once per run and host
+fn missing_rsync(config: &Config) -> bool {
+    if let Some(missing) = MISSING_RSYNC
+        .lock()
+        .ok()
+        .and_then(|probed| probed.get(&config.host).copied())
+    {
+        return missing;
+    }
+    let missing = run_ssh_command_output(config, "command -v rsync >/dev/null || echo missing")
+        .is_ok_and(|output| output.trim() == "missing");
+    if let Ok(mut probed) = MISSING_RSYNC.lock() {
+        probed.insert(config.host.clone(), missing);
+    }
+    missing
+}
+
+/// Say that the sync uses tar and what that costs
+pub(crate) fn announce(config: &Config) {
+    if matches!(config.output_level(), OutputLevel::Quiet) {
+        return;
+    }
+    let reason = if config.transport == "tar" {
+        "transport: tar".to_string()
+    } else {
+        format!("no rsync on {}", config.host)
+    };
+    println!(
+        "   ℹ Syncing with tar over ssh ({}); slower, as every file is sent each time",
+        reason
+    );
+}
+
+/// The files a tar sync sends: the git file list, or else every file
+/// outside the excludes
+pub(crate) fn files_to_send(
+    project_dir: &Path,
+    config: &Config,
+    git_list: Option<&[String]>,
+) -> Vec<String> {
+    match git_list {
+        Some(files) => files.to_vec(),
+        None => {
+            let mut files = Vec::new();
+            walk(project_dir, "", &sync_excludes(config, true), &mut files);
+            files
+        }
+    }
+}
+
+/// Recursively collect the files and symlinks that aren't excluded
+fn walk(dir: &Path, prefix: &str, excludes: &ExcludeSet, files: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.flatten().collect();
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let rel = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if excludes.excludes_entry(&rel, file_type.is_dir()) {
+            continue;
+        }
+        if file_type.is_dir() {
+            walk(&entry.path(), &rel, excludes, files);
+        } else {
+            files.push(rel);
+        }
+    }
+}
+
+/// Send `files`, relative to the project directory, into the remote
+/// directory with one tar stream
+pub(crate) fn upload(config: &Config, project_dir: &Path, files: &[String]) -> Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let temp_dir = dirs::cache_dir().unwrap_or_else(env::temp_dir);
+    let list = temp_dir.join(format!("remotebuild_tar_{}", std::process::id()));
+    let mut names = files.join("\0");
+    names.push('\0');
+    fs::write(&list, names).with_context(|| format!("Failed to write {}", list.display()))?;
+
+    let result = (|| {
+        // macOS tar would add AppleDouble `._` files for extended attributes
+        let mut tar = Command::new("tar")
+            .env("COPYFILE_DISABLE", "1")
+            .arg("-C")
+            .arg(project_dir)
+            .arg("--null")
+            .arg("-T")
+            .arg(&list)
+            .arg("-czf")
+            .arg("-")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run tar")?;
+        let archive = tar
+            .stdout
+            .take()
+            .ok_or_else(|| anyhow!("Failed to read tar's output"))?;
+        let extract = format!(
+            "cd {} && {}tar -xzf -",
+            config.remote_dir().shell(),
+            permissions::build_prefix(config)?
+        );
+        let ssh = remote_command(config, &extract)
+            .stdin(archive)
+            .output()
+            .context("Failed to run SSH command")?;
+        let packed = tar.wait_with_output().context("Failed to wait for tar")?;
+
+        if !packed.status.success() {
+            return Err(anyhow!(
+                "tar failed with {}{}",
+                packed.status,
+                indented_tail(&String::from_utf8_lossy(&packed.stderr))
+            ));
+        }
+        if !ssh.status.success() {
+            return Err(anyhow!(
+                "Remote tar failed with {}{}",
+                ssh.status,
+                indented_tail(&String::from_utf8_lossy(&ssh.stderr))
+            ));
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_file(&list);
+    result
+}
+
+/// The remote `cd` and tar members for copying `pattern` back, like rsync
+/// copies it: its last component, or with a trailing `/` the contents
+pub(crate) fn artifact_members(config: &Config, pattern: &str) -> (String, String) {
+    let trimmed = pattern.trim_end_matches('/');
+    let (dir, name) = match trimmed.rsplit_once('/') {
+        _ if pattern.ends_with('/') => (trimmed, "."),
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None => ("", trimmed),
+    };
+    // Left unquoted, so the remote shell expands globs as it does for rsync
+    (remote_cd(config, dir), name.to_string())
+}
+
+/// The remote `cd` and tar members for the paths an artifact pattern
+/// expanded to
+pub(crate) fn expansion_members(config: &Config, expansion: &Expansion) -> (String, String) {
+    let members: Vec<_> = expansion
+        .paths
+        .iter()
+        .map(|path| escape(Cow::Borrowed(path.as_str())))
+        .collect();
+    (remote_cd(config, &expansion.base), members.join(" "))
+}
+
+/// A command entering `dir`, relative to the remote path unless absolute
+fn remote_cd(config: &Config, dir: &str) -> String {
+    if dir.starts_with('/') {
+        format!("cd {}", escape(Cow::Borrowed(dir)))
+    } else if dir.is_empty() {
+        format!("cd {}", config.remote_dir().shell())
+    } else {
+        format!(
+            "cd {} && cd {}",
+            config.remote_dir().shell(),
+            escape(Cow::Borrowed(dir))
+        )
+    }
+}
+
+/// A command copying `members` of the remote directory `cd` enters into
+/// `into` with one tar stream
+///
+/// ssh runs as the first command of a local `sh` pipeline, with its
+/// arguments passed through unchanged; its exit status is tar's.
+pub(crate) fn download(config: &Config, cd: &str, members: &str, into: &Path) -> Command {
+    let pack = format!(
+        "{} && tar -czf - --exclude={} {}",
+        cd, REMOTE_META_DIR, members
+    );
+    let ssh = remote_command(config, &pack);
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg("\"$0\" \"$@\" | tar -xzf - -C \"$REMOTEBUILD_TAR_DIR\"")
+        .arg(ssh.get_program())
+        .args(ssh.get_args())
+        .env("REMOTEBUILD_TAR_DIR", into);
+    command
+}
+
+/// The directory an artifact with a `local` name is extracted into before
+/// [`place`] moves it there
+pub(crate) fn staging_dir(destination: &Path) -> PathBuf {
+    let name = destination
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    destination.with_file_name(format!(".{}.remotebuild-tar", name))
+}
+
+/// Move what was extracted into `staging` to `destination` as rsync would
+/// have copied it: a single file becomes `destination` unless that is a
+/// directory, anything else is moved into it, replacing what was there
+pub(crate) fn place(staging: &Path, destination: &Path) -> Result<()> {
+    let result = (|| {
+        let entries: Vec<_> = fs::read_dir(staging)
+            .with_context(|| format!("Failed to read {}", staging.display()))?
+            .collect::<Result<_, _>>()?;
+        if let [entry] = entries.as_slice() {
+            if !entry.file_type()?.is_dir() && !destination.is_dir() {
+                return fs::rename(entry.path(), destination)
+                    .with_context(|| format!("Failed to write {}", destination.display()));
+            }
+        }
+        fs::create_dir_all(destination)
+            .with_context(|| format!("Failed to create {}", destination.display()))?;
+        for entry in entries {
+            let target = destination.join(entry.file_name());
+            if target.is_dir() && !target.is_symlink() {
+                fs::remove_dir_all(&target)
+                    .with_context(|| format!("Failed to replace {}", target.display()))?;
+            }
+            fs::rename(entry.path(), &target)
+                .with_context(|| format!("Failed to write {}", target.display()))?;
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(staging);
+    result
+}
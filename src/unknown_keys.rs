@@ -0,0 +1,109 @@
+//! Rejecting config keys remotebuild doesn't know
+//!
+//! Keys no option reads, at any depth, used to be dropped silently, so a
+//! typo such as `exclude_pattern` simply had no effect. They are now
+//! collected while the config is deserialized, and loading fails with every
+//! unknown key, where it came from and the closest valid name when one is a
+//! likely typo. `--lax-config` keeps ignoring them, for configs that carry
+//! keys of their own.
+
+use anyhow::{anyhow, Result};
+use serde_yaml::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::Config;
+
+/// Set by `--lax-config`
+static LAX: AtomicBool = AtomicBool::new(false);
+
+/// Ignore unknown keys for the rest of the run
+pub(crate) fn allow() {
+    LAX.store(true, Ordering::SeqCst);
+}
+
+/// Keys of the path of an ignored value, without the steps through options
+/// and newtypes
+pub(crate) fn segments(path: &serde_ignored::Path) -> Vec<String> {
+    let mut segments = match path {
+        serde_ignored::Path::Root => return Vec::new(),
+        serde_ignored::Path::Seq { parent, .. }
+        | serde_ignored::Path::Map { parent, .. }
+        | serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => segments(parent),
+    };
+    match path {
+        serde_ignored::Path::Seq { index, .. } => segments.push(index.to_string()),
+        serde_ignored::Path::Map { key, .. } => segments.push(key.clone()),
+        _ => {}
+    }
+    segments
+}
+
+/// Fail on the `unknown` key paths found while loading `config` from
+/// `source`, unless `--lax-config` was given
+pub(crate) fn check(config: &Config, unknown: &[Vec<String>], source: &str) -> Result<()> {
+    if unknown.is_empty() || LAX.load(Ordering::SeqCst) {
+        return Ok(());
+    }
+    let known = serde_yaml::to_value(config).ok();
+    let lines: Vec<String> = unknown
+        .iter()
+        .map(|path| {
+            let suggestion = path.split_last().and_then(|(key, parent)| {
+                let siblings = known.as_ref().and_then(|known| lookup(known, parent))?;
+                closest(key, siblings)
+            });
+            match suggestion {
+                Some(name) => format!("  {} (did you mean {}?)", path.join("."), name),
+                None => format!("  {}", path.join(".")),
+            }
+        })
+        .collect();
+    Err(anyhow!(
+        "Unknown {} in config file: {}\n{}\n\
+         Pass --lax-config to ignore keys remotebuild doesn't use",
+        if lines.len() == 1 { "key" } else { "keys" },
+        source,
+        lines.join("\n")
+    ))
+}
+
+/// Mapping at `path` in the serialized config
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a serde_yaml::Mapping> {
+    let mut value = value;
+    for segment in path {
+        value = match value {
+            Value::Mapping(mapping) => mapping.get(segment.as_str())?,
+            Value::Sequence(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    value.as_mapping()
+}
+
+/// Key of `siblings` closest to `key`, if it is near enough to be a typo
+fn closest(key: &str, siblings: &serde_yaml::Mapping) -> Option<String> {
+    siblings
+        .keys()
+        .filter_map(Value::as_str)
+        .map(|name| (edit_distance(key, name), name))
+        .filter(|(distance, _)| *distance <= (key.chars().count() / 3).max(2))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name.to_string())
+}
+
+/// Levenshtein distance between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
@@ -0,0 +1,442 @@
+//! Reproducibility check: the same build remotely and locally
+//!
+//! `remotebuild verify` runs the usual remote build, downloading each
+//! artifact pattern into its own temporary directory, then runs
+//! `build_command` (with `env`) locally in the project directory. Every
+//! artifact file is compared by a streamed blake3 hash. Files that differ
+//! are compared byte by byte, again streaming, to report how many bytes
+//! differ and where the first difference is.
+//!
+//! `verify.normalize` is a local command that reads a file on stdin and
+//! prints a normalized version, e.g. with embedded timestamps removed. When it
+//! is set, files whose normalized output matches count as equal.
+//!
+//! Both build durations are printed. Mismatches only fail the command with
+//! `--strict`.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::artifact::Artifact;
+use crate::patterns::glob_match;
+use crate::{
+    container, env_exports, nix, run_remote_build_command, setup, sync_artifacts, sync_to_remote,
+    Config, SyncScope,
+};
+
+/// Size of the chunks files are read in
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Settings of `remotebuild verify`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct VerifyConfig {
+    /// Local command normalizing an artifact read on stdin to stdout
+    #[serde(default)]
+    normalize: Option<String>,
+}
+
+/// Options for a verify run
+pub(crate) struct VerifyOptions {
+    /// Fail when any artifact differs
+    pub(crate) strict: bool,
+    /// Which files the sync considers
+    pub(crate) scope: SyncScope,
+}
+
+/// How one artifact file compares
+enum Comparison {
+    /// Identical bytes
+    Same,
+    /// Equal after `verify.normalize`
+    SameNormalized,
+    /// Different contents
+    Differs(DiffStats),
+    /// Produced only by the remote build
+    RemoteOnly,
+    /// Produced only by the local build
+    LocalOnly,
+}
+
+/// How much two files differ
+struct DiffStats {
+    /// Size of the remote file
+    remote_size: u64,
+    /// Size of the local file
+    local_size: u64,
+    /// Bytes that differ within the shorter length, plus the length difference
+    differing: u64,
+    /// Offset of the first difference
+    first: u64,
+}
+
+/// Build remotely and locally and compare the artifacts, returning the exit
+/// code
+pub(crate) fn run_verify(
+    project_dir: &Path,
+    config: &Config,
+    options: &VerifyOptions,
+) -> Result<i32> {
+    if config.artifacts.is_empty() {
+        return Err(anyhow!("No artifacts configured to compare"));
+    }
+    let normalize = config.verify.as_ref().and_then(|v| v.normalize.as_deref());
+
+    let download_dir = env::temp_dir().join(format!("remotebuild-verify-{}", std::process::id()));
+    let result = verify(project_dir, config, options, normalize, &download_dir);
+    let _ = fs::remove_dir_all(&download_dir);
+    result
+}
+
+/// Run both builds and print the comparison
+fn verify(
+    project_dir: &Path,
+    config: &Config,
+    options: &VerifyOptions,
+    normalize: Option<&str>,
+    download_dir: &Path,
+) -> Result<i32> {
+    println!("🔍 Verifying that remote and local builds match");
+
+    // Remote: the usual pipeline, with each pattern downloaded separately so
+    // its files can be matched to the local ones
+    let remote_start = Instant::now();
+    if config.docker.is_some() {
+        container::prepare(config)?;
+    }
+    nix::check_installed(config)?;
+    sync_to_remote(project_dir, config, options.scope)?;
+    setup::run_setup(config)?;
+    nix::enter_shell(config)?;
+    let build_start = Instant::now();
+    run_remote_build_command(config)?;
+    let remote_build = build_start.elapsed();
+    for (i, artifact) in config.artifacts.iter().enumerate() {
+        let dir = download_dir.join(i.to_string());
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create temp dir: {}", dir.display()))?;
+        // Compared where the local build leaves it, not at its destination
+        let artifact_config = Config {
+            artifacts: vec![Artifact::from(artifact.remote.clone())],
+            rewrite_paths: false,
+            ..config.clone()
+        };
+        sync_artifacts(&artifact_config, project_dir, &dir)?;
+    }
+    let remote_total = remote_start.elapsed();
+
+    let local_build = run_local_build(project_dir, config)?;
+
+    let mut differing = 0;
+    let mut compared = 0;
+    println!();
+    for (i, artifact) in config.artifacts.iter().enumerate() {
+        let remote = files_below(&download_dir.join(i.to_string()));
+        let local = local_files(project_dir, &artifact.remote);
+        let names: Vec<&String> = {
+            let mut names: Vec<&String> = remote.keys().chain(local.keys()).collect();
+            names.sort();
+            names.dedup();
+            names
+        };
+        if names.is_empty() {
+            println!("   - {}: built by neither side", artifact);
+            continue;
+        }
+        for name in names {
+            let comparison = match (remote.get(name), local.get(name)) {
+                (Some(remote), Some(local)) => compare(remote, local, normalize)?,
+                (Some(_), None) => Comparison::RemoteOnly,
+                (None, _) => Comparison::LocalOnly,
+            };
+            compared += 1;
+            if !matches!(comparison, Comparison::Same | Comparison::SameNormalized) {
+                differing += 1;
+            }
+            print_comparison(&display_name(&artifact.remote, name), &comparison);
+        }
+    }
+
+    println!();
+    println!(
+        "   Remote build: {:.1}s ({:.1}s with sync and download)",
+        remote_build.as_secs_f64(),
+        remote_total.as_secs_f64()
+    );
+    println!("   Local build:  {:.1}s", local_build.as_secs_f64());
+    println!("   {}", speed_summary(remote_total, local_build));
+    println!();
+
+    if differing == 0 {
+        println!("✅ All {} artifact files match", compared);
+        return Ok(0);
+    }
+    println!("❌ {} of {} artifact files differ", differing, compared);
+    Ok(if options.strict { 1 } else { 0 })
+}
+
+/// Run the build command locally in the project directory
+fn run_local_build(project_dir: &Path, config: &Config) -> Result<Duration> {
+    println!();
+    println!("🏠 Building locally");
+    let command = format!(
+        "{}{}",
+        env_exports(&config.env)?,
+        config.build_command.script(true)
+    );
+    let start = Instant::now();
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .current_dir(project_dir)
+        .status()
+        .context("Failed to run the local build")?;
+    if !status.success() {
+        return Err(anyhow!("Local build failed with {}", status));
+    }
+    Ok(start.elapsed())
+}
+
+/// Files below a directory, keyed by their path relative to it
+fn files_below(dir: &Path) -> BTreeMap<String, PathBuf> {
+    let mut files = BTreeMap::new();
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            collect(&entry.path(), &name, &mut files);
+        }
+    }
+    files
+}
+
+/// The local files an artifact pattern stands for, keyed like rsync names
+/// them in the download directory: from the last path component on
+fn local_files(project_dir: &Path, artifact: &str) -> BTreeMap<String, PathBuf> {
+    if artifact.ends_with('/') {
+        // A trailing slash downloads the directory's contents
+        return files_below(&project_dir.join(artifact.trim_end_matches('/')));
+    }
+
+    // Absolute artifacts are at the same path on both sides
+    let root = if artifact.starts_with('/') {
+        Path::new("/")
+    } else {
+        project_dir
+    };
+    let mut matches = vec![root.to_path_buf()];
+    for component in artifact.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        let mut next = Vec::new();
+        for dir in &matches {
+            if !component.contains(['*', '?', '[']) {
+                next.push(dir.join(component));
+                continue;
+            }
+            if let Ok(entries) = fs::read_dir(dir) {
+                for entry in entries.filter_map(|entry| entry.ok()) {
+                    if glob_match(component, &entry.file_name().to_string_lossy()) {
+                        next.push(entry.path());
+                    }
+                }
+            }
+        }
+        matches = next;
+    }
+
+    let mut files = BTreeMap::new();
+    for path in matches {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        collect(&path, &name, &mut files);
+    }
+    files
+}
+
+/// Add the regular files at or below `path`, named from `name` down
+fn collect(path: &Path, name: &str, files: &mut BTreeMap<String, PathBuf>) {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return;
+    };
+    if meta.is_file() {
+        files.insert(name.to_string(), path.to_path_buf());
+    } else if meta.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let child = format!("{}/{}", name, entry.file_name().to_string_lossy());
+                collect(&entry.path(), &child, files);
+            }
+        }
+    }
+}
+
+/// The name of a compared file for display, relative to the project
+fn display_name(artifact: &str, name: &str) -> String {
+    let trimmed = artifact.trim_end_matches('/');
+    let parent = if artifact.ends_with('/') {
+        Some(trimmed)
+    } else {
+        trimmed.rsplit_once('/').map(|(parent, _)| parent)
+    };
+    match parent {
+        // A glob in the parent doesn't name a real directory
+        Some(parent) if !parent.contains(['*', '?', '[']) => format!("{}/{}", parent, name),
+        _ => name.to_string(),
+    }
+}
+
+/// Compare a remote and a local file
+fn compare(remote: &Path, local: &Path, normalize: Option<&str>) -> Result<Comparison> {
+    if hash_file(remote)? == hash_file(local)? {
+        return Ok(Comparison::Same);
+    }
+    if let Some(command) = normalize {
+        if hash_normalized(remote, command)? == hash_normalized(local, command)? {
+            return Ok(Comparison::SameNormalized);
+        }
+    }
+    Ok(Comparison::Differs(diff_stats(remote, local)?))
+}
+
+/// blake3 hash of a file, read in chunks
+fn hash_file(path: &Path) -> Result<blake3::Hash> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    hash_reader(file)
+}
+
+/// blake3 hash of a file's output from the normalize command
+fn hash_normalized(path: &Path, command: &str) -> Result<blake3::Hash> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(file)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run verify.normalize")?;
+    let hash = match child.stdout.take() {
+        Some(stdout) => hash_reader(stdout)?,
+        None => return Err(anyhow!("verify.normalize has no output")),
+    };
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow!(
+            "verify.normalize failed with {} on {}",
+            status,
+            path.display()
+        ));
+    }
+    Ok(hash)
+}
+
+/// blake3 hash of everything a reader yields
+fn hash_reader(mut reader: impl Read) -> Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; CHUNK_BYTES];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Count the differing bytes of two files, reading both in lockstep
+fn diff_stats(remote: &Path, local: &Path) -> Result<DiffStats> {
+    let remote_size = fs::metadata(remote)?.len();
+    let local_size = fs::metadata(local)?.len();
+    let mut a = File::open(remote)?;
+    let mut b = File::open(local)?;
+    let mut buf_a = vec![0u8; CHUNK_BYTES];
+    let mut buf_b = vec![0u8; CHUNK_BYTES];
+    let (mut offset, mut differing, mut first) = (0u64, 0u64, None);
+
+    loop {
+        let read_a = read_full(&mut a, &mut buf_a)?;
+        let read_b = read_full(&mut b, &mut buf_b)?;
+        let common = read_a.min(read_b);
+        for (i, (x, y)) in buf_a[..common].iter().zip(&buf_b[..common]).enumerate() {
+            if x != y {
+                differing += 1;
+                first.get_or_insert(offset + i as u64);
+            }
+        }
+        offset += common as u64;
+        if read_a != read_b || read_a == 0 {
+            break;
+        }
+    }
+
+    let common_len = remote_size.min(local_size);
+    Ok(DiffStats {
+        remote_size,
+        local_size,
+        differing: differing + remote_size.abs_diff(local_size),
+        first: first.unwrap_or(common_len),
+    })
+}
+
+/// Read until `buffer` is full or the input ends, returning the bytes read
+fn read_full(input: &mut impl Read, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match input.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Print the outcome for one file
+fn print_comparison(name: &str, comparison: &Comparison) {
+    match comparison {
+        Comparison::Same => println!("   ✓ {}", name),
+        Comparison::SameNormalized => println!("   ✓ {} (after normalization)", name),
+        Comparison::RemoteOnly => println!("   ✗ {}: only built remotely", name),
+        Comparison::LocalOnly => println!("   ✗ {}: only built locally", name),
+        Comparison::Differs(stats) => {
+            let size = if stats.remote_size == stats.local_size {
+                format!("{} bytes", stats.remote_size)
+            } else {
+                format!(
+                    "{} bytes remotely, {} locally",
+                    stats.remote_size, stats.local_size
+                )
+            };
+            let percent = 100.0 * stats.differing as f64
+                / stats.remote_size.max(stats.local_size).max(1) as f64;
+            println!(
+                "   ✗ {}: {} bytes differ ({:.1}%), first at offset {:#x}; {}",
+                name, stats.differing, percent, stats.first, size
+            );
+        }
+    }
+}
+
+/// Which build was faster, and by how much
+fn speed_summary(remote: Duration, local: Duration) -> String {
+    let (remote, local) = (remote.as_secs_f64(), local.as_secs_f64());
+    if remote <= 0.0 || local <= 0.0 {
+        return "Both builds took no measurable time".to_string();
+    }
+    if remote < local {
+        format!(
+            "Remote is {:.1}× faster, including sync and download",
+            local / remote
+        )
+    } else {
+        format!(
+            "Local is {:.1}× faster than the remote round trip",
+            remote / local
+        )
+    }
+}
@@ -0,0 +1,183 @@
+//! Rebuilding on file changes
+//!
+//! `--watch` builds once, then again whenever a synced file changes. Changes
+//! are found by polling the size and modification time of every file the
+//! sync would send, so no platform file-notification API is needed. A change
+//! during a build supersedes it through its [`CancelToken`]: the running
+//! cycle stops at its next phase boundary (a remote build gets
+//! [`crate::supersede::DEBOUNCE`] to finish first) and the next cycle starts
+//! with the new files.
+
+use anyhow::Result;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::patterns::ExcludeSet;
+use crate::supersede::{CancelToken, Superseded};
+use crate::{run_cancellable_build, sync_exclude_patterns, Config, SyncScope};
+
+/// Interval between scans of the project
+const POLL: Duration = Duration::from_millis(500);
+
+/// Build `project_dir` now and after every change, until interrupted
+///
+/// A clean sync is only done for the first build.
+pub(crate) fn run_watch(project_dir: &Path, config: &Config, scope: SyncScope) -> Result<()> {
+    let patterns = sync_exclude_patterns(config, true);
+    let excludes = ExcludeSet::new(patterns.iter().map(String::as_str));
+    let mut seen = fingerprint(project_dir, &excludes);
+    let mut scope = scope;
+    println!(
+        "👀 Watching {} for changes (Ctrl+C to stop)",
+        project_dir.display()
+    );
+
+    loop {
+        let token = CancelToken::default();
+        let (result, changed) = thread::scope(|threads| {
+            let build = threads.spawn(|| run_cancellable_build(project_dir, config, scope, &token));
+            let mut changed = false;
+            while !build.is_finished() {
+                thread::sleep(POLL);
+                if !changed && settled_change(project_dir, &excludes, &mut seen) {
+                    changed = true;
+                    token.cancel();
+                }
+            }
+            let result = build
+                .join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Build thread panicked")));
+            (result, changed)
+        });
+
+        match result {
+            Ok(()) => println!("✅ Build complete; watching for changes"),
+            Err(e) if e.downcast_ref::<Superseded>().is_some() => {
+                println!("🛑 Files changed; cancelled the build to start a new one");
+            }
+            Err(e) => eprintln!("❌ {:#}", e),
+        }
+        if scope == SyncScope::Clean {
+            scope = SyncScope::Full;
+        }
+        if changed {
+            continue;
+        }
+        while !settled_change(project_dir, &excludes, &mut seen) {
+            thread::sleep(POLL);
+        }
+        println!("🔄 Files changed; rebuilding");
+    }
+}
+
+/// Whether the project changed since `seen` and then stayed unchanged for
+/// one more poll, so a save touching several files starts a single build
+fn settled_change(project_dir: &Path, excludes: &ExcludeSet, seen: &mut u64) -> bool {
+    let mut current = fingerprint(project_dir, excludes);
+    if current == *seen {
+        return false;
+    }
+    loop {
+        thread::sleep(POLL);
+        let next = fingerprint(project_dir, excludes);
+        if next == current {
+            *seen = current;
+            return true;
+        }
+        current = next;
+    }
+}
+
+/// Hash of the paths, sizes and modification times of the files under
+/// `project_dir` that the sync would send
+fn fingerprint(project_dir: &Path, excludes: &ExcludeSet) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_dir(project_dir, "", excludes, &mut hasher);
+    hasher.finish()
+}
+
+/// Feed the non-excluded entries below `dir` into `hasher`, in name order
+fn hash_dir(dir: &Path, prefix: &str, excludes: &ExcludeSet, hasher: &mut DefaultHasher) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut entries: Vec<_> = entries.flatten().collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let rel = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+
+        if meta.is_dir() {
+            if !excludes.excludes_entry(&rel, true) {
+                hash_dir(&entry.path(), &rel, excludes, hasher);
+            }
+        } else if !excludes.excludes_entry(&rel, false) {
+            rel.hash(hasher);
+            meta.len().hash(hasher);
+            meta.modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .hash(hasher);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Synced files change the fingerprint, excluded ones don't
+    #[test]
+    fn fingerprint_follows_synced_files() {
+        let dir =
+            std::env::temp_dir().join(format!("remotebuild-test-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("src/main.c"), "int main;").unwrap();
+        let excludes = ExcludeSet::new(["build/", "*.o"]);
+
+        let before = fingerprint(&dir, &excludes);
+        fs::write(dir.join("build/app"), "output").unwrap();
+        fs::write(dir.join("src/main.o"), "object").unwrap();
+        assert_eq!(fingerprint(&dir, &excludes), before);
+
+        fs::write(dir.join("src/main.c"), "int main(void);").unwrap();
+        let edited = fingerprint(&dir, &excludes);
+        assert_ne!(edited, before);
+        fs::write(dir.join("src/util.c"), "").unwrap();
+        assert_ne!(fingerprint(&dir, &excludes), edited);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A change is reported once it settled, and only once
+    #[test]
+    fn change_is_reported_once() {
+        let dir = std::env::temp_dir().join(format!(
+            "remotebuild-test-watch-settle-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let excludes = ExcludeSet::new([]);
+        let mut seen = fingerprint(&dir, &excludes);
+        assert!(!settled_change(&dir, &excludes, &mut seen));
+
+        fs::write(dir.join("new.c"), "").unwrap();
+        assert!(settled_change(&dir, &excludes, &mut seen));
+        assert!(!settled_change(&dir, &excludes, &mut seen));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,604 @@
+//! Monorepo workspace support
+//!
+//! A workspace is a project root whose subdirectories contain their own config
+//! files. The whole repository is synced once using the root config, then each
+//! component is built in its own remote subdirectory. Components whose inputs
+//! have not changed since their last successful build are skipped.
+//!
+//! The pre-sync hook and setup are the root's, run once around the sync. A
+//! component runs the remaining phases of a single-project build, with the
+//! hooks of its own directory.
+//!
+//! The root config is optional: without one, the host comes from the
+//! components and the repository syncs to the default remote path. Affected
+//! detection fingerprints the git file list, so outside of git every
+//! component is built on every run.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::hooks::{self, Hook, RunReport};
+use crate::state::{ComponentState, State};
+use crate::supersede::{CancelToken, Cancellation};
+use crate::{abort, estimate, history, resilient, setup};
+use crate::{
+    get_git_files, load_config, run_build_phases, stable_hash, sync_to_remote, zero_config,
+    BuildFailed, Config, OutputLevel, SyncScope,
+};
+
+/// Maximum directory depth searched for component configs outside of git
+const MAX_DISCOVERY_DEPTH: usize = 4;
+
+/// Options controlling a workspace build
+pub(crate) struct WorkspaceOptions<'a> {
+    /// Name of the config file to look for in each component
+    pub(crate) config_name: &'a str,
+    /// Which files the sync considers
+    pub(crate) scope: SyncScope,
+    /// Stop at the first failing component
+    pub(crate) fail_fast: bool,
+    /// Build components even when their inputs are unchanged
+    pub(crate) rebuild_unchanged: bool,
+}
+
+/// A component discovered in the workspace
+struct Component {
+    /// Path of the component relative to the workspace root
+    rel_path: String,
+    /// The component's own configuration
+    config: Config,
+    /// Fingerprint of the component's inputs, if it could be computed
+    fingerprint: Option<String>,
+}
+
+/// Result of building a single component
+enum Outcome {
+    /// Built successfully in the given time
+    Built(Duration),
+    /// Skipped because nothing changed since the last successful build
+    Unchanged,
+    /// The build or artifact download failed
+    Failed(Duration),
+    /// Not attempted because an earlier component failed with --fail-fast
+    NotRun,
+}
+
+/// Build every component of the workspace rooted at `root`
+pub(crate) fn run_workspace_build(
+    root: &Path,
+    root_config: &Config,
+    options: &WorkspaceOptions,
+) -> Result<()> {
+    let output = root_config.output_level();
+
+    let files = get_git_files(root, root_config.sync_submodules).unwrap_or_default();
+    let component_paths = discover_components(root, options.config_name, &files)?;
+    if component_paths.is_empty() {
+        return Err(anyhow!(
+            "No components found: no subdirectory of {} contains a {} file",
+            root.display(),
+            options.config_name
+        ));
+    }
+
+    let components = component_paths
+        .into_iter()
+        .map(|rel_path| load_component(root, root_config, options.config_name, &files, rel_path))
+        .collect::<Result<Vec<_>>>()?;
+
+    estimate::load(root);
+    let mut state = State::load(root);
+    let affected_flags: Vec<bool> = components
+        .iter()
+        .map(|component| {
+            options.rebuild_unchanged
+                || match (
+                    &component.fingerprint,
+                    state.components.get(&component.rel_path),
+                ) {
+                    (Some(current), Some(previous)) => *current != previous.fingerprint,
+                    _ => true,
+                }
+        })
+        .collect();
+    let affected = affected_flags.iter().filter(|a| **a).count();
+
+    if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) {
+        println!(
+            "🗂  Workspace: {} components, {} affected",
+            components.len(),
+            affected
+        );
+        println!();
+    }
+
+    if affected == 0 {
+        if matches!(output, OutputLevel::Normal | OutputLevel::Verbose) {
+            println!("✅ All {} components are up to date", components.len());
+        }
+        return Ok(());
+    }
+
+    // Sync the whole repository once; components build in subdirectories of it
+    let guard = abort::RunGuard::new(None);
+    let report = RunReport::new(root, root_config);
+    guard.enter("pre-sync hook");
+    hooks::run_hook(root, root_config, Hook::PreSync, &report)?;
+    guard.enter("sync");
+    let mut reconnects = resilient::Reconnects::default();
+    resilient::retry(root_config, &mut reconnects, "Sync", || {
+        sync_to_remote(root, root_config, options.scope)
+    })?;
+    guard.enter("setup");
+    setup::run_setup(root_config)?;
+    drop(guard);
+
+    let token = CancelToken::default();
+    let mut outcomes = Vec::with_capacity(components.len());
+    let mut stop = false;
+    for (component, is_affected) in components.iter().zip(affected_flags) {
+        if !is_affected {
+            outcomes.push(Outcome::Unchanged);
+            continue;
+        }
+        if stop {
+            outcomes.push(Outcome::NotRun);
+            continue;
+        }
+
+        print_header(output, &component.rel_path);
+        let start = Instant::now();
+        let result = build_component(root, component, options.scope, &token);
+        match result {
+            Ok(()) => {
+                if let Some(fingerprint) = &component.fingerprint {
+                    state.components.insert(
+                        component.rel_path.clone(),
+                        ComponentState {
+                            fingerprint: fingerprint.clone(),
+                            last_success: unix_now(),
+                        },
+                    );
+                }
+                outcomes.push(Outcome::Built(start.elapsed()));
+            }
+            Err(e) => {
+                eprintln!("   ✗ {}: {}", component.rel_path, e);
+                stop = options.fail_fast;
+                outcomes.push(Outcome::Failed(start.elapsed()));
+            }
+        }
+        if !matches!(output, OutputLevel::Quiet) {
+            println!();
+        }
+    }
+
+    // The sync updated the state file too, so only the components are merged in
+    let mut latest = State::load(root);
+    latest.components = state.components;
+    if let Err(e) = latest.save(root) {
+        eprintln!("   ⚠ Warning: Could not save workspace state: {}", e);
+    }
+
+    print_summary(&components, &outcomes);
+
+    let failed: Vec<&str> = components
+        .iter()
+        .zip(&outcomes)
+        .filter(|(_, outcome)| matches!(outcome, Outcome::Failed(_)))
+        .map(|(component, _)| component.rel_path.as_str())
+        .collect();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} of {} components failed: {}",
+            failed.len(),
+            affected,
+            failed.join(", ")
+        ))
+    }
+}
+
+/// Print the line naming the component built next, bold on a terminal
+fn print_header(output: OutputLevel, rel_path: &str) {
+    if matches!(output, OutputLevel::Quiet) {
+        return;
+    }
+    if std::io::stdout().is_terminal() {
+        println!("\x1b[1m── {} ──\x1b[0m", rel_path);
+    } else {
+        println!("── {} ──", rel_path);
+    }
+}
+
+/// Build one component in the synced tree, with the phases and hooks of a
+/// single-project build except the sync and setup
+///
+/// The component's hooks are those of its own directory. Its build is
+/// recorded in the history like that of a project.
+fn build_component(
+    root: &Path,
+    component: &Component,
+    scope: SyncScope,
+    token: &CancelToken,
+) -> Result<()> {
+    let config = &component.config;
+    let component_dir = root.join(&component.rel_path);
+    let cancel = Cancellation::start(&component_dir, config, token);
+    let guard = abort::RunGuard::new(Some(component.rel_path.clone()));
+    let mut report = RunReport::new(&component_dir, config);
+    let result = run_build_phases(&component_dir, config, scope, &mut report, &guard, &cancel);
+    if let Err(e) = &result {
+        report.exit_code = e
+            .downcast_ref::<BuildFailed>()
+            .and_then(|b| b.status.code());
+    }
+
+    if let Some(build) = report.phases.iter().find(|p| p.name == "build") {
+        let duration = Duration::from_secs_f64(build.duration_secs);
+        let tags = history::RunTags::new(scope);
+        history::record_build(root, config, report.exit_code, duration, tags);
+    }
+
+    if let Err(e) = result {
+        report.error = Some(e.to_string());
+        if let Err(hook_error) = hooks::run_hook(&component_dir, config, Hook::OnFailure, &report) {
+            eprintln!("   ⚠ Warning: {}", hook_error);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Config of a workspace root without a config file of its own
+///
+/// The components must agree on the host; the remote path is left to the
+/// default for the root directory.
+pub(crate) fn root_config(root: &Path, config_name: &str) -> Result<Config> {
+    let mut config = zero_config()?;
+    let files = get_git_files(root, config.sync_submodules).unwrap_or_default();
+    let mut hosts: Vec<(String, String)> = Vec::new();
+    for rel_path in discover_components(root, config_name, &files)? {
+        let config = load_config(&root.join(&rel_path).join(config_name))?;
+        if !config.host.is_empty() {
+            hosts.push((rel_path, config.host));
+        }
+    }
+
+    let Some((first_path, host)) = hosts.first() else {
+        return Err(anyhow!(
+            "No host configured: set host in a component's {name} or a {name} in {}, \
+             or pass --host",
+            root.display(),
+            name = config_name
+        ));
+    };
+    if let Some((rel_path, other)) = hosts.iter().find(|(_, other)| other != host) {
+        return Err(anyhow!(
+            "Components {} and {} use different hosts ({} and {}); set host in a {} in {} \
+             or pass --host",
+            first_path,
+            rel_path,
+            host,
+            other,
+            config_name,
+            root.display()
+        ));
+    }
+
+    config.host = host.clone();
+    Ok(config)
+}
+
+/// Print the per-component summary table
+fn print_summary(components: &[Component], outcomes: &[Outcome]) {
+    let width = components
+        .iter()
+        .map(|c| c.rel_path.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    println!("📋 Workspace summary");
+    for (component, outcome) in components.iter().zip(outcomes) {
+        let (mark, detail) = match outcome {
+            Outcome::Built(duration) => ("✓", format!("built in {:.1}s", duration.as_secs_f64())),
+            Outcome::Unchanged => ("-", "unchanged".to_string()),
+            Outcome::Failed(duration) => {
+                ("✗", format!("failed after {:.1}s", duration.as_secs_f64()))
+            }
+            Outcome::NotRun => ("-", "not run (fail-fast)".to_string()),
+        };
+        println!(
+            "   {} {:<width$}  {}",
+            mark,
+            component.rel_path,
+            detail,
+            width = width
+        );
+    }
+}
+
+/// Find component directories containing a config file, relative to `root`
+///
+/// When the workspace is a git repository the tracked file list is used,
+/// otherwise the directory tree is walked to a limited depth.
+fn discover_components(root: &Path, config_name: &str, files: &[String]) -> Result<Vec<String>> {
+    let mut components: Vec<String> = if files.is_empty() {
+        let mut found = Vec::new();
+        walk_for_configs(root, root, config_name, 0, &mut found)?;
+        found
+    } else {
+        files
+            .iter()
+            .filter_map(|file| file.strip_suffix(config_name))
+            .filter_map(|dir| dir.strip_suffix('/'))
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| dir.to_string())
+            .collect()
+    };
+
+    components.sort();
+    components.dedup();
+    Ok(components)
+}
+
+/// Recursively collect directories below `dir` that contain a config file
+fn walk_for_configs(
+    root: &Path,
+    dir: &Path,
+    config_name: &str,
+    depth: usize,
+    found: &mut Vec<String>,
+) -> Result<()> {
+    if depth >= MAX_DISCOVERY_DEPTH {
+        return Ok(());
+    }
+
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if hidden || !path.is_dir() {
+            continue;
+        }
+
+        if path.join(config_name).is_file() {
+            if let Ok(rel) = path.strip_prefix(root) {
+                found.push(rel.to_string_lossy().replace('\\', "/"));
+            }
+        }
+        walk_for_configs(root, &path, config_name, depth + 1, found)?;
+    }
+
+    Ok(())
+}
+
+/// Load a component config and adapt it to build inside the workspace sync
+fn load_component(
+    root: &Path,
+    root_config: &Config,
+    config_name: &str,
+    files: &[String],
+    rel_path: String,
+) -> Result<Component> {
+    let mut config = load_config(&root.join(&rel_path).join(config_name))?;
+
+    if config.host.is_empty() {
+        config.host = root_config.host.clone();
+    } else if config.host != root_config.host {
+        return Err(anyhow!(
+            "Component {} uses host {} but the workspace syncs to {}",
+            rel_path,
+            config.host,
+            root_config.host
+        ));
+    }
+
+    if config.build_command.is_empty() {
+        return Err(anyhow!("Component {} has no build_command", rel_path));
+    }
+
+    config.remote_path = format!(
+        "{}/{}",
+        root_config.remote_path.trim_end_matches('/'),
+        rel_path
+    );
+    config.output = root_config.output.clone();
+    config.resilient |= root_config.resilient;
+    config.presynced = true;
+    config.local_log_dir = root_config.local_log_dir.clone();
+    config.keep_local_logs = root_config.keep_local_logs;
+
+    let mut inputs = vec![rel_path.clone()];
+    inputs.extend(config.depends_on.iter().cloned());
+    let fingerprint = if files.is_empty() {
+        None
+    } else {
+        Some(fingerprint(root, files, &inputs))
+    };
+
+    Ok(Component {
+        rel_path,
+        config,
+        fingerprint,
+    })
+}
+
+/// Hash the paths, sizes and modification times of all files under `inputs`
+fn fingerprint(root: &Path, files: &[String], inputs: &[String]) -> String {
+    let prefixes: Vec<&str> = inputs.iter().map(|p| p.trim_end_matches('/')).collect();
+    let mut matching: Vec<&String> = files
+        .iter()
+        .filter(|file| {
+            prefixes.iter().any(|prefix| {
+                file.as_str() == *prefix
+                    || (file.starts_with(prefix) && file[prefix.len()..].starts_with('/'))
+            })
+        })
+        .collect();
+    matching.sort();
+    matching.dedup();
+
+    let mut data = Vec::new();
+    for file in matching {
+        data.extend_from_slice(file.as_bytes());
+        data.push(0);
+        match fs::metadata(root.join(file)) {
+            Ok(meta) => {
+                let mtime = meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_nanos())
+                    .unwrap_or(0);
+                data.extend_from_slice(format!("{}:{}", meta.len(), mtime).as_bytes());
+            }
+            Err(_) => data.extend_from_slice(b"missing"),
+        }
+        data.push(b'\n');
+    }
+
+    format!("{:016x}", stable_hash(&data))
+}
+
+/// Current time as seconds since the Unix epoch
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A workspace in the temp directory with the given component configs
+    fn workspace(name: &str, components: &[(&str, &str)]) -> std::path::PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("remotebuild-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (rel_path, config) in components {
+            fs::create_dir_all(root.join(rel_path)).unwrap();
+            fs::write(root.join(rel_path).join(".remotebuild.yaml"), config).unwrap();
+        }
+        root
+    }
+
+    /// Without a root config the host comes from the components that set one
+    #[test]
+    fn root_config_takes_component_host() {
+        let root = workspace(
+            "workspace-host",
+            &[
+                ("firmware", "host: build-box\nbuild_command: make"),
+                ("tools", "build_command: cargo build"),
+            ],
+        );
+        let config = root_config(&root, ".remotebuild.yaml").unwrap();
+        assert_eq!(config.host, "build-box");
+        assert!(config.remote_path.is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Components on different hosts, or none at all, need a root config
+    #[test]
+    fn root_config_needs_one_host() {
+        let root = workspace(
+            "workspace-hosts",
+            &[
+                ("firmware", "host: build-box\nbuild_command: make"),
+                ("tools", "host: other-box\nbuild_command: cargo build"),
+            ],
+        );
+        let error = root_config(&root, ".remotebuild.yaml").unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("firmware and tools use different hosts"));
+        fs::remove_dir_all(&root).unwrap();
+
+        let root = workspace("workspace-no-host", &[("tools", "build_command: make")]);
+        let error = root_config(&root, ".remotebuild.yaml").unwrap_err();
+        assert!(error.to_string().starts_with("No host configured"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Components run their own hooks and resilient builds in the tree the
+    /// root's pre-sync hook and sync prepared
+    #[test]
+    fn components_build_like_projects() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let host = "workspace-hooks";
+        let state = crate::resilient::tests::install_fake_ssh();
+        fs::write(state.join(format!("{}.drops", host)), "1").unwrap();
+        let component = "transport: tar\nremote_shell: login\n";
+        let root = workspace(
+            "workspace-phases",
+            &[
+                (
+                    "app",
+                    &format!(
+                        "{}build_command: echo built > out.bin\nartifacts: [out.bin]",
+                        component
+                    ),
+                ),
+                ("broken", &format!("{}build_command: exit 3", component)),
+            ],
+        );
+        for (dir, hook) in [
+            ("", "pre-sync"),
+            ("app", "post-artifacts"),
+            ("broken", "on-failure"),
+        ] {
+            let hooks = root.join(dir).join(".remotebuild/hooks");
+            fs::create_dir_all(&hooks).unwrap();
+            let script = format!("#!/bin/sh\ncat > {}.json\n", hook);
+            fs::write(hooks.join(hook), script).unwrap();
+            fs::set_permissions(hooks.join(hook), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let root_config: Config = serde_yaml::from_str(&format!(
+            "host: {}\nremote_path: {}-remote\ntransport: tar\nremote_shell: login\n\
+             resilient: true\noutput: quiet",
+            host,
+            root.display()
+        ))
+        .unwrap();
+        let options = WorkspaceOptions {
+            config_name: ".remotebuild.yaml",
+            scope: SyncScope::Full,
+            fail_fast: false,
+            rebuild_unchanged: true,
+        };
+
+        let error = run_workspace_build(&root, &root_config, &options).unwrap_err();
+        assert_eq!(error.to_string(), "1 of 2 components failed: broken");
+        let report = |path: &str| -> serde_json::Value {
+            serde_json::from_str(&fs::read_to_string(root.join(path)).unwrap()).unwrap()
+        };
+        assert_eq!(report("pre-sync.json")["phases"], serde_json::json!([]));
+        assert_eq!(
+            fs::read_to_string(root.join("app/out.bin")).unwrap(),
+            "built\n"
+        );
+        let app = report("app/post-artifacts.json");
+        assert_eq!(app["exit_code"], 0);
+        assert_eq!(app["reconnects"], 1);
+        let phases: Vec<&str> = app["phases"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|phase| phase["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(phases, ["build", "artifacts"]);
+        assert_eq!(report("broken/on-failure.json")["exit_code"], 3);
+        fs::remove_dir_all(&root).unwrap();
+        let _ = fs::remove_dir_all(format!("{}-remote", root.display()));
+    }
+}